@@ -0,0 +1,186 @@
+use std::env;
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::name_prefix;
+use crate::model::{Model, Piece};
+use crate::output::PendingFiles;
+use crate::{Args, GroupBy};
+
+const SCRIPT_IMPORTS: &str = r#"import gzip
+import re
+
+import bpy
+from bpy_extras.io_utils import axis_conversion
+from mathutils import Matrix
+"#;
+
+/// Builds `PIECES` from the `# transform:` comments of an obj written with
+/// --embed-transforms, each pivot being its parent's plus the offset.
+const READ_TRANSFORMS: &str = r##"
+
+TRANSFORM = re.compile(r"# transform:(?: parent=(\S+))? offset=(\S+) (\S+) (\S+)$")
+
+
+def read_transforms(path):
+    pieces = []
+    indexes = {}
+    transform = None
+    opener = gzip.open if path.endswith(".gz") else open
+    with opener(path, "rt") as obj:
+        for line in obj:
+            line = line.rstrip("\n")
+            match = TRANSFORM.match(line)
+            if match:
+                transform = match
+            elif line.startswith("o ") and transform:
+                name = line[2:]
+                parent = transform.group(1)
+                offset = [float(transform.group(i)) for i in (2, 3, 4)]
+                if parent is None:
+                    index = None
+                    pivot = tuple(offset)
+                else:
+                    index = indexes[parent]
+                    base = pieces[index][2]
+                    pivot = tuple(b + o for b, o in zip(base, offset))
+                indexes[name] = len(pieces)
+                pieces.append((name, index, pivot))
+                transform = None
+    return pieces
+"##;
+
+/// Runs inside Blender after the obj is imported. Each mesh is found by its
+/// `o` name, and the importer's transform of that mesh is reused so the
+/// empties get the same axis conversion and scale.
+const SCRIPT_BODY: &str = r#"
+
+def find_mesh(name, taken):
+    # blender adds .001 and so on to names already in the scene
+    pattern = re.compile(re.escape(name) + r"(\.\d+)?$")
+    for obj in sorted(bpy.data.objects, key=lambda obj: obj.name):
+        if obj.type == "MESH" and obj.name not in taken and pattern.match(obj.name):
+            taken.add(obj.name)
+            return obj
+    return None
+
+
+# the importer's default axis conversion, for pieces without a mesh
+fallback = axis_conversion(from_forward="-Z", from_up="Y").to_4x4()
+
+taken = set()
+empties = []
+meshes = []
+for name, parent, pivot in PIECES:
+    mesh = find_mesh(name, taken)
+    conversion = mesh.matrix_world.copy() if mesh else fallback
+    if LOCAL and mesh:
+        # the mesh was written around its pivot, so it goes there
+        mesh.matrix_world = conversion @ Matrix.Translation(pivot)
+
+    empty = bpy.data.objects.new(name + "_pivot", None)
+    empty.empty_display_type = "PLAIN_AXES"
+    bpy.context.collection.objects.link(empty)
+    empty.matrix_world = conversion @ Matrix.Translation(pivot)
+    empties.append(empty)
+    meshes.append(mesh)
+
+for (name, parent, pivot), empty, mesh in zip(PIECES, empties, meshes):
+    if parent is not None:
+        empty.parent = empties[parent]
+        empty.matrix_parent_inverse = empties[parent].matrix_world.inverted()
+    if mesh:
+        mesh.parent = empty
+        mesh.matrix_parent_inverse = empty.matrix_world.inverted()
+    else:
+        print("no mesh named", name, "only its empty was made")
+"#;
+
+fn collect_pieces<'a>(
+    piece: &'a Piece,
+    parent: Option<usize>,
+    pieces: &mut Vec<(&'a Piece, Option<usize>)>,
+) {
+    let index = pieces.len();
+    pieces.push((piece, parent));
+    for child in &piece.children {
+        collect_pieces(child, Some(index), pieces);
+    }
+}
+
+/// Writes `<stem>.py`, a script that rebuilds the piece tree in Blender as
+/// empties at each pivot and parents the imported meshes to them. Returns
+/// the path written.
+pub fn write_blender_helper(
+    args: &Args,
+    model: &Model,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<String> {
+    let mut pieces = Vec::new();
+    for piece in &model.pieces {
+        collect_pieces(piece, None, &mut pieces);
+    }
+
+    let path = output_stem.to_owned() + ".py";
+    let file = pending.create(&path)?;
+    let mut py_writter = BufWriter::new(file);
+
+    writeln!(
+        py_writter,
+        "# Generated by ta-3do-to-obj. Import {}.obj into Blender, then run this",
+        output_stem
+    )?;
+    writeln!(
+        py_writter,
+        "# from the text editor to parent each piece to an empty at its pivot."
+    )?;
+    writeln!(py_writter)?;
+    py_writter.write_all(SCRIPT_IMPORTS.as_bytes())?;
+    writeln!(py_writter)?;
+
+    let local = args.embed_transforms && args.group_by == GroupBy::Piece;
+    writeln!(
+        py_writter,
+        "LOCAL = {}",
+        if local { "True" } else { "False" }
+    )?;
+    if args.helper_reads_transforms {
+        let mut obj_path = env::current_dir().unwrap_or_default();
+        obj_path.push(output_stem.to_owned() + ".obj");
+        if args.compress {
+            obj_path.as_mut_os_string().push(".gz");
+        }
+        let obj_path = serde_json::to_string(&obj_path.to_string_lossy()).unwrap();
+        writeln!(py_writter, "OBJ_PATH = {}", obj_path)?;
+        py_writter.write_all(READ_TRANSFORMS.as_bytes())?;
+        writeln!(py_writter)?;
+        writeln!(py_writter)?;
+        writeln!(py_writter, "PIECES = read_transforms(OBJ_PATH)")?;
+        py_writter.write_all(SCRIPT_BODY.as_bytes())?;
+        py_writter.flush()?;
+        return Ok(path);
+    }
+
+    // name as written to the obj, index of the parent piece, pivot
+    let prefix = name_prefix(args, output_stem);
+    writeln!(py_writter, "PIECES = [")?;
+    for (piece, parent) in pieces {
+        let name = serde_json::to_string(&format!("{}{}", prefix, piece.name)).unwrap();
+        let parent = match parent {
+            Some(parent) => parent.to_string(),
+            None => "None".to_string(),
+        };
+        let [x, y, z] = piece.origin;
+        writeln!(
+            py_writter,
+            "    ({}, {}, ({:?}, {:?}, {:?})),",
+            name, parent, x, y, z
+        )?;
+    }
+    writeln!(py_writter, "]")?;
+
+    py_writter.write_all(SCRIPT_BODY.as_bytes())?;
+    py_writter.flush()?;
+
+    Ok(path)
+}
@@ -0,0 +1,151 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Face, Material, Model};
+
+/// Limits a model has to fit in for a game or renderer, any of which can be
+/// left out.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    /// vertexes in any one piece
+    pub piece_vertexes: Option<usize>,
+    /// triangles in the whole model once every face is split into them
+    pub triangles: Option<usize>,
+    pub pieces: Option<usize>,
+    /// width and height of any texture, as saved
+    pub texture_size: Option<u32>,
+}
+
+/// Names of the budgets that can be given instead of a file.
+const PRESETS: [&str; 2] = ["spring", "index16"];
+
+fn preset(name: &str) -> Option<Budget> {
+    match name {
+        // Spring keeps piece vertexes in 16 bits
+        "spring" => Some(Budget {
+            piece_vertexes: Some(65535),
+            texture_size: Some(4096),
+            ..Budget::default()
+        }),
+        // 16 bit index buffers, shared by the whole model
+        "index16" => Some(Budget {
+            piece_vertexes: Some(65535),
+            triangles: Some(65535 / 3),
+            texture_size: Some(2048),
+            ..Budget::default()
+        }),
+        _ => None,
+    }
+}
+
+impl Budget {
+    /// The preset called `spec`, or else the toml file at that path.
+    pub fn load(spec: &str) -> Result<Budget, String> {
+        if let Some(budget) = preset(spec) {
+            return Ok(budget);
+        }
+        let text = fs::read_to_string(spec).map_err(|err| {
+            format!(
+                "{} is neither a budget preset ({}) nor a readable file: {}",
+                spec,
+                PRESETS.join(", "),
+                err
+            )
+        })?;
+        toml::from_str(&text).map_err(|err| format!("{} isn't a budget: {}", spec, err))
+    }
+
+    /// Every way `model` and its textures go over the budget, naming what
+    /// does.
+    pub fn check(
+        &self,
+        model: &Model,
+        extracted: &HashMap<String, ExtractedTexture>,
+    ) -> Vec<String> {
+        let mut violations = Vec::new();
+        let pieces = model.all_pieces();
+
+        if let Some(max) = self.piece_vertexes {
+            for piece in &pieces {
+                if piece.vertexes.len() > max {
+                    violations.push(format!(
+                        "piece {} has {} vertexes, over the budget of {}",
+                        piece.name,
+                        piece.vertexes.len(),
+                        max
+                    ));
+                }
+            }
+        }
+
+        if let Some(max) = self.triangles {
+            let triangles = |faces: &[Face]| -> usize {
+                faces
+                    .iter()
+                    .map(|face| face.vertex_indexes.len().saturating_sub(2))
+                    .sum()
+            };
+            let total: usize = pieces.iter().map(|piece| triangles(&piece.faces)).sum();
+            if total > max {
+                let mut biggest: Vec<(&str, usize)> = pieces
+                    .iter()
+                    .map(|piece| (piece.name.as_str(), triangles(&piece.faces)))
+                    .collect();
+                biggest.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+                let biggest: Vec<String> = biggest
+                    .iter()
+                    .take(3)
+                    .map(|(name, count)| format!("{} {}", name, count))
+                    .collect();
+                violations.push(format!(
+                    "the model has {} triangles, over the budget of {}, most in {}",
+                    total,
+                    max,
+                    biggest.join(", ")
+                ));
+            }
+        }
+
+        if let Some(max) = self.pieces {
+            if pieces.len() > max {
+                violations.push(format!(
+                    "the model has {} pieces, over the budget of {}",
+                    pieces.len(),
+                    max
+                ));
+            }
+        }
+
+        if let Some(max) = self.texture_size {
+            let mut names: Vec<&String> = extracted.keys().collect();
+            names.sort();
+            for name in names {
+                let texture = &extracted[name];
+                if texture.canvas_width > max || texture.canvas_height > max {
+                    let users: Vec<&str> = pieces
+                        .iter()
+                        .filter(|piece| {
+                            piece.faces.iter().any(
+                                |face| matches!(&face.material, Material::Texture(t) if t == name),
+                            )
+                        })
+                        .map(|piece| piece.name.as_str())
+                        .collect();
+                    violations.push(format!(
+                        "texture {} is {}x{}, over the budget of {}, used by {}",
+                        name,
+                        texture.canvas_width,
+                        texture.canvas_height,
+                        max,
+                        users.join(", ")
+                    ));
+                }
+            }
+        }
+
+        violations
+    }
+}
@@ -0,0 +1,90 @@
+use clap::ValueEnum;
+use glob::{MatchOptions, Pattern};
+
+use crate::model::Model;
+use crate::piece_select::{drop_subtrees, keep_subtrees};
+use crate::Args;
+
+/// Names the community gives pieces only shown while a unit is being built,
+/// used unless --stage-pattern gives others.
+const BUILD_PATTERNS: [&str; 3] = ["*build*", "*scaffold*", "nano*"];
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BuildStage {
+    /// every piece
+    All,
+    /// without the pieces only shown during construction
+    Final,
+    /// only the pieces shown during construction
+    OnlyBuild,
+}
+
+/// Index in `Model::all_pieces` of each piece whose name marks it as a
+/// build piece, with the pattern it matched.
+fn build_pieces(model: &Model, patterns: &[String]) -> Vec<(usize, String)> {
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+    let patterns: Vec<(&String, Pattern)> = patterns
+        .iter()
+        .filter_map(|pattern| match Pattern::new(pattern) {
+            Ok(compiled) => Some((pattern, compiled)),
+            Err(err) => {
+                warning!("ignoring --stage-pattern {}, {}", pattern, err);
+                None
+            }
+        })
+        .collect();
+
+    model
+        .all_pieces()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, piece)| {
+            patterns
+                .iter()
+                .find(|(_, compiled)| compiled.matches_with(&piece.name, options))
+                .map(|(pattern, _)| (i, pattern.to_string()))
+        })
+        .collect()
+}
+
+/// Lists which pieces are build pieces and why, then keeps or drops them,
+/// along with everything under them, as --build-stage asks. Fails when
+/// only the build pieces are wanted and there are none.
+pub fn apply_build_stage(args: &Args, model: &mut Model) -> Result<(), String> {
+    let patterns = if args.stage_pattern.is_empty() {
+        BUILD_PATTERNS.map(String::from).to_vec()
+    } else {
+        args.stage_pattern.clone()
+    };
+    let build = build_pieces(model, &patterns);
+
+    let pieces = model.all_pieces();
+    if build.is_empty() {
+        info!(
+            "No piece is named like a build piece ({}).",
+            patterns.join(", ")
+        );
+    } else {
+        let listed: Vec<String> = build
+            .iter()
+            .map(|(i, pattern)| format!("{} ({})", pieces[*i].name, pattern))
+            .collect();
+        info!("Build pieces: {}.", listed.join(", "));
+    }
+
+    let chosen: Vec<usize> = build.iter().map(|(i, _)| *i).collect();
+    match args.build_stage {
+        BuildStage::All => {}
+        BuildStage::Final => drop_subtrees(model, &chosen),
+        BuildStage::OnlyBuild if chosen.is_empty() => {
+            return Err(
+                "there are no build pieces to write with --build-stage only-build.".to_string(),
+            );
+        }
+        BuildStage::OnlyBuild => keep_subtrees(model, &chosen),
+    }
+    Ok(())
+}
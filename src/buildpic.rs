@@ -0,0 +1,53 @@
+use std::io::{self, BufWriter};
+
+use crate::exporter::expand_prefix;
+use crate::gaf_extractor::find_buildpic;
+use crate::output::PendingFiles;
+use crate::Args;
+
+/// Writes the build picture of the unit `pattern` names, `{name}` being the
+/// output file name, to `<stem>_buildpic.png`. Returns the path written, or
+/// nothing with a warning when no gaf has the picture.
+pub fn write_buildpic(
+    args: &Args,
+    pattern: &str,
+    gaf_folder: &str,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<Option<String>> {
+    let name = expand_prefix(pattern, output_stem);
+    let (image, gaf_name) = match find_buildpic(args, gaf_folder, &name) {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            warning!("no gaf has a build picture called {}", name);
+            return Ok(None);
+        }
+        Err(err) => {
+            warning!("unable to look for the build picture {}, {}", name, err);
+            return Ok(None);
+        }
+    };
+    if args.verbose {
+        info!("Build picture {} came from {}.", name, gaf_name);
+    }
+
+    let path = format!("{}_buildpic.png", output_stem);
+    let file = pending.create(&path)?;
+    let (width, height) = (image.get_width(), image.get_height());
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x, y);
+            data.extend([pixel.r, pixel.g, pixel.b]);
+        }
+    }
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(io::Error::other)?;
+    Ok(Some(path))
+}
@@ -0,0 +1,150 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::frame_cache::GafId;
+use crate::gaf_extractor::gaf_search_path;
+use crate::log;
+use crate::output::write_file;
+use crate::Args;
+
+/// Where the results of previous conversions are remembered, next to the
+/// outputs.
+const CACHE_FILE: &str = ".ta-3do-to-obj-cache.json";
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    input_hash: u64,
+    options: String,
+    outputs: Vec<String>,
+}
+
+/// Inputs that were converted successfully, with the options used and the
+/// files that were written.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    /// where it was loaded from and is saved to
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+lazy_static! {
+    /// Hashes of the gafs read so far, which are too big to read again for
+    /// every model of a batch or every --serve request.
+    static ref GAF_HASHES: Mutex<HashMap<GafId, u64>> = Mutex::new(HashMap::new());
+}
+
+pub fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hash of the file at `path`, 0 when it can't be read.
+fn hash_file(path: &Path) -> u64 {
+    fs::read(path).map_or(0, |data| hash_bytes(&data))
+}
+
+/// Hash of the gaf at `path`, only read again once it has changed.
+fn hash_gaf(path: &Path) -> u64 {
+    let id = GafId::new(path);
+    let mut hashes = GAF_HASHES.lock().unwrap_or_else(|err| err.into_inner());
+    *hashes.entry(id).or_insert_with(|| hash_file(path))
+}
+
+/// Every option that can change the output, and the contents of the files
+/// they name and of the gafs textures come from, so changing any of them
+/// makes the cached conversion stale.
+pub fn options_fingerprint(args: &Args) -> String {
+    let mut contents = Vec::new();
+    for path in [&args.texture_map, &args.mtl_overrides, &args.budget]
+        .into_iter()
+        .flatten()
+    {
+        // --budget can be a preset rather than a file
+        let path = Path::new(path);
+        if path.is_file() {
+            contents.push(format!("{:016x}", hash_file(path)));
+        }
+    }
+    if !args.no_textures && !args.no_mtl {
+        // the conversion warns about the search path itself
+        if let Ok((gaf_paths, _)) = log::quietly(|| gaf_search_path(args, "./gaf_textures/")) {
+            for gaf_path in gaf_paths {
+                contents.push(format!("{:016x}", hash_gaf(&gaf_path)));
+            }
+        }
+    }
+
+    let args = Args {
+        command: None,
+        file: None,
+        rebuild: false,
+        verbose: false,
+        json: false,
+        ..args.clone()
+    };
+    format!(
+        "{} {:?} {}",
+        env!("CARGO_PKG_VERSION"),
+        args,
+        contents.join(" ")
+    )
+}
+
+impl Cache {
+    /// The cache in `folder`, where the outputs it remembers are written.
+    pub fn load(folder: &Path) -> Cache {
+        let path = folder.join(CACHE_FILE);
+        let cache: Cache = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Cache { path, ..cache }
+    }
+
+    /// Whether `input` was last converted from the same bytes with the same
+    /// options, and everything it wrote is still there.
+    pub fn is_fresh(&self, input: &str, input_hash: u64, options: &str) -> bool {
+        self.entries.get(input).is_some_and(|entry| {
+            entry.input_hash == input_hash
+                && entry.options == options
+                && entry
+                    .outputs
+                    .iter()
+                    .all(|output| Path::new(output).exists())
+        })
+    }
+
+    /// What the last conversion of `input` wrote.
+    pub fn outputs(&self, input: &str) -> &[String] {
+        self.entries
+            .get(input)
+            .map_or(&[], |entry| &entry.outputs[..])
+    }
+
+    pub fn record(&mut self, input: &str, input_hash: u64, options: String, outputs: Vec<String>) {
+        self.entries.insert(
+            input.to_string(),
+            CacheEntry {
+                input_hash,
+                options,
+                outputs,
+            },
+        );
+    }
+
+    pub fn save(&self) {
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        let path = self.path.to_string_lossy();
+        if let Err(err) = write_file(&path, |file| std::io::Write::write_all(file, &data)) {
+            warning!("unable to write {}: {}", path, err);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use clap::ValueEnum;
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::name_prefix;
+use crate::geometry::{cross, dot, length, sub};
+use crate::model::{Model, Piece};
+use crate::output::PendingFiles;
+use crate::Args;
+
+/// How thick, as a fraction of its largest extent, the box standing in for
+/// a flat piece is made.
+const THIN_BOX_FRACTION: f64 = 0.01;
+
+/// How far, as a fraction of the piece's size, a point has to be outside a
+/// hull face to count as outside.
+const HULL_EPSILON: f64 = 1e-9;
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CollisionShape {
+    /// the convex hull of each piece
+    Hull,
+    /// the box each piece fits in, along the axes
+    Box,
+}
+
+/// Corners, and faces as indexes into them wound counter-clockwise seen
+/// from outside.
+type Mesh = (Vec<[f64; 3]>, Vec<Vec<usize>>);
+
+fn bounds(points: &[[f64; 3]]) -> ([f64; 3], [f64; 3]) {
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for point in points {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+    (min, max)
+}
+
+fn centroid(points: &[[f64; 3]]) -> [f64; 3] {
+    let mut sum = [0.0; 3];
+    for point in points {
+        for axis in 0..3 {
+            sum[axis] += point[axis];
+        }
+    }
+    sum.map(|s| s / points.len() as f64)
+}
+
+/// Unit normal of the triangle `a b c`, by the right hand rule.
+fn triangle_normal(a: [f64; 3], b: [f64; 3], c: [f64; 3]) -> [f64; 3] {
+    let normal = cross(sub(b, a), sub(c, a));
+    let length = length(normal);
+    normal.map(|n| n / length)
+}
+
+/// The box `points` fit in, made at least `min_thickness` along every axis.
+fn box_mesh(points: &[[f64; 3]], min_thickness: f64) -> Mesh {
+    let (mut min, mut max) = bounds(points);
+    for axis in 0..3 {
+        let missing = min_thickness - (max[axis] - min[axis]);
+        if missing > 0.0 {
+            min[axis] -= missing / 2.0;
+            max[axis] += missing / 2.0;
+        }
+    }
+
+    // bit 0 of a corner's index picks its x, bit 1 its y and bit 2 its z
+    let corners: Vec<[f64; 3]> = (0..8)
+        .map(|i| {
+            [0, 1, 2].map(|axis| {
+                if i & (1 << axis) == 0 {
+                    min[axis]
+                } else {
+                    max[axis]
+                }
+            })
+        })
+        .collect();
+    let sides = [
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+    ];
+    (corners, sides.map(|side| side.to_vec()).to_vec())
+}
+
+/// Convex hull of `points`, grown a point at a time from a tetrahedron of
+/// extreme points. Nothing when the points are all on one plane.
+fn hull(points: &[[f64; 3]]) -> Option<Mesh> {
+    let mut points = points.to_vec();
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    points.dedup();
+    if points.len() < 4 {
+        return None;
+    }
+
+    let (min, max) = bounds(&points);
+    let epsilon = length(sub(max, min)) * HULL_EPSILON;
+    let farthest = |distance: &dyn Fn([f64; 3]) -> f64| {
+        (0..points.len())
+            .max_by(|&a, &b| distance(points[a]).total_cmp(&distance(points[b])))
+            .unwrap()
+    };
+
+    let p0 = 0;
+    let p1 = farthest(&|p| length(sub(p, points[p0])));
+    let axis = sub(points[p1], points[p0]);
+    let p2 = farthest(&|p| length(cross(axis, sub(p, points[p0]))));
+    let normal = triangle_normal(points[p0], points[p1], points[p2]);
+    let p3 = farthest(&|p| dot(normal, sub(p, points[p0])).abs());
+    if !normal.iter().all(|n| n.is_finite())
+        || dot(normal, sub(points[p3], points[p0])).abs() <= epsilon
+    {
+        return None;
+    }
+
+    // stays inside the hull however it grows
+    let inside = centroid(&[points[p0], points[p1], points[p2], points[p3]]);
+    let outward = |face: [usize; 3]| {
+        let [a, b, c] = face.map(|i| points[i]);
+        if dot(triangle_normal(a, b, c), sub(a, inside)) < 0.0 {
+            [face[0], face[2], face[1]]
+        } else {
+            face
+        }
+    };
+    let mut faces: Vec<[usize; 3]> = [[p0, p1, p2], [p0, p3, p1], [p1, p3, p2], [p2, p3, p0]]
+        .map(outward)
+        .to_vec();
+
+    for (i, &point) in points.iter().enumerate() {
+        let visible: Vec<bool> = faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.map(|i| points[i]);
+                dot(triangle_normal(a, b, c), sub(point, a)) > epsilon
+            })
+            .collect();
+        if !visible.contains(&true) {
+            continue;
+        }
+
+        // edges of the visible faces that no other visible face shares
+        // outline the hole the point gets joined to
+        let visible_faces: Vec<[usize; 3]> = faces
+            .iter()
+            .zip(&visible)
+            .filter(|(_, &visible)| visible)
+            .map(|(face, _)| *face)
+            .collect();
+        let edges = |face: [usize; 3]| [(face[0], face[1]), (face[1], face[2]), (face[2], face[0])];
+        let mut horizon = Vec::new();
+        for &face in &visible_faces {
+            for (a, b) in edges(face) {
+                let shared = visible_faces
+                    .iter()
+                    .any(|&other| edges(other).contains(&(b, a)));
+                if !shared {
+                    horizon.push((a, b));
+                }
+            }
+        }
+
+        let mut visible = visible.into_iter();
+        faces.retain(|_| !visible.next().unwrap());
+        faces.extend(horizon.into_iter().map(|(a, b)| [a, b, i]));
+    }
+
+    // only the points on the hull are kept
+    let mut used: Vec<usize> = faces.iter().flatten().copied().collect();
+    used.sort();
+    used.dedup();
+    let corners = used.iter().map(|&i| points[i]).collect();
+    let faces = faces
+        .iter()
+        .map(|face| {
+            face.iter()
+                .map(|i| used.binary_search(i).unwrap())
+                .collect()
+        })
+        .collect();
+    Some((corners, faces))
+}
+
+/// The collision shape of a piece, from the vertexes its faces use. Pieces
+/// without faces or any extent get none.
+fn collision_mesh(shape: CollisionShape, piece: &Piece) -> Option<Mesh> {
+    let mut indexes: Vec<usize> = piece
+        .faces
+        .iter()
+        .flat_map(|face| face.vertex_indexes.iter().map(|&i| i as usize))
+        .filter(|&i| i < piece.vertexes.len())
+        .collect();
+    indexes.sort();
+    indexes.dedup();
+    let points: Vec<[f64; 3]> = indexes.iter().map(|&i| piece.vertexes[i]).collect();
+    if points.is_empty() {
+        return None;
+    }
+
+    let (min, max) = bounds(&points);
+    let size = length(sub(max, min));
+    if size == 0.0 {
+        return None;
+    }
+    let thickness = size * THIN_BOX_FRACTION;
+    match shape {
+        CollisionShape::Box => Some(box_mesh(&points, thickness)),
+        // flat pieces have no volume to wrap
+        CollisionShape::Hull => hull(&points).or_else(|| Some(box_mesh(&points, thickness))),
+    }
+}
+
+/// Writes an `o <piece>_collision` object for each piece with a shape,
+/// `first_vertex` being the obj index the first of its vertexes gets.
+pub fn write_collision(
+    model: &Model,
+    shape: CollisionShape,
+    prefix: &str,
+    obj_writter: &mut impl Write,
+    first_vertex: u32,
+) -> io::Result<()> {
+    let mut first_vertex = first_vertex;
+    for piece in model.all_pieces() {
+        let Some((corners, faces)) = collision_mesh(shape, piece) else {
+            continue;
+        };
+        writeln!(obj_writter)?;
+        writeln!(obj_writter, "o {}{}_collision", prefix, piece.name)?;
+        for v in &corners {
+            writeln!(obj_writter, "v {} {} {}", v[0], v[1], v[2])?;
+        }
+        for face in faces {
+            write!(obj_writter, "f")?;
+            for i in face {
+                write!(obj_writter, " {}", first_vertex + i as u32)?;
+            }
+            writeln!(obj_writter)?;
+        }
+        first_vertex += corners.len() as u32;
+    }
+    Ok(())
+}
+
+/// Writes the collision shapes to `<stem>_collision.obj` on their own,
+/// without materials. Returns the path written.
+pub fn write_collision_obj(
+    args: &Args,
+    model: &Model,
+    shape: CollisionShape,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<String> {
+    let path = format!("{}_collision.obj", output_stem);
+    let file = pending.create(&path)?;
+    let mut obj_writter = BufWriter::new(file);
+    let prefix = name_prefix(args, output_stem);
+    write_collision(model, shape, &prefix, &mut obj_writter, 1)?;
+    obj_writter.flush()?;
+    Ok(path)
+}
@@ -0,0 +1,615 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::exporter::{escape, name_prefix, Exporter, Scene};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model, Piece};
+use crate::output::{relative_path, PendingFiles};
+use crate::{texture_folder, Args, ColorSpace, DedupeMode, TextureFallback};
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+/// A material as it goes in the effects and materials libraries.
+struct DaeMaterial {
+    id: String,
+    name: String,
+    diffuse: [f32; 3],
+    emissive: bool,
+    /// id of the image, for textured materials
+    image: Option<String>,
+}
+
+/// Turns a name into a valid xml id, only letters, digits, underscores,
+/// dashes and dots and not starting with a digit, unique among `taken`.
+fn xml_id(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut id: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !id.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        id.insert(0, '_');
+    }
+
+    let mut unique = id.clone();
+    let mut suffix = 1;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}_{}", id, suffix);
+        suffix += 1;
+    }
+    unique
+}
+
+fn join<T>(values: impl IntoIterator<Item = T>, format: impl Fn(T) -> String) -> String {
+    values.into_iter().map(format).collect::<Vec<_>>().join(" ")
+}
+
+/// The time now as an xml date, for the asset's created and modified.
+fn now() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // days since 1970 to a proleptic gregorian date
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn write_effect(dae_writter: &mut impl Write, material: &DaeMaterial) -> io::Result<()> {
+    let id = &material.id;
+    let [r, g, b] = material.diffuse;
+
+    writeln!(dae_writter, "    <effect id=\"{}-effect\">", id)?;
+    writeln!(dae_writter, "      <profile_COMMON>")?;
+    if let Some(image) = &material.image {
+        writeln!(dae_writter, "        <newparam sid=\"{}-surface\">", id)?;
+        writeln!(dae_writter, "          <surface type=\"2D\">")?;
+        writeln!(dae_writter, "            <init_from>{}</init_from>", image)?;
+        writeln!(dae_writter, "          </surface>")?;
+        writeln!(dae_writter, "        </newparam>")?;
+        writeln!(dae_writter, "        <newparam sid=\"{}-sampler\">", id)?;
+        writeln!(dae_writter, "          <sampler2D>")?;
+        writeln!(dae_writter, "            <source>{}-surface</source>", id)?;
+        // keeps the game's pixels sharp
+        writeln!(dae_writter, "            <minfilter>NEAREST</minfilter>")?;
+        writeln!(dae_writter, "            <magfilter>NEAREST</magfilter>")?;
+        writeln!(dae_writter, "          </sampler2D>")?;
+        writeln!(dae_writter, "        </newparam>")?;
+    }
+    writeln!(dae_writter, "        <technique sid=\"common\">")?;
+    writeln!(dae_writter, "          <lambert>")?;
+    if material.emissive {
+        writeln!(dae_writter, "            <emission>")?;
+        writeln!(
+            dae_writter,
+            "              <color>{} {} {} 1</color>",
+            r, g, b
+        )?;
+        writeln!(dae_writter, "            </emission>")?;
+    }
+    writeln!(dae_writter, "            <diffuse>")?;
+    match &material.image {
+        Some(_) => writeln!(
+            dae_writter,
+            "              <texture texture=\"{}-sampler\" texcoord=\"UVMap\"/>",
+            id
+        )?,
+        None => writeln!(
+            dae_writter,
+            "              <color>{} {} {} 1</color>",
+            r, g, b
+        )?,
+    }
+    writeln!(dae_writter, "            </diffuse>")?;
+    writeln!(dae_writter, "          </lambert>")?;
+    writeln!(dae_writter, "        </technique>")?;
+    writeln!(dae_writter, "      </profile_COMMON>")?;
+    writeln!(dae_writter, "    </effect>")?;
+    Ok(())
+}
+
+/// What every piece of the document is written with.
+type Document<'a> = Scene<'a, DaeMaterial>;
+
+/// A piece's geometry, by the id the nodes instance it with.
+struct Geometry<'a> {
+    id: String,
+    piece: &'a Piece,
+}
+
+/// Gives `piece` and its children a geometry id each, in the order they're
+/// written, for those with faces.
+fn collect_geometries<'a>(
+    piece: &'a Piece,
+    prefix: &str,
+    taken: &mut HashSet<String>,
+    geometries: &mut Vec<Geometry<'a>>,
+) {
+    if !piece.faces.is_empty() {
+        geometries.push(Geometry {
+            id: xml_id(&format!("{}{}-mesh", prefix, piece.name), taken),
+            piece,
+        });
+    }
+    for child in &piece.children {
+        collect_geometries(child, prefix, taken, geometries);
+    }
+}
+
+/// Writes the mesh of a piece around its origin, with a polylist per
+/// material in the order the faces first use them.
+fn write_geometry(
+    document: &Document,
+    dae_writter: &mut impl Write,
+    geometry: &Geometry,
+) -> io::Result<()> {
+    let prefix = &document.prefix;
+    let Geometry { id, piece } = geometry;
+    let [x, y, z] = piece.origin;
+
+    writeln!(
+        dae_writter,
+        "    <geometry id=\"{}\" name=\"{}\">",
+        id,
+        escape(&format!("{}{}", prefix, piece.name))
+    )?;
+    writeln!(dae_writter, "      <mesh>")?;
+
+    writeln!(dae_writter, "        <source id=\"{}-positions\">", id)?;
+    writeln!(
+        dae_writter,
+        "          <float_array id=\"{}-positions-array\" count=\"{}\">{}</float_array>",
+        id,
+        piece.vertexes.len() * 3,
+        join(&piece.vertexes, |v| format!(
+            "{} {} {}",
+            v[0] - x,
+            v[1] - y,
+            v[2] - z
+        ))
+    )?;
+    writeln!(dae_writter, "          <technique_common>")?;
+    writeln!(
+        dae_writter,
+        "            <accessor source=\"#{}-positions-array\" count=\"{}\" stride=\"3\">",
+        id,
+        piece.vertexes.len()
+    )?;
+    for axis in ["X", "Y", "Z"] {
+        writeln!(
+            dae_writter,
+            "              <param name=\"{}\" type=\"float\"/>",
+            axis
+        )?;
+    }
+    writeln!(dae_writter, "            </accessor>")?;
+    writeln!(dae_writter, "          </technique_common>")?;
+    writeln!(dae_writter, "        </source>")?;
+
+    // every corner gets its own uv, as faces don't share theirs
+    let has_uvs = piece
+        .faces
+        .iter()
+        .all(|face| face.uvs.len() == face.vertex_indexes.len());
+    let uvs: Vec<[f64; 2]> = piece
+        .faces
+        .iter()
+        .flat_map(|face| document.uvs(face))
+        .collect();
+    if has_uvs {
+        writeln!(dae_writter, "        <source id=\"{}-uvs\">", id)?;
+        writeln!(
+            dae_writter,
+            "          <float_array id=\"{}-uvs-array\" count=\"{}\">{}</float_array>",
+            id,
+            uvs.len() * 2,
+            join(&uvs, |uv| format!("{} {}", uv[0], uv[1]))
+        )?;
+        writeln!(dae_writter, "          <technique_common>")?;
+        writeln!(
+            dae_writter,
+            "            <accessor source=\"#{}-uvs-array\" count=\"{}\" stride=\"2\">",
+            id,
+            uvs.len()
+        )?;
+        writeln!(
+            dae_writter,
+            "              <param name=\"S\" type=\"float\"/>"
+        )?;
+        writeln!(
+            dae_writter,
+            "              <param name=\"T\" type=\"float\"/>"
+        )?;
+        writeln!(dae_writter, "            </accessor>")?;
+        writeln!(dae_writter, "          </technique_common>")?;
+        writeln!(dae_writter, "        </source>")?;
+    }
+
+    writeln!(dae_writter, "        <vertices id=\"{}-vertices\">", id)?;
+    writeln!(
+        dae_writter,
+        "          <input semantic=\"POSITION\" source=\"#{}-positions\"/>",
+        id
+    )?;
+    writeln!(dae_writter, "        </vertices>")?;
+
+    // where each face's uvs start
+    let mut first_uv = Vec::new();
+    let mut uv_count = 0;
+    for face in &piece.faces {
+        first_uv.push(uv_count);
+        uv_count += face.uvs.len();
+    }
+
+    let mut groups: Vec<(Option<&DaeMaterial>, Vec<usize>)> = Vec::new();
+    for (i, face) in piece.faces.iter().enumerate() {
+        let material = document.material(face);
+        match groups
+            .iter_mut()
+            .find(|(m, _)| m.map(|m| &m.id) == material.map(|m| &m.id))
+        {
+            Some((_, faces)) => faces.push(i),
+            None => groups.push((material, vec![i])),
+        }
+    }
+    for (material, faces) in groups {
+        let material = material
+            .map(|m| format!(" material=\"{}-material\"", m.id))
+            .unwrap_or_default();
+        writeln!(
+            dae_writter,
+            "        <polylist{} count=\"{}\">",
+            material,
+            faces.len()
+        )?;
+        writeln!(
+            dae_writter,
+            "          <input semantic=\"VERTEX\" source=\"#{}-vertices\" offset=\"0\"/>",
+            id
+        )?;
+        if has_uvs {
+            writeln!(
+                dae_writter,
+                "          <input semantic=\"TEXCOORD\" source=\"#{}-uvs\" offset=\"1\" set=\"0\"/>",
+                id
+            )?;
+        }
+        writeln!(
+            dae_writter,
+            "          <vcount>{}</vcount>",
+            join(&faces, |&i| piece.faces[i].vertex_indexes.len().to_string())
+        )?;
+        let indexes = join(&faces, |&i| {
+            let face = &piece.faces[i];
+            join(face.vertex_indexes.iter().enumerate(), |(corner, v)| {
+                if has_uvs {
+                    format!("{} {}", v, first_uv[i] + corner)
+                } else {
+                    v.to_string()
+                }
+            })
+        });
+        writeln!(dae_writter, "          <p>{}</p>", indexes)?;
+        writeln!(dae_writter, "        </polylist>")?;
+    }
+
+    writeln!(dae_writter, "      </mesh>")?;
+    writeln!(dae_writter, "    </geometry>")?;
+    Ok(())
+}
+
+/// Writes `piece` as a node translated from its parent's origin,
+/// instancing its geometry and then holding its children.
+fn write_node(
+    document: &Document,
+    dae_writter: &mut impl Write,
+    piece: &Piece,
+    geometries: &[Geometry],
+    parent_origin: [f64; 3],
+    depth: usize,
+    taken: &mut HashSet<String>,
+) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    let name = format!("{}{}", document.prefix, piece.name);
+    let id = xml_id(&name, taken);
+    let [x, y, z] = piece.origin;
+
+    writeln!(
+        dae_writter,
+        "{}<node id=\"{}\" name=\"{}\" type=\"NODE\">",
+        indent,
+        id,
+        escape(&name)
+    )?;
+    writeln!(
+        dae_writter,
+        "{}  <translate sid=\"location\">{} {} {}</translate>",
+        indent,
+        x - parent_origin[0],
+        y - parent_origin[1],
+        z - parent_origin[2]
+    )?;
+
+    if let Some(geometry) = geometries.iter().find(|g| std::ptr::eq(g.piece, piece)) {
+        writeln!(
+            dae_writter,
+            "{}  <instance_geometry url=\"#{}\">",
+            indent, geometry.id
+        )?;
+        let mut used: Vec<&DaeMaterial> = Vec::new();
+        for face in &piece.faces {
+            if let Some(material) = document.material(face) {
+                if !used.iter().any(|m| m.id == material.id) {
+                    used.push(material);
+                }
+            }
+        }
+        if !used.is_empty() {
+            writeln!(dae_writter, "{}    <bind_material>", indent)?;
+            writeln!(dae_writter, "{}      <technique_common>", indent)?;
+            for material in used {
+                writeln!(
+                    dae_writter,
+                    "{}        <instance_material symbol=\"{}-material\" target=\"#{}-material\">",
+                    indent, material.id, material.id
+                )?;
+                writeln!(
+                    dae_writter,
+                    "{}          <bind_vertex_input semantic=\"UVMap\" input_semantic=\"TEXCOORD\" input_set=\"0\"/>",
+                    indent
+                )?;
+                writeln!(dae_writter, "{}        </instance_material>", indent)?;
+            }
+            writeln!(dae_writter, "{}      </technique_common>", indent)?;
+            writeln!(dae_writter, "{}    </bind_material>", indent)?;
+        }
+        writeln!(dae_writter, "{}  </instance_geometry>", indent)?;
+    }
+
+    for child in &piece.children {
+        write_node(
+            document,
+            dae_writter,
+            child,
+            geometries,
+            piece.origin,
+            depth + 1,
+            taken,
+        )?;
+    }
+
+    writeln!(dae_writter, "{}</node>", indent)?;
+    Ok(())
+}
+
+/// Writes a collada document with a node per piece, translated from its
+/// parent, and a lambert material per colour and texture. Textures are
+/// referenced as the extracted bmps.
+pub struct DaeExporter;
+
+impl Exporter for DaeExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let dae_path = output_stem.to_owned() + ".dae";
+        let prefix = name_prefix(args, output_stem);
+        let file_prefix = if args.prefix_textures { &prefix } else { "" };
+
+        // every id in a document has to be unique
+        let mut taken = HashSet::new();
+        let mut materials = HashMap::new();
+        let mut material_order = Vec::new();
+        let mut images = Vec::new();
+        if !args.no_mtl {
+            let palette = Palette::new(args.fullbright_range.clone());
+            for &colour_index in &model.used_colours {
+                let name = Material::Colour(colour_index).name(args);
+                if materials.contains_key(&name) {
+                    continue;
+                }
+                let full_name = format!("{}{}", prefix, name);
+                material_order.push(name.clone());
+                materials.insert(
+                    name,
+                    DaeMaterial {
+                        id: xml_id(&full_name, &mut taken),
+                        name: full_name,
+                        diffuse: colour_factors(
+                            palette.get(colour_index),
+                            args.color_space == ColorSpace::Linear,
+                        ),
+                        emissive: !args.no_emissive && palette.is_fullbright(colour_index),
+                        image: None,
+                    },
+                );
+            }
+
+            let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+            let untextured = args.no_textures && args.texture_fallback == TextureFallback::Kd;
+            for texture in &model.used_textures {
+                let extracted = textures.get(texture);
+                if merge_duplicates && extracted.is_some_and(|t| t.duplicate_of.is_some()) {
+                    continue;
+                }
+
+                let full_name = format!("{}{}", prefix, texture);
+                let id = xml_id(&full_name, &mut taken);
+                let image = (!untextured).then(|| {
+                    let file = match extracted {
+                        Some(t) => relative_path(&t.path(), output_stem),
+                        None => relative_path(
+                            &format!("{}{}{}.bmp", texture_folder(args), file_prefix, texture),
+                            output_stem,
+                        ),
+                    };
+                    let image = xml_id(&format!("{}-image", id), &mut taken);
+                    images.push((image.clone(), texture.clone(), file));
+                    image
+                });
+                material_order.push(texture.clone());
+                materials.insert(
+                    texture.clone(),
+                    DaeMaterial {
+                        id,
+                        name: full_name,
+                        diffuse: [0.5, 0.5, 0.5],
+                        emissive: false,
+                        image,
+                    },
+                );
+            }
+        }
+
+        let mut geometries = Vec::new();
+        for piece in &model.pieces {
+            collect_geometries(piece, &prefix, &mut taken, &mut geometries);
+        }
+
+        let file = pending.create(&dae_path)?;
+        let mut dae_writter = BufWriter::new(file);
+        let created = now();
+        writeln!(dae_writter, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+        writeln!(
+            dae_writter,
+            "<COLLADA xmlns=\"http://www.collada.org/2005/11/COLLADASchema\" version=\"1.4.1\">"
+        )?;
+        writeln!(dae_writter, "  <asset>")?;
+        writeln!(dae_writter, "    <contributor>")?;
+        writeln!(
+            dae_writter,
+            "      <authoring_tool>ta-3do-to-obj {}</authoring_tool>",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(dae_writter, "    </contributor>")?;
+        writeln!(dae_writter, "    <created>{}</created>", created)?;
+        writeln!(dae_writter, "    <modified>{}</modified>", created)?;
+        writeln!(dae_writter, "    <up_axis>Y_UP</up_axis>")?;
+        writeln!(dae_writter, "  </asset>")?;
+
+        if !images.is_empty() {
+            writeln!(dae_writter, "  <library_images>")?;
+            for (id, name, file) in &images {
+                writeln!(
+                    dae_writter,
+                    "    <image id=\"{}\" name=\"{}\">",
+                    id,
+                    escape(name)
+                )?;
+                writeln!(dae_writter, "      <init_from>{}</init_from>", escape(file))?;
+                writeln!(dae_writter, "    </image>")?;
+            }
+            writeln!(dae_writter, "  </library_images>")?;
+        }
+
+        if !material_order.is_empty() {
+            writeln!(dae_writter, "  <library_effects>")?;
+            for name in &material_order {
+                write_effect(&mut dae_writter, &materials[name])?;
+            }
+            writeln!(dae_writter, "  </library_effects>")?;
+            writeln!(dae_writter, "  <library_materials>")?;
+            for name in &material_order {
+                let material = &materials[name];
+                writeln!(
+                    dae_writter,
+                    "    <material id=\"{}-material\" name=\"{}\">",
+                    material.id,
+                    escape(&material.name)
+                )?;
+                writeln!(
+                    dae_writter,
+                    "      <instance_effect url=\"#{}-effect\"/>",
+                    material.id
+                )?;
+                writeln!(dae_writter, "    </material>")?;
+            }
+            writeln!(dae_writter, "  </library_materials>")?;
+        }
+
+        let document = Document {
+            args,
+            prefix,
+            materials,
+            textures,
+        };
+        if !geometries.is_empty() {
+            writeln!(dae_writter, "  <library_geometries>")?;
+            for geometry in &geometries {
+                write_geometry(&document, &mut dae_writter, geometry)?;
+            }
+            writeln!(dae_writter, "  </library_geometries>")?;
+        }
+
+        let scene = xml_id(
+            &Path::new(output_stem)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            &mut taken,
+        );
+        writeln!(dae_writter, "  <library_visual_scenes>")?;
+        writeln!(
+            dae_writter,
+            "    <visual_scene id=\"{}\" name=\"{}\">",
+            scene, scene
+        )?;
+        for piece in &model.pieces {
+            write_node(
+                &document,
+                &mut dae_writter,
+                piece,
+                &geometries,
+                [0.0; 3],
+                3,
+                &mut taken,
+            )?;
+        }
+        writeln!(dae_writter, "    </visual_scene>")?;
+        writeln!(dae_writter, "  </library_visual_scenes>")?;
+        writeln!(dae_writter, "  <scene>")?;
+        writeln!(
+            dae_writter,
+            "    <instance_visual_scene url=\"#{}\"/>",
+            scene
+        )?;
+        writeln!(dae_writter, "  </scene>")?;
+        writeln!(dae_writter, "</COLLADA>")?;
+        dae_writter.flush()?;
+
+        Ok(vec![dae_path])
+    }
+}
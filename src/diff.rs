@@ -0,0 +1,353 @@
+use serde::Serialize;
+use std::fs;
+
+use ta_3do_to_obj::palette::{Palette, PALETTE};
+use ta_3do_to_obj::records::{
+    check_count, check_tree, read_cstring, read_object, read_primitives, read_vertexes,
+    DEFAULT_MAX_DEPTH, DEFAULT_MAX_OBJECTS, DEFAULT_MAX_PRIMITIVES, DEFAULT_MAX_VERTEXES,
+    TA_VERSION_SIGNATURE,
+};
+
+use crate::FIXED_POINT_SCALE;
+
+/// What the diff needs to know about a piece.
+struct DiffPiece {
+    name: String,
+    /// child indexes from the root down to this piece, e.g. 0.1.0
+    position: String,
+    offset: [i32; 3],
+    vertexes: Vec<[i32; 3]>,
+    materials: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct ModelDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    renamed: Vec<Rename>,
+    changed: Vec<PieceDiff>,
+}
+
+#[derive(Serialize)]
+struct Rename {
+    from: String,
+    to: String,
+    position: String,
+}
+
+#[derive(Serialize)]
+struct PieceDiff {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vertex_count: Option<[usize; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    primitive_count: Option<[usize; 2]>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    materials: Vec<MaterialChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<[[f64; 3]; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bounding_box_delta: Option<BoundingBoxDelta>,
+    /// same number of vertexes, but some of them moved
+    vertexes_moved: bool,
+}
+
+#[derive(Serialize)]
+struct MaterialChange {
+    primitive: usize,
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize)]
+struct BoundingBoxDelta {
+    min: [f64; 3],
+    max: [f64; 3],
+}
+
+fn primitive_material(data: &[u8], texture_offset: u32, colour: Option<u32>) -> String {
+    if texture_offset != 0 {
+        read_cstring(data, texture_offset, 256)
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .unwrap_or_else(|_| format!("texture_{:x}", texture_offset))
+    } else if let Some(colour_index) = colour {
+        match PALETTE.get(colour_index as usize) {
+            Some(_) => Palette::default().name(colour_index as usize),
+            None => format!("colour {}", colour_index),
+        }
+    } else {
+        "none".to_string()
+    }
+}
+
+fn collect_pieces(
+    data: &[u8],
+    object_offset: u32,
+    position: String,
+    pieces: &mut Vec<DiffPiece>,
+) -> Result<(), String> {
+    let object = &read_object(data, object_offset)?;
+    let name = read_cstring(data, object.offset_to_object_name, 256)
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .unwrap_or_else(|_| format!("piece_{:x}", object.offset_to_object_name));
+    check_tree(
+        &name,
+        object_offset,
+        position.split('.').count() as u32,
+        pieces.len() as u32 + 1,
+        DEFAULT_MAX_DEPTH,
+        DEFAULT_MAX_OBJECTS,
+    )?;
+
+    check_count(object.number_of_vertexes, DEFAULT_MAX_VERTEXES, "vertexes")
+        .and_then(|_| {
+            check_count(
+                object.number_of_primitives,
+                DEFAULT_MAX_PRIMITIVES,
+                "primitives",
+            )
+        })
+        .map_err(|err| format!("piece {} has {}", name, err))?;
+    let vertexes =
+        read_vertexes(data, object).map_err(|err| format!("piece {} has {}", name, err))?;
+
+    let materials = read_primitives(data, object)
+        .map_err(|err| format!("piece {} has {}", name, err))?
+        .iter()
+        .map(|p| {
+            let colour = if p.is_colored != 0 {
+                Some(p.color_index)
+            } else {
+                None
+            };
+            primitive_material(data, p.offset_to_texture_name, colour)
+        })
+        .collect();
+
+    pieces.push(DiffPiece {
+        name,
+        position: position.clone(),
+        offset: [
+            object.x_from_parent,
+            object.y_from_parent,
+            object.z_from_parent,
+        ],
+        vertexes,
+        materials,
+    });
+
+    if object.offset_to_child_object != 0 {
+        collect_pieces(
+            data,
+            object.offset_to_child_object,
+            format!("{}.0", position),
+            pieces,
+        )?;
+    }
+
+    if object.offset_to_sibling_object != 0 {
+        let (parent, index) = match position.rsplit_once('.') {
+            Some((parent, index)) => (format!("{}.", parent), index),
+            None => (String::new(), position.as_str()),
+        };
+        let next = format!("{}{}", parent, index.parse::<usize>().unwrap() + 1);
+        collect_pieces(data, object.offset_to_sibling_object, next, pieces)?;
+    }
+
+    Ok(())
+}
+
+fn read_pieces(file: &str) -> Result<Vec<DiffPiece>, String> {
+    let data = fs::read(file).map_err(|err| format!("unable to read {}: {}", file, err))?;
+    let root =
+        read_object(&data, 0).map_err(|_| format!("{} is too short to be a 3do file", file))?;
+    let version_signature = root.version_signature;
+    if version_signature != TA_VERSION_SIGNATURE {
+        return Err(format!(
+            "{} has version signature {:#010x}, it may not be a 3do file",
+            file, version_signature
+        ));
+    }
+
+    let mut pieces = Vec::new();
+    collect_pieces(&data, 0, "0".to_string(), &mut pieces)
+        .map_err(|err| format!("{}: {}", file, err))?;
+    Ok(pieces)
+}
+
+fn bounding_box(vertexes: &[[i32; 3]]) -> ([f64; 3], [f64; 3]) {
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for v in vertexes {
+        for axis in 0..3 {
+            let value = v[axis] as f64 / *FIXED_POINT_SCALE;
+            min[axis] = min[axis].min(value);
+            max[axis] = max[axis].max(value);
+        }
+    }
+    if vertexes.is_empty() {
+        return ([0.0; 3], [0.0; 3]);
+    }
+    (min, max)
+}
+
+fn compare_piece(a: &DiffPiece, b: &DiffPiece) -> Option<PieceDiff> {
+    let to_units = |offset: [i32; 3]| offset.map(|c| c as f64 / *FIXED_POINT_SCALE);
+
+    let materials: Vec<MaterialChange> = a
+        .materials
+        .iter()
+        .zip(&b.materials)
+        .enumerate()
+        .filter(|(_, (from, to))| from != to)
+        .map(|(primitive, (from, to))| MaterialChange {
+            primitive,
+            from: from.clone(),
+            to: to.clone(),
+        })
+        .collect();
+
+    let (min_a, max_a) = bounding_box(&a.vertexes);
+    let (min_b, max_b) = bounding_box(&b.vertexes);
+    let delta = |from: [f64; 3], to: [f64; 3]| [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+    let bounding_box_delta = BoundingBoxDelta {
+        min: delta(min_a, min_b),
+        max: delta(max_a, max_b),
+    };
+
+    let diff = PieceDiff {
+        name: b.name.clone(),
+        vertex_count: (a.vertexes.len() != b.vertexes.len())
+            .then_some([a.vertexes.len(), b.vertexes.len()]),
+        primitive_count: (a.materials.len() != b.materials.len())
+            .then_some([a.materials.len(), b.materials.len()]),
+        materials,
+        offset: (a.offset != b.offset).then_some([to_units(a.offset), to_units(b.offset)]),
+        bounding_box_delta: bounding_box_delta
+            .min
+            .iter()
+            .chain(&bounding_box_delta.max)
+            .any(|&d| d != 0.0)
+            .then_some(bounding_box_delta),
+        vertexes_moved: a.vertexes.len() == b.vertexes.len() && a.vertexes != b.vertexes,
+    };
+
+    let unchanged = diff.vertex_count.is_none()
+        && diff.primitive_count.is_none()
+        && diff.materials.is_empty()
+        && diff.offset.is_none()
+        && diff.bounding_box_delta.is_none()
+        && !diff.vertexes_moved;
+    if unchanged {
+        None
+    } else {
+        Some(diff)
+    }
+}
+
+fn diff_models(a: &[DiffPiece], b: &[DiffPiece]) -> ModelDiff {
+    let mut diff = ModelDiff::default();
+    let mut matched_a = vec![false; a.len()];
+    let mut matched_b = vec![false; b.len()];
+    let mut pairs = Vec::new();
+
+    // pieces are matched by name first, then whatever is left by where it
+    // sits in the tree, which catches renames
+    for (i, piece_a) in a.iter().enumerate() {
+        if let Some(j) = (0..b.len()).find(|&j| !matched_b[j] && b[j].name == piece_a.name) {
+            matched_a[i] = true;
+            matched_b[j] = true;
+            pairs.push((i, j));
+        }
+    }
+    for (i, piece_a) in a.iter().enumerate() {
+        if matched_a[i] {
+            continue;
+        }
+        if let Some(j) = (0..b.len()).find(|&j| !matched_b[j] && b[j].position == piece_a.position)
+        {
+            matched_a[i] = true;
+            matched_b[j] = true;
+            pairs.push((i, j));
+            diff.renamed.push(Rename {
+                from: piece_a.name.clone(),
+                to: b[j].name.clone(),
+                position: piece_a.position.clone(),
+            });
+        }
+    }
+
+    diff.removed = (0..a.len())
+        .filter(|&i| !matched_a[i])
+        .map(|i| a[i].name.clone())
+        .collect();
+    diff.added = (0..b.len())
+        .filter(|&j| !matched_b[j])
+        .map(|j| b[j].name.clone())
+        .collect();
+
+    pairs.sort();
+    diff.changed = pairs
+        .into_iter()
+        .filter_map(|(i, j)| compare_piece(&a[i], &b[j]))
+        .collect();
+
+    diff
+}
+
+fn print_diff(diff: &ModelDiff) {
+    for name in &diff.removed {
+        println!("- {}", name);
+    }
+    for name in &diff.added {
+        println!("+ {}", name);
+    }
+    for rename in &diff.renamed {
+        println!(
+            "~ {} renamed to {} ({})",
+            rename.from, rename.to, rename.position
+        );
+    }
+    for piece in &diff.changed {
+        println!("~ {}", piece.name);
+        if let Some([from, to]) = piece.vertex_count {
+            println!("    vertexes: {} -> {}", from, to);
+        }
+        if let Some([from, to]) = piece.primitive_count {
+            println!("    primitives: {} -> {}", from, to);
+        }
+        for change in &piece.materials {
+            println!(
+                "    primitive {} material: {} -> {}",
+                change.primitive, change.from, change.to
+            );
+        }
+        if let Some([from, to]) = piece.offset {
+            println!("    offset: {:?} -> {:?}", from, to);
+        }
+        if let Some(delta) = &piece.bounding_box_delta {
+            println!("    bounding box: min {:?}, max {:?}", delta.min, delta.max);
+        }
+        if piece.vertexes_moved {
+            println!("    vertex positions changed");
+        }
+    }
+}
+
+/// Compares two 3do files piece by piece, returning whether they differ.
+pub fn diff_3do(file_a: &str, file_b: &str, json: bool) -> Result<bool, String> {
+    let a = read_pieces(file_a)?;
+    let b = read_pieces(file_b)?;
+    let diff = diff_models(&a, &b);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+    } else {
+        print_diff(&diff);
+    }
+
+    Ok(!(diff.added.is_empty()
+        && diff.removed.is_empty()
+        && diff.renamed.is_empty()
+        && diff.changed.is_empty()))
+}
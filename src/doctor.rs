@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use ta_3do_to_obj::records::{read_object, TA_VERSION_SIGNATURE};
+
+use crate::gaf_extractor::{gaf_entry_names, gaf_search_path};
+use crate::model::{read_model, Model};
+use crate::texture_map::{Target, TextureMap};
+use crate::{input_path, input_stem, texture_folder, Args, Buffer};
+
+/// Prints the outcome of one check, with what to do about it when it failed.
+fn report(passed: bool, message: &str, suggestion: &str) -> bool {
+    if passed {
+        println!("ok    {}", message);
+    } else {
+        println!("FAIL  {}", message);
+        if !suggestion.is_empty() {
+            println!("      {}", suggestion);
+        }
+    }
+    passed
+}
+
+/// Creates and removes a file in `folder` to see whether outputs can go there.
+fn check_writable(folder: &Path) -> Result<(), String> {
+    let probe = folder.join(".ta-3do-to-obj-doctor");
+    fs::write(&probe, b"").map_err(|err| err.to_string())?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+fn check_input(args: &Args, texture_map: &TextureMap) -> Result<Model, String> {
+    let input = input_path(args);
+    let data = fs::read(&input).map_err(|err| format!("unable to read {}: {}", input, err))?;
+    let root_object = read_object(&data, 0).map_err(|_| {
+        format!(
+            "{} is only {} bytes long, too short to be a 3do file",
+            input,
+            data.len()
+        )
+    })?;
+    let version_signature = root_object.version_signature;
+    if version_signature != TA_VERSION_SIGNATURE && !args.force_parse {
+        return Err(format!(
+            "{} has version signature {:#010x} instead of {:#010x}",
+            input, version_signature, TA_VERSION_SIGNATURE
+        ));
+    }
+
+    read_model(args, texture_map, &data).map_err(|err| format!("{} doesn't parse: {}", input, err))
+}
+
+/// Runs every check a conversion with `args` depends on, printing a line for
+/// each. Returns whether all of them passed.
+pub fn doctor(args: &Args) -> bool {
+    let mut healthy = true;
+
+    let texture_map = match &args.texture_map {
+        Some(path) => match TextureMap::load(Path::new(path)) {
+            Ok(texture_map) => {
+                report(true, &format!("{} loads", path), "");
+                texture_map
+            }
+            Err(err) => {
+                healthy &= report(
+                    false,
+                    &err,
+                    "each line needs an old and a new name, with at most one * in the old name",
+                );
+                TextureMap::default()
+            }
+        },
+        None => TextureMap::default(),
+    };
+
+    let model = match check_input(args, &texture_map) {
+        Ok(model) => {
+            let pieces = model.all_pieces().len();
+            report(
+                true,
+                &format!(
+                    "{} parses, {} pieces {} levels deep",
+                    input_path(args),
+                    pieces,
+                    model.depth()
+                ),
+                "",
+            );
+            healthy &= report(
+                model.empty_texture_names.is_empty(),
+                &match model.empty_texture_names.len() {
+                    0 => "no primitive names an empty texture".to_string(),
+                    count => format!(
+                        "{} primitives name an empty texture: {}",
+                        count,
+                        model.empty_texture_names.join(", ")
+                    ),
+                },
+                "they're drawn with their colour, or left out when they have none; give them a texture name in a 3do editor",
+            );
+            Some(model)
+        }
+        Err(err) => {
+            healthy &= report(
+                false,
+                &err,
+                "check the path, use --force-parse for a 3do from another game, or raise the --max-* limits for an unusually big model",
+            );
+            None
+        }
+    };
+
+    let mut texture_names = HashSet::new();
+    if args.no_textures || args.no_mtl {
+        report(true, "textures are skipped, not checking gaf files", "");
+    } else {
+        let (gaf_paths, game_archives) = match gaf_search_path(args, "./gaf_textures/") {
+            Ok(search_path) => search_path,
+            Err(err) => {
+                healthy &= report(false, &err, "quote the pattern, or fix its brackets");
+                (Vec::new(), Vec::new())
+            }
+        };
+        if gaf_paths.is_empty() {
+            let suggestion = if game_archives.is_empty() {
+                "create a gaf_textures folder with the game's .gaf files in it, point --gaf-dir at a folder of them, or point --game-dir at your Total Annihilation folder"
+            } else {
+                "the game's gafs are still inside its archives, extract them with an HPI tool into gaf_textures"
+            };
+            healthy &= report(false, "no gaf files to take textures from", suggestion);
+        }
+
+        for gaf_path in &gaf_paths {
+            let gaf_name = gaf_path.to_string_lossy();
+            let names = fs::read(gaf_path)
+                .map_err(|err| err.to_string())
+                .and_then(|data| gaf_entry_names(&mut Buffer::new(data)));
+            match names {
+                Ok(names) => {
+                    report(
+                        true,
+                        &format!("{} has {} entries", gaf_name, names.len()),
+                        "",
+                    );
+                    // named the way extraction renames them
+                    texture_names.extend(names.iter().filter_map(|name| {
+                        match texture_map.target(name) {
+                            Some(Target::File(_)) => None,
+                            Some(target) => Some(target.name()),
+                            None => Some(name.clone()),
+                        }
+                    }));
+                }
+                Err(err) => {
+                    healthy &= report(
+                        false,
+                        &format!("{} doesn't parse: {}", gaf_name, err),
+                        "remove it from the search path, or replace it with a fresh copy from the game",
+                    );
+                }
+            }
+        }
+
+        if let Some(model) = &model {
+            let missing: Vec<&str> = model
+                .used_textures
+                .iter()
+                .filter(|texture| {
+                    !texture_names.contains(*texture)
+                        && !model
+                            .texture_files
+                            .get(*texture)
+                            .is_some_and(|path| path.exists())
+                })
+                .map(|texture| texture.as_str())
+                .collect();
+            healthy &= report(
+                missing.is_empty(),
+                &match missing.len() {
+                    0 => format!("all {} textures were found", model.used_textures.len()),
+                    _ => format!(
+                        "{} of {} textures weren't found: {}",
+                        missing.len(),
+                        model.used_textures.len(),
+                        missing.join(", ")
+                    ),
+                },
+                "add the gafs of the mod the model comes from, or use --no-textures",
+            );
+        }
+    }
+
+    let output_folder = Path::new(input_stem(args))
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let texture_folder = texture_folder(args);
+    let mut folders = vec![output_folder];
+    if !args.no_textures && !args.no_mtl {
+        // the textures folder is created if it doesn't exist, in the
+        // nearest folder that does
+        folders.push(
+            Path::new(&texture_folder)
+                .ancestors()
+                .find(|folder| folder.is_dir())
+                .unwrap_or(Path::new(".")),
+        );
+    }
+    folders.dedup();
+    for folder in folders {
+        let result = check_writable(folder);
+        healthy &= report(
+            result.is_ok(),
+            &match result {
+                Ok(()) => format!("{} is writable", folder.to_string_lossy()),
+                Err(err) => format!("{} isn't writable: {}", folder.to_string_lossy(), err),
+            },
+            "run from a folder you can write to",
+        );
+    }
+
+    healthy
+}
@@ -0,0 +1,170 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::dae::DaeExporter;
+use crate::fbx::FbxExporter;
+use crate::gaf_extractor::ExtractedTexture;
+use crate::gltf::{GlbExporter, GltfExporter};
+use crate::model::{Face, Material, Model};
+use crate::obj::ObjExporter;
+use crate::output::PendingFiles;
+use crate::ply::PlyExporter;
+use crate::s3o::S3oExporter;
+use crate::stl::StlExporter;
+use crate::threemf::ThreeMfExporter;
+use crate::usda::UsdaExporter;
+use crate::usdz::UsdzExporter;
+use crate::x3d::X3dExporter;
+use crate::{Args, DedupeMode};
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// Wavefront .obj with a .mtl
+    Obj,
+    /// ascii usd stage with the piece hierarchy
+    Usda,
+    /// the same usd stage packaged with its textures, for AR Quick Look
+    Usdz,
+    /// gltf 2.0 scene with a node per piece, and its .bin
+    Gltf,
+    /// the same gltf scene as one binary file, textures included
+    Glb,
+    /// collada document with a node per piece, for older dcc pipelines
+    Dae,
+    /// ascii fbx scene with a model per piece, for game engines
+    Fbx,
+    /// ascii ply with each face's colour baked into its vertexes
+    Ply,
+    /// binary stl of the whole model, z up, for 3d printing
+    Stl,
+    /// the same stl as text
+    StlAscii,
+    /// spring s3o with the textures and colours packed into one texture
+    S3o,
+    /// 3mf of the whole model, z up, with its colours, for slicers
+    #[value(name = "3mf")]
+    ThreeMf,
+    /// x3d scene with a nested Transform per piece
+    X3d,
+    /// the same scene as a vrml97 .wrl, for older web viewers
+    Vrml,
+}
+
+impl Format {
+    pub fn exporter(self) -> Box<dyn Exporter> {
+        match self {
+            Format::Obj => Box::new(ObjExporter),
+            Format::Usda => Box::new(UsdaExporter),
+            Format::Usdz => Box::new(UsdzExporter),
+            Format::Gltf => Box::new(GltfExporter),
+            Format::Glb => Box::new(GlbExporter),
+            Format::Dae => Box::new(DaeExporter),
+            Format::Fbx => Box::new(FbxExporter),
+            Format::Ply => Box::new(PlyExporter),
+            Format::Stl => Box::new(StlExporter { ascii: false }),
+            Format::StlAscii => Box::new(StlExporter { ascii: true }),
+            Format::S3o => Box::new(S3oExporter),
+            Format::ThreeMf => Box::new(ThreeMfExporter),
+            Format::X3d => Box::new(X3dExporter { vrml: false }),
+            Format::Vrml => Box::new(X3dExporter { vrml: true }),
+        }
+    }
+}
+
+/// Writes a model read from a 3do in some output format.
+pub trait Exporter {
+    /// Writes `model` to files named after `output_stem`, created through
+    /// `pending` so they only appear once everything worked, and returns
+    /// the paths written.
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>>;
+}
+
+/// What --prefix expands to for the model written to `output_stem`, empty
+/// without it.
+pub fn name_prefix(args: &Args, output_stem: &str) -> String {
+    expand_prefix(args.prefix.as_deref().unwrap_or_default(), output_stem)
+}
+
+/// `pattern` with `{name}` replaced by the file name of `output_stem`.
+pub fn expand_prefix(pattern: &str, output_stem: &str) -> String {
+    let name = Path::new(output_stem)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    pattern.replace("{name}", &name)
+}
+
+/// `text` with the characters xml gives a meaning escaped, for element text
+/// and attribute values alike.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The name a face's material goes by, following merged duplicate textures
+/// to the texture they copy.
+pub fn material_name(
+    args: &Args,
+    material: &Material,
+    textures: &HashMap<String, ExtractedTexture>,
+) -> String {
+    match material {
+        Material::Texture(name) if args.dedupe_textures == Some(DedupeMode::Merge) => textures
+            .get(name)
+            .and_then(|t| t.duplicate_of.clone())
+            .unwrap_or_else(|| name.clone()),
+        _ => material.name(args),
+    }
+}
+
+/// Where `uv` lands on the canvas of `texture`, as padded textures only fill
+/// the top left of theirs. Without a texture it stays where it is.
+pub fn canvas_uv(texture: Option<&ExtractedTexture>, [u, v]: [f64; 2]) -> [f64; 2] {
+    match texture {
+        Some(t) => [
+            u * t.width as f64 / t.canvas_width as f64,
+            1.0 - (1.0 - v) * t.height as f64 / t.canvas_height as f64,
+        ],
+        None => [u, v],
+    }
+}
+
+/// What every piece of a scene is written with, `M` being a material as the
+/// format writes it.
+pub struct Scene<'a, M> {
+    pub args: &'a Args,
+    /// what --prefix expands to, put before every piece's name
+    pub prefix: String,
+    /// keyed by the material name faces use, like the mtl
+    pub materials: HashMap<String, M>,
+    pub textures: &'a HashMap<String, ExtractedTexture>,
+}
+
+impl<M> Scene<'_, M> {
+    /// The name of the material `face` uses, following merged duplicates.
+    pub fn material_name(&self, face: &Face) -> String {
+        material_name(self.args, &face.material, self.textures)
+    }
+
+    /// The material `face` is written with, if the scene has one for it.
+    pub fn material(&self, face: &Face) -> Option<&M> {
+        self.materials.get(&self.material_name(face))
+    }
+
+    /// The uvs of `face`'s corners, on the canvas of its texture.
+    pub fn uvs<'s>(&'s self, face: &'s Face) -> impl Iterator<Item = [f64; 2]> + 's {
+        let texture = face.texture.as_ref().and_then(|t| self.textures.get(t));
+        face.uvs.iter().map(move |&uv| canvas_uv(texture, uv))
+    }
+}
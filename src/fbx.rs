@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::{name_prefix, Exporter, Scene};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model, Piece};
+use crate::output::{relative_path, PendingFiles};
+use crate::{texture_folder, Args, ColorSpace, DedupeMode, TextureFallback};
+use ta_3do_to_obj::count_pieces;
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+/// A texture as the Texture object and the Video it plays, both pointing at
+/// the extracted bmp.
+struct FbxTexture {
+    id: i64,
+    video_id: i64,
+    file: String,
+}
+
+/// A material as it goes in the objects.
+struct FbxMaterial {
+    id: i64,
+    name: String,
+    diffuse: [f32; 3],
+    emissive: bool,
+    texture: Option<FbxTexture>,
+}
+
+/// Names are written between quotes, which ascii fbx can't escape.
+fn quoted(name: &str) -> String {
+    name.replace('"', "'")
+}
+
+fn list<T>(values: impl IntoIterator<Item = T>, format: impl Fn(T) -> String) -> String {
+    values.into_iter().map(format).collect::<Vec<_>>().join(",")
+}
+
+fn count_meshes(pieces: &[Piece]) -> usize {
+    pieces
+        .iter()
+        .map(|piece| !piece.faces.is_empty() as usize + count_meshes(&piece.children))
+        .sum()
+}
+
+fn write_material(fbx_writter: &mut impl Write, material: &FbxMaterial) -> io::Result<()> {
+    let [r, g, b] = material.diffuse;
+    writeln!(
+        fbx_writter,
+        "\tMaterial: {}, \"Material::{}\", \"\" {{",
+        material.id,
+        quoted(&material.name)
+    )?;
+    writeln!(fbx_writter, "\t\tVersion: 102")?;
+    writeln!(fbx_writter, "\t\tShadingModel: \"lambert\"")?;
+    writeln!(fbx_writter, "\t\tMultiLayer: 0")?;
+    writeln!(fbx_writter, "\t\tProperties70:  {{")?;
+    writeln!(
+        fbx_writter,
+        "\t\t\tP: \"DiffuseColor\", \"Color\", \"\", \"A\",{},{},{}",
+        r, g, b
+    )?;
+    if material.emissive {
+        writeln!(
+            fbx_writter,
+            "\t\t\tP: \"EmissiveColor\", \"Color\", \"\", \"A\",{},{},{}",
+            r, g, b
+        )?;
+        writeln!(
+            fbx_writter,
+            "\t\t\tP: \"EmissiveFactor\", \"Number\", \"\", \"A\",1"
+        )?;
+    }
+    writeln!(fbx_writter, "\t\t}}")?;
+    writeln!(fbx_writter, "\t}}")?;
+
+    if let Some(texture) = &material.texture {
+        let name = quoted(&material.name);
+        let file = quoted(&texture.file);
+        writeln!(
+            fbx_writter,
+            "\tVideo: {}, \"Video::{}\", \"Clip\" {{",
+            texture.video_id, name
+        )?;
+        writeln!(fbx_writter, "\t\tType: \"Clip\"")?;
+        writeln!(fbx_writter, "\t\tProperties70:  {{")?;
+        writeln!(
+            fbx_writter,
+            "\t\t\tP: \"Path\", \"KString\", \"XRefUrl\", \"\", \"{}\"",
+            file
+        )?;
+        writeln!(fbx_writter, "\t\t}}")?;
+        writeln!(fbx_writter, "\t\tFileName: \"{}\"", file)?;
+        writeln!(fbx_writter, "\t\tRelativeFilename: \"{}\"", file)?;
+        writeln!(fbx_writter, "\t}}")?;
+
+        writeln!(
+            fbx_writter,
+            "\tTexture: {}, \"Texture::{}\", \"\" {{",
+            texture.id, name
+        )?;
+        writeln!(fbx_writter, "\t\tType: \"TextureVideoClip\"")?;
+        writeln!(fbx_writter, "\t\tVersion: 202")?;
+        writeln!(fbx_writter, "\t\tTextureName: \"Texture::{}\"", name)?;
+        writeln!(fbx_writter, "\t\tMedia: \"Video::{}\"", name)?;
+        writeln!(fbx_writter, "\t\tFileName: \"{}\"", file)?;
+        writeln!(fbx_writter, "\t\tRelativeFilename: \"{}\"", file)?;
+        writeln!(fbx_writter, "\t}}")?;
+    }
+    Ok(())
+}
+
+/// The ids objects get and the `C:` lines between them, written once every
+/// object is.
+struct Connections {
+    /// the id the next object gets, 0 being the scene's root
+    next_id: i64,
+    lines: Vec<String>,
+}
+
+impl Connections {
+    fn next_id(&mut self) -> i64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// Writes the mesh of a piece around its origin, with a material index per
+/// polygon into `used`, the materials connected to its model in order.
+fn write_geometry(
+    scene: &Scene<FbxMaterial>,
+    fbx_writter: &mut impl Write,
+    id: i64,
+    piece: &Piece,
+    used: &[&FbxMaterial],
+) -> io::Result<()> {
+    let [x, y, z] = piece.origin;
+    writeln!(
+        fbx_writter,
+        "\tGeometry: {}, \"Geometry::{}{}\", \"Mesh\" {{",
+        id,
+        quoted(&scene.prefix),
+        quoted(&piece.name)
+    )?;
+    writeln!(
+        fbx_writter,
+        "\t\tVertices: *{} {{",
+        piece.vertexes.len() * 3
+    )?;
+    writeln!(
+        fbx_writter,
+        "\t\t\ta: {}",
+        list(&piece.vertexes, |v| format!(
+            "{},{},{}",
+            v[0] - x,
+            v[1] - y,
+            v[2] - z
+        ))
+    )?;
+    writeln!(fbx_writter, "\t\t}}")?;
+
+    // the last corner of each polygon is stored as its bitwise not
+    let indexes: Vec<i64> = piece
+        .faces
+        .iter()
+        .flat_map(|face| {
+            let last = face.vertex_indexes.len() - 1;
+            face.vertex_indexes
+                .iter()
+                .enumerate()
+                .map(move |(corner, &v)| {
+                    if corner == last {
+                        !(v as i64)
+                    } else {
+                        v as i64
+                    }
+                })
+        })
+        .collect();
+    writeln!(fbx_writter, "\t\tPolygonVertexIndex: *{} {{", indexes.len())?;
+    writeln!(
+        fbx_writter,
+        "\t\t\ta: {}",
+        list(&indexes, |i| i.to_string())
+    )?;
+    writeln!(fbx_writter, "\t\t}}")?;
+    writeln!(fbx_writter, "\t\tGeometryVersion: 124")?;
+
+    let has_uvs = piece
+        .faces
+        .iter()
+        .all(|face| face.uvs.len() == face.vertex_indexes.len());
+    if has_uvs {
+        let uvs: Vec<[f64; 2]> = piece
+            .faces
+            .iter()
+            .flat_map(|face| scene.uvs(face))
+            .collect();
+        writeln!(fbx_writter, "\t\tLayerElementUV: 0 {{")?;
+        writeln!(fbx_writter, "\t\t\tVersion: 101")?;
+        writeln!(fbx_writter, "\t\t\tName: \"UVMap\"")?;
+        writeln!(
+            fbx_writter,
+            "\t\t\tMappingInformationType: \"ByPolygonVertex\""
+        )?;
+        writeln!(
+            fbx_writter,
+            "\t\t\tReferenceInformationType: \"IndexToDirect\""
+        )?;
+        writeln!(fbx_writter, "\t\t\tUV: *{} {{", uvs.len() * 2)?;
+        writeln!(
+            fbx_writter,
+            "\t\t\t\ta: {}",
+            list(&uvs, |uv| format!("{},{}", uv[0], uv[1]))
+        )?;
+        writeln!(fbx_writter, "\t\t\t}}")?;
+        writeln!(fbx_writter, "\t\t\tUVIndex: *{} {{", uvs.len())?;
+        writeln!(
+            fbx_writter,
+            "\t\t\t\ta: {}",
+            list(0..uvs.len(), |i| i.to_string())
+        )?;
+        writeln!(fbx_writter, "\t\t\t}}")?;
+        writeln!(fbx_writter, "\t\t}}")?;
+    }
+
+    if !used.is_empty() {
+        let material_indexes = list(&piece.faces, |face| {
+            scene
+                .material(face)
+                .and_then(|material| used.iter().position(|m| m.id == material.id))
+                .unwrap_or(0)
+                .to_string()
+        });
+        writeln!(fbx_writter, "\t\tLayerElementMaterial: 0 {{")?;
+        writeln!(fbx_writter, "\t\t\tVersion: 101")?;
+        writeln!(fbx_writter, "\t\t\tName: \"\"")?;
+        writeln!(fbx_writter, "\t\t\tMappingInformationType: \"ByPolygon\"")?;
+        writeln!(
+            fbx_writter,
+            "\t\t\tReferenceInformationType: \"IndexToDirect\""
+        )?;
+        writeln!(fbx_writter, "\t\t\tMaterials: *{} {{", piece.faces.len())?;
+        writeln!(fbx_writter, "\t\t\t\ta: {}", material_indexes)?;
+        writeln!(fbx_writter, "\t\t\t}}")?;
+        writeln!(fbx_writter, "\t\t}}")?;
+    }
+
+    writeln!(fbx_writter, "\t\tLayer: 0 {{")?;
+    writeln!(fbx_writter, "\t\t\tVersion: 100")?;
+    for (element, written) in [
+        ("LayerElementMaterial", !used.is_empty()),
+        ("LayerElementUV", has_uvs),
+    ] {
+        if written {
+            writeln!(fbx_writter, "\t\t\tLayerElement:  {{")?;
+            writeln!(fbx_writter, "\t\t\t\tType: \"{}\"", element)?;
+            writeln!(fbx_writter, "\t\t\t\tTypedIndex: 0")?;
+            writeln!(fbx_writter, "\t\t\t}}")?;
+        }
+    }
+    writeln!(fbx_writter, "\t\t}}")?;
+    writeln!(fbx_writter, "\t}}")?;
+    Ok(())
+}
+
+/// Writes `piece` as a model translated from its parent's origin, with its
+/// mesh, and then its children connected to it.
+fn write_piece(
+    scene: &Scene<FbxMaterial>,
+    connections: &mut Connections,
+    fbx_writter: &mut impl Write,
+    piece: &Piece,
+    parent: (i64, [f64; 3]),
+) -> io::Result<()> {
+    let (parent_id, parent_origin) = parent;
+    let model_id = connections.next_id();
+    let [x, y, z] = piece.origin;
+
+    let kind = if piece.faces.is_empty() {
+        "Null"
+    } else {
+        "Mesh"
+    };
+    writeln!(
+        fbx_writter,
+        "\tModel: {}, \"Model::{}{}\", \"{}\" {{",
+        model_id,
+        quoted(&scene.prefix),
+        quoted(&piece.name),
+        kind
+    )?;
+    writeln!(fbx_writter, "\t\tVersion: 232")?;
+    writeln!(fbx_writter, "\t\tProperties70:  {{")?;
+    writeln!(
+        fbx_writter,
+        "\t\t\tP: \"Lcl Translation\", \"Lcl Translation\", \"\", \"A\",{},{},{}",
+        x - parent_origin[0],
+        y - parent_origin[1],
+        z - parent_origin[2]
+    )?;
+    writeln!(fbx_writter, "\t\t}}")?;
+    writeln!(fbx_writter, "\t\tShading: T")?;
+    writeln!(fbx_writter, "\t\tCulling: \"CullingOff\"")?;
+    writeln!(fbx_writter, "\t}}")?;
+    connections
+        .lines
+        .push(format!("\tC: \"OO\",{},{}", model_id, parent_id));
+
+    if !piece.faces.is_empty() {
+        let geometry_id = connections.next_id();
+        let mut used: Vec<&FbxMaterial> = Vec::new();
+        for face in &piece.faces {
+            if let Some(material) = scene.material(face) {
+                if !used.iter().any(|m| m.id == material.id) {
+                    used.push(material);
+                }
+            }
+        }
+
+        write_geometry(scene, fbx_writter, geometry_id, piece, &used)?;
+        connections
+            .lines
+            .push(format!("\tC: \"OO\",{},{}", geometry_id, model_id));
+        // the order materials connect in is what the indexes count
+        for material in used {
+            connections
+                .lines
+                .push(format!("\tC: \"OO\",{},{}", material.id, model_id));
+        }
+    }
+
+    for child in &piece.children {
+        write_piece(
+            scene,
+            connections,
+            fbx_writter,
+            child,
+            (model_id, piece.origin),
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes an ascii fbx 7.4 scene with a model per piece, translated from
+/// its parent, and a lambert material per colour and texture. Textures are
+/// referenced as the extracted bmps.
+pub struct FbxExporter;
+
+impl Exporter for FbxExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let fbx_path = output_stem.to_owned() + ".fbx";
+        let prefix = name_prefix(args, output_stem);
+        let file_prefix = if args.prefix_textures { &prefix } else { "" };
+
+        let mut next_id = 0;
+        let mut new_id = || {
+            next_id += 1;
+            next_id
+        };
+        let mut materials = HashMap::new();
+        let mut material_order = Vec::new();
+        if !args.no_mtl {
+            let palette = Palette::new(args.fullbright_range.clone());
+            for &colour_index in &model.used_colours {
+                let name = Material::Colour(colour_index).name(args);
+                if materials.contains_key(&name) {
+                    continue;
+                }
+                material_order.push(name.clone());
+                materials.insert(
+                    name.clone(),
+                    FbxMaterial {
+                        id: new_id(),
+                        name: format!("{}{}", prefix, name),
+                        diffuse: colour_factors(
+                            palette.get(colour_index),
+                            args.color_space == ColorSpace::Linear,
+                        ),
+                        emissive: !args.no_emissive && palette.is_fullbright(colour_index),
+                        texture: None,
+                    },
+                );
+            }
+
+            let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+            let untextured = args.no_textures && args.texture_fallback == TextureFallback::Kd;
+            for texture in &model.used_textures {
+                let extracted = textures.get(texture);
+                if merge_duplicates && extracted.is_some_and(|t| t.duplicate_of.is_some()) {
+                    continue;
+                }
+
+                let id = new_id();
+                let fbx_texture = (!untextured).then(|| FbxTexture {
+                    id: new_id(),
+                    video_id: new_id(),
+                    file: match extracted {
+                        Some(t) => relative_path(&t.path(), output_stem),
+                        None => relative_path(
+                            &format!("{}{}{}.bmp", texture_folder(args), file_prefix, texture),
+                            output_stem,
+                        ),
+                    },
+                });
+                material_order.push(texture.clone());
+                materials.insert(
+                    texture.clone(),
+                    FbxMaterial {
+                        id,
+                        name: format!("{}{}", prefix, texture),
+                        diffuse: [0.5, 0.5, 0.5],
+                        emissive: false,
+                        texture: fbx_texture,
+                    },
+                );
+            }
+        }
+
+        let models = count_pieces(&model.pieces, |piece| &piece.children);
+        let geometries = count_meshes(&model.pieces);
+        let textured = materials.values().filter(|m| m.texture.is_some()).count();
+
+        let file = pending.create(&fbx_path)?;
+        let mut fbx_writter = BufWriter::new(file);
+        writeln!(fbx_writter, "; FBX 7.4.0 project file")?;
+        writeln!(
+            fbx_writter,
+            "; written by ta-3do-to-obj {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(fbx_writter)?;
+        writeln!(fbx_writter, "FBXHeaderExtension:  {{")?;
+        writeln!(fbx_writter, "\tFBXHeaderVersion: 1003")?;
+        writeln!(fbx_writter, "\tFBXVersion: 7400")?;
+        writeln!(
+            fbx_writter,
+            "\tCreator: \"ta-3do-to-obj {}\"",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(fbx_writter, "}}")?;
+
+        // y up, z forward and right handed like the obj, in the model's units
+        writeln!(fbx_writter, "GlobalSettings:  {{")?;
+        writeln!(fbx_writter, "\tVersion: 1000")?;
+        writeln!(fbx_writter, "\tProperties70:  {{")?;
+        for (property, value) in [
+            ("UpAxis", 1),
+            ("UpAxisSign", 1),
+            ("FrontAxis", 2),
+            ("FrontAxisSign", 1),
+            ("CoordAxis", 0),
+            ("CoordAxisSign", 1),
+        ] {
+            writeln!(
+                fbx_writter,
+                "\t\tP: \"{}\", \"int\", \"Integer\", \"\",{}",
+                property, value
+            )?;
+        }
+        writeln!(
+            fbx_writter,
+            "\t\tP: \"UnitScaleFactor\", \"double\", \"Number\", \"\",1"
+        )?;
+        writeln!(fbx_writter, "\t}}")?;
+        writeln!(fbx_writter, "}}")?;
+
+        let definitions = [
+            ("Model", models),
+            ("Geometry", geometries),
+            ("Material", materials.len()),
+            ("Texture", textured),
+            ("Video", textured),
+        ];
+        writeln!(fbx_writter, "Definitions:  {{")?;
+        writeln!(fbx_writter, "\tVersion: 100")?;
+        writeln!(
+            fbx_writter,
+            "\tCount: {}",
+            definitions.iter().map(|(_, count)| count).sum::<usize>()
+        )?;
+        for (object_type, count) in definitions {
+            if count > 0 {
+                writeln!(fbx_writter, "\tObjectType: \"{}\" {{", object_type)?;
+                writeln!(fbx_writter, "\t\tCount: {}", count)?;
+                writeln!(fbx_writter, "\t}}")?;
+            }
+        }
+        writeln!(fbx_writter, "}}")?;
+
+        writeln!(fbx_writter, "Objects:  {{")?;
+        let mut connections = Vec::new();
+        for name in &material_order {
+            let material = &materials[name];
+            write_material(&mut fbx_writter, material)?;
+            if let Some(texture) = &material.texture {
+                connections.push(format!(
+                    "\tC: \"OP\",{},{}, \"DiffuseColor\"",
+                    texture.id, material.id
+                ));
+                connections.push(format!("\tC: \"OO\",{},{}", texture.video_id, texture.id));
+            }
+        }
+
+        let scene = Scene {
+            args,
+            prefix,
+            materials,
+            textures,
+        };
+        let mut connections = Connections {
+            next_id,
+            lines: connections,
+        };
+        for piece in &model.pieces {
+            write_piece(
+                &scene,
+                &mut connections,
+                &mut fbx_writter,
+                piece,
+                (0, [0.0; 3]),
+            )?;
+        }
+        writeln!(fbx_writter, "}}")?;
+
+        writeln!(fbx_writter, "Connections:  {{")?;
+        for connection in &connections.lines {
+            writeln!(fbx_writter, "{}", connection)?;
+        }
+        writeln!(fbx_writter, "}}")?;
+        fbx_writter.flush()?;
+
+        Ok(vec![fbx_path])
+    }
+}
@@ -0,0 +1,172 @@
+use bmp::Image;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::gaf_extractor::GafFrameData;
+use crate::logos::Team;
+use ta_3do_to_obj::palette::{Rgb, PALETTE};
+
+/// A decoded frame, its frame data and where the frame data was.
+pub type Frame = (Image, GafFrameData, u32);
+
+/// A gaf file as it was when it was read, so frames of a file that has
+/// changed since aren't taken from the cache.
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub struct GafId {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl GafId {
+    pub fn new(path: &Path) -> GafId {
+        let metadata = fs::metadata(path).ok();
+        GafId {
+            path: path.to_path_buf(),
+            len: metadata.as_ref().map_or(0, |m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()),
+        }
+    }
+}
+
+/// The gaf frames are being read from, and the colours they're decoded in.
+pub struct FrameSource {
+    gaf: GafId,
+    pub team: Option<Team>,
+}
+
+impl FrameSource {
+    pub fn new(path: &Path, team: Option<Team>) -> FrameSource {
+        FrameSource {
+            gaf: GafId::new(path),
+            team,
+        }
+    }
+
+    pub fn palette(&self) -> [Rgb; 256] {
+        self.team.map_or(PALETTE, Team::palette)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+struct FrameKey {
+    gaf: GafId,
+    entry: String,
+    frame: u16,
+    team: Option<Team>,
+}
+
+struct Cached {
+    frame: Frame,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Frames decoded so far, shared by every conversion the process makes.
+#[derive(Default)]
+struct FrameCache {
+    frames: HashMap<FrameKey, Cached>,
+    bytes: usize,
+    /// counts up on every use, so the least recently used frame has the
+    /// lowest `last_used`
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+lazy_static! {
+    static ref FRAMES: Mutex<FrameCache> = Mutex::new(FrameCache::default());
+}
+
+fn lock() -> std::sync::MutexGuard<'static, FrameCache> {
+    // a conversion that panicked can't have left the cache half changed
+    FRAMES.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Frame `frame` of the entry `entry`, from the cache when it's been decoded
+/// before and from `decode` when it hasn't. The cache keeps the most
+/// recently used frames that fit in `limit_mb` megabytes, nothing with 0.
+/// It isn't locked while decoding, so conversions running side by side
+/// don't wait on each other.
+pub fn cached_frame<E>(
+    source: &FrameSource,
+    entry: &str,
+    frame: u16,
+    limit_mb: u32,
+    decode: impl FnOnce() -> Result<Frame, E>,
+) -> Result<Frame, E> {
+    if limit_mb == 0 {
+        return decode();
+    }
+    let key = FrameKey {
+        gaf: source.gaf.clone(),
+        entry: entry.to_string(),
+        frame,
+        team: source.team,
+    };
+    {
+        let mut cache = lock();
+        cache.clock += 1;
+        let clock = cache.clock;
+        if let Some(cached) = cache.frames.get_mut(&key) {
+            cached.last_used = clock;
+            let frame = cached.frame.clone();
+            cache.hits += 1;
+            return Ok(frame);
+        }
+        cache.misses += 1;
+    }
+
+    let decoded = decode()?;
+    let (image, _, _) = &decoded;
+    let bytes = (image.get_width() * image.get_height() * 3) as usize;
+    let limit = limit_mb as usize * 1024 * 1024;
+    if bytes > limit {
+        return Ok(decoded);
+    }
+
+    let mut cache = lock();
+    while cache.bytes + bytes > limit {
+        let Some(oldest) = cache
+            .frames
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        let evicted = cache.frames.remove(&oldest).unwrap();
+        cache.bytes -= evicted.bytes;
+    }
+    let last_used = cache.clock;
+    if let Some(replaced) = cache.frames.insert(
+        key,
+        Cached {
+            frame: decoded.clone(),
+            bytes,
+            last_used,
+        },
+    ) {
+        // another conversion decoded it at the same time
+        cache.bytes -= replaced.bytes;
+    }
+    cache.bytes += bytes;
+    Ok(decoded)
+}
+
+/// Prints how often frames were found in the cache, since the process
+/// started.
+pub fn print_stats() {
+    let cache = lock();
+    info!(
+        "Gaf frame cache: {} hits, {} misses, {} frames taking {:.1} MB.",
+        cache.hits,
+        cache.misses,
+        cache.frames.len(),
+        cache.bytes as f64 / (1024.0 * 1024.0)
+    );
+}
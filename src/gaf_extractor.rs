@@ -1,12 +1,37 @@
-use serde::Deserialize;
+use glob::glob;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::mem::size_of;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem::offset_of;
+use std::path::{Path, PathBuf};
 extern crate bmp;
 use bmp::Image;
 use bmp::Pixel;
 
-use crate::palette::PALETTE;
-use crate::{read_struct, Buffer};
+use crate::frame_cache::{cached_frame, print_stats, FrameSource};
+use crate::gaf_index;
+use crate::game::{archives, find_game_dir, gaf_dirs, gafs_in, loose_gafs};
+use crate::log;
+use crate::logos::{is_logos_gaf, Team};
+use crate::output::{save_image, write_file};
+use crate::texture_adjust::Adjustment;
+use crate::texture_map::{Target, TextureMap};
+use crate::textures::open_image;
+use crate::{check_unknown, read_struct, Args, Buffer, PotMode, Record};
+use ta_3do_to_obj::palette::{Rgb, PALETTE};
+
+/// `version` of a Total Annihilation GAF.
+const GAF_VERSION_TA: u32 = 0x00010100;
+/// `version` of a Total Annihilation: Kingdoms GAF.
+const GAF_VERSION_TAK: u32 = 0x00010200;
+
+/// TAK frame formats stored in `GafFrameData::compressed`.
+const TAK_FORMAT_ARGB4444: u8 = 4;
+const TAK_FORMAT_ARGB1555: u8 = 5;
 
 #[repr(C, packed)]
 #[derive(Deserialize, Debug, Copy, Clone)]
@@ -34,7 +59,7 @@ struct GafFrameEntry {
 
 #[repr(C, packed)]
 #[derive(Deserialize, Debug, Copy, Clone)]
-struct GafFrameData {
+pub struct GafFrameData {
     width: u16,
     height: u16,
     x_pos: u16,
@@ -47,118 +72,1212 @@ struct GafFrameData {
     unknown_3: u32,
 }
 
+impl Record for GafHeader {
+    const SIZE: usize = 12;
+}
+
+impl Record for GafEntry {
+    const SIZE: usize = 40;
+}
+
+impl Record for GafFrameEntry {
+    const SIZE: usize = 8;
+}
+
+impl Record for GafFrameData {
+    const SIZE: usize = 24;
+}
+
 fn read_string(raw: [u8; 32]) -> String {
     let string_len = raw.iter().position(|&c| c == b'\0').unwrap_or(31);
 
-    String::from_utf8(raw[..string_len].to_vec()).unwrap()
+    String::from_utf8_lossy(&raw[..string_len]).into_owned()
+}
+
+/// Why a frame couldn't be decoded.
+#[derive(Debug)]
+enum FrameError {
+    UnknownFormat(u8),
+    /// claims a size over --max-frame-size or --max-frame-pixels
+    TooBig(u16, u16),
+    /// a pointer that's 0 or leads past the end of the file
+    BadPointer(&'static str, u32),
+    /// a record that couldn't be read
+    Unreadable(String),
+}
+
+impl From<String> for FrameError {
+    fn from(err: String) -> Self {
+        FrameError::Unreadable(err)
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::UnknownFormat(format) => write!(f, "unknown frame format {}", format),
+            FrameError::TooBig(width, height) => write!(
+                f,
+                "its frame claims to be {}x{}, over --max-frame-size or --max-frame-pixels",
+                width, height
+            ),
+            FrameError::BadPointer(field, 0) => write!(f, "its {} pointer is 0", field),
+            FrameError::BadPointer(field, pointer) => write!(
+                f,
+                "its {} pointer {:#x} is past the end of the file",
+                field, pointer
+            ),
+            FrameError::Unreadable(err) => write!(f, "{}", err),
+        }
+    }
 }
 
-fn read_image(buf: &mut Buffer, width: u16, height: u16, compressed: u8) -> Image {
+/// Decodes a frame of palette indexes, compressed or not. Skipped pixels
+/// and the rest of a compressed line are the frame's `transparency` index.
+fn read_image(
+    buf: &mut Buffer,
+    width: u16,
+    height: u16,
+    compressed: u8,
+    transparency: u8,
+    palette: &[Rgb; 256],
+) -> Result<Image, String> {
     let mut image = Image::new(width.into(), height.into());
     let mut raw = Vec::new();
 
     if compressed != 0 {
         // we have to uncompress it outself >:(
         for _ in 0..height {
-            let line_bytes = read_struct::<u16>(buf);
-            buf.seek_relative(size_of::<u16>() as i64);
+            let line_bytes = read_struct::<u16>(buf)?;
+            buf.seek_relative(u16::SIZE as i64);
+            let line_end = buf.cursor + line_bytes as usize;
+            let mut line = Vec::new();
 
-            for _ in 0..line_bytes {
-                let mask = read_struct::<u8>(buf);
+            // line_bytes counts bytes, not the runs they make up
+            while buf.cursor < line_end {
+                let mask = read_struct::<u8>(buf)?;
                 buf.seek_relative(1);
 
                 if (mask & 0x01) == 0x01 {
-                    for _ in 0..(mask >> 1) {
-                        raw.push(0);
-                    }
+                    line.resize(line.len() + (mask >> 1) as usize, transparency);
                 } else if (mask & 0x02) == 0x02 {
-                    let byte = read_struct::<u8>(buf);
+                    let byte = read_struct::<u8>(buf)?;
                     buf.seek_relative(1);
-                    for _ in 0..((mask >> 2) + 1) {
-                        raw.push(byte)
-                    }
+                    line.resize(line.len() + (mask >> 2) as usize + 1, byte);
                 } else {
-                    for _ in 0..((mask & 0x02) + 1) {
-                        let byte = read_struct::<u8>(buf);
+                    for _ in 0..((mask >> 2) + 1) {
+                        let byte = read_struct::<u8>(buf)?;
                         buf.seek_relative(1);
-                        raw.push(byte);
+                        line.push(byte);
                     }
                 }
             }
+            buf.seek(line_end as u32);
+
+            // a line stops at its last opaque pixel, the rest is transparent
+            line.resize(width as usize, transparency);
+            raw.extend(line);
         }
     }
 
+    let pixels = width as usize * height as usize;
     if compressed == 0 {
-        raw.extend(buf.read((width * height).into()).to_vec());
+        raw.extend(buf.read(pixels)?);
     }
 
-    for i in 0..(width * height) {
-        let byte = raw[i as usize];
-        let colour = PALETTE[byte as usize];
+    let width = width as usize;
+    for (i, &byte) in raw.iter().take(pixels).enumerate() {
+        let colour = palette[byte as usize];
         let pixel = Pixel::new(colour[0], colour[1], colour[2]);
-        image.set_pixel((i % width).into(), (i / width).into(), pixel);
+        image.set_pixel((i % width) as u32, (i / width) as u32, pixel);
     }
 
-    return image;
+    Ok(image)
 }
 
-fn extract_gaf(buf: &mut Buffer, used_textures: &Vec<String>, extract_folder: &str) {
-    let header = read_struct::<GafHeader>(buf);
-    buf.seek_relative(size_of::<GafHeader>() as i64);
+fn read_truecolor_image(
+    buf: &mut Buffer,
+    width: u16,
+    height: u16,
+    format: u8,
+) -> Result<Image, String> {
+    let mut image = Image::new(width.into(), height.into());
+
+    for y in 0..height {
+        for x in 0..width {
+            let value = read_struct::<u16>(buf)?;
+            buf.seek_relative(u16::SIZE as i64);
+
+            // alpha is dropped, bmp has nowhere to put it
+            let pixel = if format == TAK_FORMAT_ARGB4444 {
+                let expand = |v: u16| ((v & 0x0F) * 0x11) as u8;
+                Pixel::new(expand(value >> 8), expand(value >> 4), expand(value))
+            } else {
+                let expand = |v: u16| (((v & 0x1F) * 255 + 15) / 31) as u8;
+                Pixel::new(expand(value >> 10), expand(value >> 5), expand(value))
+            };
+            image.set_pixel(x.into(), y.into(), pixel);
+        }
+    }
+
+    Ok(image)
+}
+
+/// What the entries of gafs are extracted with, and where to.
+struct Extraction<'a> {
+    args: &'a Args,
+    texture_map: &'a TextureMap,
+    /// the only entries extracted, unless --logos wants every logo
+    used_textures: &'a [String],
+    extract_folder: &'a str,
+    file_prefix: &'a str,
+}
+
+/// A gaf whose entries are being extracted.
+struct Gaf<'a> {
+    extraction: &'a Extraction<'a>,
+    name: &'a str,
+    version: u32,
+    source: FrameSource,
+}
+
+/// Extracts the entries of the gaf in `buf` that `extraction` wants,
+/// returning how many of them were broken. Fails when the header or the
+/// entry table can't be read.
+fn extract_gaf(
+    extraction: &Extraction,
+    buf: &mut Buffer,
+    gaf_path: &Path,
+    gaf_name: &str,
+    extracted: &mut HashMap<String, ExtractedTexture>,
+) -> Result<usize, String> {
+    let args = extraction.args;
+    let header = read_struct::<GafHeader>(buf)?;
+    buf.seek_relative(GafHeader::SIZE as i64);
+    check_unknown(
+        args,
+        gaf_name,
+        offset_of!(GafHeader, unknown_1),
+        "GafHeader.unknown_1",
+        header.unknown_1,
+        0,
+    );
+
+    let version = header.version;
+    if version != GAF_VERSION_TA && version != GAF_VERSION_TAK {
+        warning!(
+            "skipping {}, unknown GAF version {:#010x}",
+            gaf_name,
+            version
+        );
+        return Ok(0);
+    }
 
     let mut entry_pointers = Vec::new();
 
     for _ in 0..header.entries {
-        let entry_pointer = read_struct::<u32>(buf);
-        buf.seek_relative(size_of::<u32>() as i64);
+        let entry_pointer = read_struct::<u32>(buf)?;
+        buf.seek_relative(u32::SIZE as i64);
         entry_pointers.push(entry_pointer);
     }
 
+    let gaf = Gaf {
+        extraction,
+        name: gaf_name,
+        version,
+        source: FrameSource::new(gaf_path, args.logos.filter(|_| is_logos_gaf(gaf_name))),
+    };
+    let mut failed = 0;
     for p in entry_pointers {
+        if let Err(err) = check_pointer(buf, "entry", p, GafEntry::SIZE) {
+            warning!("skipping an entry of {}, {}", gaf_name, err);
+            failed += 1;
+            continue;
+        }
         buf.seek(p);
-        let entry = read_struct::<GafEntry>(buf);
+        let entry = match read_struct::<GafEntry>(buf) {
+            Ok(entry) => entry,
+            Err(err) => {
+                warning!("skipping the entry at {:#x} in {}, {}", p, gaf_name, err);
+                failed += 1;
+                continue;
+            }
+        };
         let name = read_string(entry.name);
+        check_unknown(
+            args,
+            gaf_name,
+            p as usize + offset_of!(GafEntry, unknown_1),
+            "GafEntry.unknown_1",
+            entry.unknown_1 as u32,
+            1,
+        );
+        check_unknown(
+            args,
+            gaf_name,
+            p as usize + offset_of!(GafEntry, unknown_2),
+            "GafEntry.unknown_2",
+            entry.unknown_2,
+            0,
+        );
+
+        // entries are renamed the same way as the model's textures, and
+        // textures mapped to a bmp don't come from gafs at all
+        let name = match extraction.texture_map.target(&name) {
+            Some(Target::File(_)) => continue,
+            Some(target) => target.name(),
+            None => name,
+        };
+
+        // the first gaf to have a texture wins, later ones are only checked
+        // for having different pixels. With --logos every logo is extracted,
+        // as models name them in ways that can't be told from the 3do
+        if !extraction.used_textures.contains(&name) && gaf.source.team.is_none() {
+            continue;
+        }
+        if entry.frames == 0 {
+            warning!("skipping {} in {}, it has no frames", name, gaf_name);
+            continue;
+        }
+        let frame_table = p + GafEntry::SIZE as u32;
+        let frame_table_size = entry.frames as usize * GafFrameEntry::SIZE;
+        if let Err(err) = check_pointer(buf, "frame table", frame_table, frame_table_size) {
+            warning!("skipping {} in {}, {}", name, gaf_name, err);
+            failed += 1;
+            continue;
+        }
+
+        buf.seek(p);
+        if let Err(err) = extract_entry(&gaf, buf, &name, entry, extracted) {
+            warning!("skipping {} in {}, {}", name, gaf_name, err);
+            failed += 1;
+        }
+    }
 
-        if used_textures.contains(&name) {
-            buf.seek_relative(size_of::<GafEntry>() as i64);
-            let frame_entry = read_struct::<GafFrameEntry>(buf);
+    Ok(failed)
+}
 
-            buf.seek(frame_entry.frame_table_pointer);
-            let mut frame_data = read_struct::<GafFrameData>(buf);
+/// Extracts the texture of one gaf entry, `buf` being at the entry. Fails
+/// when the frames of an entry with --all-frames can't be read.
+fn extract_entry(
+    gaf: &Gaf,
+    buf: &mut Buffer,
+    name: &str,
+    entry: GafEntry,
+    extracted: &mut HashMap<String, ExtractedTexture>,
+) -> Result<(), String> {
+    let Extraction {
+        args,
+        extract_folder,
+        file_prefix,
+        ..
+    } = gaf.extraction;
+    let (gaf_name, source) = (gaf.name, &gaf.source);
+    buf.seek_relative(GafEntry::SIZE as i64);
+    let frame_table = buf.cursor;
+
+    match choose_frame(gaf, buf, name, entry.frames, frame_table) {
+        Ok((image, _, _)) if image.get_width() == 0 || image.get_height() == 0 => {
+            warning!("skipping {} in {}, its frame is empty", name, gaf_name);
+            return Ok(());
+        }
+        Ok((mut image, frame_data, frame)) => {
+            let palette = source.palette();
+            let adjustment = Adjustment::from_args(args);
+            if let Some(adjustment) = &adjustment {
+                adjustment.apply(&mut image, Some(transparent_colour(&frame_data, &palette)));
+            }
+            // unknown_1 is the frame's transparency index
+            let fill = palette[frame_data.unknown_1 as usize];
+            let fitted = fit_power_of_two(&image, args.pot, fill);
+            let pixel_hash = hash_pixels(&fitted);
 
-            // we have subframes, just extract the first subframe.
-            if frame_data.frame_pointers > 0 {
-                buf.seek(frame_data.frame_data_pointer);
-                let data_pointer = read_struct::<u32>(buf);
-                buf.seek(data_pointer);
-                frame_data = read_struct::<GafFrameData>(buf);
+            if let Some(existing) = extracted.get_mut(name) {
+                if existing.pixel_hash != pixel_hash {
+                    existing.conflicts.push(format!(
+                        "{} ({}x{})",
+                        gaf_name,
+                        image.get_width(),
+                        image.get_height()
+                    ));
+                }
+                return Ok(());
             }
 
-            buf.seek(frame_data.frame_data_pointer);
-            let image = read_image(
-                buf,
-                frame_data.width,
-                frame_data.height,
-                frame_data.compressed,
+            // identical images share the first one's file
+            let duplicate_of = match args.dedupe_textures {
+                Some(_) => extracted
+                    .iter()
+                    .find(|(other, t)| {
+                        *other != name && t.duplicate_of.is_none() && t.pixel_hash == pixel_hash
+                    })
+                    .map(|(other, _)| other.clone()),
+                None => None,
+            };
+
+            let file = match &duplicate_of {
+                Some(original) => extracted[original].file.clone(),
+                None => {
+                    let mut file = format!("{}{}.bmp", file_prefix, name);
+                    // objs sharing an mtl may already point at a different
+                    // image by this name, which mustn't change under them
+                    if args.shared_mtl.is_some() {
+                        let existing = bmp::open(format!("{}{}", extract_folder, file));
+                        if existing.is_ok_and(|existing| hash_pixels(&existing) != pixel_hash) {
+                            file = format!("{}{}_{:016x}.bmp", file_prefix, name, pixel_hash);
+                        }
+                    }
+                    let path = format!("{}{}", extract_folder, file);
+                    if let Err(err) = save_image(&fitted, &path) {
+                        warning!("unable to write {} for {}, {}", path, name, err);
+                        return Ok(());
+                    }
+                    file
+                }
+            };
+
+            extracted.insert(
+                name.to_string(),
+                ExtractedTexture {
+                    file,
+                    folder: extract_folder.to_string(),
+                    width: image.get_width(),
+                    height: image.get_height(),
+                    canvas_width: fitted.get_width(),
+                    canvas_height: fitted.get_height(),
+                    pixel_hash,
+                    frame,
+                    gaf: gaf_name.to_string(),
+                    colour_key: has_transparent_pixels(&image, &frame_data, &palette).then(|| {
+                        let key = transparent_colour(&frame_data, &palette);
+                        [key.r, key.g, key.b]
+                    }),
+                    adjustment,
+                    team: source.team,
+                    conflicts: Vec::new(),
+                    duplicate_of,
+                },
             );
-            let _ = image.save(format!("{}{}.bmp", extract_folder, name));
         }
+        Err(err) => {
+            warning!("skipping {} in {}, {}", name, gaf_name, err);
+            return Ok(());
+        }
+    }
+
+    if args.all_frames && (entry.frames > 1 || args.always_manifest) {
+        extract_all_frames(gaf, buf, name, entry.frames, frame_table)?;
+    }
+    Ok(())
+}
+
+/// Resizes an image up to power of two dimensions, either padding it with
+/// `fill` on the right and bottom or stretching it.
+fn fit_power_of_two(image: &Image, mode: PotMode, fill: [u8; 3]) -> Image {
+    let (width, height) = (image.get_width(), image.get_height());
+    let (canvas_width, canvas_height) = (width.next_power_of_two(), height.next_power_of_two());
+    if mode == PotMode::Off || (canvas_width == width && canvas_height == height) {
+        return image.clone();
+    }
+
+    let mut canvas = Image::new(canvas_width, canvas_height);
+    for (x, y) in canvas.coordinates() {
+        let pixel = match mode {
+            PotMode::Pad if x >= width || y >= height => Pixel::new(fill[0], fill[1], fill[2]),
+            PotMode::Pad => image.get_pixel(x, y),
+            _ => image.get_pixel(x * width / canvas_width, y * height / canvas_height),
+        };
+        canvas.set_pixel(x, y, pixel);
+    }
+
+    canvas
+}
+
+/// Checks that `size` bytes can be read at `pointer`, 0 meaning there's
+/// nothing there.
+fn check_pointer(
+    buf: &Buffer,
+    field: &'static str,
+    pointer: u32,
+    size: usize,
+) -> Result<(), FrameError> {
+    if pointer == 0 || pointer as usize + size > buf.len() {
+        return Err(FrameError::BadPointer(field, pointer));
     }
+    Ok(())
+}
+
+/// Decodes the frame a frame table entry points to, along with where its
+/// frame data was read from. Frames claiming to be bigger than the limits
+/// aren't decoded, so a corrupt size can't allocate gigabytes.
+fn read_frame(
+    args: &Args,
+    buf: &mut Buffer,
+    version: u32,
+    frame_table_pointer: u32,
+    palette: &[Rgb; 256],
+) -> Result<(Image, GafFrameData, u32), FrameError> {
+    let mut frame_data_offset = frame_table_pointer;
+    check_pointer(buf, "frame", frame_data_offset, GafFrameData::SIZE)?;
+    buf.seek(frame_data_offset);
+    let mut frame_data = read_struct::<GafFrameData>(buf)?;
+
+    // we have subframes, just extract the first subframe.
+    if frame_data.frame_pointers > 0 {
+        let subframes = frame_data.frame_data_pointer;
+        let table_size = frame_data.frame_pointers as usize * u32::SIZE;
+        check_pointer(buf, "subframe table", subframes, table_size)?;
+        buf.seek(subframes);
+        for _ in 0..frame_data.frame_pointers {
+            let subframe = read_struct::<u32>(buf)?;
+            check_pointer(buf, "subframe", subframe, GafFrameData::SIZE)?;
+            buf.seek_relative(u32::SIZE as i64);
+        }
+
+        buf.seek(subframes);
+        frame_data_offset = read_struct::<u32>(buf)?;
+        buf.seek(frame_data_offset);
+        frame_data = read_struct::<GafFrameData>(buf)?;
+    }
+
+    let (width, height) = (frame_data.width, frame_data.height);
+    if width as u32 > args.max_frame_size
+        || height as u32 > args.max_frame_size
+        || width as u64 * height as u64 > args.max_frame_pixels
+    {
+        return Err(FrameError::TooBig(width, height));
+    }
+    if width > 0 && height > 0 {
+        // compressed rows are at least their u16 length each
+        let pixels = width as usize * height as usize;
+        let data_size = match (version, frame_data.compressed) {
+            (GAF_VERSION_TAK, TAK_FORMAT_ARGB4444 | TAK_FORMAT_ARGB1555) => pixels * 2,
+            (_, 0) => pixels,
+            _ => height as usize * u16::SIZE,
+        };
+        check_pointer(buf, "pixel data", frame_data.frame_data_pointer, data_size)?;
+    }
+
+    buf.seek(frame_data.frame_data_pointer);
+    let image = match (version, frame_data.compressed) {
+        (GAF_VERSION_TAK, TAK_FORMAT_ARGB4444 | TAK_FORMAT_ARGB1555) => read_truecolor_image(
+            buf,
+            frame_data.width,
+            frame_data.height,
+            frame_data.compressed,
+        )?,
+        (GAF_VERSION_TAK, 0 | 1) | (GAF_VERSION_TA, _) => read_image(
+            buf,
+            frame_data.width,
+            frame_data.height,
+            frame_data.compressed,
+            frame_data.unknown_1,
+            palette,
+        )?,
+        (_, format) => return Err(FrameError::UnknownFormat(format)),
+    };
+
+    Ok((image, frame_data, frame_data_offset))
+}
+
+/// Frames narrower or shorter than this are taken to be animation padding.
+const MIN_FRAME_SIZE: u32 = 2;
+
+/// Whether a frame is too small or too transparent to be the real texture.
+fn is_empty_frame(image: &Image, frame_data: &GafFrameData, palette: &[Rgb; 256]) -> bool {
+    if image.get_width() < MIN_FRAME_SIZE || image.get_height() < MIN_FRAME_SIZE {
+        return true;
+    }
+
+    let transparent = transparent_colour(frame_data, palette);
+    image
+        .coordinates()
+        .all(|(x, y)| image.get_pixel(x, y) == transparent)
+}
+
+/// Colour of the frame's transparency index, which unknown_1 holds, in the
+/// palette the frame was decoded with.
+fn transparent_colour(frame_data: &GafFrameData, palette: &[Rgb; 256]) -> Pixel {
+    let [r, g, b] = palette[frame_data.unknown_1 as usize];
+    Pixel::new(r, g, b)
+}
+
+fn has_transparent_pixels(image: &Image, frame_data: &GafFrameData, palette: &[Rgb; 256]) -> bool {
+    let transparent = transparent_colour(frame_data, palette);
+    image
+        .coordinates()
+        .any(|(x, y)| image.get_pixel(x, y) == transparent)
+}
+
+/// Frame `frame` of the entry `name` whose frame table is at `frame_table`,
+/// decoded unless the frame cache already has it.
+fn read_cached_frame(
+    gaf: &Gaf,
+    buf: &mut Buffer,
+    frame_table: usize,
+    name: &str,
+    frame: u16,
+) -> Result<(Image, GafFrameData, u32), FrameError> {
+    let args = gaf.extraction.args;
+    cached_frame(&gaf.source, name, frame, args.frame_cache_mb, || {
+        buf.seek((frame_table + frame as usize * GafFrameEntry::SIZE) as u32);
+        let frame_entry = read_struct::<GafFrameEntry>(buf)?;
+        read_frame(
+            args,
+            buf,
+            gaf.version,
+            frame_entry.frame_table_pointer,
+            &gaf.source.palette(),
+        )
+    })
+}
+
+/// Reads the frame to use as an entry's texture, either the one asked for
+/// with --frame or the first one that isn't empty, along with its index.
+fn choose_frame(
+    gaf: &Gaf,
+    buf: &mut Buffer,
+    name: &str,
+    frames: u16,
+    frame_table: usize,
+) -> Result<(Image, GafFrameData, u16), FrameError> {
+    let (args, gaf_name) = (gaf.extraction.args, gaf.name);
+    let mut read_frame_at = |i: u16| {
+        read_cached_frame(gaf, buf, frame_table, name, i).map(|(image, frame_data, offset)| {
+            for (field, field_offset, value) in [
+                (
+                    "GafFrameData.unknown_2",
+                    offset_of!(GafFrameData, unknown_2),
+                    frame_data.unknown_2,
+                ),
+                (
+                    "GafFrameData.unknown_3",
+                    offset_of!(GafFrameData, unknown_3),
+                    frame_data.unknown_3,
+                ),
+            ] {
+                check_unknown(
+                    args,
+                    gaf_name,
+                    offset as usize + field_offset,
+                    field,
+                    value,
+                    0,
+                );
+            }
+            (image, frame_data, i)
+        })
+    };
+
+    match args.frame {
+        Some(frame) if frame < frames => return read_frame_at(frame),
+        Some(frame) => warning!(
+            "{} in {} only has {} frames, can't use frame {}",
+            name,
+            gaf_name,
+            frames,
+            frame
+        ),
+        None => {}
+    }
+
+    let palette = gaf.source.palette();
+    let first = read_frame_at(0);
+    if first
+        .as_ref()
+        .is_ok_and(|(image, frame_data, _)| !is_empty_frame(image, frame_data, &palette))
+    {
+        return first;
+    }
+    for i in 1..frames {
+        if let Ok(frame) = read_frame_at(i) {
+            if !is_empty_frame(&frame.0, &frame.1, &palette) {
+                info!(
+                    "{} in {} has an empty first frame, using frame {}",
+                    name, gaf_name, i
+                );
+                return Ok(frame);
+            }
+        }
+    }
+    first
+}
+
+/// Saves the bmps and pngs --texture-map points at as the textures they stand in for,
+/// the same way gaf entries are.
+pub fn load_texture_files(
+    args: &Args,
+    files: &HashMap<String, PathBuf>,
+    extract_folder: &str,
+    file_prefix: &str,
+    extracted: &mut HashMap<String, ExtractedTexture>,
+) {
+    let mut names: Vec<&String> = files.keys().collect();
+    names.sort();
+    for name in names {
+        let path = &files[name];
+        let image = match open_image(path) {
+            Ok(image) => image,
+            Err(err) => {
+                warning!(
+                    "unable to read {} for {}, {}",
+                    path.to_string_lossy(),
+                    name,
+                    err
+                );
+                continue;
+            }
+        };
+
+        let fitted = fit_power_of_two(&image, args.pot, [0, 0, 0]);
+        let file = format!("{}{}.bmp", file_prefix, name);
+        let saved_path = format!("{}{}", extract_folder, file);
+        let saved =
+            fs::create_dir_all(extract_folder).and_then(|_| save_image(&fitted, &saved_path));
+        if let Err(err) = saved {
+            warning!("unable to write {} for {}, {}", saved_path, name, err);
+            continue;
+        }
+        extracted.insert(
+            name.clone(),
+            ExtractedTexture {
+                file,
+                folder: extract_folder.to_string(),
+                width: image.get_width(),
+                height: image.get_height(),
+                canvas_width: fitted.get_width(),
+                canvas_height: fitted.get_height(),
+                pixel_hash: hash_pixels(&fitted),
+                frame: 0,
+                gaf: path.to_string_lossy().into_owned(),
+                colour_key: None,
+                adjustment: None,
+                team: None,
+                conflicts: Vec::new(),
+                duplicate_of: None,
+            },
+        );
+    }
+}
+
+/// Where an extracted texture was saved, and its size and that of the canvas
+/// it was saved on.
+pub struct ExtractedTexture {
+    pub file: String,
+    /// folder `file` is in, ending in a slash
+    pub folder: String,
+    pub width: u32,
+    pub height: u32,
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    pub pixel_hash: u64,
+    /// which frame of the gaf entry was used
+    pub frame: u16,
+    /// gaf the texture was taken from
+    pub gaf: String,
+    /// the frame's transparent colour, when any pixel has it
+    pub colour_key: Option<[u8; 3]>,
+    /// --texture-gain and the like, when they changed the pixels
+    pub adjustment: Option<Adjustment>,
+    /// player colour --logos painted a texture from the logos gaf in
+    pub team: Option<Team>,
+    /// other gafs with a different image under the same name, and their sizes
+    pub conflicts: Vec<String>,
+    /// texture with identical pixels whose file this one reuses
+    pub duplicate_of: Option<String>,
+}
+
+impl ExtractedTexture {
+    /// Path of the file from the working folder.
+    pub fn path(&self) -> String {
+        format!("{}{}", self.folder, self.file)
+    }
+}
+
+fn hash_pixels(image: &Image) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (image.get_width(), image.get_height()).hash(&mut hasher);
+    for (x, y) in image.coordinates() {
+        let pixel = image.get_pixel(x, y);
+        (pixel.r, pixel.g, pixel.b).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[derive(Serialize)]
+struct AnimManifest {
+    name: String,
+    frame_count: u16,
+    canvas_width: i32,
+    canvas_height: i32,
+    frames: Vec<AnimFrame>,
+}
+
+#[derive(Serialize)]
+struct AnimFrame {
+    file: String,
+    width: u16,
+    height: u16,
+    x_offset: i16,
+    y_offset: i16,
+    /// second dword of the frame table entry, thought to be the frame's display time
+    duration: u32,
+}
+
+/// Extracts every frame of an entry as `<prefix><name>_<n>.bmp` and
+/// describes the sequence in `<prefix><name>.anim.json`.
+fn extract_all_frames(
+    gaf: &Gaf,
+    buf: &mut Buffer,
+    name: &str,
+    frames: u16,
+    frame_table: usize,
+) -> Result<(), String> {
+    let Extraction {
+        args,
+        extract_folder,
+        file_prefix,
+        ..
+    } = gaf.extraction;
+    let gaf_name = gaf.name;
+    let mut manifest = AnimManifest {
+        name: name.to_string(),
+        frame_count: frames,
+        canvas_width: 0,
+        canvas_height: 0,
+        frames: Vec::new(),
+    };
+
+    // frames are drawn with their x/y offset as the origin, so the canvas
+    // is the union of every frame placed at (-x_offset, -y_offset)
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, 0, 0);
+
+    for i in 0..frames {
+        buf.seek((frame_table + i as usize * GafFrameEntry::SIZE) as u32);
+        let frame_entry = read_struct::<GafFrameEntry>(buf)?;
+
+        let frame = read_cached_frame(gaf, buf, frame_table, name, i);
+        let (mut image, frame_data, _) = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                warning!("skipping frame {} of {} in {}, {}", i, name, gaf_name, err);
+                continue;
+            }
+        };
+        if let Some(adjustment) = Adjustment::from_args(args) {
+            let transparent = transparent_colour(&frame_data, &gaf.source.palette());
+            adjustment.apply(&mut image, Some(transparent));
+        }
+
+        let file = format!("{}{}_{}.bmp", file_prefix, name, i);
+        let path = format!("{}{}", extract_folder, file);
+        if let Err(err) = save_image(&image, &path) {
+            warning!("unable to write frame {} of {}, {}", i, name, err);
+            continue;
+        }
+
+        let x_offset = frame_data.x_pos as i16;
+        let y_offset = frame_data.y_pos as i16;
+        left = left.min(-(x_offset as i32));
+        top = top.min(-(y_offset as i32));
+        right = right.max(frame_data.width as i32 - x_offset as i32);
+        bottom = bottom.max(frame_data.height as i32 - y_offset as i32);
+
+        manifest.frames.push(AnimFrame {
+            file,
+            width: frame_data.width,
+            height: frame_data.height,
+            x_offset,
+            y_offset,
+            duration: frame_entry.unknown_1,
+        });
+    }
+
+    manifest.canvas_width = right - left;
+    manifest.canvas_height = bottom - top;
+
+    let path = format!("{}{}{}.anim.json", extract_folder, file_prefix, name);
+    if let Err(err) = write_file(&path, |file| {
+        serde_json::to_writer_pretty(file, &manifest).map_err(io::Error::from)
+    }) {
+        warning!("unable to write {}, {}", path, err);
+    }
+    Ok(())
+}
+
+/// The gaf files textures are looked for in, in the order they're searched,
+/// and the game archives that can't be searched yet. Fails on a --gaf that
+/// isn't a valid pattern.
+pub fn gaf_search_path(
+    args: &Args,
+    gaf_folder: &str,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+    let mut gaf_paths = Vec::new();
+    for pattern in &args.gaf {
+        let matches: Vec<PathBuf> = glob(pattern)
+            .map_err(|err| format!("--gaf {} isn't a valid pattern: {}", pattern, err))?
+            .flatten()
+            .collect();
+        if matches.is_empty() {
+            warning!("--gaf {} didn't match any files", pattern);
+        }
+        gaf_paths.extend(matches);
+    }
+
+    // --gaf-dir folders are searched after any --gaf files
+    let gaf_dirs = gaf_dirs(args);
+    if args.gaf.is_empty() && gaf_dirs.is_empty() {
+        if let Ok(gaf_files) = fs::read_dir(gaf_folder) {
+            let mut folder_paths: Vec<PathBuf> =
+                gaf_files.flatten().map(|gaf| gaf.path()).collect();
+            folder_paths.sort();
+            gaf_paths.extend(folder_paths);
+        }
+    }
+    for gaf_dir in gaf_dirs {
+        if !gaf_dir.is_dir() {
+            warning!("gaf folder {} doesn't exist", gaf_dir.to_string_lossy());
+        }
+        gaf_paths.extend(gafs_in(&gaf_dir));
+    }
+
+    let mut game_archives = Vec::new();
+    if args.gaf.is_empty() {
+        if let Some(game_dir) = find_game_dir(args) {
+            if !game_dir.is_dir() {
+                warning!("game folder {} doesn't exist", game_dir.to_string_lossy());
+            }
+            gaf_paths.extend(loose_gafs(&game_dir));
+            game_archives = archives(&game_dir);
+        }
+    }
+
+    // --prefer moves the named gafs to the front, in the order they were given
+    let preference = |path: &PathBuf| {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        args.prefer
+            .iter()
+            .position(|prefer| prefer.eq_ignore_ascii_case(&file_name))
+            .unwrap_or(args.prefer.len())
+    };
+    gaf_paths.sort_by_key(preference);
+
+    Ok((gaf_paths, game_archives))
+}
+
+/// First frame of the entry called `name` in a gaf, if it has one.
+fn read_named_frame(args: &Args, buf: &mut Buffer, name: &str) -> Result<Option<Image>, String> {
+    let header = read_struct::<GafHeader>(buf)?;
+    buf.seek_relative(GafHeader::SIZE as i64);
+
+    let version = header.version;
+    if version != GAF_VERSION_TA && version != GAF_VERSION_TAK {
+        return Err(format!("unknown GAF version {:#010x}", version));
+    }
+
+    let mut entry_pointers = Vec::new();
+    for _ in 0..header.entries {
+        entry_pointers.push(read_struct::<u32>(buf)?);
+        buf.seek_relative(u32::SIZE as i64);
+    }
+
+    for p in entry_pointers {
+        buf.seek(p);
+        let entry = read_struct::<GafEntry>(buf)?;
+        if entry.frames == 0 || !read_string(entry.name).eq_ignore_ascii_case(name) {
+            continue;
+        }
+        buf.seek(p + GafEntry::SIZE as u32);
+        let frame_entry = read_struct::<GafFrameEntry>(buf)?;
+        return match read_frame(
+            args,
+            buf,
+            version,
+            frame_entry.frame_table_pointer,
+            &PALETTE,
+        ) {
+            Ok((image, _, _)) => Ok(Some(image)),
+            Err(err) => Err(format!("{}, {}", name, err)),
+        };
+    }
+    Ok(None)
+}
+
+/// Looks for the build picture of the unit `name` along the gaf search
+/// path, gafs with "pic" in their name first, returning it and the gaf it
+/// came from.
+pub fn find_buildpic(
+    args: &Args,
+    gaf_folder: &str,
+    name: &str,
+) -> Result<Option<(Image, String)>, String> {
+    let (mut gaf_paths, _) = gaf_search_path(args, gaf_folder)?;
+    gaf_paths.sort_by_key(|path| {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        !file_name.to_ascii_lowercase().contains("pic")
+    });
+
+    for gaf_path in gaf_paths {
+        let gaf_name = gaf_path.file_name().unwrap_or_default().to_string_lossy();
+        let Ok(data) = fs::read(&gaf_path) else {
+            continue;
+        };
+        match read_named_frame(args, &mut Buffer::new(data), name) {
+            Ok(Some(image)) => return Ok(Some((image, gaf_name.into_owned()))),
+            Ok(None) => {}
+            Err(err) => warning!("skipping {} looking for a build picture, {}", gaf_name, err),
+        }
+    }
+    Ok(None)
+}
+
+/// Names of every entry in a gaf, without reading any frames.
+pub fn gaf_entry_names(buf: &mut Buffer) -> Result<Vec<String>, String> {
+    let header = read_struct::<GafHeader>(buf)?;
+    buf.seek_relative(GafHeader::SIZE as i64);
+
+    let version = header.version;
+    if version != GAF_VERSION_TA && version != GAF_VERSION_TAK {
+        return Err(format!("unknown GAF version {:#010x}", version));
+    }
+
+    let mut entry_pointers = Vec::new();
+    for _ in 0..header.entries {
+        entry_pointers.push(read_struct::<u32>(buf)?);
+        buf.seek_relative(u32::SIZE as i64);
+    }
+
+    let mut names = Vec::new();
+    for p in entry_pointers {
+        buf.seek(p);
+        names.push(read_string(read_struct::<GafEntry>(buf)?.name));
+    }
+    Ok(names)
+}
+
+/// Extracts every entry of the gaf files at `paths` into `extract_folder`,
+/// whatever model would use them. When entries of two gafs share a name the
+/// first one listed wins, as in a conversion. Fails on a gaf that can't be
+/// read at all, broken entries are skipped with a warning.
+pub fn extract_all_from_gafs(
+    args: &Args,
+    paths: &[String],
+    extract_folder: &str,
+) -> Result<HashMap<String, ExtractedTexture>, String> {
+    let mut extracted = HashMap::new();
+    fs::create_dir_all(extract_folder)
+        .map_err(|err| format!("unable to create {}: {}", extract_folder, err))?;
+    for path in paths {
+        let gaf_path = Path::new(path);
+        let gaf_name = gaf_path.file_name().unwrap_or_default().to_string_lossy();
+        let data = fs::read(gaf_path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+        let names = gaf_entry_names(&mut Buffer::new(data.clone()))
+            .map_err(|err| format!("{}: {}", path, err))?;
+        let mut buf = Buffer::new(data);
+        let extraction = Extraction {
+            args,
+            texture_map: &TextureMap::default(),
+            used_textures: &names,
+            extract_folder,
+            file_prefix: "",
+        };
+        let failed = extract_gaf(&extraction, &mut buf, gaf_path, &gaf_name, &mut extracted)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        if failed > 0 {
+            info!("Skipped {} broken entries of {}.", failed, gaf_name);
+        }
+    }
+    Ok(extracted)
 }
 
 pub fn extract_textures_from_gafs(
-    used_textures: &Vec<String>,
+    args: &Args,
+    texture_map: &TextureMap,
+    used_textures: &[String],
     gaf_folder: &str,
     extract_folder: &str,
-) {
-    if let Ok(gaf_files) = fs::read_dir(gaf_folder) {
-        fs::create_dir_all(extract_folder).unwrap();
-        for gaf in gaf_files.flatten() {
-            let data = fs::read(gaf.path()).unwrap();
-            let mut buf = Buffer::new(data);
-            extract_gaf(&mut buf, used_textures, extract_folder);
-        }
-    } else {
-        println!("To have textures in your .obj create a folder named gaf_textures in this directory and copy all .gaf files from the game to it.");
+    file_prefix: &str,
+) -> Result<HashMap<String, ExtractedTexture>, String> {
+    let mut extracted = HashMap::new();
+
+    let (gaf_paths, game_archives) = gaf_search_path(args, gaf_folder)?;
+    if gaf_paths.is_empty()
+        && args.gaf.is_empty()
+        && gaf_dirs(args).is_empty()
+        && find_game_dir(args).is_none()
+    {
+        info!("To have textures in your .obj create a folder named gaf_textures in this directory and copy all .gaf files from the game to it, point --gaf-dir at a folder of them, or point --game-dir at your Total Annihilation folder.");
+    }
+
+    if args.verbose {
+        info!("Gaf search order:");
+        for gaf_path in &gaf_paths {
+            info!("  {}", gaf_path.to_string_lossy());
+        }
+        // the engine lets later archives win, so they are searched first
+        for archive in game_archives.iter().rev() {
+            info!("  {} (archives aren't read yet)", archive.to_string_lossy());
+        }
+    }
+
+    if !gaf_paths.is_empty() {
+        fs::create_dir_all(extract_folder)
+            .map_err(|err| format!("unable to create {}: {}", extract_folder, err))?;
+    }
+    let has_logos = gaf_paths
+        .iter()
+        .any(|path| is_logos_gaf(&path.file_name().unwrap_or_default().to_string_lossy()));
+    if args.logos.is_some() && !has_logos {
+        warning!("--logos didn't find a logos.gaf to take the logos from.");
+    }
+    let extraction = Extraction {
+        args,
+        texture_map,
+        used_textures,
+        extract_folder,
+        file_prefix,
+    };
+    let mut failed = 0;
+    // the textures an entry is extracted as, after --texture-map renames it
+    let wanted = |name: &str| match texture_map.target(name) {
+        Some(Target::File(_)) => false,
+        Some(target) => used_textures.contains(&target.name()),
+        None => used_textures.iter().any(|used| used == name),
+    };
+    for gaf_path in gaf_paths {
+        let gaf_name = gaf_path.file_name().unwrap_or_default().to_string_lossy();
+        // --logos takes every logo, whatever the model names
+        let logos = args.logos.is_some() && is_logos_gaf(&gaf_name);
+        if !logos && gaf_index::lacks(&gaf_path, wanted) {
+            continue;
+        }
+        let data = match fs::read(&gaf_path) {
+            Ok(data) => data,
+            Err(err) => {
+                warning!("unable to read {}, {}", gaf_path.to_string_lossy(), err);
+                continue;
+            }
+        };
+        let mut buf = Buffer::new(data);
+        match extract_gaf(&extraction, &mut buf, &gaf_path, &gaf_name, &mut extracted) {
+            Ok(failed_entries) => failed += failed_entries,
+            Err(err) => {
+                warning!("skipping the rest of {}, {}", gaf_name, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        info!("Skipped {} broken gaf entries.", failed);
+    }
+    if args.verbose && args.frame_cache_mb > 0 {
+        print_stats();
+    }
+    log::update(|result| result.counts.broken_gaf_entries = failed as u32);
+
+    let missing = used_textures
+        .iter()
+        .filter(|texture| !extracted.contains_key(*texture))
+        .count();
+    if missing > 0 && !game_archives.is_empty() {
+        info!(
+            "{} textures weren't found in loose gaf files. Textures inside .hpi/.ufo/.ccx archives can't be read yet, extract the gafs with an HPI tool.",
+            missing
+        );
+    }
+
+    let mut names: Vec<&String> = extracted.keys().collect();
+    names.sort();
+    for name in names {
+        let texture = &extracted[name];
+        if !texture.conflicts.is_empty() {
+            warning!(
+                "{} differs between gafs, using {} ({}x{}) over {}. Use --prefer to pick another.",
+                name,
+                texture.gaf,
+                texture.width,
+                texture.height,
+                texture.conflicts.join(", ")
+            );
+        }
+    }
+
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_header() {
+        let mut buf = Buffer::new(vec![
+            0x00, 0x01, 0x01, 0x00, // version
+            0x03, 0x00, 0x00, 0x00, // entries
+            0xef, 0xbe, 0xad, 0xde, // unknown_1
+        ]);
+        let header = read_struct::<GafHeader>(&mut buf).unwrap();
+        assert_eq!({ header.version }, GAF_VERSION_TA);
+        assert_eq!({ header.entries }, 3);
+        assert_eq!({ header.unknown_1 }, 0xdeadbeef);
+        assert_eq!(offset_of!(GafHeader, unknown_1), 8);
+    }
+
+    #[test]
+    fn reads_an_entry() {
+        let mut data = vec![
+            0x02, 0x00, // frames
+            0x34, 0x12, // unknown_1
+            0xef, 0xcd, 0xab, 0x89, // unknown_2
+        ];
+        data.extend(b"armtex1\0");
+        data.resize(GafEntry::SIZE, 0);
+        let entry = read_struct::<GafEntry>(&mut Buffer::new(data)).unwrap();
+        assert_eq!({ entry.frames }, 2);
+        assert_eq!({ entry.unknown_1 }, 0x1234);
+        assert_eq!({ entry.unknown_2 }, 0x89abcdef);
+        assert_eq!(read_string(entry.name), "armtex1");
+        assert_eq!(offset_of!(GafEntry, unknown_1), 2);
+        assert_eq!(offset_of!(GafEntry, unknown_2), 4);
+    }
+
+    #[test]
+    fn reads_a_frame_entry() {
+        let mut buf = Buffer::new(vec![
+            0x44, 0x33, 0x22, 0x11, // frame_table_pointer
+            0x88, 0x77, 0x66, 0x55, // unknown_1
+        ]);
+        let frame_entry = read_struct::<GafFrameEntry>(&mut buf).unwrap();
+        assert_eq!({ frame_entry.frame_table_pointer }, 0x11223344);
+        assert_eq!({ frame_entry.unknown_1 }, 0x55667788);
+    }
+
+    #[test]
+    fn reads_frame_data() {
+        let mut buf = Buffer::new(vec![
+            0x02, 0x01, // width
+            0x04, 0x03, // height
+            0x06, 0x05, // x_pos
+            0x08, 0x07, // y_pos
+            0x09, // unknown_1
+            0x01, // compressed
+            0x0b, 0x0a, // frame_pointers
+            0x0f, 0x0e, 0x0d, 0x0c, // unknown_2
+            0x13, 0x12, 0x11, 0x10, // frame_data_pointer
+            0x17, 0x16, 0x15, 0x14, // unknown_3
+        ]);
+        let frame = read_struct::<GafFrameData>(&mut buf).unwrap();
+        assert_eq!({ frame.width }, 0x0102);
+        assert_eq!({ frame.height }, 0x0304);
+        assert_eq!({ frame.x_pos }, 0x0506);
+        assert_eq!({ frame.y_pos }, 0x0708);
+        assert_eq!(frame.unknown_1, 0x09);
+        assert_eq!(frame.compressed, 0x01);
+        assert_eq!({ frame.frame_pointers }, 0x0a0b);
+        assert_eq!({ frame.unknown_2 }, 0x0c0d0e0f);
+        assert_eq!({ frame.frame_data_pointer }, 0x10111213);
+        assert_eq!({ frame.unknown_3 }, 0x14151617);
+        assert_eq!(offset_of!(GafFrameData, unknown_2), 12);
+        assert_eq!(offset_of!(GafFrameData, unknown_3), 20);
     }
 }
@@ -0,0 +1,41 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::frame_cache::GafId;
+use crate::gaf_extractor::gaf_entry_names;
+use crate::Buffer;
+
+lazy_static! {
+    /// The entry names of each gaf indexed, as the file was when it was read.
+    static ref INDEX: Mutex<HashMap<GafId, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Reads the entry names of each of `paths` into the index, so conversions
+/// after it can skip the gafs without any of their textures. Returns how
+/// many gafs were indexed, those that can't be read are left out.
+pub fn build(paths: &[PathBuf]) -> usize {
+    let mut index = INDEX.lock().unwrap_or_else(|err| err.into_inner());
+    index.clear();
+    for path in paths {
+        let Ok(data) = fs::read(path) else {
+            continue;
+        };
+        if let Ok(names) = gaf_entry_names(&mut Buffer::new(data)) {
+            index.insert(GafId::new(path), names);
+        }
+    }
+    index.len()
+}
+
+/// Whether the gaf at `path` is indexed as it is now and has no entry that
+/// `wanted` is true for. A gaf that isn't indexed, or changed since, may
+/// have anything.
+pub fn lacks(path: &Path, wanted: impl Fn(&str) -> bool) -> bool {
+    let index = INDEX.lock().unwrap_or_else(|err| err.into_inner());
+    index
+        .get(&GafId::new(path))
+        .is_some_and(|names| !names.iter().any(|name| wanted(name)))
+}
@@ -0,0 +1,70 @@
+use glob::{glob_with, MatchOptions};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::Args;
+
+/// Archive extensions the engine loads, in the order it loads them. Files in
+/// later archives replace those of the same name in earlier ones.
+const ARCHIVE_EXTENSIONS: [&str; 4] = ["hpi", "ufo", "ccx", "gp3"];
+
+const CASE_INSENSITIVE: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// The game install to take textures from, from --game-dir or TA_PATH.
+pub fn find_game_dir(args: &Args) -> Option<PathBuf> {
+    args.game_dir
+        .clone()
+        .or_else(|| env::var("TA_PATH").ok().filter(|path| !path.is_empty()))
+        .map(PathBuf::from)
+}
+
+/// Folders of unpacked gafs to search instead of ./gaf_textures/, from
+/// --gaf-dir or TA_GAF_DIR, which lists them the way PATH does.
+pub fn gaf_dirs(args: &Args) -> Vec<PathBuf> {
+    if !args.gaf_dir.is_empty() {
+        return args.gaf_dir.iter().map(PathBuf::from).collect();
+    }
+    env::var_os("TA_GAF_DIR")
+        .map(|dirs| env::split_paths(&dirs).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect()
+}
+
+fn find(game_dir: &Path, pattern: &str) -> Vec<PathBuf> {
+    let pattern = game_dir.join(pattern);
+    let mut paths: Vec<PathBuf> = glob_with(&pattern.to_string_lossy(), CASE_INSENSITIVE)
+        .map(|paths| paths.flatten().collect())
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+/// Loose .gaf files anywhere under the game folder, which the engine
+/// prefers over anything inside an archive.
+pub fn loose_gafs(game_dir: &Path) -> Vec<PathBuf> {
+    find(game_dir, "**/*.gaf")
+}
+
+/// .gaf files directly in `folder`, in name order.
+pub fn gafs_in(folder: &Path) -> Vec<PathBuf> {
+    find(folder, "*.gaf")
+}
+
+/// 3do files anywhere under `folder`, in name order.
+pub fn models_under(folder: &Path) -> Vec<PathBuf> {
+    find(folder, "**/*.3do")
+}
+
+/// Archives in the game folder, in the order the engine loads them.
+pub fn archives(game_dir: &Path) -> Vec<PathBuf> {
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .flat_map(|extension| find(game_dir, &format!("*.{}", extension)))
+        .collect()
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::model::{Face, Material};
+
+/// How far apart, as a fraction of the face size, two faces can be and
+/// still count as lying on the same plane.
+const COPLANAR_EPSILON: f64 = 1e-4;
+
+/// Smallest area, as a fraction of the squared longest edge, a face needs to
+/// not count as a sliver.
+const DEGENERATE_EPSILON: f64 = 1e-6;
+
+pub fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub fn length(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+/// Area weighted normal of a polygon, using Newell's method so it copes with
+/// collinear corners.
+pub fn polygon_normal(points: &[[f64; 3]]) -> [f64; 3] {
+    let mut normal = [0.0; 3];
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        normal[0] += (a[1] - b[1]) * (a[2] + b[2]);
+        normal[1] += (a[2] - b[2]) * (a[0] + b[0]);
+        normal[2] += (a[0] - b[0]) * (a[1] + b[1]);
+    }
+    normal
+}
+
+fn face_points(face: &Face, points: &[[f64; 3]]) -> Vec<[f64; 3]> {
+    face.vertex_indexes
+        .iter()
+        .map(|&i| points.get(i as usize).copied().unwrap_or([0.0; 3]))
+        .collect()
+}
+
+/// Whether a face has fewer than three distinct corners or next to no area,
+/// which importers struggle to make a normal for.
+pub fn is_degenerate(face: &Face, points: &[[f64; 3]]) -> bool {
+    let mut corners = face_points(face, points);
+    let polygon = corners.clone();
+
+    corners.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    corners.dedup();
+    if corners.len() < 3 {
+        return true;
+    }
+
+    let longest_edge = (0..polygon.len())
+        .map(|i| length(sub(polygon[(i + 1) % polygon.len()], polygon[i])))
+        .fold(0.0, f64::max);
+    let area = length(polygon_normal(&polygon)) / 2.0;
+    area <= longest_edge * longest_edge * DEGENERATE_EPSILON
+}
+
+/// The edges of a face, each from one corner to the next.
+fn edges(face: &Face) -> Vec<(u16, u16)> {
+    let indexes = &face.vertex_indexes;
+    (0..indexes.len())
+        .map(|i| (indexes[i], indexes[(i + 1) % indexes.len()]))
+        .collect()
+}
+
+/// Joins two faces along the single edge they share, if they lie on the same
+/// plane and the result is still a convex polygon. Only coloured faces are
+/// joined, as a textured one's uvs are worked out per face and would be
+/// stretched over both.
+fn merge_pair(a: &Face, b: &Face, points: &[[f64; 3]]) -> Option<Face> {
+    if !matches!(a.material, Material::Colour(_))
+        || a.material != b.material
+        || a.texture != b.texture
+    {
+        return None;
+    }
+
+    let b_edges: HashSet<(u16, u16)> = edges(b).into_iter().collect();
+    let shared: Vec<(u16, u16)> = edges(a)
+        .into_iter()
+        .filter(|&(from, to)| b_edges.contains(&(to, from)))
+        .collect();
+    if shared.len() != 1 {
+        return None;
+    }
+    let (from, to) = shared[0];
+
+    // walk a from the end of the shared edge round to its start, then b from
+    // the start of the shared edge back round to its end
+    let rotate = |face: &Face, start: u16| -> Vec<u16> {
+        let indexes = &face.vertex_indexes;
+        let position = indexes.iter().position(|&i| i == start).unwrap();
+        indexes[position..]
+            .iter()
+            .chain(&indexes[..position])
+            .copied()
+            .collect()
+    };
+    let mut merged = rotate(a, to);
+    let b_rotated = rotate(b, from);
+    merged.extend(&b_rotated[1..b_rotated.len() - 1]);
+
+    let mut unique = merged.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() != merged.len() {
+        return None;
+    }
+
+    let face = Face {
+        material: a.material.clone(),
+        texture: a.texture.clone(),
+        vertex_indexes: merged,
+        uvs: Vec::new(),
+    };
+    let corners = face_points(&face, points);
+
+    let normal_a = polygon_normal(&face_points(a, points));
+    let normal_b = polygon_normal(&face_points(b, points));
+    let (length_a, length_b) = (length(normal_a), length(normal_b));
+    if length_a == 0.0 || length_b == 0.0 {
+        return None;
+    }
+    let normal = normal_a.map(|c| c / length_a);
+    if dot(normal, normal_b.map(|c| c / length_b)) < 1.0 - COPLANAR_EPSILON {
+        return None;
+    }
+
+    let size = corners
+        .iter()
+        .map(|&p| length(sub(p, corners[0])))
+        .fold(0.0, f64::max);
+    if corners
+        .iter()
+        .any(|&p| dot(sub(p, corners[0]), normal).abs() > size * COPLANAR_EPSILON)
+    {
+        return None;
+    }
+
+    // every corner has to turn the same way for the outline to stay convex
+    for i in 0..corners.len() {
+        let previous = corners[(i + corners.len() - 1) % corners.len()];
+        let next = corners[(i + 1) % corners.len()];
+        let turn = cross(sub(corners[i], previous), sub(next, corners[i]));
+        if dot(turn, normal) < -size * size * COPLANAR_EPSILON {
+            return None;
+        }
+    }
+
+    Some(face)
+}
+
+/// Repeatedly merges adjacent coplanar faces of a piece that share a colour,
+/// leaving anything that would become concave alone. Only faces sharing an
+/// edge are tried, found by the edge.
+pub fn merge_coplanar_faces(faces: Vec<Face>, points: &[[f64; 3]]) -> Vec<Face> {
+    let mut faces: Vec<Option<Face>> = faces.into_iter().map(Some).collect();
+    let mut by_edge: HashMap<(u16, u16), Vec<usize>> = HashMap::new();
+    for (i, face) in faces.iter().enumerate() {
+        for edge in edges(face.as_ref().unwrap()) {
+            by_edge.entry(edge).or_default().push(i);
+        }
+    }
+
+    for i in 0..faces.len() {
+        // a face that grew can have new neighbours to merge with
+        'grow: while let Some(face) = &faces[i] {
+            // the faces on the other side of its edges, and how many edges
+            // each shares with it, as only those sharing one can be merged
+            let mut neighbours = Vec::new();
+            let mut shared: HashMap<usize, usize> = HashMap::new();
+            for (from, to) in edges(face) {
+                for &j in by_edge.get(&(to, from)).into_iter().flatten() {
+                    let count = shared.entry(j).or_insert(0);
+                    if *count == 0 {
+                        neighbours.push(j);
+                    }
+                    *count += 1;
+                }
+            }
+
+            for j in neighbours {
+                if j == i || shared[&j] > 1 {
+                    continue;
+                }
+                let Some(other) = &faces[j] else {
+                    continue;
+                };
+                let Some(merged) = merge_pair(face, other, points) else {
+                    continue;
+                };
+                for (index, old) in [(i, face), (j, other)] {
+                    for edge in edges(old) {
+                        if let Some(owners) = by_edge.get_mut(&edge) {
+                            owners.retain(|&owner| owner != index);
+                        }
+                    }
+                }
+                for edge in edges(&merged) {
+                    by_edge.entry(edge).or_default().push(i);
+                }
+                faces[i] = Some(merged);
+                faces[j] = None;
+                continue 'grow;
+            }
+            break;
+        }
+    }
+    faces.into_iter().flatten().collect()
+}
@@ -0,0 +1,669 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::exporter::{self, canvas_uv, name_prefix, Exporter};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model, Piece};
+use crate::output::{relative_path, PendingFiles};
+use crate::textures::{convert_texture, png_bytes, ImageFormat};
+use crate::{texture_folder, Args, DedupeMode, TextureFallback};
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const TRIANGLES: u32 = 4;
+/// Sampler filter that keeps the game's pixels sharp.
+const NEAREST: u32 = 9728;
+
+#[derive(Serialize)]
+pub struct Asset {
+    version: &'static str,
+    generator: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Scene {
+    nodes: Vec<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Node {
+    name: String,
+    translation: [f64; 3],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mesh: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Attributes {
+    #[serde(rename = "POSITION")]
+    position: usize,
+    #[serde(rename = "TEXCOORD_0", skip_serializing_if = "Option::is_none")]
+    texcoord: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct MeshPrimitive {
+    attributes: Attributes,
+    indices: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    material: Option<usize>,
+    mode: u32,
+}
+
+#[derive(Serialize)]
+pub struct Mesh {
+    name: String,
+    primitives: Vec<MeshPrimitive>,
+}
+
+#[derive(Serialize)]
+pub struct TextureInfo {
+    index: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PbrMetallicRoughness {
+    base_color_factor: [f32; 4],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_color_texture: Option<TextureInfo>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GltfMaterial {
+    name: String,
+    pbr_metallic_roughness: PbrMetallicRoughness,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emissive_factor: Option<[f32; 3]>,
+}
+
+#[derive(Serialize)]
+pub struct Texture {
+    sampler: usize,
+    source: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sampler {
+    mag_filter: u32,
+    min_filter: u32,
+}
+
+/// An image, by path or, in a glb, in the buffer.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Image {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buffer_view: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime_type: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Buffer {
+    /// left out in a glb, whose buffer is the file's binary chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    byte_length: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferView {
+    buffer: usize,
+    byte_offset: usize,
+    byte_length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Accessor {
+    buffer_view: usize,
+    component_type: u32,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<Vec<f32>>,
+}
+
+/// A gltf document, with everything in one buffer.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gltf {
+    asset: Asset,
+    scene: usize,
+    scenes: Vec<Scene>,
+    nodes: Vec<Node>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    meshes: Vec<Mesh>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    materials: Vec<GltfMaterial>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    textures: Vec<Texture>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    samplers: Vec<Sampler>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<Image>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    accessors: Vec<Accessor>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    buffer_views: Vec<BufferView>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    buffers: Vec<Buffer>,
+    /// what the one buffer holds
+    #[serde(skip)]
+    bin: Vec<u8>,
+}
+
+impl Gltf {
+    /// Appends `data` to the buffer as a view of its own, starting on a four
+    /// byte boundary as accessors need, and returns the view's index.
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        while !self.bin.len().is_multiple_of(4) {
+            self.bin.push(0);
+        }
+        self.buffer_views.push(BufferView {
+            buffer: 0,
+            byte_offset: self.bin.len(),
+            byte_length: data.len(),
+            target,
+        });
+        self.bin.extend_from_slice(data);
+        self.buffer_views.len() - 1
+    }
+
+    fn push_accessor(&mut self, data: &[u8], target: u32, accessor: Accessor) -> usize {
+        let buffer_view = self.push_view(data, Some(target));
+        self.accessors.push(Accessor {
+            buffer_view,
+            ..accessor
+        });
+        self.accessors.len() - 1
+    }
+}
+
+/// The png an extracted texture is referenced as from the gltf at
+/// `gltf_path`, converted from its bmp next to it, or where it would be when
+/// it wasn't extracted.
+fn texture_png(
+    args: &Args,
+    texture: &str,
+    extracted: Option<&ExtractedTexture>,
+    file_prefix: &str,
+    gltf_path: &str,
+    outputs: &mut Vec<String>,
+) -> String {
+    let Some(extracted) = extracted else {
+        let png = format!("{}{}{}.png", texture_folder(args), file_prefix, texture);
+        return relative_path(&png, gltf_path);
+    };
+    let bmp = extracted.path();
+    match convert_texture(Path::new(&bmp), ImageFormat::Bmp, ImageFormat::Png) {
+        Ok(png) => {
+            let png = png.to_string_lossy().to_string();
+            if !outputs.contains(&png) {
+                outputs.push(png.clone());
+            }
+            relative_path(&png, gltf_path)
+        }
+        Err(err) => {
+            warning!("unable to convert {} to png, {}", bmp, err);
+            relative_path(&bmp, gltf_path)
+        }
+    }
+}
+
+/// An image of an extracted texture in the buffer, for a glb, or `None`
+/// with a warning when there's nothing to put there.
+fn embedded_image(
+    gltf: &mut Gltf,
+    texture: &str,
+    extracted: Option<&ExtractedTexture>,
+) -> Option<Image> {
+    let Some(extracted) = extracted else {
+        warning!(
+            "texture {} wasn't extracted, so it isn't in the glb",
+            texture
+        );
+        return None;
+    };
+    let bmp = extracted.path();
+    match png_bytes(Path::new(&bmp)) {
+        Ok(png) => Some(Image {
+            name: texture.to_string(),
+            uri: None,
+            buffer_view: Some(gltf.push_view(&png, None)),
+            mime_type: Some("image/png"),
+        }),
+        Err(err) => {
+            warning!("unable to put {} in the glb, {}", bmp, err);
+            None
+        }
+    }
+}
+
+/// The gltf materials for the model's colours and textures, and the index
+/// of each by the name faces use. With `embed` the textures go in the
+/// buffer as pngs, otherwise they're referenced relative to the gltf.
+fn add_materials(
+    gltf: &mut Gltf,
+    embed: bool,
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    output_stem: &str,
+    outputs: &mut Vec<String>,
+) -> HashMap<String, usize> {
+    let mut indexes = HashMap::new();
+    if args.no_mtl {
+        return indexes;
+    }
+    let prefix = &name_prefix(args, output_stem);
+    let file_prefix = if args.prefix_textures { prefix } else { "" };
+    let gltf_path = format!("{}.gltf", output_stem);
+
+    let palette = Palette::new(args.fullbright_range.clone());
+    for &colour_index in &model.used_colours {
+        let name = Material::Colour(colour_index).name(args);
+        if indexes.contains_key(&name) {
+            continue;
+        }
+        // gltf colours are linear
+        let [r, g, b] = colour_factors(palette.get(colour_index), true);
+        let emissive = !args.no_emissive && palette.is_fullbright(colour_index);
+        gltf.materials.push(GltfMaterial {
+            name: format!("{}{}", prefix, name),
+            pbr_metallic_roughness: PbrMetallicRoughness {
+                base_color_factor: [r, g, b, 1.0],
+                base_color_texture: None,
+                metallic_factor: 0.0,
+                roughness_factor: 1.0,
+            },
+            emissive_factor: emissive.then_some([r, g, b]),
+        });
+        indexes.insert(name, gltf.materials.len() - 1);
+    }
+
+    let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+    let untextured = args.no_textures && args.texture_fallback == TextureFallback::Kd;
+    for texture in &model.used_textures {
+        let extracted = textures.get(texture);
+        if merge_duplicates && extracted.is_some_and(|t| t.duplicate_of.is_some()) {
+            continue;
+        }
+
+        let image = match (untextured, embed) {
+            (true, _) => None,
+            (false, true) => embedded_image(gltf, texture, extracted),
+            (false, false) => Some(Image {
+                name: texture.clone(),
+                uri: Some(texture_png(
+                    args,
+                    texture,
+                    extracted,
+                    file_prefix,
+                    &gltf_path,
+                    outputs,
+                )),
+                buffer_view: None,
+                mime_type: None,
+            }),
+        };
+        let base_color_texture = image.map(|image| {
+            if gltf.samplers.is_empty() {
+                gltf.samplers.push(Sampler {
+                    mag_filter: NEAREST,
+                    min_filter: NEAREST,
+                });
+            }
+            gltf.images.push(image);
+            gltf.textures.push(Texture {
+                sampler: 0,
+                source: gltf.images.len() - 1,
+            });
+            TextureInfo {
+                index: gltf.textures.len() - 1,
+            }
+        });
+        gltf.materials.push(GltfMaterial {
+            name: format!("{}{}", prefix, texture),
+            pbr_metallic_roughness: PbrMetallicRoughness {
+                base_color_factor: [1.0; 4],
+                base_color_texture,
+                metallic_factor: 0.0,
+                roughness_factor: 1.0,
+            },
+            emissive_factor: None,
+        });
+        indexes.insert(texture.clone(), gltf.materials.len() - 1);
+    }
+    indexes
+}
+
+fn floats(values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+    values.into_iter().flat_map(f32::to_le_bytes).collect()
+}
+
+/// A mesh primitive for the faces of `piece` using one material, around
+/// the piece's origin. Corners get a vertex for each uv they're used with,
+/// and faces are split into triangles fanning from their first corner.
+fn add_primitive(
+    gltf: &mut Gltf,
+    piece: &Piece,
+    faces: &[usize],
+    material: Option<usize>,
+    textures: &HashMap<String, ExtractedTexture>,
+) -> Option<MeshPrimitive> {
+    let mut corners: HashMap<(u16, Option<[u64; 2]>), u32> = HashMap::new();
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    for &i in faces {
+        let face = &piece.faces[i];
+        let texture = face.texture.as_ref().and_then(|t| textures.get(t));
+        let mut face_corners = Vec::new();
+        for (corner, &v) in face.vertex_indexes.iter().enumerate() {
+            let Some(vertex) = piece.vertexes.get(v as usize) else {
+                continue;
+            };
+            let uv = face.uvs.get(corner).map(|&uv| {
+                let [u, v] = canvas_uv(texture, uv);
+                // gltf's v runs down the image
+                [u, 1.0 - v]
+            });
+            let index = *corners
+                .entry((v, uv.map(|uv| uv.map(f64::to_bits))))
+                .or_insert_with(|| {
+                    positions
+                        .push([0, 1, 2].map(|axis| (vertex[axis] - piece.origin[axis]) as f32));
+                    if let Some(uv) = uv {
+                        uvs.push(uv.map(|c| c as f32));
+                    }
+                    positions.len() as u32 - 1
+                });
+            face_corners.push(index);
+        }
+        for i in 2..face_corners.len() {
+            indices.extend([face_corners[0], face_corners[i - 1], face_corners[i]]);
+        }
+    }
+    if indices.is_empty() {
+        return None;
+    }
+
+    let min = (0..3)
+        .map(|axis| positions.iter().map(|p| p[axis]).fold(f32::MAX, f32::min))
+        .collect();
+    let max = (0..3)
+        .map(|axis| positions.iter().map(|p| p[axis]).fold(f32::MIN, f32::max))
+        .collect();
+    let position = gltf.push_accessor(
+        &floats(positions.iter().flatten().copied()),
+        ARRAY_BUFFER,
+        Accessor {
+            buffer_view: 0,
+            component_type: FLOAT,
+            count: positions.len(),
+            kind: "VEC3",
+            min: Some(min),
+            max: Some(max),
+        },
+    );
+    // uvs either come with every corner or none
+    let texcoord = (uvs.len() == positions.len()).then(|| {
+        gltf.push_accessor(
+            &floats(uvs.iter().flatten().copied()),
+            ARRAY_BUFFER,
+            Accessor {
+                buffer_view: 0,
+                component_type: FLOAT,
+                count: uvs.len(),
+                kind: "VEC2",
+                min: None,
+                max: None,
+            },
+        )
+    });
+    let indices_data: Vec<u8> = indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+    let indices = gltf.push_accessor(
+        &indices_data,
+        ELEMENT_ARRAY_BUFFER,
+        Accessor {
+            buffer_view: 0,
+            component_type: UNSIGNED_INT,
+            count: indices.len(),
+            kind: "SCALAR",
+            min: None,
+            max: None,
+        },
+    );
+
+    Some(MeshPrimitive {
+        attributes: Attributes { position, texcoord },
+        indices,
+        material,
+        mode: TRIANGLES,
+    })
+}
+
+/// What every node of the document is added with, each material by its
+/// index in the document.
+type Scenery<'a> = exporter::Scene<'a, usize>;
+
+/// Adds `piece` and its children as nodes translated from their parent's
+/// origin, returning the piece's node.
+fn add_node(gltf: &mut Gltf, scenery: &Scenery, piece: &Piece, parent_origin: [f64; 3]) -> usize {
+    let name = format!("{}{}", scenery.prefix, piece.name);
+
+    // one primitive per material, in the order the faces first use them
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, face) in piece.faces.iter().enumerate() {
+        let material = scenery.material_name(face);
+        match groups.iter_mut().find(|(name, _)| *name == material) {
+            Some((_, faces)) => faces.push(i),
+            None => groups.push((material, vec![i])),
+        }
+    }
+    let primitives: Vec<MeshPrimitive> = groups
+        .iter()
+        .filter_map(|(material, faces)| {
+            let material = scenery.materials.get(material).copied();
+            add_primitive(gltf, piece, faces, material, scenery.textures)
+        })
+        .collect();
+    let mesh = (!primitives.is_empty()).then(|| {
+        gltf.meshes.push(Mesh {
+            name: name.clone(),
+            primitives,
+        });
+        gltf.meshes.len() - 1
+    });
+
+    let node = gltf.nodes.len();
+    gltf.nodes.push(Node {
+        name,
+        translation: [0, 1, 2].map(|axis| piece.origin[axis] - parent_origin[axis]),
+        mesh,
+        children: Vec::new(),
+    });
+    let children = piece
+        .children
+        .iter()
+        .map(|child| add_node(gltf, scenery, child, piece.origin))
+        .collect();
+    gltf.nodes[node].children = children;
+    node
+}
+
+/// The model as a gltf document, with a node for each piece. With `embed`
+/// the textures go in its buffer as pngs, otherwise they're referenced by
+/// path and the pngs made for them are added to `outputs`.
+fn build_gltf(
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    output_stem: &str,
+    embed: bool,
+    outputs: &mut Vec<String>,
+) -> Gltf {
+    let mut gltf = Gltf {
+        asset: Asset {
+            version: "2.0",
+            generator: concat!("ta-3do-to-obj ", env!("CARGO_PKG_VERSION")),
+        },
+        scene: 0,
+        scenes: Vec::new(),
+        nodes: Vec::new(),
+        meshes: Vec::new(),
+        materials: Vec::new(),
+        textures: Vec::new(),
+        samplers: Vec::new(),
+        images: Vec::new(),
+        accessors: Vec::new(),
+        buffer_views: Vec::new(),
+        buffers: Vec::new(),
+        bin: Vec::new(),
+    };
+
+    let prefix = name_prefix(args, output_stem);
+    let materials = add_materials(
+        &mut gltf,
+        embed,
+        args,
+        model,
+        textures,
+        output_stem,
+        outputs,
+    );
+    let scenery = Scenery {
+        args,
+        prefix,
+        materials,
+        textures,
+    };
+    let roots = model
+        .pieces
+        .iter()
+        .map(|piece| add_node(&mut gltf, &scenery, piece, [0.0; 3]))
+        .collect();
+    gltf.scenes.push(Scene { nodes: roots });
+    gltf
+}
+
+/// Writes a `.gltf` with a node per piece, placed relative to its parent,
+/// and its buffer in a `.bin` next to it. Textures are referenced as pngs,
+/// made from the extracted bmps.
+pub struct GltfExporter;
+
+impl Exporter for GltfExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let gltf_path = output_stem.to_owned() + ".gltf";
+        let bin_path = output_stem.to_owned() + ".bin";
+        let mut outputs = vec![gltf_path.clone()];
+        let mut gltf = build_gltf(args, model, textures, output_stem, false, &mut outputs);
+
+        if !gltf.bin.is_empty() {
+            let bin_name = Path::new(&bin_path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            gltf.buffers.push(Buffer {
+                uri: Some(bin_name),
+                byte_length: gltf.bin.len(),
+            });
+            let mut file = pending.create(&bin_path)?;
+            file.write_all(&gltf.bin)?;
+            outputs.insert(1, bin_path);
+        }
+
+        let mut file = pending.create(&gltf_path)?;
+        let data = serde_json::to_vec_pretty(&gltf).unwrap();
+        file.write_all(&data)?;
+        Ok(outputs)
+    }
+}
+
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+
+/// A glb chunk, padded to four bytes with `padding`.
+fn glb_chunk(kind: u32, data: &[u8], padding: u8) -> Vec<u8> {
+    let mut chunk = Vec::new();
+    let padded = data.len().next_multiple_of(4);
+    chunk.extend_from_slice(&(padded as u32).to_le_bytes());
+    chunk.extend_from_slice(&kind.to_le_bytes());
+    chunk.extend_from_slice(data);
+    chunk.resize(8 + padded, padding);
+    chunk
+}
+
+/// Writes the same document as `GltfExporter` as one `.glb`, with its
+/// buffer and the textures, as pngs, inside it.
+pub struct GlbExporter;
+
+impl Exporter for GlbExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let glb_path = output_stem.to_owned() + ".glb";
+        let mut outputs = vec![glb_path.clone()];
+        let mut gltf = build_gltf(args, model, textures, output_stem, true, &mut outputs);
+        if !gltf.bin.is_empty() {
+            gltf.buffers.push(Buffer {
+                uri: None,
+                byte_length: gltf.bin.len(),
+            });
+        }
+
+        // json is padded with spaces and the binary chunk with zeroes
+        let json = serde_json::to_vec(&gltf).unwrap();
+        let mut chunks = glb_chunk(CHUNK_JSON, &json, b' ');
+        if !gltf.bin.is_empty() {
+            chunks.extend(glb_chunk(CHUNK_BIN, &gltf.bin, 0));
+        }
+
+        let mut glb = Vec::new();
+        glb.extend_from_slice(&GLB_MAGIC.to_le_bytes());
+        glb.extend_from_slice(&GLB_VERSION.to_le_bytes());
+        glb.extend_from_slice(&(12 + chunks.len() as u32).to_le_bytes());
+        glb.extend(chunks);
+        let mut file = pending.create(&glb_path)?;
+        file.write_all(&glb)?;
+        Ok(outputs)
+    }
+}
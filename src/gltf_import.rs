@@ -0,0 +1,494 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::model::Material;
+use crate::to3do::{colour_index, Object};
+use ta_3do_to_obj::palette::{colour_factors, PALETTE};
+
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+
+const TRIANGLES: u32 = 4;
+const TRIANGLE_STRIP: u32 = 5;
+const TRIANGLE_FAN: u32 = 6;
+
+#[derive(Deserialize)]
+struct Scene {
+    #[serde(default)]
+    nodes: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    name: Option<String>,
+    #[serde(default)]
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    translation: Option<[f64; 3]>,
+    rotation: Option<[f64; 4]>,
+    scale: Option<[f64; 3]>,
+    matrix: Option<[f64; 16]>,
+}
+
+#[derive(Deserialize)]
+struct MeshPrimitive {
+    attributes: HashMap<String, usize>,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "triangles")]
+    mode: u32,
+}
+
+fn triangles() -> u32 {
+    TRIANGLES
+}
+
+#[derive(Deserialize)]
+struct Mesh {
+    primitives: Vec<MeshPrimitive>,
+}
+
+#[derive(Deserialize)]
+struct TextureInfo {
+    index: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    base_color_factor: Option<[f64; 4]>,
+    base_color_texture: Option<TextureInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfMaterial {
+    name: Option<String>,
+    pbr_metallic_roughness: Option<PbrMetallicRoughness>,
+}
+
+#[derive(Deserialize)]
+struct Texture {
+    source: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Image {
+    name: Option<String>,
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<serde_json::Value>,
+}
+
+/// The parts of a gltf document a 3do can have.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Gltf {
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<Scene>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    meshes: Vec<Mesh>,
+    #[serde(default)]
+    materials: Vec<GltfMaterial>,
+    #[serde(default)]
+    textures: Vec<Texture>,
+    #[serde(default)]
+    images: Vec<Image>,
+    #[serde(default)]
+    accessors: Vec<Accessor>,
+    #[serde(default)]
+    buffer_views: Vec<BufferView>,
+    #[serde(default)]
+    buffers: Vec<Buffer>,
+}
+
+/// A column major 4x4 matrix, as gltf writes them.
+type Matrix = [f64; 16];
+
+const IDENTITY: Matrix = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [0.0; 16];
+    for column in 0..4 {
+        for row in 0..4 {
+            product[column * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).sum();
+        }
+    }
+    product
+}
+
+fn transform_point(m: &Matrix, [x, y, z]: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|row| m[row] * x + m[4 + row] * y + m[8 + row] * z + m[12 + row])
+}
+
+/// The node's transform relative to its parent, from its matrix or its
+/// translation, rotation and scale.
+fn local_matrix(node: &Node) -> Matrix {
+    if let Some(matrix) = node.matrix {
+        return matrix;
+    }
+    let [tx, ty, tz] = node.translation.unwrap_or([0.0; 3]);
+    let [x, y, z, w] = node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let [sx, sy, sz] = node.scale.unwrap_or([1.0; 3]);
+    [
+        (1.0 - 2.0 * (y * y + z * z)) * sx,
+        2.0 * (x * y + z * w) * sx,
+        2.0 * (x * z - y * w) * sx,
+        0.0,
+        2.0 * (x * y - z * w) * sy,
+        (1.0 - 2.0 * (x * x + z * z)) * sy,
+        2.0 * (y * z + x * w) * sy,
+        0.0,
+        2.0 * (x * z + y * w) * sz,
+        2.0 * (y * z - x * w) * sz,
+        (1.0 - 2.0 * (x * x + y * y)) * sz,
+        0.0,
+        tx,
+        ty,
+        tz,
+        1.0,
+    ]
+}
+
+/// The json of a gltf or glb, and the glb's binary chunk.
+fn split_glb(data: Vec<u8>) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    let word = |at: usize| {
+        data.get(at..at + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    if word(0) != Some(GLB_MAGIC) {
+        return Ok((data, None));
+    }
+
+    let mut json = None;
+    let mut bin = None;
+    let mut at = 12;
+    while let (Some(length), Some(kind)) = (word(at), word(at + 4)) {
+        let chunk = data
+            .get(at + 8..at + 8 + length as usize)
+            .ok_or("glb chunk runs past the end of the file")?;
+        match kind {
+            CHUNK_JSON => json = Some(chunk.to_vec()),
+            CHUNK_BIN => bin = Some(chunk.to_vec()),
+            _ => {}
+        }
+        at += 8 + length as usize;
+    }
+    Ok((json.ok_or("glb has no json chunk")?, bin))
+}
+
+/// The contents of every buffer, from data uris, files next to the gltf
+/// or the glb's binary chunk.
+fn load_buffers(gltf: &Gltf, path: &str, mut bin: Option<Vec<u8>>) -> Result<Vec<Vec<u8>>, String> {
+    let folder = Path::new(path).parent().unwrap_or(Path::new(""));
+    gltf.buffers
+        .iter()
+        .map(|buffer| match &buffer.uri {
+            None => bin.take().ok_or_else(|| "buffer has no uri".to_string()),
+            Some(uri) if uri.starts_with("data:") => {
+                let (_, encoded) = uri.split_once(";base64,").ok_or("data uri isn't base64")?;
+                STANDARD
+                    .decode(encoded)
+                    .map_err(|err| format!("unable to decode buffer: {}", err))
+            }
+            Some(uri) => {
+                let file = folder.join(uri);
+                fs::read(&file).map_err(|err| format!("unable to read {}: {}", file.display(), err))
+            }
+        })
+        .collect()
+}
+
+/// The accessor's values, one after another whatever their type.
+fn read_accessor(gltf: &Gltf, buffers: &[Vec<u8>], index: usize) -> Result<Vec<f64>, String> {
+    let accessor = gltf
+        .accessors
+        .get(index)
+        .ok_or_else(|| format!("no accessor {}", index))?;
+    if accessor.sparse.is_some() {
+        return Err(format!(
+            "accessor {} is sparse, which isn't supported",
+            index
+        ));
+    }
+    let components = match accessor.kind.as_str() {
+        "SCALAR" => 1,
+        "VEC2" => 2,
+        "VEC3" => 3,
+        "VEC4" => 4,
+        kind => return Err(format!("accessor {} is a {}", index, kind)),
+    };
+    let size = match accessor.component_type {
+        5120 | 5121 => 1,
+        5122 | 5123 => 2,
+        5125 | 5126 => 4,
+        kind => return Err(format!("accessor {} has component type {}", index, kind)),
+    };
+    // an accessor without a view is all zeroes
+    let Some(view) = accessor.buffer_view else {
+        return Ok(vec![0.0; accessor.count * components]);
+    };
+    let view = gltf
+        .buffer_views
+        .get(view)
+        .ok_or_else(|| format!("no buffer view {}", view))?;
+    let buffer = buffers
+        .get(view.buffer)
+        .ok_or_else(|| format!("no buffer {}", view.buffer))?;
+    let data = buffer
+        .get(view.byte_offset..view.byte_offset + view.byte_length)
+        .ok_or_else(|| format!("buffer view of accessor {} runs past its buffer", index))?;
+    let stride = view.byte_stride.unwrap_or(components * size);
+
+    let mut values = Vec::with_capacity(accessor.count * components);
+    for element in 0..accessor.count {
+        for component in 0..components {
+            let at = accessor.byte_offset + element * stride + component * size;
+            let bytes = data
+                .get(at..at + size)
+                .ok_or_else(|| format!("accessor {} runs past its buffer view", index))?;
+            values.push(match accessor.component_type {
+                5120 => bytes[0] as i8 as f64,
+                5121 => bytes[0] as f64,
+                5122 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                5123 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                5125 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+                _ => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            });
+        }
+    }
+    Ok(values)
+}
+
+/// What faces using the material become: a colour when it's named like the
+/// converter names colours, its name or its image's as a texture, or the
+/// palette colour nearest its base colour.
+fn face_material(gltf: &Gltf, material: &GltfMaterial) -> Material {
+    if let Some(name) = &material.name {
+        return match colour_index(name) {
+            Some(colour) => Material::Colour(colour),
+            None => Material::Texture(name.clone()),
+        };
+    }
+
+    let pbr = material.pbr_metallic_roughness.as_ref();
+    let image = pbr
+        .and_then(|pbr| pbr.base_color_texture.as_ref())
+        .and_then(|info| gltf.textures.get(info.index))
+        .and_then(|texture| texture.source)
+        .and_then(|source| gltf.images.get(source));
+    if let Some(image) = image {
+        let uri_stem = image.uri.as_ref().map(|uri| {
+            Path::new(uri)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        });
+        if let Some(name) = image.name.clone().or(uri_stem) {
+            return Material::Texture(name);
+        }
+    }
+
+    // gltf colours are linear
+    let factor = pbr
+        .and_then(|pbr| pbr.base_color_factor)
+        .unwrap_or([1.0; 4]);
+    let distance = |colour: [u8; 3]| {
+        let linear = colour_factors(colour, true);
+        (0..3)
+            .map(|i| (linear[i] as f64 - factor[i]).powi(2))
+            .sum::<f64>()
+    };
+    let nearest = (0..PALETTE.len())
+        .min_by(|&a, &b| distance(PALETTE[a]).total_cmp(&distance(PALETTE[b])))
+        .unwrap();
+    Material::Colour(nearest)
+}
+
+/// The corners of each triangle of a primitive, as indexes into its
+/// vertexes.
+fn primitive_triangles(
+    gltf: &Gltf,
+    buffers: &[Vec<u8>],
+    primitive: &MeshPrimitive,
+    vertex_count: usize,
+) -> Result<Vec<[usize; 3]>, String> {
+    let indices: Vec<usize> = match primitive.indices {
+        Some(indices) => read_accessor(gltf, buffers, indices)?
+            .into_iter()
+            .map(|i| i as usize)
+            .collect(),
+        None => (0..vertex_count).collect(),
+    };
+    if let Some(&index) = indices.iter().find(|&&i| i >= vertex_count) {
+        return Err(format!("index {} is past the primitive's vertexes", index));
+    }
+
+    let count = indices.len().saturating_sub(2);
+    Ok(match primitive.mode {
+        TRIANGLES => indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect(),
+        // every other triangle of a strip is wound the other way
+        TRIANGLE_STRIP => (0..count)
+            .map(|i| match i % 2 {
+                0 => [indices[i], indices[i + 1], indices[i + 2]],
+                _ => [indices[i + 1], indices[i], indices[i + 2]],
+            })
+            .collect(),
+        TRIANGLE_FAN => (0..count)
+            .map(|i| [indices[0], indices[i + 1], indices[i + 2]])
+            .collect(),
+        _ => {
+            warning!("skipping a primitive of points or lines, which a 3do can't have");
+            Vec::new()
+        }
+    })
+}
+
+/// Reads the node tree of a gltf or glb as objects for a 3do: a node is a
+/// piece pivoting where the node's transform puts its origin, with every
+/// rotation and scale on the way baked into its vertexes since a 3do's
+/// pieces are only offset from each other.
+pub fn read_gltf(path: &str) -> Result<(Vec<Object>, Vec<[f64; 3]>), String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+    let (json, bin) = split_glb(data).map_err(|err| format!("{}: {}", path, err))?;
+    let gltf: Gltf = serde_json::from_slice(&json)
+        .map_err(|err| format!("{} isn't a gltf document: {}", path, err))?;
+    let buffers = load_buffers(&gltf, path, bin).map_err(|err| format!("{}: {}", path, err))?;
+
+    let roots = match gltf.scenes.get(gltf.scene.unwrap_or(0)) {
+        Some(scene) => scene.nodes.clone(),
+        None => {
+            let children: HashSet<usize> = gltf
+                .nodes
+                .iter()
+                .flat_map(|node| node.children.iter().copied())
+                .collect();
+            (0..gltf.nodes.len())
+                .filter(|i| !children.contains(i))
+                .collect()
+        }
+    };
+
+    let mut objects = Vec::new();
+    let mut vertexes = Vec::new();
+    let mut names = HashSet::new();
+    // the node, its parent's object and its parent's transform in place
+    let mut stack: Vec<(usize, Option<String>, Matrix)> = roots
+        .into_iter()
+        .rev()
+        .map(|root| (root, None, IDENTITY))
+        .collect();
+    let mut visited = HashSet::new();
+    while let Some((index, parent, parent_matrix)) = stack.pop() {
+        let node = gltf
+            .nodes
+            .get(index)
+            .ok_or_else(|| format!("{}: no node {}", path, index))?;
+        if !visited.insert(index) {
+            return Err(format!("{}: node {} is in the tree twice", path, index));
+        }
+        let matrix = multiply(&parent_matrix, &local_matrix(node));
+
+        let mut name = node
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("node{}", index));
+        if !names.insert(name.clone()) {
+            warning!(
+                "there are several nodes named {}, renaming one {}_{}",
+                name,
+                name,
+                index
+            );
+            name = format!("{}_{}", name, index);
+            names.insert(name.clone());
+        }
+
+        // vertexes at the same place are shared by the piece's primitives
+        let mut faces = Vec::new();
+        let mut places: HashMap<[u64; 3], usize> = HashMap::new();
+        let primitives = node
+            .mesh
+            .and_then(|mesh| gltf.meshes.get(mesh))
+            .map_or(&[][..], |mesh| &mesh.primitives[..]);
+        for primitive in primitives {
+            let Some(&position) = primitive.attributes.get("POSITION") else {
+                continue;
+            };
+            let positions = read_accessor(&gltf, &buffers, position)
+                .map_err(|err| format!("{}: {}", path, err))?;
+            let global: Vec<usize> = positions
+                .chunks_exact(3)
+                .map(|p| {
+                    let point = transform_point(&matrix, [p[0], p[1], p[2]]);
+                    *places.entry(point.map(f64::to_bits)).or_insert_with(|| {
+                        vertexes.push(point);
+                        vertexes.len() - 1
+                    })
+                })
+                .collect();
+            let material = primitive
+                .material
+                .and_then(|material| gltf.materials.get(material))
+                .map(|material| face_material(&gltf, material));
+            for triangle in primitive_triangles(&gltf, &buffers, primitive, global.len())
+                .map_err(|err| format!("{}: {}", path, err))?
+            {
+                faces.push((material.clone(), triangle.map(|i| global[i]).to_vec()));
+            }
+        }
+
+        for &child in node.children.iter().rev() {
+            stack.push((child, Some(name.clone()), matrix));
+        }
+        objects.push(Object {
+            name,
+            parent,
+            pivot: Some(transform_point(&matrix, [0.0; 3])),
+            dotted: false,
+            faces,
+        });
+    }
+    Ok((objects, vertexes))
+}
@@ -0,0 +1,241 @@
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::mem::size_of;
+
+use ta_3do_to_obj::records::check_in_file;
+
+use crate::{read_struct, Buffer, Record};
+
+/// `marker` of every HPI archive, "HAPI" in ascii.
+const HPI_MARKER: u32 = 0x49504148;
+/// `version` of a Total Annihilation HPI archive.
+const HPI_VERSION_TA: u32 = 0x00010000;
+
+#[repr(C, packed)]
+#[derive(Deserialize, Debug, Copy, Clone)]
+struct HpiVersion {
+    marker: u32,
+    version: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Deserialize, Debug, Copy, Clone)]
+struct HpiHeader {
+    directory_size: u32,
+    header_key: u32,
+    start: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Deserialize, Debug, Copy, Clone)]
+struct HpiDirectory {
+    number_of_entries: u32,
+    entry_list_offset: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Deserialize, Debug, Copy, Clone)]
+struct HpiDirectoryEntry {
+    name_offset: u32,
+    data_offset: u32,
+    is_directory: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Deserialize, Debug, Copy, Clone)]
+struct HpiFileData {
+    data_offset: u32,
+    file_size: u32,
+    compression: u8,
+}
+
+impl Record for HpiVersion {
+    const SIZE: usize = 8;
+}
+
+impl Record for HpiHeader {
+    const SIZE: usize = 12;
+}
+
+impl Record for HpiDirectory {
+    const SIZE: usize = 8;
+}
+
+impl Record for HpiDirectoryEntry {
+    const SIZE: usize = 9;
+}
+
+impl Record for HpiFileData {
+    const SIZE: usize = 9;
+}
+
+const _: () = assert!(size_of::<HpiVersion>() == HpiVersion::SIZE);
+const _: () = assert!(size_of::<HpiHeader>() == HpiHeader::SIZE);
+const _: () = assert!(size_of::<HpiDirectory>() == HpiDirectory::SIZE);
+const _: () = assert!(size_of::<HpiDirectoryEntry>() == HpiDirectoryEntry::SIZE);
+const _: () = assert!(size_of::<HpiFileData>() == HpiFileData::SIZE);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HpiEntry {
+    pub path: String,
+    pub is_directory: bool,
+    pub size: u32,
+    pub compression: &'static str,
+}
+
+fn compression_name(compression: u8) -> &'static str {
+    match compression {
+        0 => "none",
+        1 => "lz77",
+        2 => "zlib",
+        _ => "unknown",
+    }
+}
+
+/// Undoes the xor encryption HPI archives apply to everything after the
+/// header, `position` being the file offset of the first byte.
+fn decrypt(data: &mut [u8], key: u8, position: usize) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        let position_key = (position + i) as u8 ^ key;
+        *byte = position_key ^ !*byte;
+    }
+}
+
+/// Adds the entries of the directory at `offset` and everything under it to
+/// `entries`. `visited` holds the directories already read, so offsets that
+/// lead back up the tree can't make it read forever.
+fn read_directory(
+    buf: &mut Buffer,
+    offset: u32,
+    prefix: &str,
+    entries: &mut Vec<HpiEntry>,
+    visited: &mut HashSet<u32>,
+) -> Result<(), String> {
+    if !visited.insert(offset) {
+        return Err(format!(
+            "directory at {:#x} is listed inside itself",
+            offset
+        ));
+    }
+    check_in_file(&buf.data, offset, 1, HpiDirectory::SIZE, "a directory")?;
+    buf.seek(offset);
+    let directory = read_struct::<HpiDirectory>(buf)?;
+    check_in_file(
+        &buf.data,
+        directory.entry_list_offset,
+        directory.number_of_entries,
+        HpiDirectoryEntry::SIZE,
+        "directory entries",
+    )?;
+
+    for i in 0..directory.number_of_entries {
+        buf.seek(directory.entry_list_offset + i * HpiDirectoryEntry::SIZE as u32);
+        let entry = read_struct::<HpiDirectoryEntry>(buf)?;
+
+        buf.seek(entry.name_offset);
+        let name = buf
+            .read_string(256)
+            .unwrap_or_else(|_| format!("entry_{}", i));
+        let path = format!("{}{}", prefix, name);
+
+        if entry.is_directory != 0 {
+            entries.push(HpiEntry {
+                path: path.clone() + "/",
+                is_directory: true,
+                size: 0,
+                compression: "none",
+            });
+            read_directory(buf, entry.data_offset, &(path + "/"), entries, visited)?;
+        } else {
+            check_in_file(&buf.data, entry.data_offset, 1, HpiFileData::SIZE, &path)?;
+            buf.seek(entry.data_offset);
+            let file_data = read_struct::<HpiFileData>(buf)?;
+            entries.push(HpiEntry {
+                path,
+                is_directory: false,
+                size: file_data.file_size,
+                compression: compression_name(file_data.compression),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads the directory tree of an HPI archive, returning every file and
+/// directory with its full path inside the archive.
+pub fn read_hpi_entries(data: Vec<u8>) -> Result<Vec<HpiEntry>, String> {
+    let mut buf = Buffer::new(data);
+    if buf.data.len() < HpiVersion::SIZE + HpiHeader::SIZE {
+        return Err("file is too short to be an HPI archive".to_string());
+    }
+
+    let version = read_struct::<HpiVersion>(&mut buf)?;
+    let (marker, version) = (version.marker, version.version);
+    if marker != HPI_MARKER {
+        return Err(format!("not an HPI archive (marker {:#010x})", marker));
+    }
+    if version != HPI_VERSION_TA {
+        return Err(format!("unsupported HPI version {:#010x}", version));
+    }
+
+    buf.seek_relative(HpiVersion::SIZE as i64);
+    let header = read_struct::<HpiHeader>(&mut buf)?;
+    let (directory_size, header_key, start) =
+        (header.directory_size, header.header_key, header.start);
+
+    if start > directory_size || directory_size as usize > buf.data.len() {
+        return Err("directory lies outside the file".to_string());
+    }
+
+    if header_key != 0 {
+        let key = !((header_key.wrapping_mul(4)) | (header_key >> 6)) as u8;
+        decrypt(
+            &mut buf.data[start as usize..directory_size as usize],
+            key,
+            start as usize,
+        );
+    }
+
+    let mut entries = Vec::new();
+    read_directory(&mut buf, start, "", &mut entries, &mut HashSet::new())?;
+
+    Ok(entries)
+}
+
+/// Prints the entries of the archive at `archive`, only the files matching
+/// `filter` when there is one.
+pub fn list_hpi(archive: &str, filter: Option<&str>, json: bool) -> Result<(), String> {
+    let data = fs::read(archive).map_err(|err| format!("unable to read {}: {}", archive, err))?;
+    let entries = read_hpi_entries(data).map_err(|err| format!("{}: {}", archive, err))?;
+
+    let pattern = filter
+        .map(|f| Pattern::new(f).map_err(|err| format!("{} isn't a valid filter: {}", f, err)))
+        .transpose()?;
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+    let entries: Vec<HpiEntry> = entries
+        .into_iter()
+        .filter(|e| match &pattern {
+            Some(pattern) => !e.is_directory && pattern.matches_with(&e.path, options),
+            None => true,
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return Ok(());
+    }
+
+    for entry in &entries {
+        if entry.is_directory {
+            println!("{}", entry.path);
+        } else {
+            println!("{}\t{}\t{}", entry.path, entry.size, entry.compression);
+        }
+    }
+    Ok(())
+}
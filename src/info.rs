@@ -0,0 +1,53 @@
+use std::fs;
+
+use ta_3do_to_obj::{count_pieces, read_3do, Piece};
+
+use crate::FIXED_POINT_SCALE;
+
+fn print_pieces(pieces: &[Piece], depth: usize) {
+    for piece in pieces {
+        let raw = &piece.raw;
+        let name = raw
+            .name
+            .as_deref()
+            .map(String::from_utf8_lossy)
+            .unwrap_or("(unreadable name)".into());
+        let [x, y, z] = raw.from_parent.map(|c| c as f64 / *FIXED_POINT_SCALE);
+        print!(
+            "{}{} at {}, {}, {}: {} vertexes, {} primitives",
+            "  ".repeat(depth),
+            name,
+            x,
+            y,
+            z,
+            raw.vertexes.len(),
+            raw.primitives.len()
+        );
+        if let Some(primitive) = raw.primitives.get(raw.selection_primitive as usize) {
+            print!(
+                ", selected by primitive {} of {} corners",
+                raw.selection_primitive,
+                primitive.vertex_indexes.len()
+            );
+        }
+        println!();
+        print_pieces(&piece.children, depth + 1);
+    }
+}
+
+/// Prints the piece tree of the 3do at `path`, each piece indented under its
+/// parent with its offset from it in game units, how many vertexes and
+/// primitives it has and which primitive selects the unit.
+pub fn print_info(path: &str) -> Result<(), String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+    let model = read_3do(&data).map_err(|err| format!("{}: {}", path, err))?;
+    println!(
+        "{}: {} bytes, {} pieces, version signature {:#010x}",
+        path,
+        data.len(),
+        count_pieces(&model.pieces, |piece| &piece.children),
+        model.pieces[0].raw.version_signature
+    );
+    print_pieces(&model.pieces, 0);
+    Ok(())
+}
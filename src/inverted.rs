@@ -0,0 +1,127 @@
+use crate::geometry::{cross, dot, sub};
+use crate::log::{self, InvertedPiece};
+use crate::model::{Model, Piece};
+
+/// Smallest volume, as a fraction of the cube of the piece's size, a piece
+/// needs for the way it's wound to be told. Flat pieces enclose nothing.
+const VOLUME_EPSILON: f64 = 1e-6;
+
+/// Volume the faces of `piece` enclose, from tetrahedra between its centroid
+/// and each triangle of the faces. Its sign says which way they're wound.
+/// `None` when the piece is too flat to tell.
+pub fn signed_volume(piece: &Piece) -> Option<f64> {
+    let corners: Vec<[f64; 3]> = piece
+        .faces
+        .iter()
+        .flat_map(|face| &face.vertex_indexes)
+        .filter_map(|&i| piece.vertexes.get(i as usize).copied())
+        .collect();
+    if corners.is_empty() {
+        return None;
+    }
+    let centre = corners
+        .iter()
+        .fold([0.0; 3], |sum, c| [0, 1, 2].map(|i| sum[i] + c[i]))
+        .map(|sum| sum / corners.len() as f64);
+
+    let mut volume = 0.0;
+    for face in &piece.faces {
+        let points: Vec<[f64; 3]> = face
+            .vertex_indexes
+            .iter()
+            .filter_map(|&i| piece.vertexes.get(i as usize).copied())
+            .map(|point| sub(point, centre))
+            .collect();
+        for i in 2..points.len() {
+            volume += dot(points[0], cross(points[i - 1], points[i])) / 6.0;
+        }
+    }
+
+    let size = (0..3)
+        .map(|axis| {
+            let values = corners.iter().map(|c| c[axis]);
+            values.clone().fold(f64::MIN, f64::max) - values.fold(f64::MAX, f64::min)
+        })
+        .fold(0.0, f64::max);
+    (volume.abs() > size * size * size * VOLUME_EPSILON).then_some(volume)
+}
+
+/// Index in `Model::all_pieces` of each piece wound the other way from most
+/// of the model, with its volume.
+fn find_inverted(model: &Model) -> Vec<(usize, f64)> {
+    let volumes: Vec<Option<f64>> = model.all_pieces().into_iter().map(signed_volume).collect();
+    let positive = volumes.iter().flatten().filter(|&&v| v > 0.0).count();
+    let negative = volumes.iter().flatten().filter(|&&v| v < 0.0).count();
+    // a tie goes to whichever way the bulk of the model is wound
+    let usual_sign = match positive.cmp(&negative) {
+        std::cmp::Ordering::Greater => 1.0,
+        std::cmp::Ordering::Less => -1.0,
+        std::cmp::Ordering::Equal => volumes.iter().flatten().sum::<f64>().signum(),
+    };
+    volumes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, volume)| volume.map(|volume| (i, volume)))
+        .filter(|&(_, volume)| volume.signum() != usual_sign)
+        .collect()
+}
+
+/// Reverses the corners of every face of the chosen pieces, and their uvs
+/// along with them.
+fn reverse_winding(model: &mut Model, chosen: &[usize]) {
+    fn walk(piece: &mut Piece, number: &mut usize, chosen: &[usize]) {
+        if chosen.contains(number) {
+            for face in &mut piece.faces {
+                face.vertex_indexes.reverse();
+                face.uvs.reverse();
+            }
+        }
+        *number += 1;
+        for child in &mut piece.children {
+            walk(child, number, chosen);
+        }
+    }
+
+    let mut number = 0;
+    for piece in &mut model.pieces {
+        walk(piece, &mut number, chosen);
+    }
+}
+
+/// Warns about each piece wound the other way from the rest of the model,
+/// turning them around with `fix`, and lists them in the result.
+pub fn check_inverted(model: &mut Model, fix: bool) {
+    let inverted = find_inverted(model);
+    if inverted.is_empty() {
+        return;
+    }
+
+    let pieces = model.all_pieces();
+    let report: Vec<InvertedPiece> = inverted
+        .iter()
+        .map(|&(i, volume)| InvertedPiece {
+            name: pieces[i].name.clone(),
+            volume,
+            fixed: fix,
+        })
+        .collect();
+    for piece in &report {
+        if fix {
+            info!(
+                "Reversed the faces of piece {}, which was wound the other way from the rest of the model.",
+                piece.name
+            );
+        } else {
+            warning!(
+                "piece {} is wound the other way from the rest of the model and may look inside out, use --fix-inverted to turn its faces around.",
+                piece.name
+            );
+        }
+    }
+    log::update(|result| result.inverted_pieces = report);
+
+    if fix {
+        let chosen: Vec<usize> = inverted.iter().map(|&(i, _)| i).collect();
+        reverse_winding(model, &chosen);
+    }
+}
@@ -0,0 +1,309 @@
+//! The 3do format as plain data, for tools that load a model, change it and
+//! save it again rather than converting it one way.
+//!
+//! `read_3do` gives every field of every object record and primitive as the
+//! file has them, and `write_3do` lays them out again, so a file read and
+//! written untouched comes back with the same contents, if not always the
+//! same offsets.
+//!
+//! ```no_run
+//! let data = std::fs::read("armcom.3do").unwrap();
+//! let mut model = ta_3do_to_obj::read_3do(&data).unwrap();
+//! model.pieces[0].raw.from_parent[1] += 65536;
+//! std::fs::write("armcom.3do", ta_3do_to_obj::write_3do(&model)).unwrap();
+//! ```
+//!
+//! `records` reads the object and primitive records one at a time, checking
+//! each offset and count against the length of the file, for tools that walk
+//! the tree themselves. `palette` has the game's colours, and `observer` the
+//! events `read_3do_observed` reports as it goes.
+
+use std::collections::HashSet;
+
+pub mod observer;
+pub mod palette;
+pub mod records;
+pub mod visit;
+
+pub use visit::{visit_3do, Primitive, Surface, Visitor};
+
+use observer::{Event, Observer, Severity};
+use records::{
+    read_cstring, read_object, read_primitives, read_vertex_indexes, read_vertexes,
+    DEFAULT_MAX_DEPTH, OBJECT_SIZE,
+};
+
+/// A primitive exactly as the 3do has it, whether or not it became a face.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawPrimitive {
+    pub color_index: u32,
+    pub is_colored: u32,
+    pub texture_name: Option<Vec<u8>>,
+    /// empty when a conversion read more than its --max-face-indexes
+    pub vertex_indexes: Vec<u16>,
+    pub always_0: u32,
+    pub unknown_1: u32,
+    pub unknown_2: u32,
+}
+
+/// Every field of a piece's object record and what it points to, as the 3do
+/// has them, apart from the offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawPiece {
+    pub version_signature: u32,
+    /// `None` when the name couldn't be read
+    pub name: Option<Vec<u8>>,
+    pub from_parent: [i32; 3],
+    pub selection_primitive: u32,
+    pub always_0: u32,
+    pub vertexes: Vec<[i32; 3]>,
+    pub primitives: Vec<RawPrimitive>,
+}
+
+/// A piece and the pieces under it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub raw: RawPiece,
+    pub children: Vec<Piece>,
+}
+
+impl Piece {
+    /// The piece's name, with anything that isn't utf-8 replaced.
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(self.raw.name.as_deref().unwrap_or_default()).into_owned()
+    }
+}
+
+/// Adds the names of `pieces` and everything under them to `names`, parents
+/// before their children.
+pub fn all_names(pieces: &[Piece], names: &mut Vec<String>) {
+    for piece in pieces {
+        names.push(piece.name());
+        all_names(&piece.children, names);
+    }
+}
+
+/// How many pieces there are in `pieces` and under them, for any tree of
+/// pieces `children` gives the children of.
+pub fn count_pieces<T>(pieces: &[T], children: fn(&T) -> &[T]) -> usize {
+    pieces
+        .iter()
+        .map(|piece| 1 + count_pieces(children(piece), children))
+        .sum()
+}
+
+/// The piece tree of a 3do, the root piece and any siblings it has.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Model {
+    pub pieces: Vec<Piece>,
+}
+
+/// Reads the object record at `offset` and its siblings after it, with
+/// everything under them, `depth` levels down the tree.
+fn read_siblings(
+    data: &[u8],
+    mut offset: u32,
+    depth: u32,
+    visited: &mut HashSet<u32>,
+    observer: &mut dyn Observer,
+) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    loop {
+        if !visited.insert(offset) {
+            return Err(format!("object at {:#x} is in the tree twice", offset));
+        }
+        if depth > DEFAULT_MAX_DEPTH {
+            return Err(format!(
+                "object at {:#x} is {} levels deep, over the limit of {}",
+                offset, depth, DEFAULT_MAX_DEPTH
+            ));
+        }
+        let object = read_object(data, offset)?;
+        let name =
+            read_cstring(data, object.offset_to_object_name, usize::MAX).map(|name| name.to_vec());
+        if let Err(err) = &name {
+            observer.event(&Event::Message {
+                severity: Severity::Warning,
+                text: &format!("unreadable name of the piece at {:#x}, {}", offset, err),
+            });
+        }
+        let primitives = read_primitives(data, &object)?
+            .iter()
+            .map(|primitive| {
+                Ok(RawPrimitive {
+                    color_index: primitive.color_index,
+                    is_colored: primitive.is_colored,
+                    texture_name: match primitive.offset_to_texture_name {
+                        0 => None,
+                        offset => Some(read_cstring(data, offset, usize::MAX)?.to_vec()),
+                    },
+                    vertex_indexes: read_vertex_indexes(data, primitive)?,
+                    always_0: primitive.always_0,
+                    unknown_1: primitive.unknown_1,
+                    unknown_2: primitive.unknown_2,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let raw = RawPiece {
+            version_signature: object.version_signature,
+            name: name.ok(),
+            from_parent: [
+                object.x_from_parent,
+                object.y_from_parent,
+                object.z_from_parent,
+            ],
+            selection_primitive: object.offset_to_selection_primitive,
+            always_0: object.always_0,
+            vertexes: read_vertexes(data, &object)?,
+            primitives,
+        };
+        observer.event(&Event::PieceRead {
+            name: &String::from_utf8_lossy(raw.name.as_deref().unwrap_or_default()),
+            vertexes: raw.vertexes.len(),
+            faces: raw.primitives.len(),
+        });
+
+        pieces.push(Piece {
+            raw,
+            children: match object.offset_to_child_object {
+                0 => Vec::new(),
+                child => read_siblings(data, child, depth + 1, visited, observer)?,
+            },
+        });
+
+        match object.offset_to_sibling_object {
+            0 => return Ok(pieces),
+            sibling => offset = sibling,
+        }
+    }
+}
+
+/// Reads the piece tree starting at the root object at the start of the
+/// file. Unlike a conversion it has no limits on counts or names beyond the
+/// size of the file, apart from trees deeper than `DEFAULT_MAX_DEPTH`, and
+/// nothing is checked or warned about.
+pub fn read_3do(data: &[u8]) -> Result<Model, String> {
+    read_3do_observed(data, &mut Unobserved)
+}
+
+/// `read_3do`, telling `observer` about each piece as it's read and warning
+/// it about names that can't be.
+pub fn read_3do_observed(data: &[u8], observer: &mut dyn Observer) -> Result<Model, String> {
+    if data.len() < OBJECT_SIZE {
+        return Err("too short to hold an object record".to_string());
+    }
+    Ok(Model {
+        pieces: read_siblings(data, 0, 0, &mut HashSet::new(), observer)?,
+    })
+}
+
+/// Observer of a read nobody asked to hear about.
+struct Unobserved;
+
+impl Observer for Unobserved {
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Lists `siblings` and everything under them, parents before their children,
+/// along with the index of each one's next sibling and first child.
+fn flatten<'a>(
+    siblings: &'a [Piece],
+    records: &mut Vec<(&'a RawPiece, Option<usize>, Option<usize>)>,
+) {
+    let mut previous: Option<usize> = None;
+    for piece in siblings {
+        let index = records.len();
+        if let Some(previous) = previous {
+            records[previous].1 = Some(index);
+        }
+        records.push((&piece.raw, None, None));
+        if !piece.children.is_empty() {
+            records[index].2 = Some(records.len());
+            flatten(&piece.children, records);
+        }
+        previous = Some(index);
+    }
+}
+
+/// Appends `value` little endian, as the game's files have their integers.
+pub fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend(value.to_le_bytes());
+}
+
+fn push_cstring(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend(bytes);
+    data.push(0);
+}
+
+/// Lays the pieces out as a 3do again: every object record first, then the
+/// names, vertexes, index arrays, texture names and primitives they point to.
+pub fn write_3do(model: &Model) -> Vec<u8> {
+    let mut records = Vec::new();
+    flatten(&model.pieces, &mut records);
+
+    let data_start = records.len() * OBJECT_SIZE;
+    let object_offset = |index: Option<usize>| index.map_or(0, |i| (i * OBJECT_SIZE) as u32);
+
+    let mut headers = Vec::new();
+    let mut data = Vec::new();
+    for &(raw, sibling, child) in &records {
+        let offset = |data: &Vec<u8>| (data_start + data.len()) as u32;
+
+        let name_offset = offset(&data);
+        push_cstring(&mut data, raw.name.as_deref().unwrap_or_default());
+
+        let vertex_offset = offset(&data);
+        for vertex in &raw.vertexes {
+            for coordinate in vertex {
+                data.extend(coordinate.to_le_bytes());
+            }
+        }
+
+        let mut primitive_offsets = Vec::new();
+        for primitive in &raw.primitives {
+            let index_offset = offset(&data);
+            for index in &primitive.vertex_indexes {
+                data.extend(index.to_le_bytes());
+            }
+            let texture_offset = match &primitive.texture_name {
+                Some(name) => {
+                    let texture_offset = offset(&data);
+                    push_cstring(&mut data, name);
+                    texture_offset
+                }
+                None => 0,
+            };
+            primitive_offsets.push((index_offset, texture_offset));
+        }
+
+        let primitive_offset = offset(&data);
+        for (primitive, (index_offset, texture_offset)) in
+            raw.primitives.iter().zip(primitive_offsets)
+        {
+            push_u32(&mut data, primitive.color_index);
+            push_u32(&mut data, primitive.vertex_indexes.len() as u32);
+            push_u32(&mut data, primitive.always_0);
+            push_u32(&mut data, index_offset);
+            push_u32(&mut data, texture_offset);
+            push_u32(&mut data, primitive.unknown_1);
+            push_u32(&mut data, primitive.unknown_2);
+            push_u32(&mut data, primitive.is_colored);
+        }
+
+        push_u32(&mut headers, raw.version_signature);
+        push_u32(&mut headers, raw.vertexes.len() as u32);
+        push_u32(&mut headers, raw.primitives.len() as u32);
+        push_u32(&mut headers, raw.selection_primitive);
+        for coordinate in raw.from_parent {
+            headers.extend(coordinate.to_le_bytes());
+        }
+        push_u32(&mut headers, name_offset);
+        push_u32(&mut headers, raw.always_0);
+        push_u32(&mut headers, vertex_offset);
+        push_u32(&mut headers, primitive_offset);
+        push_u32(&mut headers, object_offset(sibling));
+        push_u32(&mut headers, object_offset(child));
+    }
+    headers.extend(data);
+    headers
+}
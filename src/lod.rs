@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::exporter::Exporter;
+use crate::gaf_extractor::ExtractedTexture;
+use crate::geometry::{cross, dot, length, sub};
+use crate::model::{Face, Material, Model, Piece};
+use crate::obj::ObjExporter;
+use crate::output::PendingFiles;
+use crate::piece_select::forget_unused_materials;
+use crate::Args;
+
+/// Smallest area, as a fraction of the area before, a triangle can be left
+/// with by a collapse.
+const SLIVER_FRACTION: f64 = 1e-3;
+
+/// A corner of a triangle, as an index into the welded vertexes, with its uv.
+type Corner = (usize, Option<[f64; 2]>);
+
+struct Triangle {
+    corners: [Corner; 3],
+    material: Material,
+    texture: Option<String>,
+}
+
+impl Triangle {
+    fn has(&self, vertex: usize) -> bool {
+        self.corners.iter().any(|&(v, _)| v == vertex)
+    }
+
+    fn normal(&self, points: &[[f64; 3]]) -> [f64; 3] {
+        let [a, b, c] = self.corners.map(|(v, _)| points[v]);
+        cross(sub(b, a), sub(c, a))
+    }
+}
+
+/// Parses a --lod-fractions value, which has to be above 0 and at most 1.
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value > 0.0 && value <= 1.0 => Ok(value),
+        Ok(_) => Err(format!("{} has to be above 0 and at most 1", s)),
+        Err(err) => Err(format!("{} isn't a number: {}", s, err)),
+    }
+}
+
+/// Fraction of the triangles --lods level `level` keeps, from 1.
+fn level_fraction(args: &Args, level: usize) -> f64 {
+    match args.lod_fractions.get(level - 1) {
+        Some(&fraction) => fraction,
+        None => {
+            let given = args.lod_fractions.len();
+            let last = args.lod_fractions.last().copied().unwrap_or(1.0);
+            last * 0.5f64.powi((level - given) as i32)
+        }
+    }
+}
+
+/// Vertexes at exactly the same place made into one, and what each vertex
+/// became.
+fn weld(vertexes: &[[f64; 3]]) -> (Vec<[f64; 3]>, Vec<usize>) {
+    let mut welded = Vec::new();
+    let mut seen: HashMap<[u64; 3], usize> = HashMap::new();
+    let remap = vertexes
+        .iter()
+        .map(|vertex| {
+            *seen.entry(vertex.map(f64::to_bits)).or_insert_with(|| {
+                welded.push(*vertex);
+                welded.len() - 1
+            })
+        })
+        .collect();
+    (welded, remap)
+}
+
+/// The faces of a piece split into triangles fanning from their first
+/// corner, leaving out any that welding closed up.
+fn triangulate(faces: &[Face], remap: &[usize]) -> Vec<Triangle> {
+    let mut triangles = Vec::new();
+    for face in faces {
+        let corners: Vec<Corner> = face
+            .vertex_indexes
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| (remap[v as usize], face.uvs.get(i).copied()))
+            .collect();
+        for i in 2..corners.len() {
+            let corners = [corners[0], corners[i - 1], corners[i]];
+            let [a, b, c] = corners.map(|(v, _)| v);
+            if a == b || b == c || a == c {
+                continue;
+            }
+            triangles.push(Triangle {
+                corners,
+                material: face.material.clone(),
+                texture: face.texture.clone(),
+            });
+        }
+    }
+    triangles
+}
+
+/// Vertexes sharing a triangle with `vertex`.
+fn neighbours(triangles: &[&Triangle], vertex: usize) -> Vec<usize> {
+    let mut found: Vec<usize> = triangles
+        .iter()
+        .flat_map(|triangle| triangle.corners.map(|(v, _)| v))
+        .filter(|&v| v != vertex)
+        .collect();
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Whether moving `remove` onto `keep` leaves the surface as it was
+/// connected, without folding any triangle over or opening a hole.
+fn can_collapse(points: &[[f64; 3]], triangles: &[Triangle], keep: usize, remove: usize) -> bool {
+    let around_keep: Vec<&Triangle> = triangles.iter().filter(|t| t.has(keep)).collect();
+    let around_remove: Vec<&Triangle> = triangles.iter().filter(|t| t.has(remove)).collect();
+    let shared: Vec<&Triangle> = around_remove
+        .iter()
+        .filter(|t| t.has(keep))
+        .copied()
+        .collect();
+    if shared.is_empty() || shared.len() > 2 {
+        return false;
+    }
+
+    // the only vertexes next to both are the corners opposite the edge, or
+    // the collapse would pinch the surface together
+    let opposite: Vec<usize> = neighbours(&shared, keep)
+        .into_iter()
+        .filter(|&v| v != remove)
+        .collect();
+    let remove_neighbours = neighbours(&around_remove, remove);
+    let common: Vec<usize> = neighbours(&around_keep, keep)
+        .into_iter()
+        .filter(|v| remove_neighbours.contains(v))
+        .collect();
+    if common != opposite {
+        return false;
+    }
+
+    // an edge out of `remove` used by one triangle is on an edge of the
+    // surface, which only a collapse along that edge of the surface keeps
+    let on_border = remove_neighbours
+        .into_iter()
+        .any(|v| around_remove.iter().filter(|t| t.has(v)).count() == 1);
+    if on_border && shared.len() != 1 {
+        return false;
+    }
+
+    for triangle in around_remove.iter().filter(|t| !t.has(keep)) {
+        let before = triangle.normal(points);
+        let [a, b, c] = triangle
+            .corners
+            .map(|(v, _)| points[if v == remove { keep } else { v }]);
+        let after = cross(sub(b, a), sub(c, a));
+        if dot(before, after) <= 0.0 || length(after) <= length(before) * SLIVER_FRACTION {
+            return false;
+        }
+    }
+    true
+}
+
+/// Collapses the shortest edges it safely can until no more than `target`
+/// triangles are left or no edge can go. Each pass only touches vertexes
+/// no earlier collapse of the pass moved next to, so the result doesn't
+/// depend on anything but the input.
+fn decimate(points: &[[f64; 3]], triangles: &mut Vec<Triangle>, target: usize) {
+    while triangles.len() > target {
+        let mut edges: Vec<(f64, usize, usize)> = Vec::new();
+        for triangle in triangles.iter() {
+            for i in 0..3 {
+                let a = triangle.corners[i].0;
+                let b = triangle.corners[(i + 1) % 3].0;
+                edges.push((length(sub(points[a], points[b])), a.min(b), a.max(b)));
+            }
+        }
+        edges.sort_by(|x, y| x.0.total_cmp(&y.0).then(x.1.cmp(&y.1)).then(x.2.cmp(&y.2)));
+        edges.dedup_by(|x, y| x.1 == y.1 && x.2 == y.2);
+
+        let mut touched = vec![false; points.len()];
+        let mut collapsed = false;
+        for (_, a, b) in edges {
+            if triangles.len() <= target {
+                break;
+            }
+            if touched[a] || touched[b] {
+                continue;
+            }
+            let Some((keep, remove)) = [(a, b), (b, a)]
+                .into_iter()
+                .find(|&(keep, remove)| can_collapse(points, triangles, keep, remove))
+            else {
+                continue;
+            };
+
+            triangles.retain(|t| !(t.has(keep) && t.has(remove)));
+            for triangle in triangles.iter_mut() {
+                for corner in &mut triangle.corners {
+                    if corner.0 == remove {
+                        corner.0 = keep;
+                    }
+                }
+            }
+            touched[remove] = true;
+            for triangle in triangles.iter().filter(|t| t.has(keep)) {
+                for (v, _) in triangle.corners {
+                    touched[v] = true;
+                }
+            }
+            collapsed = true;
+        }
+        if !collapsed {
+            return;
+        }
+    }
+}
+
+/// A copy of `piece` and its children with `fraction` of their triangles,
+/// leaving out the geometry of pieces that end up with fewer than
+/// `min_triangles`, and the pieces themselves when nothing is under them.
+fn lod_piece(
+    piece: &Piece,
+    fraction: f64,
+    min_triangles: usize,
+    dropped: &mut Vec<String>,
+) -> Option<Piece> {
+    let (points, remap) = weld(&piece.vertexes);
+    let mut triangles = triangulate(&piece.faces, &remap);
+    let had_triangles = !triangles.is_empty();
+    let target = (triangles.len() as f64 * fraction).ceil() as usize;
+    decimate(&points, &mut triangles, target);
+    if triangles.len() < min_triangles {
+        if had_triangles {
+            dropped.push(piece.name.clone());
+        }
+        triangles.clear();
+    }
+    let children: Vec<Piece> = piece
+        .children
+        .iter()
+        .filter_map(|child| lod_piece(child, fraction, min_triangles, dropped))
+        .collect();
+    if triangles.is_empty() && children.is_empty() {
+        return None;
+    }
+
+    // only the vertexes still used, in the order they were welded
+    let mut used = vec![None; points.len()];
+    let mut vertexes = Vec::new();
+    for (v, point) in points.iter().enumerate() {
+        if triangles.iter().any(|t| t.has(v)) {
+            used[v] = Some(vertexes.len() as u16);
+            vertexes.push(*point);
+        }
+    }
+    let faces = triangles
+        .into_iter()
+        .map(|triangle| Face {
+            vertex_indexes: triangle.corners.map(|(v, _)| used[v].unwrap()).to_vec(),
+            uvs: triangle.corners.iter().filter_map(|&(_, uv)| uv).collect(),
+            material: triangle.material,
+            texture: triangle.texture,
+        })
+        .collect();
+
+    Some(Piece {
+        name: piece.name.clone(),
+        origin: piece.origin,
+        vertexes,
+        faces,
+        children,
+        raw: piece.raw.clone(),
+    })
+}
+
+/// `model` with `fraction` of its triangles, and the names of the pieces
+/// left out for having too few.
+fn lod_model(model: &Model, fraction: f64, min_triangles: usize) -> (Model, Vec<String>) {
+    let mut dropped = Vec::new();
+    let pieces = model
+        .pieces
+        .iter()
+        .filter_map(|piece| lod_piece(piece, fraction, min_triangles, &mut dropped))
+        .collect();
+    let mut lod = Model {
+        pieces,
+        used_colours: model.used_colours.clone(),
+        used_textures: model.used_textures.clone(),
+        texture_files: model.texture_files.clone(),
+        ..Model::default()
+    };
+    forget_unused_materials(&mut lod);
+    (lod, dropped)
+}
+
+/// Triangles in `model` once every face is split into them.
+pub fn triangle_count(model: &Model) -> usize {
+    model
+        .all_pieces()
+        .iter()
+        .flat_map(|piece| &piece.faces)
+        .map(|face| face.vertex_indexes.len().saturating_sub(2))
+        .sum()
+}
+
+/// Writes each of the --lods levels of `model` as its own obj and mtl,
+/// `<name>_lod1.obj` and on, returning the paths written and the triangles
+/// each level ended up with.
+pub fn write_lods(
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<(Vec<String>, Vec<usize>)> {
+    // the collision meshes belong with the full model
+    let lod_args = Args {
+        collision: None,
+        ..args.clone()
+    };
+    let full = triangle_count(model);
+    let mut outputs = Vec::new();
+    let mut counts = Vec::new();
+    for level in 1..=args.lods.unwrap_or(0) as usize {
+        let fraction = level_fraction(args, level);
+        let (lod, dropped) = lod_model(model, fraction, args.lod_min_triangles);
+        let triangles = triangle_count(&lod);
+        let stem = format!("{}_lod{}", output_stem, level);
+        outputs.extend(ObjExporter.export(&lod_args, &lod, textures, &stem, pending)?);
+
+        info!(
+            "LOD {}: {} triangles of {} ({:.0}% asked for).",
+            level,
+            triangles,
+            full,
+            fraction * 100.0
+        );
+        if !dropped.is_empty() {
+            info!(
+                "LOD {} leaves out {} pieces with fewer than {} triangles: {}.",
+                level,
+                dropped.len(),
+                args.lod_min_triangles,
+                dropped.join(", ")
+            );
+        }
+        counts.push(triangles);
+    }
+    Ok((outputs, counts))
+}
@@ -0,0 +1,348 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::any::Any;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic;
+use std::process;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub use ta_3do_to_obj::observer::{Event, Observer, Severity, UnknownValue};
+
+/// Prints progress to stdout, or to stderr when stdout is reserved for the
+/// --json result.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::info(format!($($arg)*))
+    };
+}
+
+/// Prints a warning to stderr and keeps it for the --json result.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::warning(format!($($arg)*))
+    };
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct Counts {
+    pub pieces: u32,
+    pub vertexes: u32,
+    pub faces: u32,
+    pub colours: u32,
+    pub textures: u32,
+    pub degenerate_faces: u32,
+    pub unused_vertexes: u32,
+    pub duplicate_textures: u32,
+    pub broken_gaf_entries: u32,
+}
+
+/// A piece wound the other way from the rest of its model.
+#[derive(Serialize, Clone)]
+pub struct InvertedPiece {
+    pub name: String,
+    /// what its faces enclose, negative when they face inwards
+    pub volume: f64,
+    /// whether --fix-inverted turned its faces around
+    pub fixed: bool,
+}
+
+/// What --json prints once the run is over, successful or not.
+#[derive(Serialize, Default, Clone)]
+pub struct RunResult {
+    pub input: Option<String>,
+    pub success: bool,
+    /// the input was unchanged since the last conversion
+    pub skipped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub outputs: Vec<String>,
+    pub counts: Counts,
+    pub warnings: Vec<String>,
+    pub missing_textures: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inverted_pieces: Vec<InvertedPiece>,
+    /// textures the engine recolours per player, extracted with --logos
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub team_colour_textures: Vec<String>,
+    /// triangles of each --lods level, from the first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lod_triangles: Vec<usize>,
+    /// only filled in with --show-unknowns
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknowns: Vec<UnknownValue>,
+    pub elapsed_ms: u128,
+}
+
+/// How the command line shows a run: messages as they come, and with
+/// --verbose, each piece, texture and file as well.
+struct Console {
+    json: bool,
+    verbose: bool,
+}
+
+impl Console {
+    fn print(&self, message: &str) {
+        if self.json {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+}
+
+impl Observer for Console {
+    fn event(&mut self, event: &Event) {
+        match *event {
+            Event::Message { severity, text } => match severity {
+                Severity::Info => self.print(text),
+                Severity::Warning => eprintln!("Warning: {}", text),
+                Severity::Error => eprintln!("Error: {}", text),
+            },
+            Event::Unknown(unknown) => self.print(&format!(
+                "{} at {:#x}: {} = {:#x}",
+                unknown.file, unknown.offset, unknown.field, unknown.value
+            )),
+            _ if !self.verbose => {}
+            Event::PieceRead {
+                name,
+                vertexes,
+                faces,
+            } => self.print(&format!(
+                "Read piece {}, {} vertexes and {} faces",
+                name, vertexes, faces
+            )),
+            Event::TextureFound { name, gaf } => self.print(&format!("Found {} in {}", name, gaf)),
+            Event::TextureMissing { name } => self.print(&format!("Didn't find {}", name)),
+            Event::FileWritten { path } => self.print(&format!("Wrote {}", path)),
+            Event::FileKept { path } => self.print(&format!("Kept {}", path)),
+        }
+    }
+}
+
+/// Writes each event as a line of json, for --events.
+pub struct EventFile {
+    writer: BufWriter<File>,
+}
+
+impl EventFile {
+    pub fn new(file: File) -> EventFile {
+        EventFile {
+            writer: BufWriter::new(file),
+        }
+    }
+}
+
+impl Observer for EventFile {
+    fn event(&mut self, event: &Event) {
+        // flushed per event, so whatever reads the file sees them as they come
+        let _ = serde_json::to_writer(&mut self.writer, event)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(self.writer))
+            .and_then(|_| self.writer.flush());
+    }
+}
+
+#[derive(Default)]
+struct LogState {
+    json: bool,
+    /// results go back to whoever called `run` instead of being printed,
+    /// a server's clients or a batch
+    serving: bool,
+    started: Option<Instant>,
+    finished: bool,
+    result: RunResult,
+    observers: Vec<Box<dyn Observer>>,
+    /// how many of the observers stay for every run, the console and those
+    /// a whole batch shares
+    kept_observers: usize,
+}
+
+lazy_static! {
+    static ref LOG: Mutex<LogState> = Mutex::new(LogState::default());
+}
+
+thread_local! {
+    /// Set while `quietly` runs.
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string())
+}
+
+fn print_result(state: &mut LogState) {
+    if state.finished {
+        return;
+    }
+    state.finished = true;
+    for observer in &mut state.observers {
+        observer.finish();
+    }
+    state.result.elapsed_ms = state
+        .started
+        .map(|started| started.elapsed().as_millis())
+        .unwrap_or_default();
+    if state.json && !state.serving {
+        println!("{}", serde_json::to_string(&state.result).unwrap());
+    }
+}
+
+/// Starts timing the run and shows it on the console. With `json`
+/// everything else goes to stderr and a single json object is printed when
+/// the run ends, even by panicking.
+pub fn start(json: bool, verbose: bool) {
+    {
+        let mut state = LOG.lock().unwrap();
+        state.json = json;
+        state.started = Some(Instant::now());
+        state.observers.push(Box::new(Console { json, verbose }));
+        state.kept_observers = state.observers.len();
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // the lock may be held by whatever panicked
+        if let Ok(mut state) = LOG.try_lock() {
+            state.result.error = Some(panic_message(info.payload()));
+            print_result(&mut state);
+        }
+    }));
+}
+
+/// Starts a server, logging like `start` with `json` except that each
+/// request's result is handed back by `run`.
+pub fn start_serving(verbose: bool) {
+    start_batch(true, verbose);
+}
+
+/// Starts converting several models, logging like `start` except that each
+/// model's result is handed back by `run`.
+pub fn start_batch(json: bool, verbose: bool) {
+    start(json, verbose);
+    LOG.lock().unwrap().serving = true;
+}
+
+/// Runs one request of a server, or one model of a batch, as a run of its
+/// own, returning its result however it ends.
+pub fn run(f: impl FnOnce() -> Result<(), String>) -> RunResult {
+    {
+        let mut state = LOG.lock().unwrap();
+        // everything after the kept observers was added by the last request
+        let kept = state.kept_observers;
+        state.observers.truncate(kept);
+        state.result = RunResult::default();
+        state.finished = false;
+        state.started = Some(Instant::now());
+    }
+
+    if let Err(err) = f() {
+        error(err);
+    }
+    let mut state = LOG.lock().unwrap();
+    print_result(&mut state);
+    state.result.clone()
+}
+
+/// Runs `f` without printing or keeping anything it logs, for work that
+/// repeats what was already reported.
+pub fn quietly<T>(f: impl FnOnce() -> T) -> T {
+    let was_quiet = QUIET.with(|quiet| quiet.replace(true));
+    let result = f();
+    QUIET.with(|quiet| quiet.set(was_quiet));
+    result
+}
+
+fn is_quiet() -> bool {
+    QUIET.with(|quiet| quiet.get())
+}
+
+/// Adds an observer that gets every event from here on.
+pub fn observe(observer: Box<dyn Observer>) {
+    LOG.lock().unwrap().observers.push(observer);
+}
+
+/// Adds an observer that, unlike one added with `observe`, stays for every
+/// run after this one.
+pub fn observe_every_run(observer: Box<dyn Observer>) {
+    let mut state = LOG.lock().unwrap();
+    state.observers.push(observer);
+    state.kept_observers = state.observers.len();
+}
+
+fn notify(state: &mut LogState, event: &Event) {
+    for observer in &mut state.observers {
+        observer.event(event);
+    }
+}
+
+pub fn emit(event: Event) {
+    if is_quiet() {
+        return;
+    }
+    notify(&mut LOG.lock().unwrap(), &event);
+}
+
+pub fn info(message: String) {
+    emit(Event::Message {
+        severity: Severity::Info,
+        text: &message,
+    });
+}
+
+pub fn warning(message: String) {
+    if is_quiet() {
+        return;
+    }
+    let mut state = LOG.lock().unwrap();
+    let event = Event::Message {
+        severity: Severity::Warning,
+        text: &message,
+    };
+    notify(&mut state, &event);
+    state.result.warnings.push(message);
+}
+
+pub fn unknown(unknown: UnknownValue) {
+    if is_quiet() {
+        return;
+    }
+    let mut state = LOG.lock().unwrap();
+    notify(&mut state, &Event::Unknown(&unknown));
+    state.result.unknowns.push(unknown);
+}
+
+pub fn error(message: String) {
+    let mut state = LOG.lock().unwrap();
+    let event = Event::Message {
+        severity: Severity::Error,
+        text: &message,
+    };
+    notify(&mut state, &event);
+    state.result.error = Some(message);
+}
+
+/// Fills in part of the --json result.
+pub fn update(update: impl FnOnce(&mut RunResult)) {
+    update(&mut LOG.lock().unwrap().result);
+}
+
+/// Ends the run successfully, printing the --json result.
+pub fn finish() {
+    let mut state = LOG.lock().unwrap();
+    state.result.success = true;
+    print_result(&mut state);
+}
+
+/// Ends the run with `code`, printing the --json result first.
+pub fn exit(code: i32) -> ! {
+    print_result(&mut LOG.lock().unwrap());
+    process::exit(code);
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use ta_3do_to_obj::palette::{Palette, Rgb, PALETTE};
+
+/// Player colours, in the order the game hands them out.
+const TEAMS: [(&str, Rgb); 10] = [
+    ("blue", [0x1F, 0x3F, 0xDF]),
+    ("red", [0xDF, 0x1F, 0x1F]),
+    ("white", [0xEF, 0xEF, 0xEF]),
+    ("green", [0x1F, 0xBF, 0x1F]),
+    ("navy", [0x1F, 0x1F, 0x7F]),
+    ("purple", [0x9F, 0x1F, 0xBF]),
+    ("yellow", [0xEF, 0xDF, 0x1F]),
+    ("black", [0x3F, 0x3F, 0x3F]),
+    ("orange", [0xFF, 0x8F, 0x1F]),
+    ("brown", [0x8F, 0x5F, 0x2F]),
+];
+
+/// What the team colour indices are drawn as without a player.
+const NEUTRAL: Rgb = [0xA0, 0xA0, 0xA0];
+
+/// How much darker each team colour index is than the one before.
+const SHADE_STEP: f64 = 0.12;
+
+/// Gaf the unit side decals are kept in, by its file name without the
+/// extension.
+const LOGOS_GAF: &str = "logos";
+
+/// The player whose colour --logos paints logos in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Team {
+    /// grey, for textures that are recoloured later
+    Neutral,
+    /// index into the game's player colours
+    Player(usize),
+}
+
+/// Parses a --logos team, a player number from 0, a colour name or
+/// `neutral`.
+pub fn parse_team(s: &str) -> Result<Team, String> {
+    if s.eq_ignore_ascii_case("neutral") {
+        return Ok(Team::Neutral);
+    }
+    let names: Vec<&str> = TEAMS.iter().map(|(name, _)| *name).collect();
+    match s.parse::<usize>() {
+        Ok(number) if number < TEAMS.len() => Ok(Team::Player(number)),
+        Ok(number) => Err(format!(
+            "there is no player {}, pick 0 to {}",
+            number,
+            TEAMS.len() - 1
+        )),
+        Err(_) => names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(s))
+            .map(Team::Player)
+            .ok_or_else(|| {
+                format!(
+                    "{} isn't a team, use a number or one of neutral, {}",
+                    s,
+                    names.join(", ")
+                )
+            }),
+    }
+}
+
+impl Team {
+    pub fn name(self) -> &'static str {
+        match self {
+            Team::Neutral => "neutral",
+            Team::Player(number) => TEAMS[number].0,
+        }
+    }
+
+    /// The game palette with the team colour indices shading from the
+    /// team's colour down.
+    pub fn palette(self) -> [Rgb; 256] {
+        let colour = match self {
+            Team::Neutral => NEUTRAL,
+            Team::Player(number) => TEAMS[number].1,
+        };
+        let mut palette = PALETTE;
+        for (step, index) in Palette::default().team_colour_range().enumerate() {
+            let shade = 1.0 - step as f64 * SHADE_STEP;
+            palette[index] = colour.map(|c| (c as f64 * shade).round() as u8);
+        }
+        palette
+    }
+}
+
+/// Whether `gaf_name` is the gaf the logos are kept in.
+pub fn is_logos_gaf(gaf_name: &str) -> bool {
+    Path::new(gaf_name)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case(LOGOS_GAF))
+}
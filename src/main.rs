@@ -1,23 +1,841 @@
-use bincode::config::{FixintEncoding, WithOtherIntEncoding};
+use bincode::config::{FixintEncoding, LittleEndian, WithOtherEndian, WithOtherIntEncoding};
 use bincode::{DefaultOptions, Options};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::mem;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::process;
 
-mod palette;
-use palette::{colour_name, PALETTE};
+#[macro_use]
+mod log;
+use log::Counts;
+
+use ta_3do_to_obj::palette::{export_palette, parse_palette_range, Palette};
+use ta_3do_to_obj::records::{
+    check_in_file, read_cstring, read_object, DEFAULT_MAX_DEPTH, DEFAULT_MAX_FACE_INDEXES,
+    DEFAULT_MAX_OBJECTS, DEFAULT_MAX_PRIMITIVES, DEFAULT_MAX_VERTEXES, TA_VERSION_SIGNATURE,
+};
+
+mod diff;
+use diff::diff_3do;
+
+mod frame_cache;
+
+mod gaf_index;
 
 mod gaf_extractor;
-use gaf_extractor::extract_textures_from_gafs;
+use gaf_extractor::{
+    extract_all_from_gafs, extract_textures_from_gafs, gaf_entry_names, load_texture_files,
+    ExtractedTexture,
+};
+
+mod geometry;
+
+mod output;
+use output::PendingFiles;
+
+mod textures;
+use textures::{convert_textures, ImageFormat};
+
+mod game;
+use game::models_under;
+
+mod verify;
+use verify::{check_transforms, verify_output};
+
+mod cache;
+use cache::{hash_bytes, options_fingerprint, Cache};
+
+mod hpi;
+use hpi::list_hpi;
+
+mod model;
+use model::{read_model, Model};
+
+mod doctor;
+use doctor::doctor;
+
+mod blender;
+use blender::write_blender_helper;
+
+mod exporter;
+use exporter::{name_prefix, Format};
+
+mod obj;
+
+mod usda;
+
+mod usdz;
+
+mod zip;
+
+mod gltf;
+
+mod dae;
+
+mod fbx;
+
+mod ply;
+
+mod stl;
+
+mod s3o;
+use s3o::read_s3o;
+
+mod threemf;
+
+mod x3d;
+
+mod preview;
+use preview::{write_contact_sheet, write_preview};
+
+mod buildpic;
+use buildpic::write_buildpic;
+
+mod script;
+use script::{write_script_skeleton, ScriptFormat};
+
+mod usage;
+use usage::TextureUsage;
+
+mod texture_map;
+use texture_map::TextureMap;
+
+mod roundtrip;
+use roundtrip::lossless_check;
+
+mod shared_mtl;
+
+mod inverted;
+use inverted::check_inverted;
+
+mod lod;
+use lod::{parse_fraction, write_lods};
+
+mod logos;
+use logos::{parse_team, Team};
+
+mod manifest;
+use manifest::Manifest;
+
+mod collision;
+use collision::{write_collision_obj, CollisionShape};
+
+mod three;
+
+mod texture_adjust;
+use texture_adjust::parse_positive;
+
+mod texture_index;
+use texture_index::write_texture_index;
+
+mod budget;
+use budget::Budget;
+
+mod server;
+use server::serve;
+
+mod build_stage;
+use build_stage::{apply_build_stage, BuildStage};
+
+mod piece_select;
+use piece_select::{ask_for_pieces, keep_subtrees, named_pieces};
+
+mod scan;
+use scan::scan_3do;
+
+mod to3do;
+use to3do::to_3do;
+
+mod gltf_import;
+
+mod merge;
+use merge::{merge_3do, parse_position};
+
+mod transform;
+use transform::transform_3do;
 
-#[derive(Parser, Debug)]
+mod optimize;
+use optimize::optimize_3do;
+
+mod retexture;
+use retexture::{parse_rename, retexture_3do};
+
+mod rename_piece;
+use rename_piece::rename_piece_3do;
+
+mod info;
+use info::print_info;
+
+mod validate;
+use validate::{validate_3do, Limits};
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(required_unless_present_any = ["export_palette", "serve", "recursive"])]
+    file: Option<String>,
+
+    /// More models to convert with the same flags, each a path or a glob like 'units/*.3do'
+    #[arg(value_name = "FILE")]
+    more_files: Vec<String>,
+
+    /// Convert every 3do anywhere under DIR
+    #[arg(long, value_name = "DIR", conflicts_with = "file")]
+    recursive: Option<String>,
+
+    /// Name the outputs after PATH instead of the model, e.g. -o build/armcom.obj, other outputs get their own extensions
+    #[arg(short, long, value_name = "PATH", conflicts_with_all = ["out_dir", "recursive", "name_from_model"])]
+    output: Option<String>,
+
+    /// Write the outputs to DIR instead of next to the model, with --recursive to the same places under DIR as the models are under its folder
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<String>,
+
+    /// Write the palette to <STEM>.png and <STEM>.gpl instead of converting a model
+    #[arg(long, value_name = "STEM")]
+    export_palette: Option<String>,
+
+    /// How vertex coordinates are converted from the 3do's fixed-point values
+    #[arg(long, value_enum, default_value_t = CoordMode::Fixed)]
+    coords: CoordMode,
+
+    /// Parse the file even if its version signature isn't a known 3do version
+    #[arg(long)]
+    force_parse: bool,
+
+    /// Palette indices that are drawn self-illuminated, e.g. 240-255
+    #[arg(long, value_parser = parse_palette_range, default_value = "240-255")]
+    fullbright_range: RangeInclusive<usize>,
+
+    /// Name colour materials by their hex value alone, as older versions did, so palette indices with the same colour share a material
+    #[arg(long)]
+    rgb_material_names: bool,
+
+    /// Don't mark fullbright palette colours as emissive
+    #[arg(long)]
+    no_emissive: bool,
+
+    /// Colour space of the Kd and Ke values written to the mtl
+    #[arg(long, value_enum, default_value_t = ColorSpace::Srgb)]
+    color_space: ColorSpace,
+
+    /// Extract every frame of the used GAF entries, not just the first
+    #[arg(long)]
+    all_frames: bool,
+
+    /// With --all-frames, also write an .anim.json for single-frame entries
+    #[arg(long, requires = "all_frames")]
+    always_manifest: bool,
+
+    /// Name the outputs after the model's root piece instead of the input file
+    #[arg(long)]
+    name_from_model: bool,
+
+    /// Write each palette colour as a small texture so every face is textured
+    #[arg(long)]
+    bake_colors: bool,
+
+    /// Which material wins when a primitive has both a colour and a texture
+    #[arg(long, value_enum, default_value_t = MaterialPriority::Texture)]
+    material_priority: MaterialPriority,
+
+    /// What texture coordinates to write and how faces reference them
+    #[arg(long, value_enum, default_value_t = UvMode::Quad)]
+    uv_mode: UvMode,
+
+    /// Write each distinct texture coordinate once, and none for faces that don't show a texture
+    #[arg(long)]
+    dedupe_uvs: bool,
+
+    /// Extract every logo from logos.gaf with its team colour indices painted in TEAM's colour, a player number from 0 or a colour name (blue, red, white, green, navy, purple, yellow, black, orange, brown). Without TEAM they're painted neutral grey
+    #[arg(long, value_name = "TEAM", value_parser = parse_team, num_args = 0..=1, default_missing_value = "neutral")]
+    logos: Option<Team>,
+
+    /// Multiply the colours of textures taken from gafs by this, after any other adjustment
+    #[arg(long, value_name = "FACTOR", value_parser = parse_positive, default_value_t = 1.0)]
+    texture_gain: f64,
+
+    /// Brighten the midtones of textures taken from gafs with a gamma above 1, or darken them below 1
+    #[arg(long, value_name = "GAMMA", value_parser = parse_positive, default_value_t = 1.0)]
+    texture_gamma: f64,
+
+    /// Stretch each texture taken from a gaf so its darkest value becomes black and its brightest white, before gamma and gain
+    #[arg(long)]
+    texture_autolevel: bool,
+
+    /// Make extracted textures power of two sized
+    #[arg(long, value_enum, default_value_t = PotMode::Off)]
+    pot: PotMode,
+
+    /// Write textures with identical pixels once and share the file between their materials
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "alias")]
+    dedupe_textures: Option<DedupeMode>,
+
+    /// Merge adjacent faces that lie on the same plane and share a colour, textured faces are left as they are
+    #[arg(long)]
+    merge_coplanar: bool,
+
+    /// Longest piece or texture name to scan for before giving up on it
+    #[arg(long, default_value_t = 256)]
+    max_name_length: usize,
+
+    /// Also read a root object nothing points to at this offset, in decimal or 0x hex, for files holding several models
+    #[arg(long, value_name = "OFFSET", value_parser = parse_offset)]
+    root_offset: Vec<u32>,
+
+    /// Print the fields of the 3do and gaf files nobody knows the meaning of whenever they aren't their usual value
+    #[arg(long)]
+    show_unknowns: bool,
+
+    /// Most vertexes a piece can have before the file is treated as broken
+    #[arg(long, default_value_t = DEFAULT_MAX_VERTEXES)]
+    max_vertexes: u32,
+
+    /// Most primitives a piece can have before the file is treated as broken
+    #[arg(long, default_value_t = DEFAULT_MAX_PRIMITIVES)]
+    max_primitives: u32,
+
+    /// Most levels of pieces under one another before the file is treated as broken
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH)]
+    max_depth: u32,
+
+    /// Most pieces a file can have before it's treated as broken
+    #[arg(long, default_value_t = DEFAULT_MAX_OBJECTS)]
+    max_objects: u32,
+
+    /// Most corners a primitive can have before the file is treated as broken
+    #[arg(long, default_value_t = DEFAULT_MAX_FACE_INDEXES)]
+    max_face_indexes: u32,
+
+    /// Widest or tallest gaf frame to decode, bigger ones are skipped as corrupt
+    #[arg(long, default_value_t = 4096)]
+    max_frame_size: u32,
+
+    /// Most pixels a gaf frame can have before it's skipped as corrupt
+    #[arg(long, default_value_t = 4096 * 1024)]
+    max_frame_pixels: u64,
+
+    /// Keep faces with repeated corners or no area instead of skipping them
+    #[arg(long)]
+    keep_degenerate: bool,
+
+    /// Export only this piece and the pieces under it, can be repeated. Without a name, shows the piece tree and asks which pieces to export
+    #[arg(long, value_name = "NAME", num_args = 0..=1)]
+    piece: Option<Vec<String>>,
+
+    /// Whether to keep the pieces only shown while the unit is built, found by their names
+    #[arg(long, value_enum, default_value_t = BuildStage::All)]
+    build_stage: BuildStage,
+
+    /// Name pattern, like nano*, marking build pieces for --build-stage instead of the usual names, can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    stage_pattern: Vec<String>,
+
+    /// Show the piece tree and ask which pieces to export, the same as --piece without a name
+    #[arg(long, conflicts_with = "piece")]
+    interactive: bool,
+
+    /// Print each piece's offset from its parent and where that places it, to compare against other tools
+    #[arg(long)]
+    debug_offsets: bool,
+
+    /// Drop vertexes no face uses instead of only reporting them
+    #[arg(long)]
+    prune_unused: bool,
+
+    /// Gaf file to take textures from instead of ./gaf_textures/, can be a glob and be repeated
+    #[arg(long, value_name = "FILE")]
+    gaf: Vec<String>,
+
+    /// Folder of gaf files to search instead of ./gaf_textures/, can be repeated, defaults to the folders in $TA_GAF_DIR
+    #[arg(long, value_name = "DIR")]
+    gaf_dir: Vec<String>,
+
+    /// Total Annihilation install to search for gaf files, defaults to $TA_PATH
+    #[arg(long)]
+    game_dir: Option<String>,
+
+    /// Print more about where things are looked for
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Re-read the written obj and mtl and fail if they reference anything that doesn't exist
+    #[arg(long)]
+    verify: bool,
+
+    /// Print a single json object describing the run to stdout, everything else goes to stderr
+    #[arg(long)]
+    json: bool,
+
+    /// Convert even if the input and options haven't changed since the last run
+    #[arg(long)]
+    rebuild: bool,
+
+    /// Gaf file name to take textures from first when several have them, can be repeated
+    #[arg(long, value_name = "GAF")]
+    prefer: Vec<String>,
+
+    /// Megabytes of decoded gaf frames to keep in memory for reuse, which helps when one run converts many models. 0 turns it off
+    #[arg(long, value_name = "MB", default_value_t = 64)]
+    frame_cache_mb: u32,
+
+    /// Frame of each gaf entry to use as its texture, instead of the first one that isn't empty
+    #[arg(long)]
+    frame: Option<u16>,
+
+    /// Don't look for textures in the gaf files at all
+    #[arg(long)]
+    no_textures: bool,
+
+    /// Write a single .obj without an mtllib, materials or textures
+    #[arg(long)]
+    no_mtl: bool,
+
+    /// Keep usemtl lines when there is no .mtl, some tools use them to group faces
+    #[arg(long, requires = "no_mtl")]
+    keep_usemtl: bool,
+
+    /// What textured materials get in the mtl when textures are skipped
+    #[arg(long, value_enum, default_value_t = TextureFallback::Map, requires = "no_textures")]
+    texture_fallback: TextureFallback,
+
+    /// Keep every line of the obj to at most this many bytes, for parsers with a fixed line buffer
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(72..))]
+    max_line_length: Option<u32>,
+
+    /// How faces too long for --max-line-length are shortened
+    #[arg(long, value_enum, default_value_t = LongFaces::Wrap, requires = "max_line_length")]
+    long_faces: LongFaces,
+
+    /// Whether the obj has an object per piece or a group per material spanning every piece
+    #[arg(long, value_enum, default_value_t = GroupBy::Piece)]
+    group_by: GroupBy,
+
+    /// Gzip the .obj and .mtl, writing them as .obj.gz and .mtl.gz
+    #[arg(long)]
+    compress: bool,
+
+    /// Which .mtl the mtllib line of a compressed obj names
+    #[arg(long, value_enum, default_value_t = MtllibTarget::Compressed, requires = "compress")]
+    mtllib_target: MtllibTarget,
+
+    /// Format to write the model as
+    #[arg(long, value_enum, default_value_t = Format::Obj)]
+    format: Format,
+
+    /// Put this before every piece and material name, so models imported into one scene don't collide. {name} becomes the output file name, and the flag alone means "{name}_"
+    #[arg(long, num_args = 0..=1, default_missing_value = "{name}_")]
+    prefix: Option<String>,
+
+    /// Also put the prefix before the names of the extracted texture files
+    #[arg(long, requires = "prefix")]
+    prefix_textures: bool,
+
+    /// Also render a SIZE by SIZE picture of the model to <name>_preview.png
+    #[arg(long, value_name = "SIZE", value_parser = clap::value_parser!(u32).range(8..=4096))]
+    preview: Option<u32>,
+
+    /// After converting several models, put all their previews side by side in one png at FILE
+    #[arg(long, value_name = "FILE", requires = "preview")]
+    contact_sheet: Option<String>,
+
+    /// Also save the unit's build picture from the gafs to <name>_buildpic.png. The picture is looked up by the output file name, or by NAME where {name} becomes the output file name
+    #[arg(long, value_name = "NAME", num_args = 0..=1, default_missing_value = "{name}")]
+    buildpic: Option<String>,
+
+    /// Also write a simple collision mesh of each piece, as `<piece>_collision` objects at the end of the obj
+    #[arg(long, value_enum, value_name = "SHAPE")]
+    collision: Option<CollisionShape>,
+
+    /// Write the --collision objects to <name>_collision.obj instead of the obj, as they always are with other formats
+    #[arg(long, requires = "collision")]
+    collision_file: bool,
+
+    /// Also write N lower detail versions of the model, triangulated, as <name>_lod1.obj and on, each with fewer triangles
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=8))]
+    lods: Option<u32>,
+
+    /// Fraction of the model's triangles each --lods level keeps, separated by commas. Levels past the last one given halve it again
+    #[arg(long, value_name = "FRACTIONS", value_delimiter = ',', value_parser = parse_fraction, default_value = "0.5,0.25", requires = "lods")]
+    lod_fractions: Vec<f64>,
+
+    /// Leave pieces out of a --lods level once they're down to fewer triangles than this
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    lod_min_triangles: usize,
+
+    /// Also write <name>.three.json with what three.js's OBJLoader leaves out of the mtl: emissive colours, colour keys and double sided materials, and the pivot of each piece by its `o` name
+    #[arg(long)]
+    three_json: bool,
+
+    /// Reverse the faces of pieces wound the other way from the rest of the model, instead of only warning about them
+    #[arg(long)]
+    fix_inverted: bool,
+
+    /// Check the model against the limits of a game or renderer, a preset (spring, index16) or a toml file of piece_vertexes, triangles, pieces and texture_size, warning about what goes over
+    #[arg(long, value_name = "PRESET|FILE")]
+    budget: Option<String>,
+
+    /// Fail instead of only warning when the model goes over the --budget
+    #[arg(long, requires = "budget")]
+    strict_budget: bool,
+
+    /// Write each piece of the obj around its own pivot, with a `# transform:` comment before it giving its parent and its offset from the parent's pivot
+    #[arg(long)]
+    embed_transforms: bool,
+
+    /// Also write a .py that rebuilds the piece hierarchy as empties when run in Blender after importing the .obj
+    #[arg(long)]
+    blender_helper: bool,
+
+    /// Have the --blender-helper script read the pieces from the obj's --embed-transforms comments when it runs, instead of listing them itself
+    #[arg(long, requires_all = ["blender_helper", "embed_transforms"])]
+    helper_reads_transforms: bool,
+
+    /// Also write the piece names in tree order as a bos piece declaration or a Spring lua table
+    #[arg(long, value_enum, value_name = "LANGUAGE")]
+    script_skeleton: Option<ScriptFormat>,
+
+    /// Add the faces using each texture to texture_usage.json and texture_usage.txt, which gather every model converted with this
+    #[arg(long, conflicts_with_all = ["no_textures", "no_mtl"])]
+    texture_usage: bool,
+
+    /// Mtl file of hand written materials to copy into the obj's mtl as they are, in place of the generated ones with the same names
+    #[arg(long, value_name = "FILE", conflicts_with = "no_mtl")]
+    mtl_overrides: Option<String>,
+
+    /// Point the obj at this mtl instead of writing its own, adding the model's materials to what earlier conversions put there. A material named like one already there but different is renamed with a "{name}_" prefix
+    #[arg(long, value_name = "PATH", conflicts_with = "no_mtl")]
+    shared_mtl: Option<String>,
+
+    /// File of `old new` lines renaming textures in the outputs, a * in the old name matching anything; a new name ending in .bmp uses that file instead of a gaf entry
+    #[arg(long, value_name = "FILE")]
+    texture_map: Option<String>,
+
+    /// Check that the model read keeps every field of the 3do, by writing it back out as one and comparing
+    #[arg(long)]
+    lossless_check: bool,
+
+    /// Write every file the run wrote, or kept from an earlier run, to FILE: a tab separated status and path per line, or a json array if FILE ends in .json
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<String>,
+
+    /// Folder the paths in the --manifest are relative to, defaults to the working folder
+    #[arg(long, value_name = "DIR", requires = "manifest")]
+    manifest_root: Option<String>,
+
+    /// Keep running and convert the models asked for in json lines sent to this unix socket, or to this port on localhost
+    #[arg(long, value_name = "SOCKET|PORT")]
+    serve: Option<String>,
+
+    /// Most clients --serve talks to at once, more are turned away
+    #[arg(long, default_value_t = 4, requires = "serve")]
+    max_connections: usize,
+
+    /// Write what happens during the run to FILE as it happens, a json object per line
+    #[arg(long, value_name = "FILE")]
+    events: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Convert a model, the same as giving its file and flags without a command, e.g. `convert armcom.3do --game-dir C:/TA`
+    #[command(disable_help_flag = true)]
+    Convert {
+        /// The input and flags of the conversion, `convert --help` lists them
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        convert_args: Vec<String>,
+    },
+    /// Extract every texture of gaf files, whatever model would use them
+    Gaf {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Folder the textures are written to
+        #[arg(short, long, default_value = "./textures/")]
+        output: String,
+
+        /// Only print the names of the entries
+        #[arg(long)]
+        list: bool,
+    },
+    /// Print the piece tree of a 3do with each piece's offset and counts
+    Info { file: String },
+    /// Check 3do files for problems the game or a conversion would trip over, exiting with 1 if any has one
+    Validate {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Most vertexes a piece can have
+        #[arg(long, default_value_t = DEFAULT_MAX_VERTEXES)]
+        max_vertexes: u32,
+
+        /// Most primitives a piece can have
+        #[arg(long, default_value_t = DEFAULT_MAX_PRIMITIVES)]
+        max_primitives: u32,
+
+        /// Most levels of pieces under one another
+        #[arg(long, default_value_t = DEFAULT_MAX_DEPTH)]
+        max_depth: u32,
+
+        /// Most pieces a file can have
+        #[arg(long, default_value_t = DEFAULT_MAX_OBJECTS)]
+        max_objects: u32,
+
+        /// Most corners a primitive can have
+        #[arg(long, default_value_t = DEFAULT_MAX_FACE_INDEXES)]
+        max_face_indexes: u32,
+    },
+    /// Compare two 3do files piece by piece, exiting with 1 if they differ
+    Diff {
+        a: String,
+        b: String,
+
+        /// Print the differences as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect HPI archives
+    Hpi {
+        #[command(subcommand)]
+        command: HpiCommand,
+    },
+    /// Check that a conversion has what it needs, given the flags it would run with
+    Doctor {
+        /// The input and flags of the conversion, e.g. `doctor unit.3do --game-dir C:/TA`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        convert_args: Vec<String>,
+    },
+    /// Count the pieces, vertexes and triangles of 3do files, and what each texture and colour covers, without converting them
+    Scan {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Print one line of json per file
+        #[arg(long)]
+        json: bool,
+    },
+    /// Work with already extracted textures
+    Textures {
+        #[command(subcommand)]
+        command: TexturesCommand,
+    },
+    /// Build a 3do from an obj whose objects are named parent.child, e.g. `base.turret.barrel`, or from a gltf or glb node tree
+    To3do {
+        input: String,
+
+        /// Path of the 3do, the input's with a .3do extension by default
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Palette index for faces without a material
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8))]
+        colour: u8,
+    },
+    /// Attach the pieces of one 3do under a piece of another, e.g. `merge hull.3do turret_base turret.3do -o tank.3do`
+    Merge {
+        base: String,
+        piece: String,
+        attachment: String,
+
+        /// Path of the merged 3do
+        #[arg(short, long)]
+        output: String,
+
+        /// Move the attached pieces by x,y,z game units from the piece they're under
+        #[arg(long, value_name = "X,Y,Z", value_parser = parse_position, allow_hyphen_values = true)]
+        offset: Option<[f64; 3]>,
+
+        /// Put this in front of every attached piece's name
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Scale or move a whole 3do and write it back out, e.g. `transform armcom.3do --scale 1.5 -o big_armcom.3do`
+    Transform {
+        input: String,
+
+        /// Path of the transformed 3do
+        #[arg(short, long)]
+        output: String,
+
+        /// Multiply every vertex and piece offset by this
+        #[arg(long, value_name = "FACTOR", value_parser = parse_positive, required_unless_present = "translate")]
+        scale: Option<f64>,
+
+        /// Move the model by x,y,z game units, after any scaling
+        #[arg(long, value_name = "X,Y,Z", value_parser = parse_position, allow_hyphen_values = true)]
+        translate: Option<[f64; 3]>,
+    },
+    /// Merge identical vertexes, drop degenerate primitives and unused vertexes, and lay a 3do out again
+    Optimize {
+        input: String,
+
+        /// Path of the optimized 3do
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Rename the textures a 3do's primitives use, e.g. `retexture armcom.3do --map armtex1=coretex1 -o corecom.3do`
+    Retexture {
+        input: String,
+
+        /// Path of the retextured 3do
+        #[arg(short, long)]
+        output: String,
+
+        /// A texture to rename, written old=new, can be repeated
+        #[arg(long = "map", value_name = "OLD=NEW", value_parser = parse_rename, required = true)]
+        renames: Vec<(String, String)>,
+    },
+
+    /// Rename pieces of a 3do, e.g. `rename-piece armcom.3do --map ltorso=torso -o armcom2.3do`
+    RenamePiece {
+        input: String,
+
+        /// Path of the renamed 3do
+        #[arg(short, long)]
+        output: String,
+
+        /// A piece to rename, written old=new, can be repeated
+        #[arg(long = "map", value_name = "OLD=NEW", value_parser = parse_rename, required = true)]
+        renames: Vec<(String, String)>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TexturesCommand {
+    /// Re-encode every texture in a folder and point mtl files at the new ones
+    Convert {
+        folder: String,
+
+        #[arg(long, value_enum)]
+        from: ImageFormat,
+
+        #[arg(long, value_enum)]
+        to: ImageFormat,
+
+        /// Mtl file whose map_Kd and map_d lines should follow, can be repeated
+        #[arg(long)]
+        mtl: Vec<String>,
+
+        /// Remove the original files once converted
+        #[arg(long)]
+        delete_originals: bool,
+
+        /// Only print what would be converted and rewritten
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum HpiCommand {
+    /// List every file in an archive with its size and compression
+    List {
+        archive: String,
+
+        /// Only list files whose path matches this glob, e.g. 'objects3d/*.3do'
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Print the listing as json
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum CoordMode {
+    /// the untouched integers from the file
+    Raw,
+    /// divide by the 16.16 fixed-point scale, keeping full precision
+    Fixed,
+    /// integer division by 1000, matching older versions of this tool
+    Legacy,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum UvMode {
+    /// every face uses the same four corners of the texture
+    Quad,
+    /// each face gets its own coordinates from projecting it onto its plane
+    PerFace,
+    /// no texture coordinates at all
+    None,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum GroupBy {
+    /// an `o` per piece, with its faces
+    Piece,
+    /// a `g` per material holding every face that uses it
+    Material,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum LongFaces {
+    /// break long lines with a `\` continuation
+    Wrap,
+    /// split long faces into a fan of triangles
+    Triangulate,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum MtllibTarget {
+    /// the .mtl.gz written next to the obj
+    Compressed,
+    /// a .mtl, for when the files are decompressed before importing
+    Uncompressed,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum ColorSpace {
+    /// palette values as they are
+    Srgb,
+    /// palette values converted to linear light
+    Linear,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum TextureFallback {
+    /// map_Kd pointing at where the texture would have been extracted
+    Map,
+    /// a plain grey Kd
+    Kd,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum PotMode {
+    /// put the texture in the corner of a power of two canvas and shrink its uvs to match
+    Pad,
+    /// nearest neighbour resize up to the next power of two
+    Stretch,
+    /// keep the original size
+    Off,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum DedupeMode {
+    /// keep every material but point duplicates at the same file
+    Alias,
+    /// replace duplicate materials with the first one using that image
+    Merge,
+}
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+enum MaterialPriority {
+    Texture,
+    Color,
 }
 
 struct Buffer {
@@ -37,38 +855,33 @@ impl Buffer {
         self.cursor = (self.cursor as i64 + index) as usize;
     }
 
-    fn read(&self, n_bytes: usize) -> &[u8] {
-        &self.data[self.cursor..(self.cursor + n_bytes)]
+    fn len(&self) -> usize {
+        self.data.len()
     }
 
-    fn read_string(&self) -> String {
-        let string_len = self.data[self.cursor..]
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap();
+    /// The cursor as a file offset, past the end of any file when it doesn't
+    /// fit in one.
+    fn offset(&self) -> u32 {
+        u32::try_from(self.cursor).unwrap_or(u32::MAX)
+    }
 
-        String::from_utf8(self.read(string_len).to_vec()).unwrap()
+    /// The `n_bytes` from the cursor, or an error when they run past the end.
+    fn read(&self, n_bytes: usize) -> Result<&[u8], String> {
+        let what = format!("{} bytes", n_bytes);
+        check_in_file(&self.data, self.offset(), 1, n_bytes, &what)?;
+        Ok(&self.data[self.cursor..self.cursor + n_bytes])
     }
-}
 
-#[repr(C, packed)]
-#[derive(Deserialize, Debug, Copy, Clone)]
-struct TagObject {
-    version_signature: u32,
-    number_of_vertexes: u32,
-    number_of_primitives: u32,
-    offset_to_selection_primitive: u32,
-    x_from_parent: i32,
-    y_from_parent: i32,
-    z_from_parent: i32,
-    offset_to_object_name: u32,
-    always_0: u32,
-    offset_to_vertex_array: u32,
-    offset_to_primitive_array: u32,
-    offset_to_sibling_object: u32,
-    offset_to_child_object: u32,
+    /// The string from the cursor up to the next nul, without it.
+    fn read_string(&self, max_len: usize) -> Result<String, String> {
+        read_cstring(&self.data, self.offset(), max_len)
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
 }
 
+/// Texture coordinates TA maps onto the corners of every face.
+const QUAD_UVS: [[f64; 2]; 4] = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+
 #[derive(Deserialize, Debug, Copy, Clone)]
 struct Offset {
     x: i32,
@@ -76,259 +889,1001 @@ struct Offset {
     z: i32,
 }
 
-#[repr(C, packed)]
-#[derive(Deserialize, Debug, Copy, Clone)]
-struct TagPrimitive {
-    color_index: u32,
-    number_of_vertex_indexes: u32,
-    always_0: u32,
-    offset_to_vertex_index_array: u32,
-    offset_to_texture_name: u32,
-    unknown_1: u32,
-    unknown_2: u32,
-    is_colored: u32,
-}
-
-#[repr(C, packed)]
-#[derive(Deserialize, Debug, Copy, Clone)]
-struct TagVertex {
-    x: i32,
-    y: i32,
-    z: i32,
-}
+/// Fixed size little endian integers, whatever the platform's own byte order.
+type Decoder = WithOtherEndian<WithOtherIntEncoding<DefaultOptions, FixintEncoding>, LittleEndian>;
 
 lazy_static! {
-    static ref DECODER: WithOtherIntEncoding<DefaultOptions, FixintEncoding> =
-        DefaultOptions::new().with_fixint_encoding();
+    static ref DECODER: Decoder = DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian();
     static ref SCALE_FACTOR: i32 = 1000;
+    static ref FIXED_POINT_SCALE: f64 = 65536.0;
 }
 
-fn read_struct<T: DeserializeOwned + Clone>(buf: &mut Buffer) -> T {
-    DECODER.deserialize(buf.read(mem::size_of::<T>())).unwrap()
+fn convert_coord(value: i32, mode: CoordMode) -> f64 {
+    match mode {
+        CoordMode::Raw => value as f64,
+        CoordMode::Fixed => value as f64 / *FIXED_POINT_SCALE,
+        CoordMode::Legacy => (value / *SCALE_FACTOR) as f64,
+    }
+}
+
+/// Something read from a file field by field, little endian and unpadded.
+trait Record: DeserializeOwned {
+    /// bytes it takes up in the file, which the struct's own layout is
+    /// checked against wherever it's declared
+    const SIZE: usize;
 }
 
-fn read_primatives(buf: &mut Buffer, object: &TagObject) -> Vec<TagPrimitive> {
-    let mut primatives = Vec::new();
+impl Record for u8 {
+    const SIZE: usize = 1;
+}
 
-    buf.seek(object.offset_to_primitive_array.into());
+impl Record for u16 {
+    const SIZE: usize = 2;
+}
+
+impl Record for u32 {
+    const SIZE: usize = 4;
+}
 
-    for _ in 0..object.number_of_primitives {
-        primatives.push(read_struct::<TagPrimitive>(buf));
-        buf.seek_relative(mem::size_of::<TagPrimitive>() as i64);
+fn read_struct<T: Record>(buf: &mut Buffer) -> Result<T, String> {
+    DECODER
+        .deserialize(buf.read(T::SIZE)?)
+        .map_err(|err| format!("unable to read a record at {:#x}: {}", buf.cursor, err))
+}
+
+/// Parses an offset into a file, given in decimal or as 0x hex.
+fn parse_offset(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
     }
+    .map_err(|err| format!("{} isn't an offset: {}", s, err))
+}
 
-    return primatives;
+/// With --show-unknowns, reports a field nobody knows the meaning of when it
+/// holds something other than the value stock files have.
+fn check_unknown(args: &Args, file: &str, offset: usize, field: &str, value: u32, usual: u32) {
+    if args.show_unknowns && value != usual {
+        log::unknown(log::UnknownValue {
+            file: file.to_string(),
+            offset,
+            field: field.to_string(),
+            value,
+        });
+    }
 }
 
-fn read_vertexes(buf: &mut Buffer, object: &TagObject) -> Vec<TagVertex> {
-    let mut vertexes = Vec::new();
+/// The input path without its extension, which outputs are named after.
+fn input_stem(args: &Args) -> &str {
+    let file = args.file.as_deref().unwrap();
+    // folders can have dots in their names too, like ./units
+    let name_start = file.rfind(['/', '\\']).map_or(0, |slash| slash + 1);
+    match file[name_start..].find('.') {
+        Some(dot) => &file[..name_start + dot],
+        None => file,
+    }
+}
 
-    buf.seek(object.offset_to_vertex_array.into());
+/// The file to convert: the input as given when it ends in .3do or .s3o,
+/// in any case, and with .3do in place of anything else.
+fn input_path(args: &Args) -> String {
+    let stem = input_stem(args);
+    let extension = &args.file.as_deref().unwrap()[stem.len()..];
+    if extension.eq_ignore_ascii_case(".3do") || extension.eq_ignore_ascii_case(".s3o") {
+        args.file.clone().unwrap()
+    } else {
+        stem.to_owned() + ".3do"
+    }
+}
 
-    for _ in 0..object.number_of_vertexes {
-        vertexes.push(read_struct::<TagVertex>(buf));
-        buf.seek_relative(mem::size_of::<TagVertex>() as i64);
+/// Folder textures are extracted to, ending in a slash: `textures/` in
+/// --out-dir or next to --output, so they go wherever the models go, and
+/// ./textures/ otherwise.
+fn texture_folder(args: &Args) -> String {
+    let root = match (&args.out_dir, &args.output) {
+        (Some(out_dir), _) => Some(out_dir.as_str()),
+        (None, Some(output)) => Path::new(output)
+            .parent()
+            .and_then(|folder| folder.to_str())
+            .filter(|folder| !folder.is_empty()),
+        (None, None) => None,
+    };
+    match root {
+        Some(root) => format!("{}/textures/", root.trim_end_matches(['/', '\\'])),
+        None => "./textures/".to_string(),
     }
+}
 
-    return vertexes;
+/// What the outputs of the model at `file_name`, without its extension, are
+/// named after: itself, --output without its extension, or with --out-dir
+/// its name in that folder, at the same place under it as the model has
+/// under --recursive's folder.
+fn output_base(args: &Args, file_name: &str) -> String {
+    if let Some(output) = &args.output {
+        return Path::new(output)
+            .with_extension("")
+            .to_string_lossy()
+            .into_owned();
+    }
+    let Some(out_dir) = &args.out_dir else {
+        return file_name.to_owned();
+    };
+    // glob leaves out the ./ a folder may have been given with
+    let without_dots = |path: &str| -> PathBuf {
+        Path::new(path)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect()
+    };
+    let path = without_dots(file_name);
+    let relative = match &args.recursive {
+        Some(root) => path.strip_prefix(without_dots(root)).ok(),
+        None => None,
+    };
+    let relative = relative
+        .or_else(|| path.file_name().map(Path::new))
+        .unwrap_or(&path);
+    Path::new(out_dir)
+        .join(relative)
+        .to_string_lossy()
+        .into_owned()
 }
 
-fn traverse(
-    buf: &mut Buffer,
-    obj_writter: &mut BufWriter<File>,
-    object: &TagObject,
-    n_verticies_written: &mut u32,
-    parent_offset: Offset,
-    used_colours: &mut Vec<[u8; 3]>,
-    used_textures: &mut Vec<String>,
-    indent: usize,
-) {
-    let offset = Offset {
-        x: parent_offset.x + (object.x_from_parent as i32),
-        y: parent_offset.y + (object.y_from_parent as i32),
-        z: parent_offset.z + (object.z_from_parent as i32),
+/// Builds an output stem next to the input file from the model's root piece
+/// name, adding a numeric suffix if another model already claimed it.
+fn model_stem(file_name: &str, model_name: &str, taken_stems: &mut HashSet<String>) -> String {
+    let sanitized: String = model_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if sanitized.is_empty() {
+        warning!("the root piece has no usable name, keeping the input file name.");
+        return file_name.to_owned();
+    }
+
+    let directory = Path::new(file_name).parent().unwrap_or(Path::new(""));
+    let stem = directory.join(&sanitized).to_string_lossy().into_owned();
+    let mut unique_stem = stem.clone();
+    let mut suffix = 1;
+    while !taken_stems.insert(unique_stem.clone()) {
+        suffix += 1;
+        unique_stem = format!("{}_{}", stem, suffix);
+    }
+
+    if unique_stem != stem {
+        warning!(
+            "another model is already named {}, writing {} instead.",
+            sanitized,
+            unique_stem
+        );
+    }
+
+    unique_stem
+}
+
+fn main() {
+    let args = Args::parse();
+    let args = match &args.command {
+        Some(Command::Convert { convert_args }) => {
+            let program = env::args().next().unwrap_or_default();
+            Args::parse_from(iter::once(program).chain(convert_args.clone()))
+        }
+        _ => args,
     };
 
-    diplay_data(
-        buf,
-        obj_writter,
-        &object,
-        n_verticies_written,
+    if let Some(Command::Gaf {
+        files,
+        output,
+        list,
+    }) = &args.command
+    {
+        log::start(false, args.verbose);
+        if let Err(err) = extract_gafs(&args, files, output, *list) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Info { file }) = &args.command {
+        if let Err(err) = print_info(file) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Validate {
+        files,
+        max_vertexes,
+        max_primitives,
+        max_depth,
+        max_objects,
+        max_face_indexes,
+    }) = &args.command
+    {
+        let limits = Limits {
+            vertexes: *max_vertexes,
+            primitives: *max_primitives,
+            depth: *max_depth,
+            objects: *max_objects,
+            face_indexes: *max_face_indexes,
+        };
+        let valid = validate_3do(files, &limits);
+        process::exit(!valid as i32);
+    }
+
+    if let Some(Command::Diff { a, b, json }) = &args.command {
+        match diff_3do(a, b, *json) {
+            Ok(differs) => process::exit(differs as i32),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(2);
+            }
+        }
+    }
+
+    if let Some(Command::Scan { files, json }) = &args.command {
+        scan_3do(files, *json);
+        return;
+    }
+
+    if let Some(Command::Merge {
+        base,
+        piece,
+        attachment,
+        output,
         offset,
-        used_colours,
-        used_textures,
-        indent,
-    );
-
-    // go over children
-    if object.offset_to_child_object != 0 {
-        buf.seek(object.offset_to_child_object.into());
-        let child = read_struct::<TagObject>(buf);
-
-        traverse(
-            buf,
-            obj_writter,
-            &child,
-            n_verticies_written,
-            offset,
-            used_colours,
-            used_textures,
-            indent + 1,
-        );
+        prefix,
+    }) = &args.command
+    {
+        log::start(false, args.verbose);
+        if let Err(err) = merge_3do(base, piece, attachment, output, *offset, prefix.as_deref()) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
     }
 
-    // go over siblings
-    if object.offset_to_sibling_object != 0 {
-        buf.seek(object.offset_to_sibling_object.into());
-        let sibling = read_struct::<TagObject>(buf);
-
-        traverse(
-            buf,
-            obj_writter,
-            &sibling,
-            n_verticies_written,
-            parent_offset,
-            used_colours,
-            used_textures,
-            indent + 1,
-        );
+    if let Some(Command::Transform {
+        input,
+        output,
+        scale,
+        translate,
+    }) = &args.command
+    {
+        if let Err(err) = transform_3do(input, output, *scale, *translate) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Optimize { input, output }) = &args.command {
+        if let Err(err) = optimize_3do(input, output) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Retexture {
+        input,
+        output,
+        renames,
+    }) = &args.command
+    {
+        log::start(false, args.verbose);
+        if let Err(err) = retexture_3do(input, output, renames) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
     }
+
+    if let Some(Command::RenamePiece {
+        input,
+        output,
+        renames,
+    }) = &args.command
+    {
+        log::start(false, args.verbose);
+        if let Err(err) = rename_piece_3do(input, output, renames) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::To3do {
+        input,
+        output,
+        colour,
+    }) = &args.command
+    {
+        log::start(false, args.verbose);
+        if let Err(err) = to_3do(input, output.as_deref(), *colour as usize) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Textures {
+        command:
+            TexturesCommand::Convert {
+                folder,
+                from,
+                to,
+                mtl,
+                delete_originals,
+                dry_run,
+            },
+    }) = &args.command
+    {
+        convert_textures(folder, *from, *to, mtl, *delete_originals, *dry_run);
+        return;
+    }
+
+    if let Some(Command::Hpi {
+        command:
+            HpiCommand::List {
+                archive,
+                filter,
+                json,
+            },
+    }) = &args.command
+    {
+        if let Err(err) = list_hpi(archive, filter.as_deref(), *json) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Doctor { convert_args }) = &args.command {
+        let program = env::args().next().unwrap_or_default();
+        let convert_args = Args::parse_from(iter::once(program).chain(convert_args.clone()));
+        log::start(false, convert_args.verbose);
+        let healthy = doctor(&convert_args);
+        process::exit(!healthy as i32);
+    }
+
+    if let Some(address) = &args.serve {
+        serve(&args, address);
+        return;
+    }
+
+    if let Some(folder) = &args.recursive {
+        let files: Vec<String> = models_under(Path::new(folder))
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        if files.is_empty() {
+            eprintln!("Error: there are no 3do files under {}", folder);
+            process::exit(1);
+        }
+        convert_batch(args, &files);
+        return;
+    }
+
+    let patterns: Vec<String> = args.file.iter().chain(&args.more_files).cloned().collect();
+    if patterns.len() > 1 || patterns.iter().any(|pattern| is_glob(pattern)) {
+        let files = match expand_inputs(&patterns) {
+            Ok(files) => files,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        };
+        if args.output.is_some() && files.len() > 1 {
+            eprintln!("Error: --output names the outputs of one model, use --out-dir for several");
+            process::exit(1);
+        }
+        convert_batch(args, &files);
+        return;
+    }
+
+    log::start(args.json, args.verbose);
+    if args.contact_sheet.is_some() {
+        warning!("--contact-sheet goes with several models, it wasn't written.");
+    }
+    if let Err(err) = convert(args, &mut HashSet::new()) {
+        log::error(err);
+        log::exit(1);
+    }
+}
+
+/// Whether `pattern` is a glob rather than the path of a file, which it can
+/// still be when a file with that name exists.
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[']) && !Path::new(pattern).exists()
 }
 
-fn diplay_data(
-    buf: &mut Buffer,
-    obj_writter: &mut BufWriter<File>,
-    object: &TagObject,
-    n_verticies_written: &mut u32,
-    parent_offset: Offset,
-    used_colours: &mut Vec<[u8; 3]>,
-    used_textures: &mut Vec<String>,
-    _indent: usize,
-) {
-    buf.seek(object.offset_to_object_name.into());
-    let name = buf.read_string();
-
-    writeln!(obj_writter).unwrap();
-    writeln!(obj_writter, "o {}", name).unwrap();
-
-    let vertexes = read_vertexes(buf, object);
-    for v in &vertexes {
-        let (x, y, z) = (v.x, v.y, v.z);
-
-        writeln!(
-            obj_writter,
-            "v {} {} {}",
-            -(parent_offset.x + x) / *SCALE_FACTOR,
-            (parent_offset.y + y) / *SCALE_FACTOR,
-            (parent_offset.z + z) / *SCALE_FACTOR
-        )
-        .unwrap();
-    }
-
-    let primatives = read_primatives(buf, object);
-    for p in primatives {
-        // non-rendered/clear material
-        if p.is_colored == 0 && p.offset_to_texture_name == 0 {
+/// The files `patterns` name, each a path or a glob whose matches come in
+/// name order, as the shell would give them on systems whose shell does.
+fn expand_inputs(patterns: &[String]) -> Result<Vec<String>, String> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        if !is_glob(pattern) {
+            files.push(pattern.clone());
             continue;
         }
-        // textured material
-        else if p.offset_to_texture_name != 0 {
-            buf.seek(p.offset_to_texture_name.into());
-            let texture_name = buf.read_string();
-            writeln!(obj_writter, "usemtl {}", texture_name).unwrap();
-            used_textures.push(texture_name);
-        }
-        // coloured material
-        else {
-            let colour = PALETTE[p.color_index as usize];
-            let colour_name = colour_name(colour);
-            writeln!(obj_writter, "usemtl {}", colour_name).unwrap();
-            used_colours.push(colour);
-        }
-
-        write!(obj_writter, "f").unwrap();
-
-        buf.seek(p.offset_to_vertex_index_array.into());
-        for i in 0..p.number_of_vertex_indexes {
-            let vertex_index = read_struct::<u16>(buf);
-            buf.seek_relative(mem::size_of::<u16>() as i64);
-
-            write!(
-                obj_writter,
-                " {}/{}",
-                *n_verticies_written + (vertex_index as u32) + 1,
-                i + 1
-            )
-            .unwrap();
+        let matches: Vec<String> = glob::glob(pattern)
+            .map_err(|err| format!("{} isn't a valid pattern: {}", pattern, err))?
+            .flatten()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        if matches.is_empty() {
+            return Err(format!("{} didn't match any files", pattern));
+        }
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// Converts each of `files` with the flags of `args` as a run of its own, so
+/// one that fails doesn't stop the rest. With --json each one's result is
+/// printed as a line of its own. Exits with 1 if any of them failed.
+fn convert_batch(args: Args, files: &[String]) {
+    log::start_batch(args.json, args.verbose);
+    // one manifest and event file for the whole batch
+    if let Some(path) = &args.manifest {
+        log::observe_every_run(Box::new(Manifest::new(path, args.manifest_root.as_deref())));
+    }
+    if let Some(path) = &args.events {
+        match fs::File::create(path) {
+            Ok(file) => log::observe_every_run(Box::new(log::EventFile::new(file))),
+            Err(err) => {
+                eprintln!("Error: unable to create {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    // --name-from-model names can collide between the models of a batch
+    let mut taken_stems = HashSet::new();
+    let mut failed = Vec::new();
+    let mut skipped = 0;
+    let mut previews = Vec::new();
+    // how many times each unknown field held each value, and in how many files
+    let mut unknowns: BTreeMap<(String, u32), (usize, usize)> = BTreeMap::new();
+    for file in files {
+        let file_args = Args {
+            file: Some(file.clone()),
+            more_files: Vec::new(),
+            manifest: None,
+            events: None,
+            ..args.clone()
+        };
+        let result = log::run(|| convert(file_args, &mut taken_stems));
+        if args.json {
+            println!("{}", serde_json::to_string(&result).unwrap());
+        }
+        if !result.success {
+            failed.push(format!(
+                "{}: {}",
+                file,
+                result.error.as_deref().unwrap_or("failed")
+            ));
+        }
+        if result.skipped {
+            skipped += 1;
+        }
+        previews.extend(
+            result
+                .outputs
+                .iter()
+                .filter(|path| path.ends_with("_preview.png"))
+                .cloned(),
+        );
+        let mut seen = HashSet::new();
+        for unknown in &result.unknowns {
+            let key = (unknown.field.clone(), unknown.value);
+            let (times, files) = unknowns.entry(key.clone()).or_default();
+            *times += 1;
+            if seen.insert(key) {
+                *files += 1;
+            }
+        }
+    }
+
+    let mut sheet_failed = false;
+    if let (Some(path), Some(size)) = (&args.contact_sheet, args.preview) {
+        if let Err(err) = write_contact_sheet(&previews, size, path) {
+            eprintln!("Error: unable to write {}: {}", path, err);
+            sheet_failed = true;
+        }
+    }
+
+    if !args.json {
+        // a skipped model succeeded without converting anything
+        let converted = files.len() - failed.len() - skipped;
+        if skipped > 0 {
+            eprintln!(
+                "Converted {} of {} models, skipped {} unchanged since their last conversion.",
+                converted,
+                files.len(),
+                skipped
+            );
+        } else {
+            eprintln!("Converted {} of {} models.", converted, files.len());
+        }
+        for failure in &failed {
+            eprintln!("  failed {}", failure);
         }
+        if !unknowns.is_empty() {
+            eprintln!("Unknown fields that weren't their usual value:");
+            for ((field, value), (times, in_files)) in &unknowns {
+                eprintln!(
+                    "  {} = {:#x}: {} times in {} of {} files",
+                    field,
+                    value,
+                    times,
+                    in_files,
+                    files.len()
+                );
+            }
+        }
+    }
+    if !failed.is_empty() || sheet_failed {
+        process::exit(1);
+    }
+}
 
-        writeln!(obj_writter).unwrap();
+/// Extracts every entry of the gaf files at `paths` into `folder`, or only
+/// lists them with `list`.
+fn extract_gafs(args: &Args, paths: &[String], folder: &str, list: bool) -> Result<(), String> {
+    if list {
+        for path in paths {
+            let data = fs::read(path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+            let names = gaf_entry_names(&mut Buffer::new(data))
+                .map_err(|err| format!("{}: {}", path, err))?;
+            for name in names {
+                println!("{}: {}", path, name);
+            }
+        }
+        return Ok(());
     }
+    let extracted = extract_all_from_gafs(args, paths, folder)?;
+    println!("Extracted {} textures to {}", extracted.len(), folder);
+    Ok(())
+}
 
-    *n_verticies_written += vertexes.len() as u32;
+/// Writes every output of `model` but its textures, and moves them into
+/// place once they're all written. Returns the paths written and the
+/// triangles in each --lods level.
+fn write_outputs(
+    args: &Args,
+    model: &Model,
+    extracted: &HashMap<String, ExtractedTexture>,
+    output_stem: &str,
+) -> io::Result<(Vec<String>, Vec<usize>)> {
+    let mut pending = PendingFiles::default();
+    let mut outputs =
+        args.format
+            .exporter()
+            .export(args, model, extracted, output_stem, &mut pending)?;
+    if args.three_json && args.format != Format::Obj {
+        warning!("--three-json goes with obj output, it wasn't written.");
+    }
+    if args.embed_transforms && args.format != Format::Obj {
+        warning!("--embed-transforms only changes obj output, the pieces are written in place.");
+    }
+    if let Some(size) = args.preview {
+        let path = output_stem.to_owned() + "_preview.png";
+        write_preview(model, extracted, size, &path, &mut pending)?;
+        outputs.push(path);
+    }
+    if let Some(shape) = args.collision {
+        if args.collision_file || args.format != Format::Obj {
+            outputs.push(write_collision_obj(
+                args,
+                model,
+                shape,
+                output_stem,
+                &mut pending,
+            )?);
+        }
+    }
+    let mut lod_triangles = Vec::new();
+    if args.lods.is_some() {
+        let (lod_outputs, triangles) =
+            write_lods(args, model, extracted, output_stem, &mut pending)?;
+        outputs.extend(lod_outputs);
+        lod_triangles = triangles;
+    }
+    if let Some(pattern) = &args.buildpic {
+        outputs.extend(write_buildpic(
+            args,
+            pattern,
+            "./gaf_textures/",
+            output_stem,
+            &mut pending,
+        )?);
+    }
+    if args.blender_helper {
+        outputs.push(write_blender_helper(
+            args,
+            model,
+            output_stem,
+            &mut pending,
+        )?);
+    }
+    if let Some(format) = args.script_skeleton {
+        outputs.push(write_script_skeleton(
+            args,
+            model,
+            format,
+            output_stem,
+            &mut pending,
+        )?);
+    }
+    pending.commit()?;
+    Ok((outputs, lod_triangles))
 }
 
-fn main() {
-    let args = Args::parse();
-    let file_name = args.file.split_terminator(".").next().unwrap();
+/// Converts the model `args` names, or exports the palette, ending the run
+/// with its result. `taken_stems` holds the outputs earlier models of the
+/// run were named from their root piece, so --name-from-model doesn't
+/// overwrite them.
+fn convert(args: Args, taken_stems: &mut HashSet<String>) -> Result<(), String> {
+    if let Some(path) = &args.manifest {
+        log::observe(Box::new(Manifest::new(path, args.manifest_root.as_deref())));
+    }
+    if let Some(path) = &args.events {
+        let file =
+            fs::File::create(path).map_err(|err| format!("unable to create {}: {}", path, err))?;
+        log::observe(Box::new(log::EventFile::new(file)));
+    }
 
-    let mut buffer = {
-        let data = fs::read(file_name.to_owned() + ".3do").unwrap();
-        Buffer::new(data)
-    };
+    if let Some(stem) = &args.export_palette {
+        let palette = Palette::new(args.fullbright_range.clone());
+        export_palette(stem, &palette, args.rgb_material_names)
+            .map_err(|err| format!("unable to export the palette to {}: {}", stem, err))?;
+        log::update(|result| result.outputs = vec![stem.clone() + ".png", stem.clone() + ".gpl"]);
+        info!("Palette exported!");
+        log::finish();
+        return Ok(());
+    }
 
-    let mut obj_writter = {
-        let file = File::create(file_name.to_owned() + ".obj").expect("unable to create file");
-        BufWriter::new(file)
+    let s3o = args
+        .file
+        .as_deref()
+        .is_some_and(|file| file.to_ascii_lowercase().ends_with(".s3o"));
+    // an s3o's faces come with their own uvs, which quad uvs would replace
+    let args = if s3o && args.uv_mode == UvMode::Quad {
+        Args {
+            uv_mode: UvMode::PerFace,
+            ..args
+        }
+    } else {
+        args
     };
 
-    writeln!(obj_writter, "mtllib {}", file_name.to_owned() + ".mtl").unwrap();
-    writeln!(obj_writter).unwrap();
-    writeln!(obj_writter, "vt 0 1").unwrap();
-    writeln!(obj_writter, "vt 1 1").unwrap();
-    writeln!(obj_writter, "vt 1 0").unwrap();
-    writeln!(obj_writter, "vt 0 0").unwrap();
-
-    let root_object = read_struct::<TagObject>(&mut buffer);
-    let mut n_verticies_written = 0;
-
-    let mut used_colours = Vec::new();
-    let mut used_textures = Vec::new();
-
-    traverse(
-        &mut buffer,
-        &mut obj_writter,
-        &root_object,
-        &mut n_verticies_written,
-        Offset { x: 0, y: 0, z: 0 },
-        &mut used_colours,
-        &mut used_textures,
-        0,
-    );
+    let file_name = input_stem(&args);
+    let input = input_path(&args);
+    log::update(|result| result.input = Some(input.clone()));
+    let data = fs::read(&input).map_err(|err| format!("unable to read {}: {}", input, err))?;
 
-    {
-        let file = File::create(file_name.to_owned() + ".mtl").expect("unable to create file");
-        let mut mtl_writter = BufWriter::new(file);
+    // kept with the outputs, so models converted into different folders
+    // don't share one
+    let output_folder = output_base(&args, file_name);
+    let output_folder = Path::new(&output_folder).parent().unwrap_or(Path::new(""));
+    let mut cache = Cache::load(output_folder);
+    let input_hash = hash_bytes(&data);
+    let options = options_fingerprint(&args);
+    // what gets picked isn't known until the model is read
+    let pick_pieces = args.interactive || args.piece.as_ref().is_some_and(Vec::is_empty);
+    if !args.rebuild && !pick_pieces && cache.is_fresh(&input, input_hash, &options) {
+        info!(
+            "{} hasn't changed since it was last converted, skipping it. Use --rebuild to convert it anyway.",
+            input
+        );
+        let kept = cache.outputs(&input).to_vec();
+        for path in &kept {
+            log::emit(log::Event::FileKept { path });
+        }
+        log::update(|result| {
+            result.skipped = true;
+            result.outputs = kept;
+        });
+        log::finish();
+        return Ok(());
+    }
 
-        for colour in used_colours {
-            writeln!(mtl_writter, "newmtl {}", colour_name(colour)).unwrap();
-            write!(mtl_writter, "Kd").unwrap();
-            write!(mtl_writter, " {}", colour[0] as f32 / 256.0).unwrap();
-            write!(mtl_writter, " {}", colour[1] as f32 / 256.0).unwrap();
-            write!(mtl_writter, " {}", colour[2] as f32 / 256.0).unwrap();
-            writeln!(mtl_writter).unwrap();
-            writeln!(mtl_writter).unwrap();
+    // an s3o has none of a 3do's header to check
+    let root_object = if s3o {
+        None
+    } else {
+        let root_object = read_object(&data, 0).map_err(|_| {
+            format!(
+                "{}.3do is only {} bytes long, too short to be a 3do file.",
+                file_name,
+                data.len()
+            )
+        })?;
+        let version_signature = root_object.version_signature;
+        if version_signature != TA_VERSION_SIGNATURE {
+            if args.force_parse {
+                warning!(
+                    "unknown version signature {:#010x}, parsing anyway.",
+                    version_signature
+                );
+            } else {
+                info!(
+                    "This may not be a 3do file at all. Use --force-parse to try converting it anyway."
+                );
+                return Err(format!(
+                    "{}.3do has version signature {:#010x} but a Total Annihilation 3do has {:#010x}.",
+                    file_name, version_signature, TA_VERSION_SIGNATURE
+                ));
+            }
         }
+        Some(root_object)
+    };
 
-        for texture in &used_textures {
-            writeln!(mtl_writter, "newmtl {}", texture).unwrap();
-            writeln!(mtl_writter, "map_Kd ./textures/{}.bmp", texture).unwrap();
-            writeln!(mtl_writter).unwrap();
+    let output_base = output_base(&args, file_name);
+    let output_stem = match root_object {
+        Some(root_object) if args.name_from_model => {
+            let model_name = read_cstring(
+                &data,
+                root_object.offset_to_object_name,
+                args.max_name_length,
+            )
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+            model_stem(&output_base, &model_name, taken_stems)
         }
+        _ => output_base,
+    };
+    // folders for --output and under --out-dir are made as they're needed
+    let output_folder = Path::new(&output_stem)
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+        .filter(|_| args.output.is_some() || args.out_dir.is_some());
+    if let Some(folder) = output_folder {
+        fs::create_dir_all(folder)
+            .map_err(|err| format!("unable to create {}: {}", folder.to_string_lossy(), err))?;
+    }
+
+    let texture_map = match &args.texture_map {
+        Some(path) => TextureMap::load(Path::new(path))?,
+        None => TextureMap::default(),
+    };
+
+    let model = if s3o {
+        read_s3o(&args, &input, &data)
+    } else {
+        read_model(&args, &texture_map, &data)
+    };
+    let mut model = model.map_err(|err| format!("{}: {}", input, err))?;
+    if args.debug_offsets {
+        model.print_offsets();
+    }
+    let unused = texture_map.unused(&model.source_textures);
+    if !unused.is_empty() {
+        warning!(
+            "{} lines of the texture map match no texture of {}: {}",
+            unused.len(),
+            input,
+            unused.join(", ")
+        );
+    }
+
+    if args.build_stage != BuildStage::All || !args.stage_pattern.is_empty() {
+        apply_build_stage(&args, &mut model)?;
+    }
+    if pick_pieces {
+        let chosen = ask_for_pieces(&model)?;
+        keep_subtrees(&mut model, &chosen);
+    } else if let Some(names) = &args.piece {
+        let chosen = named_pieces(&model, names).map_err(|err| format!("{}: {}", input, err))?;
+        keep_subtrees(&mut model, &chosen);
+    }
+
+    check_inverted(&mut model, args.fix_inverted);
+
+    let file_prefix = if args.prefix_textures {
+        name_prefix(&args, &output_stem)
+    } else {
+        String::new()
     };
 
     // OKAY NOW WE HAVE TO EXTRACT THE TEXTURES FROM THE GAF FILES!! FUN!
-    extract_textures_from_gafs(&used_textures, "./gaf_textures/", "./textures/");
+    // without an mtl nothing would point at the textures
+    let extracted = if args.no_textures || args.no_mtl {
+        HashMap::new()
+    } else {
+        // an s3o's texture is a file of its own, never in a gaf
+        let mut extracted = if s3o {
+            HashMap::new()
+        } else {
+            extract_textures_from_gafs(
+                &args,
+                &texture_map,
+                &model.used_textures,
+                "./gaf_textures/",
+                &texture_folder(&args),
+                &file_prefix,
+            )?
+        };
+        load_texture_files(
+            &args,
+            &model.texture_files,
+            &texture_folder(&args),
+            &file_prefix,
+            &mut extracted,
+        );
+        for texture in &model.used_textures {
+            log::emit(match extracted.get(texture) {
+                Some(t) => log::Event::TextureFound {
+                    name: texture,
+                    gaf: &t.gaf,
+                },
+                None => log::Event::TextureMissing { name: texture },
+            });
+        }
+        extracted
+    };
+
+    if let Some(spec) = &args.budget {
+        let budget = Budget::load(spec)?;
+        let violations = budget.check(&model, &extracted);
+        for violation in &violations {
+            warning!("{}", violation);
+        }
+        if args.strict_budget && !violations.is_empty() {
+            return Err(format!(
+                "{} goes over the budget {} times, the model wasn't written.",
+                input,
+                violations.len()
+            ));
+        }
+    }
+
+    // nothing replaces the previous output until all of it is complete
+    let (mut outputs, lod_triangles) = write_outputs(&args, &model, &extracted, &output_stem)
+        .map_err(|err| format!("unable to write {}: {}", output_stem, err))?;
+
+    let mut texture_files: Vec<String> = extracted.values().map(|t| t.path()).collect();
+    texture_files.sort();
+    texture_files.dedup();
+    outputs.extend(texture_files);
+    if !extracted.is_empty() {
+        outputs.extend(write_texture_index(&extracted, &texture_folder(&args)));
+    }
+
+    let mut team_colour_textures: Vec<String> = extracted
+        .iter()
+        .filter(|(_, t)| t.team.is_some())
+        .map(|(name, _)| name.clone())
+        .collect();
+    team_colour_textures.sort();
+    if args.logos == Some(Team::Neutral) && !team_colour_textures.is_empty() {
+        info!(
+            "Logos painted neutral grey, which the engine recolours per player: {}. Use --logos TEAM to paint them in a player's colour.",
+            team_colour_textures.join(", ")
+        );
+    }
+
+    let duplicates = extracted
+        .values()
+        .filter(|t| t.duplicate_of.is_some())
+        .count();
+    if duplicates > 0 {
+        info!("Folded {} duplicate textures.", duplicates);
+    }
+    let mut later_frames: Vec<String> = extracted
+        .iter()
+        .filter(|(_, t)| t.frame != 0)
+        .map(|(name, t)| format!("{} ({})", name, t.frame))
+        .collect();
+    if !later_frames.is_empty() {
+        later_frames.sort();
+        info!("Textures not from frame 0: {}.", later_frames.join(", "));
+    }
+    if model.degenerate_faces > 0 {
+        info!(
+            "Skipped {} degenerate faces, use --keep-degenerate to keep them.",
+            model.degenerate_faces
+        );
+    }
+    if args.prune_unused && model.unused_vertexes > 0 {
+        info!("Dropped {} vertexes no face uses.", model.unused_vertexes);
+    }
+
+    let mut missing_textures: Vec<String> = model
+        .used_textures
+        .iter()
+        .filter(|texture| !extracted.contains_key(*texture))
+        .cloned()
+        .collect();
+    missing_textures.sort();
+    let pieces = model.all_pieces();
+    log::update(|result| {
+        result.outputs = outputs.clone();
+        result.missing_textures = missing_textures;
+        result.team_colour_textures = team_colour_textures;
+        result.lod_triangles = lod_triangles;
+        result.counts = Counts {
+            pieces: pieces.len() as u32,
+            vertexes: pieces.iter().map(|p| p.vertexes.len() as u32).sum(),
+            faces: pieces.iter().map(|p| p.faces.len() as u32).sum(),
+            colours: model.used_colours.len() as u32,
+            textures: model.used_textures.len() as u32,
+            degenerate_faces: model.degenerate_faces,
+            unused_vertexes: model.unused_vertexes,
+            duplicate_textures: duplicates as u32,
+            ..result.counts.clone()
+        };
+    });
+
+    if args.verify && (args.format != Format::Obj || args.compress) {
+        warning!("--verify only checks uncompressed obj output, skipping it");
+    } else if args.verify {
+        let check_textures = !args.no_textures && !extracted.is_empty();
+        let obj_path = output_stem.clone() + ".obj";
+        let mut problems = verify_output(&obj_path, check_textures);
+        if args.embed_transforms && args.group_by == GroupBy::Piece {
+            let prefix = name_prefix(&args, &output_stem);
+            let pieces: Vec<(String, &[[f64; 3]])> = model
+                .all_pieces()
+                .iter()
+                .map(|piece| (format!("{}{}", prefix, piece.name), &piece.vertexes[..]))
+                .collect();
+            problems.extend(check_transforms(&obj_path, &pieces));
+        }
+        if !problems.is_empty() {
+            for problem in &problems {
+                warning!("{}", problem);
+            }
+            return Err(format!(
+                "found {} problems in {}.obj",
+                problems.len(),
+                output_stem
+            ));
+        }
+        info!("Checked {}.obj, no problems found.", output_stem);
+    }
+
+    if args.lossless_check && s3o {
+        warning!("--lossless-check reads the 3do again, an s3o can't be checked.");
+    } else if args.lossless_check {
+        let problems = lossless_check(&args, &model.pieces);
+        if !problems.is_empty() {
+            for problem in &problems {
+                warning!("{}", problem);
+            }
+            return Err(format!(
+                "{} fields of {} were lost reading it",
+                problems.len(),
+                input
+            ));
+        }
+        info!("Checked {}, every field survives a round trip.", input);
+    }
+
+    // only remembered once it's known to be good, so a failed --verify reruns
+    cache.record(&input, input_hash, options, outputs);
+    cache.save();
+
+    if args.texture_usage {
+        let mut usage = TextureUsage::load();
+        usage.record(&input, &model, |texture| extracted.contains_key(texture));
+        usage.save();
+    }
 
-    println!("Convert completed!");
+    info!("Convert completed!");
+    log::finish();
+    Ok(())
 }
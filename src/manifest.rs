@@ -0,0 +1,91 @@
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::log::{Event, Observer};
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Written,
+    /// already there from an earlier run
+    Kept,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    status: Status,
+}
+
+/// Lists every file the run writes for --manifest, as the writers report
+/// them, and saves the list once the run ends.
+pub struct Manifest {
+    path: String,
+    root: PathBuf,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(path: &str, root: Option<&str>) -> Manifest {
+        let working_folder = env::current_dir().unwrap_or_default();
+        Manifest {
+            path: path.to_string(),
+            root: working_folder.join(root.unwrap_or(".")),
+            entries: Vec::new(),
+        }
+    }
+
+    /// `path` relative to the root, or absolute if it's outside it.
+    fn relative(&self, path: &str) -> String {
+        let path = env::current_dir().unwrap_or_default().join(path);
+        let root: PathBuf = self.root.components().collect();
+        let path: PathBuf = path.components().collect();
+        path.strip_prefix(&root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn add(&mut self, path: &str, status: Status) {
+        let path = self.relative(path);
+        if !self.entries.iter().any(|entry| entry.path == path) {
+            self.entries.push(ManifestEntry { path, status });
+        }
+    }
+}
+
+impl Observer for Manifest {
+    fn event(&mut self, event: &Event) {
+        match *event {
+            Event::FileWritten { path } => self.add(path, Status::Written),
+            Event::FileKept { path } => self.add(path, Status::Kept),
+            _ => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        let is_json = Path::new(&self.path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let text = if is_json {
+            serde_json::to_string_pretty(&self.entries).unwrap() + "\n"
+        } else {
+            self.entries
+                .iter()
+                .map(|entry| {
+                    let status = match entry.status {
+                        Status::Written => "written",
+                        Status::Kept => "kept",
+                    };
+                    format!("{}\t{}\n", status, entry.path)
+                })
+                .collect()
+        };
+        // the log is locked while observers run, so this can't be a warning
+        if let Err(err) = fs::write(&self.path, text) {
+            eprintln!("Error: unable to write {}: {}", self.path, err);
+        }
+    }
+}
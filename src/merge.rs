@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+
+use ta_3do_to_obj::{all_names, read_3do, write_3do, Model, Piece};
+
+use crate::output::write_file;
+use crate::FIXED_POINT_SCALE;
+
+/// Parses a position written `x,y,z`, in the game's units.
+pub fn parse_position(s: &str) -> Result<[f64; 3], String> {
+    let values = s
+        .split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("{} isn't three numbers like 0,1.5,0", s))?;
+    match values[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => Err(format!("{} isn't three numbers like 0,1.5,0", s)),
+    }
+}
+
+fn read(path: &str) -> Result<Model, String> {
+    let data = fs::read(path).map_err(|err| format!("unable to read {}: {}", path, err))?;
+    read_3do(&data).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// The first piece named `name`, ignoring case as the game does, parents
+/// before their children.
+fn find_piece<'a>(pieces: &'a mut [Piece], wanted: &str) -> Option<&'a mut Piece> {
+    for piece in pieces {
+        if piece.name().eq_ignore_ascii_case(wanted) {
+            return Some(piece);
+        }
+        if let Some(found) = find_piece(&mut piece.children, wanted) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn prefix_names(pieces: &mut [Piece], prefix: &str) {
+    for piece in pieces {
+        let mut prefixed = prefix.as_bytes().to_vec();
+        prefixed.extend(piece.raw.name.as_deref().unwrap_or_default());
+        piece.raw.name = Some(prefixed);
+        prefix_names(&mut piece.children, prefix);
+    }
+}
+
+/// Puts the pieces of the 3do at `attachment` under the piece `piece` of the
+/// one at `base`, writing the two as one to `output`. The attachment's top
+/// pieces keep their offsets, now from the piece they hang off, moved by
+/// `offset` when given, and their names get `prefix`. Names the merged model
+/// has more than once are warned about, as scripts can only find one.
+pub fn merge_3do(
+    base: &str,
+    piece: &str,
+    attachment: &str,
+    output: &str,
+    offset: Option<[f64; 3]>,
+    prefix: Option<&str>,
+) -> Result<(), String> {
+    let mut model = read(base)?;
+    let mut attached = read(attachment)?.pieces;
+    if let Some(prefix) = prefix {
+        prefix_names(&mut attached, prefix);
+    }
+    if let Some(offset) = offset {
+        for root in &mut attached {
+            for (coordinate, by) in root.raw.from_parent.iter_mut().zip(offset) {
+                *coordinate += (by * *FIXED_POINT_SCALE).round() as i32;
+            }
+        }
+    }
+
+    let count = {
+        let mut names = Vec::new();
+        all_names(&attached, &mut names);
+        names.len()
+    };
+    let target = find_piece(&mut model.pieces, piece)
+        .ok_or_else(|| format!("{} has no piece named {}", base, piece))?;
+    let target_name = target.name();
+    target.children.extend(attached);
+
+    let mut names = Vec::new();
+    all_names(&model.pieces, &mut names);
+    let mut seen = HashSet::new();
+    let mut repeated: Vec<String> = names
+        .into_iter()
+        .filter(|name| !seen.insert(name.to_ascii_lowercase()))
+        .collect();
+    repeated.sort();
+    repeated.dedup();
+    if !repeated.is_empty() {
+        warning!(
+            "the merged model has more than one piece named {}, use --prefix to tell them apart",
+            repeated.join(", ")
+        );
+    }
+
+    let data = write_3do(&model);
+    write_file(output, |file| file.write_all(&data))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+    println!(
+        "Wrote {}: {} pieces of {} under {}",
+        output, count, attachment, target_name
+    );
+    Ok(())
+}
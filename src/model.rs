@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+pub use ta_3do_to_obj::{RawPiece, RawPrimitive};
+
+use ta_3do_to_obj::palette::{colour_name, Palette, PALETTE};
+use ta_3do_to_obj::records::{
+    add_offset, check_count, check_in_file, check_tree, read_cstring, read_object, read_primitives,
+    read_vertex_indexes, read_vertexes, ObjectRecord, PrimitiveRecord, INDEX_SIZE, OBJECT_SIZE,
+    PRIMITIVE_SIZE, TA_VERSION_SIGNATURE, VERTEX_SIZE,
+};
+
+use crate::geometry::{is_degenerate, merge_coplanar_faces};
+use crate::log;
+use crate::texture_map::{Target, TextureMap};
+use crate::{
+    check_unknown, convert_coord, input_path, Args, MaterialPriority, Offset, PotMode, UvMode,
+    QUAD_UVS,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Material {
+    /// palette index
+    Colour(usize),
+    /// name of a gaf entry
+    Texture(String),
+}
+
+impl Material {
+    /// Name the material goes by in the output, the colour's palette name or
+    /// the texture name.
+    pub fn name(&self, args: &Args) -> String {
+        match self {
+            Material::Colour(index) if args.rgb_material_names => colour_name(PALETTE[*index]),
+            Material::Colour(index) => Palette::new(args.fullbright_range.clone()).name(*index),
+            Material::Texture(name) => name.clone(),
+        }
+    }
+}
+
+/// A face as indices into its piece's vertexes.
+pub struct Face {
+    pub material: Material,
+    /// texture whose uvs the face uses, when textures get their own uvs
+    pub texture: Option<String>,
+    pub vertex_indexes: Vec<u16>,
+    /// uv of each corner, empty with --uv-mode none
+    pub uvs: Vec<[f64; 2]>,
+}
+
+/// A piece of the model, with its vertexes already moved into place and
+/// converted to the output coordinates.
+pub struct Piece {
+    pub name: String,
+    /// where the piece pivots, in the same coordinates as its vertexes
+    pub origin: [f64; 3],
+    pub vertexes: Vec<[f64; 3]>,
+    pub faces: Vec<Face>,
+    pub children: Vec<Piece>,
+    pub raw: RawPiece,
+}
+
+/// Everything read from a 3do, ready to be handed to an exporter.
+#[derive(Default)]
+pub struct Model {
+    pub pieces: Vec<Piece>,
+    pub used_colours: Vec<usize>,
+    pub used_textures: Vec<String>,
+    pub degenerate_faces: u32,
+    /// vertexes no face refers to, dropped with --prune-unused
+    pub unused_vertexes: u32,
+    /// byte ranges of the file the piece tree refers to
+    pub regions: Vec<Range<usize>>,
+    /// pieces read so far, checked against --max-objects
+    pub objects_read: u32,
+    /// texture names as the 3do has them, before --texture-map
+    pub source_textures: Vec<String>,
+    /// textures --texture-map, or an s3o, points at an image file for, by name
+    pub texture_files: HashMap<String, PathBuf>,
+    /// primitives whose texture name is empty, as "primitive N of piece NAME"
+    pub empty_texture_names: Vec<String>,
+    /// primitives with both a colour and a texture, --material-priority picks one
+    pub colour_and_texture: u32,
+}
+
+impl Model {
+    /// Every piece, parents before their children, in file order.
+    pub fn all_pieces(&self) -> Vec<&Piece> {
+        fn walk<'a>(piece: &'a Piece, pieces: &mut Vec<&'a Piece>) {
+            pieces.push(piece);
+            for child in &piece.children {
+                walk(child, pieces);
+            }
+        }
+
+        let mut pieces = Vec::new();
+        for piece in &self.pieces {
+            walk(piece, &mut pieces);
+        }
+        pieces
+    }
+
+    /// Levels of pieces under one another, 1 when no piece has children.
+    pub fn depth(&self) -> usize {
+        fn depth(pieces: &[Piece]) -> usize {
+            pieces
+                .iter()
+                .map(|piece| 1 + depth(&piece.children))
+                .max()
+                .unwrap_or(0)
+        }
+
+        depth(&self.pieces)
+    }
+
+    /// Index in `all_pieces` of each piece's parent, in the same order.
+    pub fn parents(&self) -> Vec<Option<usize>> {
+        fn walk(piece: &Piece, parent: Option<usize>, parents: &mut Vec<Option<usize>>) {
+            let index = parents.len();
+            parents.push(parent);
+            for child in &piece.children {
+                walk(child, Some(index), parents);
+            }
+        }
+
+        let mut parents = Vec::new();
+        for piece in &self.pieces {
+            walk(piece, None, &mut parents);
+        }
+        parents
+    }
+
+    /// Prints every piece indented under its parent, with its offset from the
+    /// parent and where that places it, in file units, and its origin in the
+    /// output.
+    pub fn print_offsets(&self) {
+        fn walk(piece: &Piece, depth: usize, parent: [i32; 3]) {
+            let from_parent = piece.raw.from_parent;
+            let placed = [0, 1, 2].map(|i| parent[i] + from_parent[i]);
+            info!(
+                "{}{}: from parent {:?}, placed at {:?}, origin {:?}",
+                "  ".repeat(depth),
+                piece.name,
+                from_parent,
+                placed,
+                piece.origin
+            );
+            for child in &piece.children {
+                walk(child, depth + 1, placed);
+            }
+        }
+
+        for piece in &self.pieces {
+            walk(piece, 0, [0; 3]);
+        }
+    }
+
+    /// Spans of a `file_len` byte file that no piece refers to.
+    pub fn unvisited(&self, file_len: usize) -> Vec<Range<usize>> {
+        let mut regions = self.regions.clone();
+        regions.sort_by_key(|region| region.start);
+
+        let mut unvisited = Vec::new();
+        let mut end = 0;
+        for region in regions {
+            if region.start > end {
+                unvisited.push(end..region.start.min(file_len));
+            }
+            end = end.max(region.end);
+            if end >= file_len {
+                return unvisited;
+            }
+        }
+        unvisited.push(end..file_len);
+        unvisited
+    }
+}
+
+/// Reads the piece tree starting at the root object at the start of the
+/// file, and at any --root-offset, failing if a piece has more of something
+/// than the limits allow. Warns about bytes nothing refers to.
+pub fn read_model(args: &Args, texture_map: &TextureMap, data: &[u8]) -> Result<Model, String> {
+    let mut model = Model::default();
+    let origin = Offset { x: 0, y: 0, z: 0 };
+    model.pieces = read_siblings(args, texture_map, data, 0, origin, 1, &mut model)?;
+    for &root_offset in &args.root_offset {
+        let pieces = read_siblings(args, texture_map, data, root_offset, origin, 1, &mut model)?;
+        model.pieces.extend(pieces);
+    }
+
+    let unvisited = model.unvisited(data.len());
+    let unvisited_bytes: usize = unvisited.iter().map(|span| span.len()).sum();
+    if unvisited_bytes > 0 {
+        warning!(
+            "{} of the {} bytes of the file were never visited, it may be padded or hold more than one model.",
+            unvisited_bytes,
+            data.len()
+        );
+    }
+    let roots: Vec<String> = unvisited
+        .into_iter()
+        .flatten()
+        .filter(|&offset| is_plausible_object(args, data, offset))
+        .map(|offset| format!("{:#x}", offset))
+        .collect();
+    if !roots.is_empty() {
+        warning!(
+            "found objects nothing points to at {}, convert them with --root-offset.",
+            roots.join(", ")
+        );
+    }
+    if model.colour_and_texture > 0 {
+        info!(
+            "{} primitives have both a colour and a texture, using the {}.",
+            model.colour_and_texture,
+            match args.material_priority {
+                MaterialPriority::Texture => "texture",
+                MaterialPriority::Color => "colour",
+            }
+        );
+    }
+
+    Ok(model)
+}
+
+/// Whether an object record could start at `offset`: the signature is right,
+/// the counts are within the limits and its name and arrays are in the file.
+fn is_plausible_object(args: &Args, data: &[u8], offset: usize) -> bool {
+    let Ok(object) = read_object(data, offset as u32) else {
+        return false;
+    };
+
+    let in_file =
+        |start: u32, count: u32, size: usize| check_in_file(data, start, count, size, "").is_ok();
+    let plausible = object.version_signature == TA_VERSION_SIGNATURE
+        && object.number_of_vertexes <= args.max_vertexes
+        && object.number_of_primitives <= args.max_primitives
+        && in_file(object.offset_to_object_name, 1, 1)
+        && in_file(
+            object.offset_to_vertex_array,
+            object.number_of_vertexes,
+            VERTEX_SIZE,
+        )
+        && in_file(
+            object.offset_to_primitive_array,
+            object.number_of_primitives,
+            PRIMITIVE_SIZE,
+        );
+    plausible
+        && read_cstring(data, object.offset_to_object_name, args.max_name_length)
+            .is_ok_and(|name| !name.is_empty())
+}
+
+/// Reads the object at `object_offset` and every sibling after it, all
+/// placed relative to the same parent.
+fn read_siblings(
+    args: &Args,
+    texture_map: &TextureMap,
+    data: &[u8],
+    mut object_offset: u32,
+    parent_offset: Offset,
+    depth: u32,
+    model: &mut Model,
+) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    loop {
+        let object = read_object(data, object_offset)?;
+
+        let offset = Offset {
+            x: add_offset(parent_offset.x, object.x_from_parent, "piece x offset")?,
+            y: add_offset(parent_offset.y, object.y_from_parent, "piece y offset")?,
+            z: add_offset(parent_offset.z, object.z_from_parent, "piece z offset")?,
+        };
+
+        let mut piece = read_piece(
+            args,
+            texture_map,
+            data,
+            &object,
+            object_offset,
+            offset,
+            model,
+        )?;
+        model.objects_read += 1;
+        check_tree(
+            &piece.name,
+            object_offset,
+            depth,
+            model.objects_read,
+            args.max_depth,
+            args.max_objects,
+        )?;
+
+        // go over children
+        if object.offset_to_child_object != 0 {
+            piece.children = read_siblings(
+                args,
+                texture_map,
+                data,
+                object.offset_to_child_object,
+                offset,
+                depth + 1,
+                model,
+            )?;
+        }
+        pieces.push(piece);
+
+        // go over siblings
+        if object.offset_to_sibling_object == 0 {
+            return Ok(pieces);
+        }
+        object_offset = object.offset_to_sibling_object;
+    }
+}
+
+fn read_piece(
+    args: &Args,
+    texture_map: &TextureMap,
+    data: &[u8],
+    object: &ObjectRecord,
+    object_offset: u32,
+    offset: Offset,
+    model: &mut Model,
+) -> Result<Piece, String> {
+    let raw_name = read_cstring(data, object.offset_to_object_name, args.max_name_length)
+        .map(|name| name.to_vec());
+    if let Ok(raw_name) = &raw_name {
+        let name_start = object.offset_to_object_name as usize;
+        model
+            .regions
+            .push(name_start..name_start + raw_name.len() + 1);
+    }
+    let name = match &raw_name {
+        Ok(raw_name) => String::from_utf8_lossy(raw_name).into_owned(),
+        Err(err) => {
+            let placeholder = format!("piece_{:x}", object.offset_to_object_name);
+            warning!("unreadable piece name ({}), using {}", err, placeholder);
+            placeholder
+        }
+    };
+
+    let object_start = object_offset as usize;
+    model.regions.push(object_start..object_start + OBJECT_SIZE);
+    let vertexes_start = object.offset_to_vertex_array as usize;
+    model
+        .regions
+        .push(vertexes_start..vertexes_start + object.number_of_vertexes as usize * VERTEX_SIZE);
+    let primitives_start = object.offset_to_primitive_array as usize;
+    model.regions.push(
+        primitives_start..primitives_start + object.number_of_primitives as usize * PRIMITIVE_SIZE,
+    );
+
+    let input = input_path(args);
+    let always_0 = object.always_0;
+    if always_0 != 0 {
+        warning!(
+            "piece {} has always_0 = {:#x} at {:#x}, the file may be corrupt",
+            name,
+            always_0,
+            object_offset as usize + ObjectRecord::ALWAYS_0
+        );
+    }
+    check_unknown(
+        args,
+        &input,
+        object_offset as usize + ObjectRecord::ALWAYS_0,
+        "TagObject.always_0",
+        always_0,
+        0,
+    );
+
+    check_count(object.number_of_vertexes, args.max_vertexes, "vertexes")
+        .map_err(|err| format!("piece {} has {} (--max-vertexes)", name, err))?;
+    let raw_vertexes =
+        read_vertexes(data, object).map_err(|err| format!("piece {} has {}", name, err))?;
+    let mut vertexes: Vec<[f64; 3]> = raw_vertexes
+        .iter()
+        .map(|&[x, y, z]| {
+            let x = add_offset(offset.x, x, "vertex x")?;
+            let x = x
+                .checked_neg()
+                .ok_or_else(|| format!("vertex x {} out of range", x))?;
+            Ok([
+                convert_coord(x, args.coords),
+                convert_coord(add_offset(offset.y, y, "vertex y")?, args.coords),
+                convert_coord(add_offset(offset.z, z, "vertex z")?, args.coords),
+            ])
+        })
+        .collect::<Result<_, String>>()
+        .map_err(|err| format!("piece {} has {}", name, err))?;
+
+    let points: Vec<[f64; 3]> = raw_vertexes.iter().map(|v| v.map(f64::from)).collect();
+
+    let mut faces = Vec::new();
+    let mut raw_primitives = Vec::new();
+    check_count(
+        object.number_of_primitives,
+        args.max_primitives,
+        "primitives",
+    )
+    .map_err(|err| format!("piece {} has {} (--max-primitives)", name, err))?;
+    let primatives =
+        read_primitives(data, object).map_err(|err| format!("piece {} has {}", name, err))?;
+    for (primitive_index, p) in primatives.into_iter().enumerate() {
+        let primitive_offset =
+            object.offset_to_primitive_array as usize + primitive_index * PRIMITIVE_SIZE;
+        for (field, field_offset, value) in [
+            (
+                "TagPrimitive.always_0",
+                PrimitiveRecord::ALWAYS_0,
+                p.always_0,
+            ),
+            (
+                "TagPrimitive.unknown_1",
+                PrimitiveRecord::UNKNOWN_1,
+                p.unknown_1,
+            ),
+            (
+                "TagPrimitive.unknown_2",
+                PrimitiveRecord::UNKNOWN_2,
+                p.unknown_2,
+            ),
+        ] {
+            check_unknown(
+                args,
+                &input,
+                primitive_offset + field_offset,
+                field,
+                value,
+                0,
+            );
+        }
+
+        let indexes_start = p.offset_to_vertex_index_array as usize;
+        model
+            .regions
+            .push(indexes_start..indexes_start + p.number_of_vertex_indexes as usize * INDEX_SIZE);
+        check_count(p.number_of_vertex_indexes, args.max_face_indexes, "corners").map_err(
+            |err| {
+                format!(
+                    "primitive {} of piece {} has {} (--max-face-indexes)",
+                    primitive_index, name, err
+                )
+            },
+        )?;
+        let vertex_indexes = read_vertex_indexes(data, &p).map_err(|err| {
+            format!(
+                "piece {} has primitive {} with {}",
+                name, primitive_index, err
+            )
+        })?;
+        let mut raw_texture_name = None;
+        if p.offset_to_texture_name != 0 {
+            if let Ok(texture_name) =
+                read_cstring(data, p.offset_to_texture_name, args.max_name_length)
+            {
+                let texture_start = p.offset_to_texture_name as usize;
+                model
+                    .regions
+                    .push(texture_start..texture_start + texture_name.len() + 1);
+                raw_texture_name = Some(texture_name.to_vec());
+            }
+        }
+        raw_primitives.push(RawPrimitive {
+            color_index: p.color_index,
+            is_colored: p.is_colored,
+            texture_name: raw_texture_name,
+            vertex_indexes: vertex_indexes.clone(),
+            always_0: p.always_0,
+            unknown_1: p.unknown_1,
+            unknown_2: p.unknown_2,
+        });
+
+        // an empty name is taken to mean no texture, as `usemtl` can't have one
+        let empty_texture_name = raw_primitives
+            .last()
+            .and_then(|raw| raw.texture_name.as_ref())
+            .is_some_and(|texture_name| texture_name.is_empty());
+        let has_texture = p.offset_to_texture_name != 0 && !empty_texture_name;
+        let has_colour = p.is_colored != 0;
+        if empty_texture_name {
+            warning!(
+                "primitive {} of piece {} names an empty texture, {}.",
+                primitive_index,
+                name,
+                if has_colour {
+                    "using its colour"
+                } else {
+                    "skipping it"
+                }
+            );
+            model
+                .empty_texture_names
+                .push(format!("primitive {} of piece {}", primitive_index, name));
+        }
+        if has_texture && has_colour {
+            model.colour_and_texture += 1;
+        }
+
+        // only textures get their own uvs, when padding changes their extent
+        let mut face_texture = None;
+
+        // non-rendered/clear material
+        let material = if !has_colour && !has_texture {
+            continue;
+        }
+        // textured material
+        else if has_texture
+            && (!has_colour || args.material_priority == MaterialPriority::Texture)
+        {
+            let texture_name = read_cstring(data, p.offset_to_texture_name, args.max_name_length)
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+                .unwrap_or_else(|err| {
+                    let placeholder = format!("texture_{:x}", p.offset_to_texture_name);
+                    warning!(
+                        "unreadable texture name in piece {} ({}), using {}",
+                        name,
+                        err,
+                        placeholder
+                    );
+                    placeholder
+                });
+            if !model.source_textures.contains(&texture_name) {
+                model.source_textures.push(texture_name.clone());
+            }
+            let texture_name = match texture_map.target(&texture_name) {
+                Some(target) => {
+                    let name = target.name();
+                    if let Target::File(path) = target {
+                        model.texture_files.insert(name.clone(), path);
+                    }
+                    name
+                }
+                None => texture_name,
+            };
+            if args.pot == PotMode::Pad {
+                face_texture = Some(texture_name.clone());
+            }
+            if !args.no_mtl && !model.used_textures.contains(&texture_name) {
+                model.used_textures.push(texture_name.clone());
+            }
+            Material::Texture(texture_name)
+        }
+        // coloured material
+        else {
+            let colour_index = p.color_index as usize;
+            if !args.no_mtl && !model.used_colours.contains(&colour_index) {
+                model.used_colours.push(colour_index);
+            }
+            Material::Colour(colour_index)
+        };
+
+        let face = Face {
+            material,
+            texture: face_texture,
+            vertex_indexes,
+            uvs: Vec::new(),
+        };
+        if !args.keep_degenerate && is_degenerate(&face, &points) {
+            model.degenerate_faces += 1;
+            continue;
+        }
+        faces.push(face);
+    }
+
+    if args.merge_coplanar {
+        faces = merge_coplanar_faces(faces, &points);
+    }
+
+    for face in &mut faces {
+        face.uvs = match args.uv_mode {
+            UvMode::None => Vec::new(),
+            UvMode::Quad => (0..face.vertex_indexes.len())
+                .map(|i| QUAD_UVS[i % 4])
+                .collect(),
+            UvMode::PerFace => {
+                let face_points: Vec<[f64; 3]> = face
+                    .vertex_indexes
+                    .iter()
+                    .map(|&i| points.get(i as usize).copied().unwrap_or([0.0; 3]))
+                    .collect();
+                planar_uvs(&face_points)
+            }
+        };
+    }
+
+    let unused = unused_vertexes(vertexes.len(), &faces);
+    if !unused.is_empty() {
+        model.unused_vertexes += unused.len() as u32;
+        if args.prune_unused {
+            prune_vertexes(&mut vertexes, &mut faces, &unused);
+        } else {
+            info!("Piece {} has {} vertexes no face uses.", name, unused.len());
+        }
+    }
+
+    log::emit(log::Event::PieceRead {
+        name: &name,
+        vertexes: vertexes.len(),
+        faces: faces.len(),
+    });
+    Ok(Piece {
+        name,
+        origin: [
+            convert_coord(-offset.x, args.coords),
+            convert_coord(offset.y, args.coords),
+            convert_coord(offset.z, args.coords),
+        ],
+        vertexes,
+        faces,
+        children: Vec::new(),
+        raw: RawPiece {
+            version_signature: object.version_signature,
+            name: raw_name.ok(),
+            from_parent: [
+                object.x_from_parent,
+                object.y_from_parent,
+                object.z_from_parent,
+            ],
+            selection_primitive: object.offset_to_selection_primitive,
+            always_0: object.always_0,
+            vertexes: raw_vertexes,
+            primitives: raw_primitives,
+        },
+    })
+}
+
+/// Indexes of the vertexes none of `faces` refer to, in order.
+fn unused_vertexes(vertex_count: usize, faces: &[Face]) -> Vec<usize> {
+    let mut used = vec![false; vertex_count];
+    for face in faces {
+        for &i in &face.vertex_indexes {
+            if let Some(used) = used.get_mut(i as usize) {
+                *used = true;
+            }
+        }
+    }
+    (0..vertex_count).filter(|&i| !used[i]).collect()
+}
+
+/// Drops the `unused` vertexes and moves the faces' indexes down to where
+/// the rest end up. Indexes already past the last vertex stay past it.
+fn prune_vertexes(vertexes: &mut Vec<[f64; 3]>, faces: &mut [Face], unused: &[usize]) {
+    let old_count = vertexes.len();
+    let mut new_index = Vec::with_capacity(old_count);
+    let mut removed = 0;
+    for i in 0..old_count {
+        new_index.push(i - removed);
+        if unused.get(removed) == Some(&i) {
+            removed += 1;
+        }
+    }
+
+    *vertexes = vertexes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| unused.binary_search(i).is_err())
+        .map(|(_, vertex)| *vertex)
+        .collect();
+    for face in faces {
+        for index in &mut face.vertex_indexes {
+            *index = match new_index.get(*index as usize) {
+                Some(&new) => new as u16,
+                None => *index - removed as u16,
+            };
+        }
+    }
+}
+
+/// Projects a face onto its own plane and scales it into the uv square, with
+/// the first edge running along u the same way the fixed quad maps it.
+fn planar_uvs(points: &[[f64; 3]]) -> Vec<[f64; 2]> {
+    let fallback = || (0..points.len()).map(|i| QUAD_UVS[i % 4]).collect();
+
+    let sub = |a: [f64; 3], b: [f64; 3]| [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let normalize = |a: [f64; 3]| {
+        let length = dot(a, a).sqrt();
+        [a[0] / length, a[1] / length, a[2] / length]
+    };
+
+    if points.len() < 3 {
+        return fallback();
+    }
+
+    let origin = points[0];
+    let u_axis = normalize(sub(points[1], origin));
+    let last_edge = sub(points[points.len() - 1], origin);
+    let along_u = dot(last_edge, u_axis);
+    let v_axis = normalize(sub(
+        last_edge,
+        [
+            u_axis[0] * along_u,
+            u_axis[1] * along_u,
+            u_axis[2] * along_u,
+        ],
+    ));
+    if u_axis.iter().chain(&v_axis).any(|c| !c.is_finite()) {
+        return fallback();
+    }
+
+    let projected: Vec<[f64; 2]> = points
+        .iter()
+        .map(|&p| [dot(sub(p, origin), u_axis), dot(sub(p, origin), v_axis)])
+        .collect();
+
+    let range = |axis: usize| {
+        let min = projected.iter().map(|p| p[axis]).fold(f64::MAX, f64::min);
+        let max = projected.iter().map(|p| p[axis]).fold(f64::MIN, f64::max);
+        (min, (max - min).max(f64::EPSILON))
+    };
+    let (min_u, size_u) = range(0);
+    let (min_v, size_v) = range(1);
+
+    // the first vertex sits at the top of the texture, like vt 0 1 in the quad
+    projected
+        .iter()
+        .map(|p| [(p[0] - min_u) / size_u, 1.0 - (p[1] - min_v) / size_v])
+        .collect()
+}
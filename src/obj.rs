@@ -0,0 +1,501 @@
+use bmp::{Image, Pixel};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::collision::write_collision;
+use crate::exporter::{self, canvas_uv, expand_prefix, name_prefix, Exporter};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::inverted::signed_volume;
+use crate::model::{Face, Material, Model};
+use crate::output::{dot_relative_path, relative_path, save_image, PendingFiles};
+use crate::shared_mtl::{parse_materials, write_materials, MaterialBlock, SharedMtl};
+use crate::three::{write_three_json, ThreeMaterial};
+use crate::{
+    texture_folder, Args, ColorSpace, DedupeMode, GroupBy, LongFaces, MtllibTarget,
+    TextureFallback, UvMode, QUAD_UVS,
+};
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+/// Width and height of the images written by --bake-colors.
+const SWATCH_SIZE: u32 = 4;
+
+/// A texture coordinate by its exact bits, and the texture it's scaled for.
+type UvKey = ([u64; 2], Option<String>);
+
+/// Texture coordinates faces refer to. They're written at the top of the obj
+/// once the sizes of the extracted textures are known.
+#[derive(Default)]
+struct UvList {
+    uvs: Vec<([f64; 2], Option<String>)>,
+    quads: HashMap<Option<String>, u32>,
+    /// with --dedupe-uvs, where each coordinate was first written
+    seen: Option<HashMap<UvKey, u32>>,
+}
+
+impl UvList {
+    /// Index of the first corner of the fixed quad used by `texture`.
+    fn quad(&mut self, texture: Option<String>) -> u32 {
+        if let Some(&start) = self.quads.get(&texture) {
+            return start;
+        }
+
+        let start = self.uvs.len() as u32;
+        for uv in QUAD_UVS {
+            self.uvs.push((uv, texture.clone()));
+        }
+        self.quads.insert(texture, start);
+        start
+    }
+
+    fn push(&mut self, uv: [f64; 2], texture: Option<String>) -> u32 {
+        let index = self.uvs.len() as u32;
+        if let Some(seen) = &mut self.seen {
+            let key = ([uv[0].to_bits(), uv[1].to_bits()], texture.clone());
+            if let Some(&existing) = seen.get(&key) {
+                return existing;
+            }
+            seen.insert(key, index);
+        }
+        self.uvs.push((uv, texture));
+        index
+    }
+}
+
+/// Writes a small solid colour image so a palette colour can be used as a texture.
+fn write_swatch(colour: [u8; 3], path: &str) {
+    let mut image = Image::new(SWATCH_SIZE, SWATCH_SIZE);
+    for (x, y) in image.coordinates() {
+        image.set_pixel(x, y, Pixel::new(colour[0], colour[1], colour[2]));
+    }
+
+    if let Some(folder) = Path::new(path).parent() {
+        let _ = fs::create_dir_all(folder);
+    }
+    let _ = save_image(&image, path);
+}
+
+/// Writes an `f` line, `first_vertex` being the obj index of the face's
+/// piece's first vertex.
+fn write_face(
+    args: &Args,
+    obj_writter: &mut Vec<u8>,
+    face: &Face,
+    first_vertex: u32,
+    uvs: &mut UvList,
+) -> io::Result<()> {
+    // colours only show a texture once they're baked into one
+    let shows_texture = args.bake_colors || matches!(face.material, Material::Texture(_));
+    let face_uvs: Vec<u32> = match args.uv_mode {
+        UvMode::None => Vec::new(),
+        _ if args.dedupe_uvs && !shows_texture => Vec::new(),
+        UvMode::Quad => {
+            let quad = uvs.quad(face.texture.clone());
+            (0..face.vertex_indexes.len())
+                .map(|i| quad + (i % 4) as u32)
+                .collect()
+        }
+        UvMode::PerFace => face
+            .uvs
+            .iter()
+            .map(|&uv| uvs.push(uv, face.texture.clone()))
+            .collect(),
+    };
+
+    let corners: Vec<String> = face
+        .vertex_indexes
+        .iter()
+        .enumerate()
+        .map(|(i, &vertex_index)| {
+            let vertex = first_vertex + (vertex_index as u32);
+            match face_uvs.get(i) {
+                Some(uv) => format!("{}/{}", vertex, uv + 1),
+                None => vertex.to_string(),
+            }
+        })
+        .collect();
+
+    let length = 1 + corners.iter().map(|c| c.len() + 1).sum::<usize>();
+    let too_long = args
+        .max_line_length
+        .is_some_and(|max| length > max as usize);
+    if too_long && args.long_faces == LongFaces::Triangulate && corners.len() > 3 {
+        for i in 2..corners.len() {
+            let triangle = [&corners[0], &corners[i - 1], &corners[i]];
+            writeln!(
+                obj_writter,
+                "f {} {} {}",
+                triangle[0], triangle[1], triangle[2]
+            )?;
+        }
+    } else {
+        writeln!(obj_writter, "f {}", corners.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Breaks lines longer than `max` bytes between words, ending every part
+/// but the last with a `\` so parsers join them back up.
+fn wrap_long_lines(text: &[u8], max: usize) -> Vec<u8> {
+    let text = String::from_utf8_lossy(text);
+    let mut wrapped = String::with_capacity(text.len());
+    for line in text.lines() {
+        if line.len() <= max {
+            wrapped.push_str(line);
+            wrapped.push('\n');
+            continue;
+        }
+
+        let mut words = line.split(' ');
+        let mut part = words.next().unwrap_or("").to_owned();
+        for word in words {
+            if part.len() + 1 + word.len() + " \\".len() > max {
+                wrapped.push_str(&part);
+                wrapped.push_str(" \\\n");
+                part.clear();
+            } else {
+                part.push(' ');
+            }
+            part.push_str(word);
+        }
+        wrapped.push_str(&part);
+        wrapped.push('\n');
+    }
+    wrapped.into_bytes()
+}
+
+/// Swaps in the block from --mtl-overrides of each material it has, found
+/// by the name the obj gives the material or the name before --prefix.
+fn apply_overrides(path: &str, materials: &mut [MaterialBlock], prefix: &str) -> io::Result<()> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("unable to read {}: {}", path, err)))?;
+    let overrides = parse_materials(&text);
+
+    let mut used = vec![false; overrides.len()];
+    for (name, body) in materials.iter_mut() {
+        let unprefixed = name.strip_prefix(prefix).unwrap_or(name);
+        let found = overrides
+            .iter()
+            .position(|(other, _)| other == name)
+            .or_else(|| overrides.iter().position(|(other, _)| other == unprefixed));
+        if let Some(i) = found {
+            *body = overrides[i].1.clone();
+            used[i] = true;
+        }
+    }
+
+    let unused: Vec<&str> = overrides
+        .iter()
+        .zip(used)
+        .filter(|(_, used)| !used)
+        .map(|((name, _), _)| name.as_str())
+        .collect();
+    if !unused.is_empty() {
+        info!(
+            "{} materials in {} aren't used by this model: {}.",
+            unused.len(),
+            path,
+            unused.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Every material the model uses, by the name the obj gives it, and what
+/// --three-json says about each of them. Textures are named relative to
+/// `mtl_path`, the mtl the materials are written to.
+fn materials(
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    prefix: &str,
+    file_prefix: &str,
+    mtl_path: &str,
+) -> (Vec<MaterialBlock>, Vec<ThreeMaterial>) {
+    let texture_folder = texture_folder(args);
+    let mut materials = Vec::new();
+    let mut three = Vec::new();
+    // flat pieces show their back as often as their front
+    let mut flat_materials = Vec::new();
+    for piece in model.all_pieces() {
+        if signed_volume(piece).is_none() {
+            for face in &piece.faces {
+                flat_materials.push(exporter::material_name(args, &face.material, textures));
+            }
+        }
+    }
+
+    let palette = Palette::new(args.fullbright_range.clone());
+    let mut written_colours = Vec::new();
+    for &colour_index in &model.used_colours {
+        // with --rgb-material-names different palette indices can share a name
+        let name = Material::Colour(colour_index).name(args);
+        if written_colours.contains(&name) {
+            continue;
+        }
+        written_colours.push(name.clone());
+
+        let colour = palette.get(colour_index);
+        let factors = colour_factors(colour, args.color_space == ColorSpace::Linear);
+        let mut body = if args.bake_colors {
+            let swatch = format!("{}{}{}.bmp", texture_folder, file_prefix, name);
+            write_swatch(colour, &swatch);
+            format!("map_Kd {}\n", dot_relative_path(&swatch, mtl_path))
+        } else {
+            format!("Kd {} {} {}\n", factors[0], factors[1], factors[2])
+        };
+        let emissive = !args.no_emissive && palette.is_fullbright(colour_index);
+        if emissive {
+            body += &format!("Ke {} {} {}\n", factors[0], factors[1], factors[2]);
+        }
+        three.push(ThreeMaterial {
+            emissive: emissive.then_some(factors),
+            double_sided: flat_materials.contains(&name),
+            ..ThreeMaterial::default()
+        });
+        materials.push((format!("{}{}", prefix, name), body));
+    }
+
+    let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+    for texture in &model.used_textures {
+        let extracted_texture = textures.get(texture);
+        if merge_duplicates && extracted_texture.is_some_and(|t| t.duplicate_of.is_some()) {
+            continue;
+        }
+
+        let file = match extracted_texture {
+            Some(t) => t.path(),
+            None => format!("{}{}{}.bmp", texture_folder, file_prefix, texture),
+        };
+        let mut body = String::new();
+        if let Some(t) = extracted_texture {
+            body += &format!("# {}x{}\n", t.canvas_width, t.canvas_height);
+        }
+        if args.no_textures && args.texture_fallback == TextureFallback::Kd {
+            body += "Kd 0.5 0.5 0.5\n";
+        } else {
+            body += &format!("map_Kd {}\n", dot_relative_path(&file, mtl_path));
+        }
+        let mut material = match extracted_texture.and_then(|t| t.colour_key) {
+            Some(key) => ThreeMaterial::keyed(key),
+            None => ThreeMaterial::default(),
+        };
+        material.double_sided |= flat_materials.contains(texture);
+        three.push(material);
+        materials.push((format!("{}{}", prefix, texture), body));
+    }
+    (materials, three)
+}
+
+pub struct ObjExporter;
+
+impl Exporter for ObjExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let extension = if args.compress { ".gz" } else { "" };
+        let obj_path = format!("{}.obj{}", output_stem, extension);
+        let mtl_path = format!("{}.mtl{}", output_stem, extension);
+        let mut outputs = vec![obj_path.clone()];
+        let prefix = name_prefix(args, output_stem);
+        let file_prefix = if args.prefix_textures { &prefix } else { "" };
+
+        let (mut materials, three_materials) = if args.no_mtl {
+            (Vec::new(), Vec::new())
+        } else {
+            let written_to = args.shared_mtl.as_deref().unwrap_or(&mtl_path);
+            materials(args, model, textures, &prefix, file_prefix, written_to)
+        };
+        // by the names given before --mtl-overrides could change the blocks
+        let three_materials: Vec<(String, ThreeMaterial)> = materials
+            .iter()
+            .map(|(name, _)| name.clone())
+            .zip(three_materials)
+            .collect();
+        if let Some(path) = &args.mtl_overrides {
+            apply_overrides(path, &mut materials, &prefix)?;
+        }
+        let mut shared = args.shared_mtl.as_deref().map(SharedMtl::load);
+        let renames = match &mut shared {
+            Some(shared) => shared.add(&materials, &expand_prefix("{name}_", output_stem)),
+            None => HashMap::new(),
+        };
+        let material_name = |material: &str| {
+            let name = format!("{}{}", prefix, material);
+            renames.get(&name).cloned().unwrap_or(name)
+        };
+
+        let mut obj_body = Vec::new();
+        let mut n_verticies_written = 0;
+        let mut uvs = UvList::default();
+        if args.dedupe_uvs {
+            uvs.seen = Some(HashMap::new());
+        } else if args.uv_mode == UvMode::Quad {
+            uvs.quad(None);
+        }
+        let write_usemtl = !args.no_mtl || args.keep_usemtl;
+        // faces of every piece by material, in the order materials are first used
+        let mut groups: Vec<(String, Vec<u8>)> = Vec::new();
+        let embed_transforms = args.embed_transforms && args.group_by == GroupBy::Piece;
+        if args.embed_transforms && !embed_transforms {
+            warning!("--embed-transforms needs an object per piece, writing the pieces in place with --group-by material.");
+        }
+        let pieces = model.all_pieces();
+        let parents = model.parents();
+        for (piece, parent) in pieces.iter().zip(&parents) {
+            writeln!(obj_body)?;
+            // each piece around its own pivot, and where that is from its
+            // parent's pivot
+            let origin = if embed_transforms {
+                let parent = parent.map(|parent| pieces[parent]);
+                let parent_origin = parent.map_or([0.0; 3], |parent| parent.origin);
+                let offset = [0, 1, 2].map(|i| piece.origin[i] - parent_origin[i]);
+                write!(obj_body, "# transform:")?;
+                if let Some(parent) = parent {
+                    write!(obj_body, " parent={}{}", prefix, parent.name)?;
+                }
+                writeln!(
+                    obj_body,
+                    " offset={} {} {}",
+                    offset[0], offset[1], offset[2]
+                )?;
+                piece.origin
+            } else {
+                [0.0; 3]
+            };
+            match args.group_by {
+                GroupBy::Piece => writeln!(obj_body, "o {}{}", prefix, piece.name)?,
+                GroupBy::Material => writeln!(obj_body, "# {}{}", prefix, piece.name)?,
+            }
+            for v in &piece.vertexes {
+                let [x, y, z] = [0, 1, 2].map(|i| v[i] - origin[i]);
+                writeln!(obj_body, "v {} {} {}", x, y, z)?;
+            }
+
+            let first_vertex = n_verticies_written + 1;
+            for face in &piece.faces {
+                let material = exporter::material_name(args, &face.material, textures);
+                match args.group_by {
+                    GroupBy::Piece => {
+                        if write_usemtl {
+                            writeln!(obj_body, "usemtl {}", material_name(&material))?;
+                        }
+                        write_face(args, &mut obj_body, face, first_vertex, &mut uvs)?;
+                    }
+                    GroupBy::Material => {
+                        let group = match groups.iter().position(|(m, _)| *m == material) {
+                            Some(group) => group,
+                            None => {
+                                groups.push((material, Vec::new()));
+                                groups.len() - 1
+                            }
+                        };
+                        write_face(args, &mut groups[group].1, face, first_vertex, &mut uvs)?;
+                    }
+                }
+            }
+            n_verticies_written += piece.vertexes.len() as u32;
+        }
+
+        for (material, faces) in groups {
+            writeln!(obj_body)?;
+            let material = material_name(&material);
+            writeln!(obj_body, "g {}", material)?;
+            if write_usemtl {
+                writeln!(obj_body, "usemtl {}", material)?;
+            }
+            obj_body.extend(faces);
+        }
+
+        if let (Some(shape), false) = (args.collision, args.collision_file) {
+            write_collision(
+                model,
+                shape,
+                &prefix,
+                &mut obj_body,
+                n_verticies_written + 1,
+            )?;
+        }
+
+        {
+            let mut obj_text = Vec::new();
+
+            if !args.no_mtl {
+                let mtllib = match (&args.shared_mtl, args.mtllib_target) {
+                    (Some(shared_mtl), _) => shared_mtl.clone(),
+                    (None, MtllibTarget::Compressed) => mtl_path.clone(),
+                    (None, MtllibTarget::Uncompressed) => output_stem.to_owned() + ".mtl",
+                };
+                writeln!(obj_text, "mtllib {}", relative_path(&mtllib, &obj_path))?;
+            }
+            if !uvs.uvs.is_empty() {
+                writeln!(obj_text)?;
+            }
+            for (uv, texture) in &uvs.uvs {
+                let texture = texture.as_ref().and_then(|t| textures.get(t));
+                let [u, v] = canvas_uv(texture, *uv);
+                writeln!(obj_text, "vt {} {}", u, v)?;
+            }
+            obj_text.extend(obj_body);
+
+            if let Some(max) = args.max_line_length {
+                let max = max as usize;
+                if args.long_faces == LongFaces::Wrap {
+                    obj_text = wrap_long_lines(&obj_text, max);
+                }
+                let long_lines = obj_text.split(|&b| b == b'\n').filter(|l| l.len() > max);
+                let long_lines = long_lines.count();
+                if long_lines > 0 {
+                    warning!(
+                        "{} lines of {} are still longer than {} bytes.",
+                        long_lines,
+                        obj_path,
+                        max
+                    );
+                }
+            }
+
+            let mut obj_writter = pending.create_writer(&obj_path, args.compress)?;
+            obj_writter.write_all(&obj_text)?;
+            obj_writter.finish()?;
+        }
+
+        if args.three_json {
+            let three_materials = three_materials
+                .into_iter()
+                .map(|(name, material)| (renames.get(&name).cloned().unwrap_or(name), material))
+                .collect();
+            outputs.push(write_three_json(
+                model,
+                three_materials,
+                &prefix,
+                &obj_path,
+                embed_transforms,
+                output_stem,
+                pending,
+            )?);
+        }
+
+        if args.no_mtl {
+            return Ok(outputs);
+        }
+
+        match shared {
+            Some(shared) => outputs.push(shared.save(pending)?),
+            None => {
+                let mut mtl_writter = pending.create_writer(&mtl_path, args.compress)?;
+                write_materials(&mut mtl_writter, &materials)?;
+                mtl_writter.finish()?;
+                outputs.push(mtl_path);
+            }
+        }
+
+        Ok(outputs)
+    }
+}
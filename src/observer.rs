@@ -0,0 +1,58 @@
+use serde::Serialize;
+
+/// A field nobody knows the meaning of that held something other than its
+/// usual value.
+#[derive(Serialize, Clone)]
+pub struct UnknownValue {
+    pub file: String,
+    pub offset: usize,
+    pub field: String,
+    pub value: u32,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Something that happened during a run.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Message {
+        severity: Severity,
+        text: &'a str,
+    },
+    Unknown(&'a UnknownValue),
+    PieceRead {
+        name: &'a str,
+        vertexes: usize,
+        faces: usize,
+    },
+    TextureFound {
+        name: &'a str,
+        gaf: &'a str,
+    },
+    TextureMissing {
+        name: &'a str,
+    },
+    FileWritten {
+        path: &'a str,
+    },
+    /// an output already there from an earlier run, left as it is
+    FileKept {
+        path: &'a str,
+    },
+}
+
+/// Receives every event of a run, in order. The command line's observers are
+/// called with its log locked, so they mustn't log anything themselves.
+pub trait Observer: Send {
+    fn event(&mut self, event: &Event);
+
+    /// Called once when the run ends, however it ends.
+    fn finish(&mut self) {}
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+use ta_3do_to_obj::{read_3do, write_3do, Piece, RawPiece};
+
+use crate::geometry::is_degenerate;
+use crate::model::{Face, Material};
+use crate::output::write_file;
+
+/// What optimizing took out.
+#[derive(Default)]
+struct Removed {
+    duplicate_vertexes: usize,
+    unused_vertexes: usize,
+    degenerate_primitives: usize,
+}
+
+/// Whether a primitive of three or more corners has fewer than three
+/// distinct ones or next to no area, judged as conversions judge faces.
+fn is_degenerate_primitive(vertex_indexes: &[u16], vertexes: &[[i32; 3]]) -> bool {
+    if vertex_indexes.len() < 3 {
+        return false;
+    }
+    let points: Vec<[f64; 3]> = vertexes.iter().map(|v| v.map(f64::from)).collect();
+    let face = Face {
+        material: Material::Colour(0),
+        texture: None,
+        vertex_indexes: vertex_indexes.to_vec(),
+        uvs: Vec::new(),
+    };
+    is_degenerate(&face, &points)
+}
+
+fn optimize_piece(raw: &mut RawPiece, removed: &mut Removed) {
+    // a piece without primitives is a marker, whose vertexes are all it has
+    if raw.primitives.is_empty() {
+        return;
+    }
+
+    // one vertex for each place
+    let mut first_at: HashMap<[i32; 3], u16> = HashMap::new();
+    let mut merged: Vec<u16> = Vec::with_capacity(raw.vertexes.len());
+    for (i, vertex) in raw.vertexes.iter().enumerate() {
+        merged.push(*first_at.entry(*vertex).or_insert(i as u16));
+    }
+    removed.duplicate_vertexes += raw.vertexes.len() - first_at.len();
+    for primitive in &mut raw.primitives {
+        for index in &mut primitive.vertex_indexes {
+            if let Some(&first) = merged.get(*index as usize) {
+                *index = first;
+            }
+        }
+    }
+
+    // the selection primitive, an index despite the field's usual name,
+    // stays whatever it looks like
+    let selection = raw.selection_primitive as usize;
+    let has_selection = selection < raw.primitives.len();
+    let mut kept = Vec::with_capacity(raw.primitives.len());
+    let mut new_selection = u32::MAX;
+    for (i, primitive) in raw.primitives.drain(..).enumerate() {
+        if i != selection && is_degenerate_primitive(&primitive.vertex_indexes, &raw.vertexes) {
+            removed.degenerate_primitives += 1;
+            continue;
+        }
+        if i == selection {
+            new_selection = kept.len() as u32;
+        }
+        kept.push(primitive);
+    }
+    raw.primitives = kept;
+    if has_selection {
+        raw.selection_primitive = new_selection;
+    }
+
+    // only the vertexes something still uses, in their order
+    let mut used = vec![false; raw.vertexes.len()];
+    for primitive in &raw.primitives {
+        for &index in &primitive.vertex_indexes {
+            if let Some(used) = used.get_mut(index as usize) {
+                *used = true;
+            }
+        }
+    }
+    let mut renumbered = vec![0u16; raw.vertexes.len()];
+    let mut vertexes = Vec::new();
+    for (i, vertex) in raw.vertexes.iter().enumerate() {
+        if used[i] {
+            renumbered[i] = vertexes.len() as u16;
+            vertexes.push(*vertex);
+        }
+    }
+    removed.unused_vertexes += first_at.len() - vertexes.len();
+    for primitive in &mut raw.primitives {
+        for index in &mut primitive.vertex_indexes {
+            if let Some(&new) = renumbered.get(*index as usize) {
+                *index = new;
+            }
+        }
+    }
+    raw.vertexes = vertexes;
+}
+
+fn optimize_pieces(pieces: &mut [Piece], removed: &mut Removed) {
+    for piece in pieces {
+        optimize_piece(&mut piece.raw, removed);
+        optimize_pieces(&mut piece.children, removed);
+    }
+}
+
+/// Writes the 3do at `input` to `output` with identical vertexes of a piece
+/// merged, degenerate primitives dropped and vertexes nothing uses any more
+/// removed, laid out again with nothing between the records. Pieces without
+/// primitives and selection primitives are left as they are.
+pub fn optimize_3do(input: &str, output: &str) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| format!("unable to read {}: {}", input, err))?;
+    let mut model = read_3do(&data).map_err(|err| format!("{}: {}", input, err))?;
+
+    let mut removed = Removed::default();
+    optimize_pieces(&mut model.pieces, &mut removed);
+
+    let optimized = write_3do(&model);
+    write_file(output, |file| file.write_all(&optimized))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+    println!(
+        "Wrote {}: merged {} duplicate vertexes, removed {} unused vertexes and {} degenerate primitives, {} bytes down to {}",
+        output,
+        removed.duplicate_vertexes,
+        removed.unused_vertexes,
+        removed.degenerate_primitives,
+        data.len(),
+        optimized.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use bmp::Image;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::log;
+
+fn temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+/// Output files that are written under a temporary name and only moved into
+/// place once the whole conversion has worked. Anything not committed is
+/// removed when this is dropped, including while unwinding from a panic.
+#[derive(Default)]
+pub struct PendingFiles {
+    paths: Vec<String>,
+}
+
+impl PendingFiles {
+    pub fn create(&mut self, path: &str) -> io::Result<File> {
+        let file = File::create(temp_path(path))?;
+        self.paths.push(path.to_string());
+        Ok(file)
+    }
+
+    /// Creates `path` for writing text, through gzip when `compress` is set.
+    /// The caller picks the name, `.gz` isn't added here.
+    pub fn create_writer(&mut self, path: &str, compress: bool) -> io::Result<OutputWriter> {
+        let file = BufWriter::new(self.create(path)?);
+        Ok(if compress {
+            OutputWriter::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            OutputWriter::Plain(file)
+        })
+    }
+
+    /// Moves every file into place. The ones not moved yet when a rename
+    /// fails are still removed on drop.
+    pub fn commit(mut self) -> io::Result<()> {
+        while let Some(path) = self.paths.first().cloned() {
+            fs::rename(temp_path(&path), &path)?;
+            self.paths.remove(0);
+            log::emit(log::Event::FileWritten { path: &path });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PendingFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(temp_path(path));
+        }
+    }
+}
+
+/// A text output, written as is or gzip compressed.
+pub enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl OutputWriter {
+    /// Flushes the file, writing the gzip trailer first when compressed.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut writer) => writer.flush(),
+            OutputWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Saves an image next to `path` and renames it over, so a killed run never
+/// leaves half a bmp behind.
+pub fn save_image(image: &Image, path: &str) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = image.save(&temp).and_then(|_| fs::rename(&temp, path));
+    match &result {
+        Ok(()) => log::emit(log::Event::FileWritten { path }),
+        Err(_) => {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+    result
+}
+
+/// Writes a file under a temporary name and renames it over `path`.
+pub fn write_file(path: &str, write: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    let temp = temp_path(path);
+    let result = File::create(&temp)
+        .and_then(|mut file| write(&mut file))
+        .and_then(|_| fs::rename(&temp, path));
+    match &result {
+        Ok(()) => log::emit(log::Event::FileWritten { path }),
+        Err(_) => {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+    result
+}
+
+/// `path` from the working folder, with `.` and `..` worked out.
+fn absolute(path: &str) -> PathBuf {
+    let mut absolute = PathBuf::new();
+    for component in env::current_dir()
+        .unwrap_or_default()
+        .join(path)
+        .components()
+    {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    absolute
+}
+
+/// `path` the way a file at `from` has to name it, both given from the
+/// working folder, since importers look for what a file names next to that
+/// file rather than where it was written from. Folders are separated by `/`.
+pub fn relative_path(path: &str, from: &str) -> String {
+    let target = absolute(path);
+    let folder = absolute(from);
+    let folder = folder.parent().unwrap_or(Path::new(""));
+    let shared = target
+        .components()
+        .zip(folder.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    // on another drive there's no way there but the whole path
+    if shared == 0 {
+        return target.to_string_lossy().into_owned();
+    }
+    let ups = folder.components().skip(shared).map(|_| "..".to_string());
+    let downs = target
+        .components()
+        .skip(shared)
+        .map(|component| component.as_os_str().to_string_lossy().into_owned());
+    ups.chain(downs).collect::<Vec<_>>().join("/")
+}
+
+/// `relative_path`, starting with `./` when it stays in the folder, the way
+/// mtl and usd files have named their textures.
+pub fn dot_relative_path(path: &str, from: &str) -> String {
+    let relative = relative_path(path, from);
+    if relative.starts_with("../") || Path::new(&relative).is_absolute() {
+        relative
+    } else {
+        format!("./{}", relative)
+    }
+}
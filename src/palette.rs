@@ -1,7 +1,146 @@
-pub fn colour_name(colour: [u8; 3]) -> String {
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+/// Palette indices the engine replaces with the owning player's colour.
+const TEAM_COLOUR_RANGE: RangeInclusive<usize> = 10..=15;
+
+pub type Rgb = [u8; 3];
+
+/// The colour's hex value, shared by every index with that colour.
+pub fn colour_name(colour: Rgb) -> String {
     format!("{:02X?}{:02X?}{:02X?}", colour[0], colour[1], colour[2]).to_string()
 }
 
+/// The TA palette, along with the indices the engine draws differently.
+pub struct Palette {
+    fullbright_range: RangeInclusive<usize>,
+}
+
+/// Fullbright indices are 240-255 unless --fullbright-range says otherwise.
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new(240..=255)
+    }
+}
+
+impl Palette {
+    pub fn new(fullbright_range: RangeInclusive<usize>) -> Palette {
+        Palette { fullbright_range }
+    }
+
+    pub fn get(&self, index: usize) -> Rgb {
+        PALETTE[index]
+    }
+
+    /// A name for the colour at `index` that no other index has, its hex
+    /// value followed by the index.
+    pub fn name(&self, index: usize) -> String {
+        format!("{}_{}", colour_name(self.get(index)), index)
+    }
+
+    /// Indices the engine replaces with the owning player's colour.
+    pub fn team_colour_range(&self) -> RangeInclusive<usize> {
+        TEAM_COLOUR_RANGE
+    }
+
+    /// Indices drawn self-illuminated.
+    pub fn fullbright_range(&self) -> RangeInclusive<usize> {
+        self.fullbright_range.clone()
+    }
+
+    pub fn is_fullbright(&self, index: usize) -> bool {
+        self.fullbright_range.contains(&index)
+    }
+}
+
+/// Converts a palette colour to 0-1 factors, optionally undoing the sRGB
+/// transfer function for consumers that expect linear values.
+pub fn colour_factors(colour: [u8; 3], linear: bool) -> [f32; 3] {
+    colour.map(|c| {
+        let c = c as f32 / 255.0;
+        if !linear {
+            c
+        } else if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+/// Parses an inclusive range of palette indices written as `start-end`.
+pub fn parse_palette_range(range: &str) -> Result<RangeInclusive<usize>, String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format!("expected a range like 240-255, got {}", range))?;
+    let start: usize = start
+        .trim()
+        .parse()
+        .map_err(|_| format!("bad index {}", start))?;
+    let end: usize = end
+        .trim()
+        .parse()
+        .map_err(|_| format!("bad index {}", end))?;
+
+    if start > end || end >= PALETTE.len() {
+        return Err(format!("{} is not a range within the palette", range));
+    }
+
+    Ok(start..=end)
+}
+
+/// Writes the palette as a 16x16 png, one pixel per index, and as a GIMP
+/// palette named with the same strings the mtl uses.
+pub fn export_palette(stem: &str, palette: &Palette, rgb_names: bool) -> io::Result<()> {
+    {
+        let file = File::create(stem.to_owned() + ".png")?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), 16, 16);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data: Vec<u8> = PALETTE.iter().flatten().copied().collect();
+        let mut png_writter = encoder.write_header().map_err(io::Error::other)?;
+        png_writter
+            .write_image_data(&data)
+            .map_err(io::Error::other)?;
+    }
+
+    let file = File::create(stem.to_owned() + ".gpl")?;
+    let mut gpl_writter = BufWriter::new(file);
+
+    writeln!(gpl_writter, "GIMP Palette")?;
+    writeln!(gpl_writter, "Name: Total Annihilation")?;
+    writeln!(gpl_writter, "Columns: 16")?;
+    writeln!(gpl_writter, "#")?;
+    writeln!(
+        gpl_writter,
+        "# team colour indices: {}-{}",
+        palette.team_colour_range().start(),
+        palette.team_colour_range().end()
+    )?;
+    writeln!(
+        gpl_writter,
+        "# fullbright indices: {}-{}",
+        palette.fullbright_range().start(),
+        palette.fullbright_range().end()
+    )?;
+
+    for (index, colour) in PALETTE.into_iter().enumerate() {
+        let name = if rgb_names {
+            colour_name(colour)
+        } else {
+            palette.name(index)
+        };
+        writeln!(
+            gpl_writter,
+            "{:3} {:3} {:3}\t{}",
+            colour[0], colour[1], colour[2], name
+        )?;
+    }
+    gpl_writter.flush()
+}
+
 pub const PALETTE: [[u8; 3]; 256] = [
     [0x00, 0x00, 0x00],
     [0x80, 0x00, 0x00],
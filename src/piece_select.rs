@@ -0,0 +1,193 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::model::{Material, Model, Piece};
+
+/// Indexes, in `Model::all_pieces` order, of the pieces called `name`,
+/// ignoring case.
+fn find_name(model: &Model, name: &str) -> Vec<usize> {
+    model
+        .all_pieces()
+        .iter()
+        .enumerate()
+        .filter(|(_, piece)| piece.name.eq_ignore_ascii_case(name))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Indexes of the pieces named by `--piece`, failing on a name no piece has.
+pub fn named_pieces(model: &Model, names: &[String]) -> Result<Vec<usize>, String> {
+    let mut chosen = Vec::new();
+    for name in names {
+        let found = find_name(model, name);
+        if found.is_empty() {
+            let all: Vec<&str> = model
+                .all_pieces()
+                .iter()
+                .map(|piece| piece.name.as_str())
+                .collect();
+            return Err(format!(
+                "no piece is named {}, the pieces are {}",
+                name,
+                all.join(", ")
+            ));
+        }
+        chosen.extend(found);
+    }
+    Ok(chosen)
+}
+
+/// Prints every piece indented under its parent, numbered from 1 in
+/// `Model::all_pieces` order, with its vertex count.
+fn print_tree(model: &Model) {
+    fn walk(piece: &Piece, depth: usize, number: &mut usize, width: usize) {
+        *number += 1;
+        eprintln!(
+            "{:>width$} {}{} ({} vertexes)",
+            number,
+            "  ".repeat(depth),
+            piece.name,
+            piece.vertexes.len()
+        );
+        for child in &piece.children {
+            walk(child, depth + 1, number, width);
+        }
+    }
+
+    let width = model.all_pieces().len().to_string().len();
+    let mut number = 0;
+    for piece in &model.pieces {
+        walk(piece, 0, &mut number, width);
+    }
+}
+
+/// Indexes of the pieces in a typed selection: a number from the tree, a
+/// piece name, or several of either separated by commas.
+fn parse_selection(model: &Model, line: &str) -> Result<Vec<usize>, String> {
+    let count = model.all_pieces().len();
+    let mut chosen = Vec::new();
+    for entry in line.split(',').map(str::trim) {
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.parse::<usize>() {
+            Ok(number) if (1..=count).contains(&number) => chosen.push(number - 1),
+            Ok(number) => return Err(format!("there is no piece {}, pick 1 to {}", number, count)),
+            Err(_) => {
+                let found = find_name(model, entry);
+                if found.is_empty() {
+                    return Err(format!("no piece is named {}", entry));
+                }
+                chosen.extend(found);
+            }
+        }
+    }
+    if chosen.is_empty() {
+        return Err("nothing was picked".to_string());
+    }
+    Ok(chosen)
+}
+
+/// Shows the piece tree and asks which pieces to export until it gets an
+/// answer it understands. Fails when stdin isn't a terminal, so scripts
+/// don't wait on it forever.
+pub fn ask_for_pieces(model: &Model) -> Result<Vec<usize>, String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(
+            "picking pieces needs a terminal to ask on, name them with --piece NAME instead."
+                .to_string(),
+        );
+    }
+
+    print_tree(model);
+    loop {
+        eprint!("Pieces to export (a number, a name, or several separated by commas): ");
+        io::stderr().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return Err("no pieces were picked.".to_string());
+        }
+        match parse_selection(model, &line) {
+            Ok(chosen) => return Ok(chosen),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+/// Keeps only the chosen pieces and everything under them, each chosen piece
+/// becoming a root where it was. Colours and textures only the dropped pieces
+/// used are forgotten.
+pub fn keep_subtrees(model: &mut Model, chosen: &[usize]) {
+    fn walk(piece: Piece, number: &mut usize, chosen: &[usize], kept: &mut Vec<Piece>) {
+        let index = *number;
+        *number += 1;
+        if chosen.contains(&index) {
+            // the children come along, numbered as if they'd been visited
+            *number += count(&piece.children);
+            kept.push(piece);
+            return;
+        }
+        for child in piece.children {
+            walk(child, number, chosen, kept);
+        }
+    }
+
+    let mut kept = Vec::new();
+    let mut number = 0;
+    for piece in std::mem::take(&mut model.pieces) {
+        walk(piece, &mut number, chosen, &mut kept);
+    }
+    model.pieces = kept;
+    forget_unused_materials(model);
+}
+
+/// Removes the chosen pieces and everything under them. Colours and textures
+/// only they used are forgotten.
+pub fn drop_subtrees(model: &mut Model, chosen: &[usize]) {
+    fn walk(mut piece: Piece, number: &mut usize, chosen: &[usize]) -> Option<Piece> {
+        let index = *number;
+        *number += 1;
+        let children = std::mem::take(&mut piece.children);
+        if chosen.contains(&index) {
+            *number += count(&children);
+            return None;
+        }
+        piece.children = children
+            .into_iter()
+            .filter_map(|child| walk(child, number, chosen))
+            .collect();
+        Some(piece)
+    }
+
+    let mut number = 0;
+    model.pieces = std::mem::take(&mut model.pieces)
+        .into_iter()
+        .filter_map(|piece| walk(piece, &mut number, chosen))
+        .collect();
+    forget_unused_materials(model);
+}
+
+fn count(pieces: &[Piece]) -> usize {
+    pieces.iter().map(|piece| 1 + count(&piece.children)).sum()
+}
+
+/// Drops the colours and textures no face uses any more, so they aren't
+/// written or extracted.
+pub fn forget_unused_materials(model: &mut Model) {
+    let mut colours = Vec::new();
+    let mut textures = Vec::new();
+    for piece in model.all_pieces() {
+        for face in &piece.faces {
+            match &face.material {
+                Material::Colour(index) => colours.push(*index),
+                Material::Texture(name) => textures.push(name.clone()),
+            }
+            textures.extend(face.texture.clone());
+        }
+    }
+    model.used_colours.retain(|index| colours.contains(index));
+    model.used_textures.retain(|name| textures.contains(name));
+    model
+        .texture_files
+        .retain(|name, _| textures.contains(name));
+}
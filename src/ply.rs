@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::Exporter;
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model};
+use crate::output::PendingFiles;
+use crate::preview::{average_colour, MISSING_TEXTURE_COLOUR};
+use crate::Args;
+use ta_3do_to_obj::palette::PALETTE;
+
+/// Writes an ascii ply of the whole model in place, with the colour of each
+/// face baked into its vertexes, so it opens in MeshLab without a material
+/// file. Textured faces get their texture's average colour. A vertex shared
+/// by faces of different colours is written once per colour.
+pub struct PlyExporter;
+
+impl Exporter for PlyExporter {
+    fn export(
+        &self,
+        _args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let ply_path = output_stem.to_owned() + ".ply";
+
+        let mut texture_colours = HashMap::new();
+        // by piece, vertex index and colour
+        let mut vertex_indexes: HashMap<(usize, u16, [u8; 3]), usize> = HashMap::new();
+        let mut vertexes: Vec<([f64; 3], [u8; 3])> = Vec::new();
+        let mut faces: Vec<Vec<usize>> = Vec::new();
+        for (piece_index, piece) in model.all_pieces().into_iter().enumerate() {
+            for face in &piece.faces {
+                let colour = match &face.material {
+                    Material::Colour(index) => PALETTE[*index],
+                    Material::Texture(name) => {
+                        *texture_colours.entry(name.clone()).or_insert_with(|| {
+                            textures
+                                .get(name)
+                                .and_then(average_colour)
+                                .unwrap_or(MISSING_TEXTURE_COLOUR)
+                        })
+                    }
+                };
+
+                let corners = face
+                    .vertex_indexes
+                    .iter()
+                    .map(|&v| {
+                        *vertex_indexes
+                            .entry((piece_index, v, colour))
+                            .or_insert_with(|| {
+                                vertexes.push((piece.vertexes[v as usize], colour));
+                                vertexes.len() - 1
+                            })
+                    })
+                    .collect();
+                faces.push(corners);
+            }
+        }
+
+        let file = pending.create(&ply_path)?;
+        let mut ply_writter = BufWriter::new(file);
+        writeln!(ply_writter, "ply")?;
+        writeln!(ply_writter, "format ascii 1.0")?;
+        writeln!(
+            ply_writter,
+            "comment written by ta-3do-to-obj {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(ply_writter, "element vertex {}", vertexes.len())?;
+        for axis in ["x", "y", "z"] {
+            writeln!(ply_writter, "property float {}", axis)?;
+        }
+        for channel in ["red", "green", "blue"] {
+            writeln!(ply_writter, "property uchar {}", channel)?;
+        }
+        writeln!(ply_writter, "element face {}", faces.len())?;
+        writeln!(ply_writter, "property list uchar int vertex_indices")?;
+        writeln!(ply_writter, "end_header")?;
+
+        for ([x, y, z], [r, g, b]) in &vertexes {
+            writeln!(ply_writter, "{} {} {} {} {} {}", x, y, z, r, g, b)?;
+        }
+        for corners in &faces {
+            write!(ply_writter, "{}", corners.len())?;
+            for corner in corners {
+                write!(ply_writter, " {}", corner)?;
+            }
+            writeln!(ply_writter)?;
+        }
+        ply_writter.flush()?;
+
+        Ok(vec![ply_path])
+    }
+}
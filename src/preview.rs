@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model};
+use crate::output::{write_file, PendingFiles};
+use ta_3do_to_obj::palette::PALETTE;
+
+/// Turn around the vertical axis and tilt towards the camera of the
+/// three-quarter view, in degrees.
+const PREVIEW_TURN: f64 = 45.0;
+const PREVIEW_TILT: f64 = 30.0;
+
+/// Share of the image left empty around the model.
+const PREVIEW_MARGIN: f64 = 0.05;
+
+/// Colour used for textures that weren't extracted.
+pub const MISSING_TEXTURE_COLOUR: [u8; 3] = [128, 128, 128];
+
+/// A triangle in view space, x right, y up and z towards the camera.
+struct Triangle {
+    corners: [[f64; 3]; 3],
+    colour: [u8; 3],
+}
+
+/// Average colour of an extracted texture, ignoring any padding.
+pub fn average_colour(texture: &ExtractedTexture) -> Option<[u8; 3]> {
+    let image = bmp::open(texture.path()).ok()?;
+    let (width, height) = (
+        texture.width.min(image.get_width()),
+        texture.height.min(image.get_height()),
+    );
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut sum = [0u64; 3];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x, y);
+            sum[0] += pixel.r as u64;
+            sum[1] += pixel.g as u64;
+            sum[2] += pixel.b as u64;
+        }
+    }
+    let count = (width * height) as u64;
+    Some(sum.map(|channel| (channel / count) as u8))
+}
+
+fn to_view(point: [f64; 3]) -> [f64; 3] {
+    let (turn_sin, turn_cos) = PREVIEW_TURN.to_radians().sin_cos();
+    let (tilt_sin, tilt_cos) = PREVIEW_TILT.to_radians().sin_cos();
+
+    let x = point[0] * turn_cos + point[2] * turn_sin;
+    let z = -point[0] * turn_sin + point[2] * turn_cos;
+    let y = point[1] * tilt_cos - z * tilt_sin;
+    let z = point[1] * tilt_sin + z * tilt_cos;
+    [x, y, z]
+}
+
+fn shade(triangle: &Triangle) -> [u8; 4] {
+    let [a, b, c] = triangle.corners;
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let normal = [
+        u[1] * v[2] - u[2] * v[1],
+        u[2] * v[0] - u[0] * v[2],
+        u[0] * v[1] - u[1] * v[0],
+    ];
+    let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+
+    // lit from over the viewer's left shoulder, both sides of a face alike
+    let light: [f64; 3] = [-0.4, 0.6, 0.7];
+    let light_length = (light[0] * light[0] + light[1] * light[1] + light[2] * light[2]).sqrt();
+    let facing = if length > 0.0 {
+        (normal[0] * light[0] + normal[1] * light[1] + normal[2] * light[2]).abs()
+            / (length * light_length)
+    } else {
+        0.0
+    };
+
+    let brightness = 0.35 + 0.65 * facing;
+    let [r, g, b] = triangle.colour;
+    [
+        (r as f64 * brightness) as u8,
+        (g as f64 * brightness) as u8,
+        (b as f64 * brightness) as u8,
+        255,
+    ]
+}
+
+/// Fills a triangle already in pixel coordinates.
+fn fill(pixels: &mut [[u8; 4]], size: u32, corners: [[f64; 2]; 3], colour: [u8; 4]) {
+    let [a, b, c] = corners;
+    let edge = |p: [f64; 2], q: [f64; 2], x: f64, y: f64| {
+        (q[0] - p[0]) * (y - p[1]) - (q[1] - p[1]) * (x - p[0])
+    };
+    let area = edge(a, b, c[0], c[1]);
+    if area == 0.0 {
+        return;
+    }
+
+    let min_x = a[0].min(b[0]).min(c[0]).floor().max(0.0) as u32;
+    let max_x = a[0].max(b[0]).max(c[0]).ceil().min(size as f64 - 1.0) as u32;
+    let min_y = a[1].min(b[1]).min(c[1]).floor().max(0.0) as u32;
+    let max_y = a[1].max(b[1]).max(c[1]).ceil().min(size as f64 - 1.0) as u32;
+
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+            let weights = [
+                edge(b, c, px, py) / area,
+                edge(c, a, px, py) / area,
+                edge(a, b, px, py) / area,
+            ];
+            if weights.iter().all(|&w| w >= 0.0) {
+                pixels[(y * size + x) as usize] = colour;
+            }
+        }
+    }
+}
+
+/// Renders the model from a fixed three-quarter view with flat shading and
+/// one colour per face, back to front, into a square `size` image.
+fn render(model: &Model, textures: &HashMap<String, ExtractedTexture>, size: u32) -> Vec<[u8; 4]> {
+    let mut texture_colours = HashMap::new();
+    let mut triangles = Vec::new();
+    for piece in model.all_pieces() {
+        for face in &piece.faces {
+            let colour = match &face.material {
+                Material::Colour(index) => PALETTE[*index],
+                Material::Texture(name) => {
+                    *texture_colours.entry(name.clone()).or_insert_with(|| {
+                        textures
+                            .get(name)
+                            .and_then(average_colour)
+                            .unwrap_or(MISSING_TEXTURE_COLOUR)
+                    })
+                }
+            };
+
+            let corners: Vec<[f64; 3]> = face
+                .vertex_indexes
+                .iter()
+                .filter_map(|&i| piece.vertexes.get(i as usize))
+                .map(|&point| to_view(point))
+                .collect();
+            for i in 2..corners.len() {
+                triangles.push(Triangle {
+                    corners: [corners[0], corners[i - 1], corners[i]],
+                    colour,
+                });
+            }
+        }
+    }
+
+    let mut pixels = vec![[0; 4]; (size * size) as usize];
+    if triangles.is_empty() {
+        return pixels;
+    }
+
+    let points = triangles.iter().flat_map(|t| t.corners.iter());
+    let (mut min, mut max) = ([f64::MAX; 2], [f64::MIN; 2]);
+    for point in points {
+        for axis in 0..2 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+    let extent = (max[0] - min[0]).max(max[1] - min[1]).max(f64::EPSILON);
+    let scale = size as f64 * (1.0 - 2.0 * PREVIEW_MARGIN) / extent;
+    let centre = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0];
+    let to_pixel = |point: [f64; 3]| {
+        [
+            size as f64 / 2.0 + (point[0] - centre[0]) * scale,
+            size as f64 / 2.0 - (point[1] - centre[1]) * scale,
+        ]
+    };
+
+    // painter's algorithm, the furthest triangles are drawn first
+    let depth = |t: &Triangle| t.corners.iter().map(|c| c[2]).sum::<f64>();
+    triangles.sort_by(|a, b| depth(a).total_cmp(&depth(b)));
+    for triangle in &triangles {
+        let corners = triangle.corners.map(to_pixel);
+        fill(&mut pixels, size, corners, shade(triangle));
+    }
+
+    pixels
+}
+
+/// Writes a `size` by `size` png preview of the model to `path`, with a
+/// transparent background.
+pub fn write_preview(
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    size: u32,
+    path: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<()> {
+    let pixels = render(model, textures, size);
+
+    let file = pending.create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), size, size);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(io::Error::other)
+}
+
+/// The rgba pixels of a preview written by `write_preview`, if it's `size`
+/// by `size`.
+fn read_preview(path: &str, size: u32) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = png::Decoder::new(file)
+        .read_info()
+        .map_err(|err| err.to_string())?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut data)
+        .map_err(|err| err.to_string())?;
+    if info.color_type != png::ColorType::Rgba || info.width != size || info.height != size {
+        return Err(format!("it isn't a {} by {} preview", size, size));
+    }
+    data.truncate(info.buffer_size());
+    Ok(data)
+}
+
+/// Writes the `size` by `size` previews at `previews` side by side to a png
+/// at `path`, in rows as long as the sheet is tall. Previews that can't be
+/// read are left out with a warning.
+pub fn write_contact_sheet(previews: &[String], size: u32, path: &str) -> io::Result<()> {
+    let images: Vec<Vec<u8>> = previews
+        .iter()
+        .filter_map(|preview| {
+            read_preview(preview, size)
+                .map_err(|err| warning!("{} left off the contact sheet: {}", preview, err))
+                .ok()
+        })
+        .collect();
+    let columns = (images.len() as f64).sqrt().ceil().max(1.0) as usize;
+    let rows = images.len().div_ceil(columns).max(1);
+    let (cell, width) = (size as usize, columns * size as usize);
+
+    let mut data = vec![0; width * rows * cell * 4];
+    for (i, image) in images.iter().enumerate() {
+        let (x, y) = (i % columns * cell, i / columns * cell);
+        for (row, pixels) in image.chunks(cell * 4).enumerate() {
+            let start = ((y + row) * width + x) * 4;
+            data[start..start + pixels.len()].copy_from_slice(pixels);
+        }
+    }
+
+    write_file(path, |file| {
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), width as u32, (rows * cell) as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(io::Error::other)
+    })
+}
@@ -0,0 +1,356 @@
+/// Size of an object record.
+pub const OBJECT_SIZE: usize = 52;
+/// Size of a primitive record.
+pub const PRIMITIVE_SIZE: usize = 32;
+/// Size of a vertex, three fixed point coordinates.
+pub const VERTEX_SIZE: usize = 12;
+/// Size of a vertex index.
+pub const INDEX_SIZE: usize = 2;
+
+/// `version_signature` of every object in a Total Annihilation 3do.
+pub const TA_VERSION_SIGNATURE: u32 = 1;
+
+/// Default limits on the counts a 3do gives for each piece, far above
+/// anything in the game's own models.
+pub const DEFAULT_MAX_VERTEXES: u32 = 65536;
+pub const DEFAULT_MAX_PRIMITIVES: u32 = 10000;
+pub const DEFAULT_MAX_FACE_INDEXES: u32 = 64;
+pub const DEFAULT_MAX_DEPTH: u32 = 64;
+pub const DEFAULT_MAX_OBJECTS: u32 = 4096;
+
+/// An object record, the header of a piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ObjectRecord {
+    pub version_signature: u32,
+    pub number_of_vertexes: u32,
+    pub number_of_primitives: u32,
+    pub offset_to_selection_primitive: u32,
+    pub x_from_parent: i32,
+    pub y_from_parent: i32,
+    pub z_from_parent: i32,
+    pub offset_to_object_name: u32,
+    pub always_0: u32,
+    pub offset_to_vertex_array: u32,
+    pub offset_to_primitive_array: u32,
+    pub offset_to_sibling_object: u32,
+    pub offset_to_child_object: u32,
+}
+
+impl ObjectRecord {
+    /// Where `always_0` is in the record.
+    pub const ALWAYS_0: usize = 32;
+}
+
+/// A primitive record, one face of a piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PrimitiveRecord {
+    pub color_index: u32,
+    pub number_of_vertex_indexes: u32,
+    pub always_0: u32,
+    pub offset_to_vertex_index_array: u32,
+    pub offset_to_texture_name: u32,
+    pub unknown_1: u32,
+    pub unknown_2: u32,
+    pub is_colored: u32,
+}
+
+impl PrimitiveRecord {
+    /// Where `always_0` is in the record.
+    pub const ALWAYS_0: usize = 8;
+    /// Where `unknown_1` is in the record.
+    pub const UNKNOWN_1: usize = 20;
+    /// Where `unknown_2` is in the record.
+    pub const UNKNOWN_2: usize = 24;
+}
+
+/// Fails unless `count` records of `size` bytes starting at `offset` are in
+/// `data`, so a bad offset is an error instead of a panic.
+pub fn check_in_file(
+    data: &[u8],
+    offset: u32,
+    count: u32,
+    size: usize,
+    what: &str,
+) -> Result<(), String> {
+    let end = offset as usize + count as usize * size;
+    if end > data.len() {
+        return Err(format!(
+            "{} at {:#x} running past the end of the file",
+            what, offset
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects a count read from the file that is over `max`, before anything is
+/// allocated for it.
+pub fn check_count(count: u32, max: u32, what: &str) -> Result<(), String> {
+    if count > max {
+        return Err(format!("{} {}, over the limit of {}", count, what, max));
+    }
+    Ok(())
+}
+
+/// Adds an offset read from the file onto a position, rejecting sums that
+/// don't fit rather than wrapping around.
+pub fn add_offset(position: i32, offset: i32, what: &str) -> Result<i32, String> {
+    position
+        .checked_add(offset)
+        .ok_or_else(|| format!("{} {} + {} out of range", what, position, offset))
+}
+
+/// Rejects a piece `depth` levels down the tree that's the `objects`th read,
+/// when either is over its limit, so overlapping offsets can't make a tree
+/// without end.
+pub fn check_tree(
+    name: &str,
+    object_offset: u32,
+    depth: u32,
+    objects: u32,
+    max_depth: u32,
+    max_objects: u32,
+) -> Result<(), String> {
+    if depth > max_depth {
+        return Err(format!(
+            "piece {} at {:#x} is {} levels deep, over the limit of {} (--max-depth)",
+            name, object_offset, depth, max_depth
+        ));
+    }
+    if objects > max_objects {
+        return Err(format!(
+            "piece {} at {:#x} is piece number {}, over the limit of {} (--max-objects)",
+            name, object_offset, objects, max_objects
+        ));
+    }
+    Ok(())
+}
+
+/// The little endian u32 at `offset`, which has to be in `data`.
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the object record at `offset`.
+pub fn read_object(data: &[u8], offset: u32) -> Result<ObjectRecord, String> {
+    check_in_file(data, offset, 1, OBJECT_SIZE, "an object")?;
+    let field = |index: usize| u32_at(data, offset as usize + index * 4);
+    Ok(ObjectRecord {
+        version_signature: field(0),
+        number_of_vertexes: field(1),
+        number_of_primitives: field(2),
+        offset_to_selection_primitive: field(3),
+        x_from_parent: field(4) as i32,
+        y_from_parent: field(5) as i32,
+        z_from_parent: field(6) as i32,
+        offset_to_object_name: field(7),
+        always_0: field(8),
+        offset_to_vertex_array: field(9),
+        offset_to_primitive_array: field(10),
+        offset_to_sibling_object: field(11),
+        offset_to_child_object: field(12),
+    })
+}
+
+/// Reads the primitive record at `offset`.
+pub fn read_primitive(data: &[u8], offset: u32) -> Result<PrimitiveRecord, String> {
+    check_in_file(data, offset, 1, PRIMITIVE_SIZE, "a primitive")?;
+    let field = |index: usize| u32_at(data, offset as usize + index * 4);
+    Ok(PrimitiveRecord {
+        color_index: field(0),
+        number_of_vertex_indexes: field(1),
+        always_0: field(2),
+        offset_to_vertex_index_array: field(3),
+        offset_to_texture_name: field(4),
+        unknown_1: field(5),
+        unknown_2: field(6),
+        is_colored: field(7),
+    })
+}
+
+/// The vertexes of `object`, in file units relative to its pivot.
+pub fn read_vertexes(data: &[u8], object: &ObjectRecord) -> Result<Vec<[i32; 3]>, String> {
+    let start = object.offset_to_vertex_array;
+    check_in_file(
+        data,
+        start,
+        object.number_of_vertexes,
+        VERTEX_SIZE,
+        "vertexes",
+    )?;
+    Ok((0..object.number_of_vertexes as usize)
+        .map(|i| {
+            let vertex = start as usize + i * VERTEX_SIZE;
+            [0, 4, 8].map(|at| u32_at(data, vertex + at) as i32)
+        })
+        .collect())
+}
+
+/// The primitive records of `object`.
+pub fn read_primitives(data: &[u8], object: &ObjectRecord) -> Result<Vec<PrimitiveRecord>, String> {
+    let start = object.offset_to_primitive_array;
+    check_in_file(
+        data,
+        start,
+        object.number_of_primitives,
+        PRIMITIVE_SIZE,
+        "primitives",
+    )?;
+    (0..object.number_of_primitives)
+        .map(|i| read_primitive(data, start + i * PRIMITIVE_SIZE as u32))
+        .collect()
+}
+
+/// The vertex indexes of `primitive`, its corners.
+pub fn read_vertex_indexes(data: &[u8], primitive: &PrimitiveRecord) -> Result<Vec<u16>, String> {
+    let start = primitive.offset_to_vertex_index_array;
+    check_in_file(
+        data,
+        start,
+        primitive.number_of_vertex_indexes,
+        INDEX_SIZE,
+        "corners",
+    )?;
+    let start = start as usize;
+    Ok(
+        data[start..start + primitive.number_of_vertex_indexes as usize * INDEX_SIZE]
+            .chunks_exact(INDEX_SIZE)
+            .map(|index| u16::from_le_bytes([index[0], index[1]]))
+            .collect(),
+    )
+}
+
+/// The bytes from `offset` up to the next nul, without it, looking no
+/// further than `max_len` bytes.
+pub fn read_cstring(data: &[u8], offset: u32, max_len: usize) -> Result<&[u8], String> {
+    let start = (offset as usize).min(data.len());
+    let end = start.saturating_add(max_len).min(data.len());
+    match data[start..end].iter().position(|&c| c == 0) {
+        Some(length) => Ok(&data[start..start + length]),
+        None => Err(format!(
+            "no string terminator within {} bytes of offset {:#x}",
+            end - start,
+            offset
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A piece at 0 with a name, two vertexes and a primitive after it.
+    fn fixture() -> Vec<u8> {
+        let mut data = vec![
+            0x01, 0x00, 0x00, 0x00, // version_signature
+            0x02, 0x00, 0x00, 0x00, // number_of_vertexes
+            0x01, 0x00, 0x00, 0x00, // number_of_primitives
+            0xff, 0xff, 0xff, 0xff, // offset_to_selection_primitive
+            0x00, 0x00, 0x01, 0x00, // x_from_parent
+            0x00, 0x00, 0xff, 0xff, // y_from_parent
+            0x03, 0x00, 0x00, 0x00, // z_from_parent
+            0x34, 0x00, 0x00, 0x00, // offset_to_object_name
+            0x07, 0x00, 0x00, 0x00, // always_0
+            0x39, 0x00, 0x00, 0x00, // offset_to_vertex_array
+            0x51, 0x00, 0x00, 0x00, // offset_to_primitive_array
+            0x44, 0x33, 0x22, 0x11, // offset_to_sibling_object
+            0x88, 0x77, 0x66, 0x55, // offset_to_child_object
+        ];
+        data.extend(b"base\0");
+        data.extend([
+            0x01, 0x00, 0x00, 0x00, // vertex 0 x
+            0x02, 0x00, 0x00, 0x00, // y
+            0x03, 0x00, 0x00, 0x00, // z
+            0xff, 0xff, 0xff, 0xff, // vertex 1 x
+            0x00, 0x00, 0x01, 0x00, // y
+            0x00, 0x00, 0x00, 0x80, // z
+        ]);
+        data.extend([
+            0x09, 0x00, 0x00, 0x00, // color_index
+            0x02, 0x00, 0x00, 0x00, // number_of_vertex_indexes
+            0x0a, 0x00, 0x00, 0x00, // always_0
+            0x71, 0x00, 0x00, 0x00, // offset_to_vertex_index_array
+            0x34, 0x00, 0x00, 0x00, // offset_to_texture_name
+            0x0b, 0x00, 0x00, 0x00, // unknown_1
+            0x0c, 0x00, 0x00, 0x00, // unknown_2
+            0x01, 0x00, 0x00, 0x00, // is_colored
+        ]);
+        data.extend([0x01, 0x00, 0x00, 0x01]);
+        data
+    }
+
+    #[test]
+    fn reads_an_object() {
+        let object = read_object(&fixture(), 0).unwrap();
+        assert_eq!(
+            object,
+            ObjectRecord {
+                version_signature: TA_VERSION_SIGNATURE,
+                number_of_vertexes: 2,
+                number_of_primitives: 1,
+                offset_to_selection_primitive: u32::MAX,
+                x_from_parent: 65536,
+                y_from_parent: -65536,
+                z_from_parent: 3,
+                offset_to_object_name: 0x34,
+                always_0: 7,
+                offset_to_vertex_array: 0x39,
+                offset_to_primitive_array: 0x51,
+                offset_to_sibling_object: 0x11223344,
+                offset_to_child_object: 0x55667788,
+            }
+        );
+        assert_eq!(fixture()[ObjectRecord::ALWAYS_0], 7);
+    }
+
+    #[test]
+    fn reads_a_primitive() {
+        let data = fixture();
+        let object = read_object(&data, 0).unwrap();
+        let primitives = read_primitives(&data, &object).unwrap();
+        assert_eq!(
+            primitives,
+            [PrimitiveRecord {
+                color_index: 9,
+                number_of_vertex_indexes: 2,
+                always_0: 10,
+                offset_to_vertex_index_array: 0x71,
+                offset_to_texture_name: 0x34,
+                unknown_1: 11,
+                unknown_2: 12,
+                is_colored: 1,
+            }]
+        );
+        let primitive = 0x51;
+        assert_eq!(data[primitive + PrimitiveRecord::ALWAYS_0], 10);
+        assert_eq!(data[primitive + PrimitiveRecord::UNKNOWN_1], 11);
+        assert_eq!(data[primitive + PrimitiveRecord::UNKNOWN_2], 12);
+        assert_eq!(
+            read_vertex_indexes(&data, &primitives[0]).unwrap(),
+            [1, 256]
+        );
+        assert_eq!(read_cstring(&data, 0x34, 256).unwrap(), b"base");
+    }
+
+    #[test]
+    fn reads_vertexes() {
+        let data = fixture();
+        let object = read_object(&data, 0).unwrap();
+        assert_eq!(
+            read_vertexes(&data, &object).unwrap(),
+            [[1, 2, 3], [-1, 65536, i32::MIN]]
+        );
+    }
+
+    #[test]
+    fn refuses_records_past_the_end() {
+        let data = fixture();
+        assert!(read_object(&data, data.len() as u32 - OBJECT_SIZE as u32 + 1).is_err());
+        assert!(read_primitive(&data, 0x56).is_err());
+        let object = ObjectRecord {
+            number_of_vertexes: 6,
+            ..read_object(&data, 0).unwrap()
+        };
+        assert!(read_vertexes(&data, &object).is_err());
+        assert!(read_cstring(&data, 0x34, 4).is_err());
+    }
+}
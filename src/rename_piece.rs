@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+
+use ta_3do_to_obj::{all_names, read_3do, write_3do, Piece};
+
+use crate::output::write_file;
+
+/// Renames the pieces of `pieces` and everything under them, counting the
+/// pieces each rename changed.
+fn rename_pieces(pieces: &mut [Piece], renames: &[(String, String)], counts: &mut [usize]) {
+    for piece in pieces {
+        // scripts find pieces ignoring case
+        let old = piece.name();
+        if let Some(i) = renames
+            .iter()
+            .position(|(wanted, _)| wanted.eq_ignore_ascii_case(&old))
+        {
+            piece.raw.name = Some(renames[i].1.as_bytes().to_vec());
+            counts[i] += 1;
+        }
+        rename_pieces(&mut piece.children, renames, counts);
+    }
+}
+
+/// Writes the 3do at `input` to `output` with the pieces named by `renames`,
+/// each pair an old name and its new one, renamed. The names are laid out
+/// again, so longer ones fit. A rename that matches no piece fails, and names
+/// the renamed model has more than once are warned about.
+pub fn rename_piece_3do(
+    input: &str,
+    output: &str,
+    renames: &[(String, String)],
+) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| format!("unable to read {}: {}", input, err))?;
+    let mut model = read_3do(&data).map_err(|err| format!("{}: {}", input, err))?;
+
+    let mut counts = vec![0; renames.len()];
+    rename_pieces(&mut model.pieces, renames, &mut counts);
+    let missing: Vec<&str> = renames
+        .iter()
+        .zip(&counts)
+        .filter(|(_, count)| **count == 0)
+        .map(|((old, _), _)| old.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} has no piece named {}",
+            input,
+            missing.join(", ")
+        ));
+    }
+
+    let mut names = Vec::new();
+    all_names(&model.pieces, &mut names);
+    let mut seen = HashSet::new();
+    let mut repeated: Vec<String> = names
+        .into_iter()
+        .filter(|name| !seen.insert(name.to_ascii_lowercase()))
+        .collect();
+    repeated.sort();
+    repeated.dedup();
+    if !repeated.is_empty() {
+        warning!(
+            "the renamed model has more than one piece named {}, scripts can only find one",
+            repeated.join(", ")
+        );
+    }
+
+    let data = write_3do(&model);
+    write_file(output, |file| file.write_all(&data))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+    println!(
+        "Wrote {}: renamed {} pieces",
+        output,
+        counts.iter().sum::<usize>()
+    );
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io::Write;
+
+use ta_3do_to_obj::{read_3do, write_3do, Piece};
+
+use crate::output::write_file;
+
+/// Parses a --map value written `old=new`.
+pub fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err(format!("{} isn't written old=new", s)),
+    }
+}
+
+/// Renames the textures of `pieces` and everything under them, counting
+/// the primitives each rename changed.
+fn rename_textures(pieces: &mut [Piece], renames: &[(String, String)], counts: &mut [usize]) {
+    for piece in pieces {
+        for primitive in &mut piece.raw.primitives {
+            let Some(name) = &primitive.texture_name else {
+                continue;
+            };
+            // gaf names are matched ignoring case by the game
+            let name = String::from_utf8_lossy(name);
+            if let Some(i) = renames
+                .iter()
+                .position(|(old, _)| old.eq_ignore_ascii_case(&name))
+            {
+                primitive.texture_name = Some(renames[i].1.as_bytes().to_vec());
+                counts[i] += 1;
+            }
+        }
+        rename_textures(&mut piece.children, renames, counts);
+    }
+}
+
+/// Writes the 3do at `input` to `output` with the textures its primitives
+/// name renamed by `renames`, each pair an old name and its new one, laid
+/// out again so names longer than the old ones fit.
+pub fn retexture_3do(
+    input: &str,
+    output: &str,
+    renames: &[(String, String)],
+) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| format!("unable to read {}: {}", input, err))?;
+    let mut model = read_3do(&data).map_err(|err| format!("{}: {}", input, err))?;
+
+    let mut counts = vec![0; renames.len()];
+    rename_textures(&mut model.pieces, renames, &mut counts);
+    for ((old, _), count) in renames.iter().zip(&counts) {
+        if *count == 0 {
+            warning!("no primitive of {} uses {}", input, old);
+        }
+    }
+
+    let data = write_3do(&model);
+    write_file(output, |file| file.write_all(&data))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+    println!(
+        "Wrote {}: renamed the texture of {} primitives",
+        output,
+        counts.iter().sum::<usize>()
+    );
+    Ok(())
+}
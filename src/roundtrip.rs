@@ -0,0 +1,115 @@
+use crate::log;
+use crate::model::{read_model, Piece, RawPiece};
+use crate::texture_map::TextureMap;
+use crate::Args;
+
+/// The raw records of `pieces` and everything under them.
+fn raw_tree(pieces: &[Piece]) -> Vec<ta_3do_to_obj::Piece> {
+    pieces
+        .iter()
+        .map(|piece| ta_3do_to_obj::Piece {
+            raw: piece.raw.clone(),
+            children: raw_tree(&piece.children),
+        })
+        .collect()
+}
+
+/// Lays the pieces out as a 3do again, from their raw records.
+pub fn write_3do(pieces: &[Piece]) -> Vec<u8> {
+    ta_3do_to_obj::write_3do(&ta_3do_to_obj::Model {
+        pieces: raw_tree(pieces),
+    })
+}
+
+fn compare_raw(path: &str, original: &RawPiece, copy: &RawPiece, problems: &mut Vec<String>) {
+    let mut differs = |field: &str, original: String, copy: String| {
+        if original != copy {
+            problems.push(format!(
+                "{}: {} was {} but came back as {}",
+                path, field, original, copy
+            ));
+        }
+    };
+    differs(
+        "version_signature",
+        format!("{:#x}", original.version_signature),
+        format!("{:#x}", copy.version_signature),
+    );
+    differs(
+        "name",
+        format!("{:?}", original.name),
+        format!("{:?}", copy.name),
+    );
+    differs(
+        "position",
+        format!("{:?}", original.from_parent),
+        format!("{:?}", copy.from_parent),
+    );
+    differs(
+        "selection primitive",
+        original.selection_primitive.to_string(),
+        copy.selection_primitive.to_string(),
+    );
+    differs(
+        "always_0",
+        format!("{:#x}", original.always_0),
+        format!("{:#x}", copy.always_0),
+    );
+    differs(
+        "vertexes",
+        format!("{:?}", original.vertexes),
+        format!("{:?}", copy.vertexes),
+    );
+    differs(
+        "primitive count",
+        original.primitives.len().to_string(),
+        copy.primitives.len().to_string(),
+    );
+
+    for (i, (original, copy)) in original.primitives.iter().zip(&copy.primitives).enumerate() {
+        if original != copy {
+            problems.push(format!(
+                "{}: primitive {} was {:?} but came back as {:?}",
+                path, i, original, copy
+            ));
+        }
+    }
+}
+
+fn compare_pieces(path: &str, original: &[Piece], copy: &[Piece], problems: &mut Vec<String>) {
+    if original.len() != copy.len() {
+        problems.push(format!(
+            "{}: had {} pieces but came back with {}",
+            path,
+            original.len(),
+            copy.len()
+        ));
+    }
+    for (original, copy) in original.iter().zip(copy) {
+        let path = format!("{}/{}", path, original.name);
+        compare_raw(&path, &original.raw, &copy.raw, problems);
+        compare_pieces(&path, &original.children, &copy.children, problems);
+    }
+}
+
+/// Writes the pieces read from a 3do back out as one and reads that again,
+/// returning every field that didn't survive the trip.
+pub fn lossless_check(args: &Args, pieces: &[Piece]) -> Vec<String> {
+    let data = write_3do(pieces);
+
+    // the copy has its own offsets, and anything odd about the original
+    // was already reported
+    let args = Args {
+        root_offset: Vec::new(),
+        show_unknowns: false,
+        ..args.clone()
+    };
+    let copy = log::quietly(|| read_model(&args, &TextureMap::default(), &data));
+
+    let mut problems = Vec::new();
+    match copy {
+        Ok(copy) => compare_pieces("", pieces, &copy.pieces, &mut problems),
+        Err(err) => problems.push(format!("the copy doesn't parse: {}", err)),
+    }
+    problems
+}
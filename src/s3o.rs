@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::exporter::{material_name, Exporter};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::geometry::{length, polygon_normal, sub};
+use crate::model::{Face, Material, Model, Piece, RawPiece, RawPrimitive};
+use crate::output::PendingFiles;
+use crate::preview::MISSING_TEXTURE_COLOUR;
+use crate::textures::encode_rgba_png;
+use crate::to3do::to_raw;
+use crate::{Args, UvMode};
+use ta_3do_to_obj::palette::Palette;
+use ta_3do_to_obj::push_u32;
+use ta_3do_to_obj::records::TA_VERSION_SIGNATURE;
+
+const S3O_MAGIC: &[u8; 12] = b"Spring unit\0";
+
+/// Bytes in the header, written last at the start of the file.
+const HEADER_SIZE: usize = 52;
+
+/// Side of the square of atlas pixels each colour gets.
+const SWATCH_SIZE: u32 = 4;
+
+/// Where a surface sits in the atlas, in pixels from the top left.
+#[derive(Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// The pixels of one surface before it's packed.
+struct Tile {
+    width: u32,
+    height: u32,
+    /// colour and team colour alpha, for the first texture
+    tex1: Vec<[u8; 4]>,
+    /// self-illumination, reflectivity and transparency, for the second
+    tex2: Vec<[u8; 4]>,
+}
+
+impl Tile {
+    fn swatch(tex1: [u8; 4], tex2: [u8; 4]) -> Tile {
+        let pixels = (SWATCH_SIZE * SWATCH_SIZE) as usize;
+        Tile {
+            width: SWATCH_SIZE,
+            height: SWATCH_SIZE,
+            tex1: vec![tex1; pixels],
+            tex2: vec![tex2; pixels],
+        }
+    }
+}
+
+/// s3o models have a single pair of textures, so every texture and colour
+/// of the model is packed into one.
+struct Atlas {
+    width: u32,
+    height: u32,
+    tex1: Vec<[u8; 4]>,
+    tex2: Vec<[u8; 4]>,
+    /// by the material name faces use
+    rects: HashMap<String, Rect>,
+}
+
+/// The texture's pixels, without any padding, or `None` when it wasn't
+/// extracted or can't be read.
+fn texture_tile(texture: &ExtractedTexture) -> Option<Tile> {
+    let image = bmp::open(texture.path()).ok()?;
+    let (width, height) = (
+        texture.width.min(image.get_width()),
+        texture.height.min(image.get_height()),
+    );
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut tile = Tile {
+        width,
+        height,
+        tex1: Vec::new(),
+        tex2: Vec::new(),
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x, y);
+            let rgb = [pixel.r, pixel.g, pixel.b];
+            tile.tex1.push([rgb[0], rgb[1], rgb[2], 0]);
+            // the colour key is see-through, as in the game
+            let opacity = if texture.colour_key == Some(rgb) {
+                0
+            } else {
+                255
+            };
+            tile.tex2.push([0, 0, 0, opacity]);
+        }
+    }
+    Some(tile)
+}
+
+/// Packs the surfaces of every face into rows, tallest first, in an atlas
+/// whose sides are powers of two.
+fn build_atlas(args: &Args, model: &Model, textures: &HashMap<String, ExtractedTexture>) -> Atlas {
+    let palette = Palette::new(args.fullbright_range.clone());
+    let mut names = Vec::new();
+    let mut tiles = Vec::new();
+    for piece in model.all_pieces() {
+        for face in &piece.faces {
+            let name = material_name(args, &face.material, textures);
+            if names.contains(&name) {
+                continue;
+            }
+            let tile = match &face.material {
+                Material::Colour(index) => {
+                    let [r, g, b] = palette.get(*index);
+                    // the engine paints opaque team colour alpha in the
+                    // player's colour
+                    let team = palette.team_colour_range().contains(index);
+                    let glow = palette.is_fullbright(*index);
+                    Tile::swatch(
+                        [r, g, b, if team { 255 } else { 0 }],
+                        [if glow { 255 } else { 0 }, 0, 0, 255],
+                    )
+                }
+                Material::Texture(texture) => match textures.get(&name).and_then(texture_tile) {
+                    Some(tile) => tile,
+                    None => {
+                        warning!(
+                            "texture {} wasn't extracted, it's grey in the s3o's texture",
+                            texture
+                        );
+                        let [r, g, b] = MISSING_TEXTURE_COLOUR;
+                        Tile::swatch([r, g, b, 0], [0, 0, 0, 255])
+                    }
+                },
+            };
+            names.push(name);
+            tiles.push(tile);
+        }
+    }
+
+    let area: u32 = tiles.iter().map(|tile| tile.width * tile.height).sum();
+    let widest = tiles.iter().map(|tile| tile.width).max().unwrap_or(1);
+    let width = widest
+        .max((area as f64).sqrt().ceil() as u32)
+        .next_power_of_two();
+
+    let mut order: Vec<usize> = (0..tiles.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(tiles[i].height));
+    let mut rects = vec![
+        Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0
+        };
+        tiles.len()
+    ];
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for i in order {
+        let tile = &tiles[i];
+        if x + tile.width > width {
+            (x, y, row_height) = (0, y + row_height, 0);
+        }
+        rects[i] = Rect {
+            x,
+            y,
+            width: tile.width,
+            height: tile.height,
+        };
+        x += tile.width;
+        row_height = row_height.max(tile.height);
+    }
+    let height = (y + row_height).max(1).next_power_of_two();
+
+    let mut atlas = Atlas {
+        width,
+        height,
+        tex1: vec![[0, 0, 0, 0]; (width * height) as usize],
+        tex2: vec![[0, 0, 0, 255]; (width * height) as usize],
+        rects: HashMap::new(),
+    };
+    for ((name, tile), rect) in names.into_iter().zip(&tiles).zip(rects) {
+        for row in 0..tile.height {
+            let from = (row * tile.width) as usize..((row + 1) * tile.width) as usize;
+            let to = ((rect.y + row) * width + rect.x) as usize;
+            atlas.tex1[to..to + tile.width as usize].copy_from_slice(&tile.tex1[from.clone()]);
+            atlas.tex2[to..to + tile.width as usize].copy_from_slice(&tile.tex2[from]);
+        }
+        atlas.rects.insert(name, rect);
+    }
+    atlas
+}
+
+/// A face corner's uv in the atlas, upwards from the bottom left as s3o
+/// has them.
+fn atlas_uv(atlas: &Atlas, rect: Rect, uv: Option<[f64; 2]>) -> [f32; 2] {
+    // colours, and faces without uvs, use the middle of their rect
+    let [u, v] = uv.unwrap_or([0.5, 0.5]);
+    let x = rect.x as f64 + u * rect.width as f64;
+    let y = rect.y as f64 + (1.0 - v) * rect.height as f64;
+    [
+        (x / atlas.width as f64) as f32,
+        (1.0 - y / atlas.height as f64) as f32,
+    ]
+}
+
+fn push_f32s(data: &mut Vec<u8>, values: &[f64]) {
+    for value in values {
+        data.extend_from_slice(&(*value as f32).to_le_bytes());
+    }
+}
+
+/// What every piece of the s3o is written with.
+struct Writer<'a> {
+    args: &'a Args,
+    textures: &'a HashMap<String, ExtractedTexture>,
+    atlas: Atlas,
+    data: Vec<u8>,
+}
+
+/// A vertex of a piece, around its origin.
+struct S3oVertex {
+    position: [f64; 3],
+    normal: [f64; 3],
+    uv: [f32; 2],
+}
+
+/// Appends a piece record after its name, vertexes, indexes and the
+/// offsets of its already written children, and returns where it is.
+fn write_record(
+    data: &mut Vec<u8>,
+    name: &str,
+    (vertexes, indexes): (&[S3oVertex], &[u32]),
+    children: &[u32],
+    from_parent: [f64; 3],
+) -> u32 {
+    let name_offset = data.len();
+    data.extend_from_slice(name.as_bytes());
+    data.push(0);
+    let vertexes_offset = data.len();
+    for vertex in vertexes {
+        push_f32s(data, &vertex.position);
+        push_f32s(data, &vertex.normal);
+        push_f32s(data, &vertex.uv.map(f64::from));
+    }
+    let indexes_offset = data.len();
+    for &index in indexes {
+        push_u32(data, index);
+    }
+    let children_offset = data.len();
+    for &child in children {
+        push_u32(data, child);
+    }
+
+    let at = data.len();
+    push_u32(data, name_offset as u32);
+    push_u32(data, children.len() as u32);
+    push_u32(data, children_offset as u32);
+    push_u32(data, vertexes.len() as u32);
+    push_u32(data, vertexes_offset as u32);
+    // vertex type, unused
+    push_u32(data, 0);
+    // triangles
+    push_u32(data, 0);
+    push_u32(data, indexes.len() as u32);
+    push_u32(data, indexes_offset as u32);
+    // collision data, unused
+    push_u32(data, 0);
+    push_f32s(data, &from_parent);
+    at as u32
+}
+
+/// Appends `piece`'s children and then `piece`, and returns where its
+/// record is. Faces are flat shaded triangles, each corner its own vertex,
+/// around the piece's origin.
+fn write_piece(writer: &mut Writer, piece: &Piece, parent_origin: [f64; 3]) -> u32 {
+    let mut vertexes = Vec::new();
+    let mut indexes = Vec::new();
+    for face in &piece.faces {
+        let corners: Vec<[f64; 3]> = face
+            .vertex_indexes
+            .iter()
+            .filter_map(|&i| piece.vertexes.get(i as usize).copied())
+            .collect();
+        if corners.len() < 3 {
+            continue;
+        }
+        let normal = polygon_normal(&corners);
+        let normal_length = length(normal);
+        let normal = if normal_length > 0.0 {
+            normal.map(|n| n / normal_length)
+        } else {
+            [0.0, 1.0, 0.0]
+        };
+        let name = material_name(writer.args, &face.material, writer.textures);
+        let rect = writer.atlas.rects[&name];
+
+        let first = vertexes.len() as u32;
+        for (corner, position) in corners.iter().enumerate() {
+            let uv = match face.material {
+                Material::Texture(_) => face.uvs.get(corner).copied(),
+                Material::Colour(_) => None,
+            };
+            vertexes.push(S3oVertex {
+                position: sub(*position, piece.origin),
+                normal,
+                uv: atlas_uv(&writer.atlas, rect, uv),
+            });
+        }
+        for i in 2..corners.len() as u32 {
+            indexes.extend([first, first + i - 1, first + i]);
+        }
+    }
+
+    let children: Vec<u32> = piece
+        .children
+        .iter()
+        .map(|child| write_piece(writer, child, piece.origin))
+        .collect();
+    write_record(
+        &mut writer.data,
+        &piece.name,
+        (&vertexes, &indexes),
+        &children,
+        sub(piece.origin, parent_origin),
+    )
+}
+
+/// Writes a Spring s3o with a piece per 3do piece, keeping their names and
+/// offsets from their parents so unit scripts still find them, and the
+/// model's textures and colours packed into `<name>_tex1.png` and
+/// `<name>_tex2.png` beside it.
+pub struct S3oExporter;
+
+impl Exporter for S3oExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let s3o_path = output_stem.to_owned() + ".s3o";
+        let tex1_path = output_stem.to_owned() + "_tex1.png";
+        let tex2_path = output_stem.to_owned() + "_tex2.png";
+        let file_name = |path: &str| {
+            Path::new(path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for piece in model.all_pieces() {
+            for v in piece.vertexes.iter().chain([&piece.origin]) {
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(v[axis]);
+                    max[axis] = max[axis].max(v[axis]);
+                }
+            }
+        }
+        if min[0] > max[0] {
+            (min, max) = ([0.0; 3], [0.0; 3]);
+        }
+        let middle = [0, 1, 2].map(|axis| (min[axis] + max[axis]) / 2.0);
+        let radius = model
+            .all_pieces()
+            .iter()
+            .flat_map(|piece| &piece.vertexes)
+            .map(|&v| length(sub(v, middle)))
+            .fold(0.0, f64::max);
+
+        let atlas = build_atlas(args, model, textures);
+        let mut writer = Writer {
+            args,
+            textures,
+            atlas,
+            data: Vec::new(),
+        };
+        writer.data.resize(HEADER_SIZE, 0);
+        let root_offset = match &model.pieces[..] {
+            [root] => write_piece(&mut writer, root, [0.0; 3]),
+            // s3o has a single root piece, so siblings at the top go under
+            // an empty one at the origin
+            pieces => {
+                let children: Vec<u32> = pieces
+                    .iter()
+                    .map(|piece| write_piece(&mut writer, piece, [0.0; 3]))
+                    .collect();
+                write_record(&mut writer.data, "root", (&[], &[]), &children, [0.0; 3])
+            }
+        };
+
+        let mut texture_offsets = Vec::new();
+        for path in [&tex1_path, &tex2_path] {
+            texture_offsets.push(writer.data.len() as u32);
+            writer.data.extend_from_slice(file_name(path).as_bytes());
+            writer.data.push(0);
+        }
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(S3O_MAGIC);
+        // version
+        push_u32(&mut header, 0);
+        push_f32s(&mut header, &[radius, max[1]]);
+        push_f32s(&mut header, &middle);
+        push_u32(&mut header, root_offset);
+        // collision data, unused
+        push_u32(&mut header, 0);
+        push_u32(&mut header, texture_offsets[0]);
+        push_u32(&mut header, texture_offsets[1]);
+        writer.data[..HEADER_SIZE].copy_from_slice(&header);
+
+        let mut s3o_file = pending.create(&s3o_path)?;
+        s3o_file.write_all(&writer.data)?;
+        let atlas = &writer.atlas;
+        for (path, pixels) in [(&tex1_path, &atlas.tex1), (&tex2_path, &atlas.tex2)] {
+            let file = pending.create(path)?;
+            encode_rgba_png(atlas.width, atlas.height, pixels, BufWriter::new(file))?;
+        }
+
+        Ok(vec![s3o_path, tex1_path, tex2_path])
+    }
+}
+
+/// Bytes in a vertex: position, normal and uv.
+const VERTEX_SIZE: usize = 32;
+
+/// Kinds of primitive a piece's indexes can make.
+const TRIANGLES: u32 = 0;
+const TRIANGLE_STRIPS: u32 = 1;
+const QUADS: u32 = 2;
+
+/// Strips are split at this index.
+const STRIP_RESTART: u32 = u32::MAX;
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| format!("{:#x} is past the end of the file", offset))
+}
+
+fn read_f32(data: &[u8], offset: usize) -> Result<f64, String> {
+    read_u32(data, offset).map(|bits| f32::from_bits(bits) as f64)
+}
+
+fn read_string(data: &[u8], offset: usize) -> Result<String, String> {
+    let rest = data
+        .get(offset..)
+        .ok_or_else(|| format!("string at {:#x} is past the end of the file", offset))?;
+    let length = rest
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or_else(|| format!("string at {:#x} has no terminator", offset))?;
+    Ok(String::from_utf8_lossy(&rest[..length]).into_owned())
+}
+
+/// The corners of each face the indexes make, triangles, strips of them or
+/// quads.
+fn primitive_faces(kind: u32, indexes: &[u32]) -> Result<Vec<Vec<u32>>, String> {
+    Ok(match kind {
+        TRIANGLES => indexes.chunks_exact(3).map(<[u32]>::to_vec).collect(),
+        QUADS => indexes.chunks_exact(4).map(<[u32]>::to_vec).collect(),
+        TRIANGLE_STRIPS => indexes
+            .split(|&index| index == STRIP_RESTART)
+            .flat_map(|strip| {
+                // every other triangle of a strip is wound the other way
+                (2..strip.len()).map(move |i| match i % 2 {
+                    0 => vec![strip[i - 2], strip[i - 1], strip[i]],
+                    _ => vec![strip[i - 1], strip[i - 2], strip[i]],
+                })
+            })
+            .collect(),
+        kind => return Err(format!("unknown primitive type {}", kind)),
+    })
+}
+
+/// Reads the piece at `offset` and everything under it, moving its vertexes
+/// into place from `parent_origin`.
+fn read_piece(
+    data: &[u8],
+    offset: usize,
+    parent_origin: [f64; 3],
+    texture: &str,
+    keep_uvs: bool,
+    depth: usize,
+) -> Result<Piece, String> {
+    if depth > data.len() / HEADER_SIZE {
+        return Err("the piece tree loops back on itself".to_string());
+    }
+    let field = |index: usize| read_u32(data, offset + index * 4).map(|value| value as usize);
+    let name = read_string(data, field(0)?)?;
+    let from_parent = [
+        read_f32(data, offset + 40)?,
+        read_f32(data, offset + 44)?,
+        read_f32(data, offset + 48)?,
+    ];
+    let origin = [0, 1, 2].map(|axis| parent_origin[axis] + from_parent[axis]);
+
+    // vertexes at the same place are shared, their uvs going with the faces
+    let (vertex_count, vertexes_offset) = (field(3)?, field(4)?);
+    let mut vertexes = Vec::new();
+    let mut places: HashMap<[u64; 3], u16> = HashMap::new();
+    let mut corners = Vec::with_capacity(vertex_count);
+    for i in 0..vertex_count {
+        let at = vertexes_offset + i * VERTEX_SIZE;
+        let mut values = [0.0; 8];
+        for (j, value) in values.iter_mut().enumerate() {
+            *value = read_f32(data, at + j * 4)?;
+        }
+        let position = [0, 1, 2].map(|axis| origin[axis] + values[axis]);
+        let next = vertexes.len();
+        let index = *places
+            .entry(position.map(f64::to_bits))
+            .or_insert(next as u16);
+        if index as usize == next {
+            if next > u16::MAX as usize {
+                return Err(format!("piece {} has more than {} vertexes", name, next));
+            }
+            vertexes.push(position);
+        }
+        corners.push((index, [values[6], values[7]]));
+    }
+
+    let (kind, index_count, indexes_offset) = (field(6)? as u32, field(7)?, field(8)?);
+    let indexes = (0..index_count)
+        .map(|i| read_u32(data, indexes_offset + i * 4))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut faces = Vec::new();
+    let mut primitives = Vec::new();
+    for face in primitive_faces(kind, &indexes)? {
+        let Some(face_corners) = face
+            .iter()
+            .map(|&i| corners.get(i as usize))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Err(format!(
+                "a face of piece {} uses a vertex it doesn't have",
+                name
+            ));
+        };
+        let vertex_indexes: Vec<u16> = face_corners.iter().map(|(index, _)| *index).collect();
+        primitives.push(RawPrimitive {
+            color_index: 0,
+            is_colored: 0,
+            texture_name: Some(texture.as_bytes().to_vec()),
+            vertex_indexes: vertex_indexes.clone(),
+            always_0: 0,
+            unknown_1: 0,
+            unknown_2: 0,
+        });
+        faces.push(Face {
+            material: Material::Texture(texture.to_string()),
+            texture: Some(texture.to_string()),
+            vertex_indexes,
+            uvs: if keep_uvs {
+                face_corners.iter().map(|(_, uv)| *uv).collect()
+            } else {
+                Vec::new()
+            },
+        });
+    }
+
+    let (child_count, children_offset) = (field(1)?, field(2)?);
+    let children = (0..child_count)
+        .map(|i| {
+            let child = read_u32(data, children_offset + i * 4)? as usize;
+            read_piece(data, child, origin, texture, keep_uvs, depth + 1)
+        })
+        .collect::<Result<_, _>>()?;
+
+    let origin_raw = to_raw(origin);
+    let parent_raw = to_raw(parent_origin);
+    Ok(Piece {
+        raw: RawPiece {
+            version_signature: TA_VERSION_SIGNATURE,
+            name: Some(name.as_bytes().to_vec()),
+            from_parent: [0, 1, 2].map(|i| origin_raw[i] - parent_raw[i]),
+            selection_primitive: u32::MAX,
+            always_0: 0,
+            vertexes: vertexes
+                .iter()
+                .map(|&v| {
+                    let raw = to_raw(v);
+                    [0, 1, 2].map(|i| raw[i] - origin_raw[i])
+                })
+                .collect(),
+            primitives,
+        },
+        name,
+        origin,
+        vertexes,
+        faces,
+        children,
+    })
+}
+
+/// Where the s3o's texture is, next to it or in the mod's `unittextures`
+/// beside or above it.
+fn find_texture(path: &str, texture: &str) -> Option<PathBuf> {
+    let folder = Path::new(path).parent().unwrap_or(Path::new(""));
+    [
+        folder.join(texture),
+        folder.join("unittextures").join(texture),
+        folder.join("..").join("unittextures").join(texture),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+/// Reads an s3o as a model like a 3do's, every face using the s3o's first
+/// texture, named after its file, with the uvs the s3o gives unless
+/// --uv-mode is none. The texture is taken from a png or bmp next to the s3o
+/// or in `unittextures`.
+pub fn read_s3o(args: &Args, path: &str, data: &[u8]) -> Result<Model, String> {
+    if data.get(..S3O_MAGIC.len()) != Some(&S3O_MAGIC[..]) {
+        return Err("not an s3o, it doesn't start with \"Spring unit\"".to_string());
+    }
+    let texture_file = match read_u32(data, 44)? {
+        0 => String::new(),
+        offset => read_string(data, offset as usize)?,
+    };
+    let texture = Path::new(&texture_file)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let texture = if texture.is_empty() {
+        "tex1".to_string()
+    } else {
+        texture
+    };
+
+    let root = read_u32(data, 36)? as usize;
+    let keep_uvs = args.uv_mode != UvMode::None;
+    let root = read_piece(data, root, [0.0; 3], &texture, keep_uvs, 0)?;
+    let mut model = Model {
+        pieces: vec![root],
+        ..Model::default()
+    };
+    if model
+        .all_pieces()
+        .iter()
+        .any(|piece| !piece.faces.is_empty())
+    {
+        model.used_textures.push(texture.clone());
+        model.source_textures.push(texture.clone());
+        match find_texture(path, &texture_file) {
+            Some(file) => {
+                model.texture_files.insert(texture, file);
+            }
+            None => warning!(
+                "texture {} of {} isn't next to it or in unittextures",
+                texture_file,
+                path
+            ),
+        }
+    }
+    Ok(model)
+}
@@ -0,0 +1,127 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::geometry::{cross, length, sub};
+use crate::FIXED_POINT_SCALE;
+use ta_3do_to_obj::{visit_3do, Primitive, Surface, Visitor};
+
+/// How much of a model one material covers.
+#[derive(Serialize, Default)]
+struct MaterialUse {
+    triangles: usize,
+    /// in square units of the converted model
+    area: f64,
+}
+
+/// Counts for one 3do, gathered by visiting it.
+#[derive(Serialize, Default)]
+struct Scan {
+    file: String,
+    pieces: usize,
+    vertexes: usize,
+    triangles: usize,
+    textures: BTreeMap<String, MaterialUse>,
+    colours: BTreeMap<String, MaterialUse>,
+    /// vertexes of the piece being visited, the only geometry kept
+    #[serde(skip)]
+    piece_vertexes: Vec<[f64; 3]>,
+}
+
+impl Visitor for Scan {
+    fn piece_start(&mut self, _name: &str, _origin: [i32; 3], _depth: u32) {
+        self.pieces += 1;
+        self.piece_vertexes.clear();
+    }
+
+    fn vertex(&mut self, position: [i32; 3]) {
+        self.vertexes += 1;
+        self.piece_vertexes
+            .push(position.map(|c| c as f64 / *FIXED_POINT_SCALE));
+    }
+
+    fn primitive(&mut self, primitive: &Primitive) {
+        let Some(surface) = &primitive.surface else {
+            return;
+        };
+        let uses = match surface {
+            Surface::Texture(_) => &mut self.textures,
+            Surface::Colour { .. } => &mut self.colours,
+        };
+        let material = uses
+            .entry(surface.material_name().into_owned())
+            .or_default();
+
+        let corners: Vec<[f64; 3]> = primitive
+            .vertex_indexes
+            .iter()
+            .filter_map(|&i| self.piece_vertexes.get(i as usize).copied())
+            .collect();
+        for i in 2..corners.len() {
+            let edges = (sub(corners[i - 1], corners[0]), sub(corners[i], corners[0]));
+            material.area += length(cross(edges.0, edges.1)) / 2.0;
+        }
+        let triangles = primitive.vertex_indexes.len().saturating_sub(2);
+        material.triangles += triangles;
+        self.triangles += triangles;
+    }
+}
+
+fn print_uses(kind: &str, uses: &BTreeMap<String, MaterialUse>) {
+    for (name, usage) in uses {
+        println!(
+            "  {} {}: {} triangles, {:.2} square units",
+            kind, name, usage.triangles, usage.area
+        );
+    }
+}
+
+/// Prints the pieces, vertexes and triangles of each of `files`, and the
+/// triangles and area each texture and colour covers, one line of json per
+/// file with `json`. Files are read one at a time without building a model,
+/// so scanning a whole unit set stays quick. Exits with 1 if any file
+/// couldn't be read.
+pub fn scan_3do(files: &[String], json: bool) {
+    let mut failed = false;
+    let mut scanned = 0;
+    let mut totals = Scan::default();
+    for file in files {
+        let mut scan = Scan {
+            file: file.clone(),
+            ..Scan::default()
+        };
+        let result = fs::read(file)
+            .map_err(|err| format!("unable to read it: {}", err))
+            .and_then(|data| visit_3do(&data, &mut scan));
+        if let Err(err) = result {
+            eprintln!("Error: {}: {}", file, err);
+            failed = true;
+            continue;
+        }
+
+        if json {
+            println!("{}", serde_json::to_string(&scan).unwrap());
+        } else {
+            println!(
+                "{}: {} pieces, {} vertexes, {} triangles",
+                scan.file, scan.pieces, scan.vertexes, scan.triangles
+            );
+            print_uses("texture", &scan.textures);
+            print_uses("colour", &scan.colours);
+        }
+        scanned += 1;
+        totals.pieces += scan.pieces;
+        totals.vertexes += scan.vertexes;
+        totals.triangles += scan.triangles;
+    }
+
+    if !json && scanned > 1 {
+        println!(
+            "{} files: {} pieces, {} vertexes, {} triangles",
+            scanned, totals.pieces, totals.vertexes, totals.triangles
+        );
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,120 @@
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::name_prefix;
+use crate::model::Model;
+use crate::output::PendingFiles;
+use crate::Args;
+
+/// Longest piece name the scripting side is known to keep whole.
+const MAX_SCRIPT_NAME_LENGTH: usize = 31;
+
+/// Words lua won't take as a bare table key.
+const LUA_KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScriptFormat {
+    /// a `piece` declaration for a TA bos script
+    Bos,
+    /// a table of `piece()` calls for a Spring lua unit script
+    Lua,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Warns about names a script will have trouble referring to.
+fn check_names(names: &[String], format: ScriptFormat) {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            warning!(
+                "piece name {} is used more than once, a script only reaches the first.",
+                name
+            );
+        }
+        if name.len() > MAX_SCRIPT_NAME_LENGTH {
+            warning!(
+                "piece name {} is longer than {} characters, scripts may not find it.",
+                name,
+                MAX_SCRIPT_NAME_LENGTH
+            );
+        }
+        if format == ScriptFormat::Bos && !is_identifier(name) {
+            warning!(
+                "piece name {} isn't a valid bos identifier, rename the piece to use it in a script.",
+                name
+            );
+        }
+    }
+}
+
+/// Writes `<stem>.bos` or `<stem>.lua` declaring every piece in tree order,
+/// under the names the obj gives them, to paste into a unit script. Returns
+/// the path written.
+pub fn write_script_skeleton(
+    args: &Args,
+    model: &Model,
+    format: ScriptFormat,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<String> {
+    let prefix = name_prefix(args, output_stem);
+    let names: Vec<String> = model
+        .all_pieces()
+        .iter()
+        .map(|piece| format!("{}{}", prefix, piece.name))
+        .collect();
+    check_names(&names, format);
+
+    let (extension, comment) = match format {
+        ScriptFormat::Bos => ("bos", "//"),
+        ScriptFormat::Lua => ("lua", "--"),
+    };
+    let path = format!("{}.{}", output_stem, extension);
+    let file = pending.create(&path)?;
+    let mut script_writter = BufWriter::new(file);
+
+    writeln!(
+        script_writter,
+        "{} Generated by ta-3do-to-obj, {} pieces of {}.obj in tree order.",
+        comment,
+        names.len(),
+        output_stem
+    )?;
+    writeln!(script_writter)?;
+
+    match format {
+        ScriptFormat::Bos => {
+            writeln!(script_writter, "piece")?;
+            for (i, name) in names.iter().enumerate() {
+                let end = if i + 1 == names.len() { ";" } else { "," };
+                writeln!(script_writter, "    {}{}", name, end)?;
+            }
+        }
+        ScriptFormat::Lua => {
+            writeln!(script_writter, "local pieces = {{")?;
+            for name in &names {
+                let quoted = serde_json::to_string(name).unwrap();
+                if is_identifier(name) && !LUA_KEYWORDS.contains(&name.as_str()) {
+                    writeln!(script_writter, "    {} = piece({}),", name, quoted)?;
+                } else {
+                    writeln!(script_writter, "    [{}] = piece({}),", quoted, quoted)?;
+                }
+            }
+            writeln!(script_writter, "}}")?;
+        }
+    }
+
+    script_writter.flush()?;
+    Ok(path)
+}
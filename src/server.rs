@@ -0,0 +1,307 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::gaf_extractor::gaf_search_path;
+use crate::gaf_index;
+use crate::log::{self, RunResult};
+use crate::{convert, Args};
+
+/// A model sent in a request, as a path or as the bytes of the 3do.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Path(String),
+    Bytes { name: String, base64: String },
+}
+
+/// One line sent to the server.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    #[serde(default)]
+    shutdown: bool,
+    input: Option<Input>,
+    /// flags of the conversion by their long name, `true` for a flag alone
+    #[serde(default)]
+    options: Map<String, Value>,
+    /// send back the text of the obj and mtl along with their paths
+    #[serde(default)]
+    inline: bool,
+}
+
+/// One line sent back, the result --json would print.
+#[derive(Serialize, Default)]
+struct Response {
+    #[serde(flatten)]
+    result: RunResult,
+    /// text of each obj and mtl written, by path, when asked for
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    files: BTreeMap<String, String>,
+}
+
+impl Response {
+    fn error(message: String) -> Response {
+        Response {
+            result: RunResult {
+                error: Some(message),
+                ..RunResult::default()
+            },
+            ..Response::default()
+        }
+    }
+}
+
+/// Everything the connections share.
+struct Server {
+    /// conversions log to one place and write to the working folder, so they
+    /// take turns
+    converting: Mutex<()>,
+    connections: AtomicUsize,
+    max_connections: usize,
+    /// where models sent as bytes are written, and converted next to
+    upload_folder: PathBuf,
+    /// unix socket to remove on shutdown
+    socket: Option<PathBuf>,
+}
+
+/// Turns the request's options into command line flags.
+fn option_args(options: &Map<String, Value>) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (name, value) in options {
+        let flag = format!("--{}", name.replace('_', "-"));
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(flag.clone()),
+                Value::Bool(false) | Value::Null => {}
+                Value::String(value) => args.extend([flag.clone(), value]),
+                Value::Number(value) => args.extend([flag.clone(), value.to_string()]),
+                _ => {
+                    return Err(format!(
+                        "option {} has to be a flag, text or a number",
+                        name
+                    ))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Path of the 3do to convert, writing one sent as bytes to the upload
+/// folder first.
+fn input_path(server: &Server, input: Input) -> Result<String, String> {
+    match input {
+        Input::Path(path) => Ok(path),
+        Input::Bytes { name, base64 } => {
+            let data = STANDARD
+                .decode(base64)
+                .map_err(|err| format!("the input isn't base64: {}", err))?;
+            let stem = Path::new(&name)
+                .file_stem()
+                .ok_or_else(|| format!("{} can't be used as a file name", name))?;
+            let path = server.upload_folder.join(stem).with_extension("3do");
+            fs::write(&path, data)
+                .map_err(|err| format!("unable to write {}: {}", path.to_string_lossy(), err))?;
+            Ok(path.to_string_lossy().into_owned())
+        }
+    }
+}
+
+fn handle(server: &Server, request: Request) -> Response {
+    let Some(input) = request.input else {
+        return Response::error("the request has no input".to_string());
+    };
+    let args = input_path(server, input).and_then(|path| {
+        let program = env::args().next().unwrap_or_default();
+        let flags = option_args(&request.options)?;
+        Args::try_parse_from([program, path].into_iter().chain(flags))
+            .map_err(|err| err.to_string().trim().to_string())
+    });
+    let args = match args {
+        Ok(args) if args.command.is_some() || args.serve.is_some() => {
+            return Response::error("a request can only convert a model".to_string())
+        }
+        Ok(args) if args.interactive || args.piece.as_ref().is_some_and(Vec::is_empty) => {
+            return Response::error(
+                "pieces can't be picked interactively here, name them".to_string(),
+            )
+        }
+        Ok(args) => args,
+        Err(err) => return Response::error(err),
+    };
+
+    let result = {
+        let _converting = server
+            .converting
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        log::run(|| convert(args, &mut HashSet::new()))
+    };
+
+    let mut files = BTreeMap::new();
+    if request.inline {
+        for path in &result.outputs {
+            if path.ends_with(".obj") || path.ends_with(".mtl") {
+                if let Ok(text) = fs::read_to_string(path) {
+                    files.insert(path.clone(), text);
+                }
+            }
+        }
+    }
+    Response { result, files }
+}
+
+/// Answers each line a client sends until it hangs up.
+fn talk(server: &Server, reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                let response = Response::error(format!("the request isn't understood: {}", err));
+                writeln!(writer, "{}", serde_json::to_string(&response).unwrap())?;
+                continue;
+            }
+        };
+
+        if request.shutdown {
+            // let a conversion that's underway finish
+            let _converting = server
+                .converting
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            writeln!(writer, "{{\"shutdown\":true}}")?;
+            writer.flush()?;
+            if let Some(socket) = &server.socket {
+                let _ = fs::remove_file(socket);
+            }
+            let _ = fs::remove_dir_all(&server.upload_folder);
+            info!("Shutting down.");
+            process::exit(0);
+        }
+
+        let response = handle(server, request);
+        writeln!(writer, "{}", serde_json::to_string(&response).unwrap())?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Talks to a client on its own thread, or turns it away when there are
+/// already --max-connections.
+fn accept<S: io::Read + Write + Send + 'static>(
+    server: &Arc<Server>,
+    stream: io::Result<S>,
+    split: fn(&S) -> io::Result<S>,
+) {
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            warning!("unable to accept a connection: {}", err);
+            return;
+        }
+    };
+    if server.connections.fetch_add(1, Ordering::SeqCst) >= server.max_connections {
+        server.connections.fetch_sub(1, Ordering::SeqCst);
+        let response = Response::error("too many connections, try again later".to_string());
+        let _ = writeln!(stream, "{}", serde_json::to_string(&response).unwrap());
+        return;
+    }
+
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        let result =
+            split(&stream).and_then(|reader| talk(&server, BufReader::new(reader), stream));
+        if let Err(err) = result {
+            warning!("lost a connection: {}", err);
+        }
+        server.connections.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Listens on `address`, a port on localhost or a unix socket, converting
+/// the models clients ask for until one of them asks it to shut down.
+pub fn serve(args: &Args, address: &str) {
+    log::start_serving(args.verbose);
+
+    let upload_folder = env::temp_dir().join(format!("ta-3do-to-obj-{}", process::id()));
+    if let Err(err) = fs::create_dir_all(&upload_folder) {
+        log::error(format!(
+            "unable to create {}: {}",
+            upload_folder.to_string_lossy(),
+            err
+        ));
+        process::exit(1);
+    }
+    // requests then only read the gafs that have their textures
+    match gaf_search_path(args, "./gaf_textures/") {
+        Ok((gaf_paths, _)) => info!("Indexed {} gaf files.", gaf_index::build(&gaf_paths)),
+        Err(err) => {
+            log::error(err);
+            process::exit(1);
+        }
+    }
+
+    let port = address.parse::<u16>().ok();
+    let server = Arc::new(Server {
+        converting: Mutex::new(()),
+        connections: AtomicUsize::new(0),
+        max_connections: args.max_connections,
+        upload_folder,
+        socket: port.is_none().then(|| PathBuf::from(address)),
+    });
+
+    if let Some(port) = port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+            log::error(format!("unable to listen on port {}: {}", port, err));
+            process::exit(1);
+        });
+        info!("Listening on port {}.", port);
+        for stream in listener.incoming() {
+            accept(&server, stream, |stream| stream.try_clone());
+        }
+        return;
+    }
+
+    #[cfg(unix)]
+    {
+        let listener = UnixListener::bind(address).unwrap_or_else(|err| {
+            log::error(format!("unable to listen on {}: {}", address, err));
+            process::exit(1);
+        });
+        info!("Listening on {}.", address);
+        for stream in listener.incoming() {
+            accept(&server, stream, |stream| stream.try_clone());
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        log::error(format!(
+            "{} isn't a port, and unix sockets aren't available here",
+            address
+        ));
+        process::exit(1);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+use crate::output::PendingFiles;
+
+/// A material's name and the lines after its `newmtl`, each ending in a
+/// newline.
+pub type MaterialBlock = (String, String);
+
+/// Splits mtl text into its materials, dropping the blank lines between them
+/// and anything before the first `newmtl`.
+pub fn parse_materials(text: &str) -> Vec<MaterialBlock> {
+    let mut materials: Vec<MaterialBlock> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim_end();
+        if let Some(name) = line.strip_prefix("newmtl ") {
+            materials.push((name.trim().to_string(), String::new()));
+        } else if let Some((_, body)) = materials.last_mut() {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+    for (_, body) in &mut materials {
+        while body.ends_with("\n\n") || body == "\n" {
+            body.pop();
+        }
+    }
+    materials
+}
+
+/// An mtl several objs point at with --shared-mtl, read back in so each
+/// conversion adds to what the earlier ones wrote.
+pub struct SharedMtl {
+    path: String,
+    materials: Vec<MaterialBlock>,
+}
+
+impl SharedMtl {
+    pub fn load(path: &str) -> SharedMtl {
+        let text = fs::read_to_string(path).unwrap_or_default();
+        SharedMtl {
+            path: path.to_string(),
+            materials: parse_materials(&text),
+        }
+    }
+
+    /// Adds a model's materials, skipping the ones already there as they
+    /// are. A material named like a different one already there is added as
+    /// `collision_prefix` + its name instead, and the renames are returned.
+    pub fn add(
+        &mut self,
+        materials: &[MaterialBlock],
+        collision_prefix: &str,
+    ) -> HashMap<String, String> {
+        let mut renames = HashMap::new();
+        for (name, body) in materials {
+            let mut name = name.clone();
+            match self.materials.iter().find(|(other, _)| *other == name) {
+                Some((_, other_body)) if other_body == body => continue,
+                Some(_) => {
+                    let renamed = format!("{}{}", collision_prefix, name);
+                    warning!(
+                        "{} already has a different material {}, adding this model's as {}.",
+                        self.path,
+                        name,
+                        renamed
+                    );
+                    renames.insert(name, renamed.clone());
+                    name = renamed;
+                }
+                None => {}
+            }
+
+            // the renamed material is this model's own, so it's replaced
+            match self.materials.iter_mut().find(|(other, _)| *other == name) {
+                Some((_, other_body)) => *other_body = body.clone(),
+                None => self.materials.push((name, body.clone())),
+            }
+        }
+        renames
+    }
+
+    pub fn save(&self, pending: &mut PendingFiles) -> io::Result<String> {
+        let file = pending.create(&self.path)?;
+        let mut mtl_writter = BufWriter::new(file);
+        write_materials(&mut mtl_writter, &self.materials)?;
+        mtl_writter.flush()?;
+        Ok(self.path.clone())
+    }
+}
+
+pub fn write_materials(writer: &mut impl Write, materials: &[MaterialBlock]) -> io::Result<()> {
+    for (name, body) in materials {
+        writeln!(writer, "newmtl {}", name)?;
+        write!(writer, "{}", body)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::exporter::Exporter;
+use crate::gaf_extractor::ExtractedTexture;
+use crate::geometry::{cross, length, sub};
+use crate::model::Model;
+use crate::output::PendingFiles;
+use crate::Args;
+
+/// A point turned from y up to the z up slicers expect.
+pub fn z_up([x, y, z]: [f64; 3]) -> [f64; 3] {
+    // adding 0 keeps a z of 0 from being written as -0
+    [x, -z + 0.0, y]
+}
+
+/// Every face of the model as a fan of triangles in place, turned z up.
+fn triangles(model: &Model) -> Vec<[[f64; 3]; 3]> {
+    let mut triangles = Vec::new();
+    for piece in model.all_pieces() {
+        for face in &piece.faces {
+            let corners: Vec<[f64; 3]> = face
+                .vertex_indexes
+                .iter()
+                .filter_map(|&i| piece.vertexes.get(i as usize))
+                .map(|&v| z_up(v))
+                .collect();
+            for i in 2..corners.len() {
+                triangles.push([corners[0], corners[i - 1], corners[i]]);
+            }
+        }
+    }
+    triangles
+}
+
+fn normal([a, b, c]: &[[f64; 3]; 3]) -> [f64; 3] {
+    let normal = cross(sub(*b, *a), sub(*c, *a));
+    let length = length(normal);
+    if length > 0.0 {
+        normal.map(|n| n / length)
+    } else {
+        [0.0; 3]
+    }
+}
+
+/// Writes the whole model as one stl solid for printing, binary or with
+/// `ascii` as text. Pieces are merged in place, faces are split into
+/// triangles, and, as for every format, primitives the game doesn't draw
+/// are already left out.
+pub struct StlExporter {
+    pub ascii: bool,
+}
+
+impl Exporter for StlExporter {
+    fn export(
+        &self,
+        _args: &Args,
+        model: &Model,
+        _textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let stl_path = output_stem.to_owned() + ".stl";
+        let name = Path::new(output_stem)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .replace(char::is_whitespace, "_");
+        let triangles = triangles(model);
+
+        let file = pending.create(&stl_path)?;
+        let mut stl_writter = BufWriter::new(file);
+        if self.ascii {
+            writeln!(stl_writter, "solid {}", name)?;
+            for triangle in &triangles {
+                let [x, y, z] = normal(triangle);
+                writeln!(stl_writter, "  facet normal {} {} {}", x, y, z)?;
+                writeln!(stl_writter, "    outer loop")?;
+                for [x, y, z] in triangle {
+                    writeln!(stl_writter, "      vertex {} {} {}", x, y, z)?;
+                }
+                writeln!(stl_writter, "    endloop")?;
+                writeln!(stl_writter, "  endfacet")?;
+            }
+            writeln!(stl_writter, "endsolid {}", name)?;
+        } else {
+            // an 80 byte header that mustn't start with "solid", then the count
+            let mut header = format!("binary stl of {}", name).into_bytes();
+            header.resize(80, 0);
+            stl_writter.write_all(&header)?;
+            stl_writter.write_all(&(triangles.len() as u32).to_le_bytes())?;
+            for triangle in &triangles {
+                let normal = normal(triangle);
+                for value in normal.iter().chain(triangle.iter().flatten()) {
+                    stl_writter.write_all(&(*value as f32).to_le_bytes())?;
+                }
+                // attribute byte count, unused
+                stl_writter.write_all(&0u16.to_le_bytes())?;
+            }
+        }
+        stl_writter.flush()?;
+
+        Ok(vec![stl_path])
+    }
+}
@@ -0,0 +1,76 @@
+use bmp::{Image, Pixel};
+use serde::{Deserialize, Serialize};
+
+use crate::Args;
+
+/// How extracted textures were brightened, as recorded in textures.json.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Adjustment {
+    /// stretched so the darkest value is 0 and the brightest 255
+    #[serde(default)]
+    pub autolevel: bool,
+    pub gamma: f64,
+    pub gain: f64,
+}
+
+/// Parses a --texture-gain or --texture-gamma value, which has to be above 0.
+pub fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Ok(_) => Err(format!("{} has to be above 0", s)),
+        Err(err) => Err(format!("{} isn't a number: {}", s, err)),
+    }
+}
+
+impl Adjustment {
+    /// The adjustment the flags ask for, or nothing when they leave
+    /// textures as they are.
+    pub fn from_args(args: &Args) -> Option<Adjustment> {
+        let adjustment = Adjustment {
+            autolevel: args.texture_autolevel,
+            gamma: args.texture_gamma,
+            gain: args.texture_gain,
+        };
+        let unchanged = !adjustment.autolevel && adjustment.gamma == 1.0 && adjustment.gain == 1.0;
+        (!unchanged).then_some(adjustment)
+    }
+
+    /// Applies the adjustment to every pixel but those of the `transparent`
+    /// colour, which stay as they are so they still mark transparency.
+    pub fn apply(&self, image: &mut Image, transparent: Option<Pixel>) {
+        let opaque: Vec<(u32, u32)> = image
+            .coordinates()
+            .filter(|&(x, y)| Some(image.get_pixel(x, y)) != transparent)
+            .collect();
+
+        // one range for all channels, so colours don't shift
+        let (mut low, mut high) = (0.0, 255.0);
+        if self.autolevel {
+            let values = opaque.iter().flat_map(|&(x, y)| {
+                let pixel = image.get_pixel(x, y);
+                [pixel.r, pixel.g, pixel.b]
+            });
+            let (min, max) = values.fold((u8::MAX, u8::MIN), |(min, max), value| {
+                (min.min(value), max.max(value))
+            });
+            if min < max {
+                (low, high) = (min as f64, max as f64);
+            }
+        }
+
+        let adjust = |value: u8| {
+            let level = ((value as f64 - low) / (high - low)).clamp(0.0, 1.0);
+            (level.powf(1.0 / self.gamma) * self.gain * 255.0)
+                .round()
+                .clamp(0.0, 255.0) as u8
+        };
+        for (x, y) in opaque {
+            let pixel = image.get_pixel(x, y);
+            image.set_pixel(
+                x,
+                y,
+                Pixel::new(adjust(pixel.r), adjust(pixel.g), adjust(pixel.b)),
+            );
+        }
+    }
+}
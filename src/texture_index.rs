@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::gaf_extractor::ExtractedTexture;
+use crate::output::{dot_relative_path, write_file};
+use crate::texture_adjust::Adjustment;
+
+/// Where the extracted textures are described, next to the folder they're in.
+const INDEX_FILE: &str = "textures.json";
+
+#[derive(Serialize, Deserialize)]
+struct TextureEntry {
+    file: String,
+    /// size of the file, padding included
+    width: u32,
+    height: u32,
+    gaf: String,
+    frame: u16,
+    transparent: bool,
+    /// how the pixels were changed from the gaf's, if at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    adjustment: Option<Adjustment>,
+    /// player colour a logo was painted in, `neutral` when it wasn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    team: Option<String>,
+}
+
+/// Adds the textures extracted for this model to the `textures.json` next to
+/// `extract_folder`, keeping what earlier conversions put there. Returns the
+/// path written.
+pub fn write_texture_index(
+    extracted: &HashMap<String, ExtractedTexture>,
+    extract_folder: &str,
+) -> Option<String> {
+    let index_path = Path::new(extract_folder.trim_end_matches(['/', '\\']))
+        .with_file_name(INDEX_FILE)
+        .to_string_lossy()
+        .into_owned();
+    let mut index: BTreeMap<String, TextureEntry> = fs::read(&index_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+    for (name, texture) in extracted {
+        index.insert(
+            name.clone(),
+            TextureEntry {
+                file: dot_relative_path(&texture.path(), &index_path),
+                width: texture.canvas_width,
+                height: texture.canvas_height,
+                gaf: texture.gaf.clone(),
+                frame: texture.frame,
+                transparent: texture.colour_key.is_some(),
+                adjustment: texture.adjustment.clone(),
+                team: texture.team.map(|team| team.name().to_string()),
+            },
+        );
+    }
+
+    let data = serde_json::to_vec_pretty(&index).unwrap();
+    match write_file(&index_path, |file| file.write_all(&data)) {
+        Ok(()) => Some(index_path),
+        Err(err) => {
+            warning!("unable to write {}: {}", index_path, err);
+            None
+        }
+    }
+}
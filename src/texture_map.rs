@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What a texture name is mapped to: another name, or a bmp to use instead of
+/// a gaf entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Name(String),
+    File(PathBuf),
+}
+
+impl Target {
+    /// Name the texture goes by in the outputs, a file's name without its
+    /// extension.
+    pub fn name(&self) -> String {
+        match self {
+            Target::Name(name) => name.clone(),
+            Target::File(path) => path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+struct Rule {
+    /// old name, with at most one `*` standing for any run of characters
+    pattern: String,
+    /// new name or path, a `*` in it replaced by what the pattern's matched
+    target: String,
+}
+
+impl Rule {
+    /// What the pattern's `*` matched in `name`, or all of it without one.
+    fn matches<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let lower = name.to_ascii_lowercase();
+        let pattern = self.pattern.to_ascii_lowercase();
+        match pattern.split_once('*') {
+            None => (lower == pattern).then_some(""),
+            Some((prefix, suffix)) => {
+                let fits = lower.len() >= prefix.len() + suffix.len()
+                    && lower.starts_with(prefix)
+                    && lower.ends_with(suffix);
+                fits.then(|| &name[prefix.len()..name.len() - suffix.len()])
+            }
+        }
+    }
+}
+
+/// Texture renames read from a --texture-map file, one `old new` pair per
+/// line. Names are matched ignoring case, the first matching line wins.
+#[derive(Default)]
+pub struct TextureMap {
+    rules: Vec<Rule>,
+}
+
+impl TextureMap {
+    pub fn load(path: &Path) -> Result<TextureMap, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read {}: {}", path.to_string_lossy(), err))?;
+
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            let [pattern, target] = columns[..] else {
+                return Err(format!(
+                    "line {} of {} should be an old and a new name",
+                    i + 1,
+                    path.to_string_lossy()
+                ));
+            };
+            if pattern.matches('*').count() > 1 {
+                return Err(format!(
+                    "line {} of {} has more than one * in the old name",
+                    i + 1,
+                    path.to_string_lossy()
+                ));
+            }
+            rules.push(Rule {
+                pattern: pattern.to_string(),
+                target: target.to_string(),
+            });
+        }
+        Ok(TextureMap { rules })
+    }
+
+    fn find(&self, name: &str) -> Option<(usize, Target)> {
+        self.rules.iter().enumerate().find_map(|(i, rule)| {
+            let wildcard = rule.matches(name)?;
+            let target = rule.target.replace('*', wildcard);
+            let is_file =
+                target.contains(['/', '\\']) || target.to_ascii_lowercase().ends_with(".bmp");
+            Some((
+                i,
+                if is_file {
+                    Target::File(PathBuf::from(target))
+                } else {
+                    Target::Name(target)
+                },
+            ))
+        })
+    }
+
+    /// What `name` is mapped to, if any line matches it.
+    pub fn target(&self, name: &str) -> Option<Target> {
+        self.find(name).map(|(_, target)| target)
+    }
+
+    /// Old names of lines that were the first match of none of `names`.
+    pub fn unused(&self, names: &[String]) -> Vec<&str> {
+        let used: Vec<usize> = names
+            .iter()
+            .filter_map(|name| self.find(name).map(|(i, _)| i))
+            .collect();
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !used.contains(i))
+            .map(|(_, rule)| rule.pattern.as_str())
+            .collect()
+    }
+}
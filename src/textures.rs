@@ -0,0 +1,304 @@
+use bmp::{Image, Pixel};
+use clap::ValueEnum;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::output::{save_image, write_file};
+
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    Bmp,
+    Png,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Decoded texture pixels, row by row from the top left.
+struct Texture {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 3]>,
+}
+
+fn read_texture(path: &Path, format: ImageFormat) -> Result<Texture, String> {
+    match format {
+        ImageFormat::Bmp => {
+            let image = bmp::open(path).map_err(|err| err.to_string())?;
+            let pixels = image
+                .coordinates()
+                .map(|(x, y)| {
+                    let pixel = image.get_pixel(x, y);
+                    [pixel.r, pixel.g, pixel.b]
+                })
+                .collect();
+            Ok(Texture {
+                width: image.get_width(),
+                height: image.get_height(),
+                pixels,
+            })
+        }
+        ImageFormat::Png => {
+            let file = File::open(path).map_err(|err| err.to_string())?;
+            let mut decoder = png::Decoder::new(file);
+            decoder
+                .set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+            let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+            let mut data = vec![0; reader.output_buffer_size()];
+            let info = reader
+                .next_frame(&mut data)
+                .map_err(|err| err.to_string())?;
+
+            let samples = info.color_type.samples();
+            let pixels = data[..info.buffer_size()]
+                .chunks(samples)
+                .map(|pixel| match samples {
+                    1 | 2 => [pixel[0]; 3],
+                    _ => [pixel[0], pixel[1], pixel[2]],
+                })
+                .collect();
+            Ok(Texture {
+                width: info.width,
+                height: info.height,
+                pixels,
+            })
+        }
+    }
+}
+
+fn write_texture(texture: &Texture, path: &Path, format: ImageFormat) -> io::Result<()> {
+    let path = path.to_string_lossy();
+    match format {
+        ImageFormat::Bmp => {
+            let mut image = Image::new(texture.width, texture.height);
+            for ((x, y), pixel) in image.coordinates().zip(&texture.pixels) {
+                image.set_pixel(x, y, Pixel::new(pixel[0], pixel[1], pixel[2]));
+            }
+            save_image(&image, &path)
+        }
+        ImageFormat::Png => write_file(&path, |file| encode_png(texture, BufWriter::new(file))),
+    }
+}
+
+fn encode_png(texture: &Texture, writer: impl io::Write) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, texture.width, texture.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = texture.pixels.iter().flatten().copied().collect();
+    let mut png_writter = encoder.write_header().map_err(io::Error::other)?;
+    png_writter
+        .write_image_data(&data)
+        .map_err(io::Error::other)
+}
+
+/// `pixels`, row by row from the top left, as a png with an alpha channel.
+pub fn encode_rgba_png(
+    width: u32,
+    height: u32,
+    pixels: &[[u8; 4]],
+    writer: impl io::Write,
+) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+    let mut png_writter = encoder.write_header().map_err(io::Error::other)?;
+    png_writter
+        .write_image_data(&data)
+        .map_err(io::Error::other)
+}
+
+/// The bmp or png at `path`, going by its extension, as an image.
+pub fn open_image(path: &Path) -> Result<Image, String> {
+    let png = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    let format = if png {
+        ImageFormat::Png
+    } else {
+        ImageFormat::Bmp
+    };
+    let texture = read_texture(path, format)?;
+    let mut image = Image::new(texture.width, texture.height);
+    for ((x, y), pixel) in image.coordinates().zip(&texture.pixels) {
+        image.set_pixel(x, y, Pixel::new(pixel[0], pixel[1], pixel[2]));
+    }
+    Ok(image)
+}
+
+/// The bmp at `path` encoded as a png in memory, for formats that carry
+/// their textures inside them.
+pub fn png_bytes(path: &Path) -> Result<Vec<u8>, String> {
+    let texture = read_texture(path, ImageFormat::Bmp)?;
+    let mut data = Vec::new();
+    encode_png(&texture, &mut data).map_err(|err| err.to_string())?;
+    Ok(data)
+}
+
+/// Points `map_Kd` and `map_d` lines of an mtl at the converted textures,
+/// leaving any line whose file wasn't converted alone.
+fn rewrite_mtl(mtl: &str, converted: &[(PathBuf, PathBuf)], from: ImageFormat, dry_run: bool) {
+    let contents = match fs::read_to_string(mtl) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Error: unable to read {}: {}", mtl, err);
+            return;
+        }
+    };
+    let mtl_folder = Path::new(mtl).parent().unwrap_or(Path::new(""));
+
+    let mut rewritten = String::new();
+    let mut changed_lines = 0;
+    for line in contents.lines() {
+        let map = ["map_Kd ", "map_d "]
+            .into_iter()
+            .find(|map| line.trim_start().starts_with(map));
+        let Some(map) = map else {
+            rewritten.push_str(line);
+            rewritten.push('\n');
+            continue;
+        };
+
+        let (start, texture) = line.rsplit_once(' ').unwrap_or((line, ""));
+        let texture_path = Path::new(texture);
+        let is_from = texture_path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(from.extension()));
+
+        let referenced = fs::canonicalize(mtl_folder.join(texture_path)).ok();
+        let conversion = converted.iter().find(|(source, _)| {
+            referenced.is_some() && fs::canonicalize(source).ok() == referenced
+        });
+
+        match conversion {
+            Some((_, target)) => {
+                let extension = target.extension().unwrap().to_string_lossy();
+                let new_texture = texture_path.with_extension(extension.as_ref());
+                println!(
+                    "{}: {}{} -> {}",
+                    mtl,
+                    map,
+                    texture,
+                    new_texture.to_string_lossy()
+                );
+                rewritten.push_str(&format!("{} {}\n", start, new_texture.to_string_lossy()));
+                changed_lines += 1;
+            }
+            None => {
+                if is_from {
+                    eprintln!(
+                        "Warning: {} references {} which wasn't converted, leaving it",
+                        mtl, texture
+                    );
+                }
+                rewritten.push_str(line);
+                rewritten.push('\n');
+            }
+        }
+    }
+
+    if changed_lines > 0 && !dry_run {
+        write_file(mtl, |file| io::Write::write_all(file, rewritten.as_bytes()))
+            .expect("unable to write mtl");
+    }
+}
+
+/// Re-encodes one texture next to itself with `to`'s extension, returning the
+/// path of the new file.
+pub fn convert_texture(
+    source: &Path,
+    from: ImageFormat,
+    to: ImageFormat,
+) -> Result<PathBuf, String> {
+    let target = source.with_extension(to.extension());
+    let texture = read_texture(source, from)?;
+    write_texture(&texture, &target, to).map_err(|err| err.to_string())?;
+    Ok(target)
+}
+
+/// Re-encodes every `from` texture in a folder as `to`, then fixes up the
+/// given mtls to match.
+pub fn convert_textures(
+    folder: &str,
+    from: ImageFormat,
+    to: ImageFormat,
+    mtls: &[String],
+    delete_originals: bool,
+    dry_run: bool,
+) {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Error: unable to read {}: {}", folder, err);
+            std::process::exit(1);
+        }
+    };
+    let mut sources: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case(from.extension()))
+        })
+        .collect();
+    sources.sort();
+
+    let mut converted = Vec::new();
+    for source in sources {
+        let target = source.with_extension(to.extension());
+        if dry_run {
+            println!(
+                "would convert {} -> {}",
+                source.to_string_lossy(),
+                target.to_string_lossy()
+            );
+            converted.push((source, target));
+            continue;
+        }
+
+        match convert_texture(&source, from, to) {
+            Ok(target) => {
+                println!(
+                    "{} -> {}",
+                    source.to_string_lossy(),
+                    target.to_string_lossy()
+                );
+                converted.push((source, target));
+            }
+            Err(err) => eprintln!(
+                "Warning: unable to convert {}: {}",
+                source.to_string_lossy(),
+                err
+            ),
+        }
+    }
+
+    for mtl in mtls {
+        rewrite_mtl(mtl, &converted, from, dry_run);
+    }
+
+    if delete_originals && !dry_run {
+        for (source, _) in &converted {
+            let _ = fs::remove_file(source);
+        }
+    }
+
+    println!(
+        "{} {} textures.",
+        if dry_run {
+            "Would convert"
+        } else {
+            "Converted"
+        },
+        converted.len()
+    );
+}
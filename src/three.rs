@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::model::Model;
+use crate::output::PendingFiles;
+
+/// Alpha below which a colour keyed texture's pixels are thrown away, once
+/// the loader has made the key colour transparent.
+const ALPHA_TEST: f32 = 0.5;
+
+/// What a three.js material needs beyond what OBJLoader takes from the mtl.
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreeMaterial {
+    /// the same colour as the mtl's `Ke`, for fullbright colours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emissive: Option<[f32; 3]>,
+    /// texture colour the game draws as see-through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_key: Option<[u8; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha_test: Option<f32>,
+    /// used by a flat piece or keyed out in places, so its back shows
+    pub double_sided: bool,
+}
+
+impl ThreeMaterial {
+    pub fn keyed(color_key: [u8; 3]) -> ThreeMaterial {
+        ThreeMaterial {
+            color_key: Some(color_key),
+            alpha_test: Some(ALPHA_TEST),
+            double_sided: true,
+            ..ThreeMaterial::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ThreePiece {
+    parent: Option<String>,
+    /// where the piece turns, in the obj's coordinates
+    pivot: [f64; 3],
+    children: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    obj: String,
+    /// whether each piece's vertexes are written around its pivot, by
+    /// --embed-transforms, rather than in place
+    local: bool,
+    materials: BTreeMap<String, ThreeMaterial>,
+    /// every piece by its `o` name
+    pieces: BTreeMap<String, ThreePiece>,
+}
+
+/// Writes `<stem>.three.json` next to the obj, with `materials` by the names
+/// the mtl gives them and the pieces by their `o` names, returning its path.
+pub fn write_three_json(
+    model: &Model,
+    materials: BTreeMap<String, ThreeMaterial>,
+    prefix: &str,
+    obj_path: &str,
+    local: bool,
+    output_stem: &str,
+    pending: &mut PendingFiles,
+) -> io::Result<String> {
+    let all_pieces = model.all_pieces();
+    let names: Vec<String> = all_pieces
+        .iter()
+        .map(|piece| format!("{}{}", prefix, piece.name))
+        .collect();
+    let parents = model.parents();
+    let pieces = all_pieces
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            let children = parents
+                .iter()
+                .enumerate()
+                .filter(|(_, parent)| **parent == Some(i))
+                .map(|(child, _)| names[child].clone())
+                .collect();
+            let piece = ThreePiece {
+                parent: parents[i].map(|parent| names[parent].clone()),
+                pivot: piece.origin,
+                children,
+            };
+            (names[i].clone(), piece)
+        })
+        .collect();
+
+    let sidecar = Sidecar {
+        obj: Path::new(obj_path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        local,
+        materials,
+        pieces,
+    };
+    let path = format!("{}.three.json", output_stem);
+    let mut file = pending.create(&path)?;
+    let data = serde_json::to_vec_pretty(&sidecar).unwrap();
+    file.write_all(&data)?;
+    Ok(path)
+}
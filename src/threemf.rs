@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::exporter::{escape, material_name, Exporter};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model};
+use crate::output::PendingFiles;
+use crate::preview::{average_colour, MISSING_TEXTURE_COLOUR};
+use crate::stl::z_up;
+use crate::zip::zip_stored;
+use crate::Args;
+use ta_3do_to_obj::palette::PALETTE;
+
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+  <Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+  <Default Extension="model" ContentType="application/vnd.ms-package.3dmanufacturing-3dmodel+xml"/>
+</Types>
+"#;
+
+const RELATIONSHIPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Target="/3D/3dmodel.model" Id="rel0" Type="http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel"/>
+</Relationships>
+"#;
+
+/// The 3mf model part, the whole model as one mesh in place, z up, with a
+/// base material per colour and texture for its triangles to point at.
+fn model_xml(
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    name: &str,
+) -> io::Result<Vec<u8>> {
+    // by the material name faces use, in the order they're first used
+    let mut materials: Vec<(String, [u8; 3])> = Vec::new();
+    let mut vertexes = Vec::new();
+    let mut triangles = Vec::new();
+    let mut vertex_count = 0;
+    for piece in model.all_pieces() {
+        for v in &piece.vertexes {
+            let [x, y, z] = z_up(*v);
+            writeln!(
+                vertexes,
+                "          <vertex x=\"{}\" y=\"{}\" z=\"{}\"/>",
+                x, y, z
+            )?;
+        }
+
+        for face in &piece.faces {
+            let name = material_name(args, &face.material, textures);
+            let material = match materials.iter().position(|(m, _)| *m == name) {
+                Some(material) => material,
+                None => {
+                    // printers have no textures, so those print in their
+                    // average colour
+                    let colour = match &face.material {
+                        Material::Colour(index) => PALETTE[*index],
+                        Material::Texture(_) => textures
+                            .get(&name)
+                            .and_then(average_colour)
+                            .unwrap_or(MISSING_TEXTURE_COLOUR),
+                    };
+                    materials.push((name, colour));
+                    materials.len() - 1
+                }
+            };
+
+            let corners: Vec<usize> = face
+                .vertex_indexes
+                .iter()
+                .map(|&i| vertex_count + i as usize)
+                .collect();
+            for i in 2..corners.len() {
+                writeln!(
+                    triangles,
+                    "          <triangle v1=\"{}\" v2=\"{}\" v3=\"{}\" pid=\"1\" p1=\"{}\"/>",
+                    corners[0],
+                    corners[i - 1],
+                    corners[i],
+                    material
+                )?;
+            }
+        }
+        vertex_count += piece.vertexes.len();
+    }
+
+    let mut xml = Vec::new();
+    writeln!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        xml,
+        "<model unit=\"millimeter\" xml:lang=\"en-US\" xmlns=\"http://schemas.microsoft.com/3dmanufacturing/core/2015/02\">"
+    )?;
+    writeln!(
+        xml,
+        "  <metadata name=\"Application\">ta-3do-to-obj {}</metadata>",
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(xml, "  <resources>")?;
+    if !materials.is_empty() {
+        writeln!(xml, "    <basematerials id=\"1\">")?;
+        for (name, [r, g, b]) in &materials {
+            writeln!(
+                xml,
+                "      <base name=\"{}\" displaycolor=\"#{:02X}{:02X}{:02X}\"/>",
+                escape(name),
+                r,
+                g,
+                b
+            )?;
+        }
+        writeln!(xml, "    </basematerials>")?;
+    }
+    writeln!(
+        xml,
+        "    <object id=\"2\" name=\"{}\" type=\"model\">",
+        escape(name)
+    )?;
+    writeln!(xml, "      <mesh>")?;
+    writeln!(xml, "        <vertices>")?;
+    xml.extend(vertexes);
+    writeln!(xml, "        </vertices>")?;
+    writeln!(xml, "        <triangles>")?;
+    xml.extend(triangles);
+    writeln!(xml, "        </triangles>")?;
+    writeln!(xml, "      </mesh>")?;
+    writeln!(xml, "    </object>")?;
+    writeln!(xml, "  </resources>")?;
+    writeln!(xml, "  <build>")?;
+    writeln!(xml, "    <item objectid=\"2\"/>")?;
+    writeln!(xml, "  </build>")?;
+    writeln!(xml, "</model>")?;
+    Ok(xml)
+}
+
+/// Writes the whole model as one object in a 3mf package for slicers, z up,
+/// with each triangle coloured by its palette colour, or its texture's
+/// average colour.
+pub struct ThreeMfExporter;
+
+impl Exporter for ThreeMfExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let threemf_path = output_stem.to_owned() + ".3mf";
+        let name = Path::new(output_stem)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        let files = [
+            ("[Content_Types].xml".to_string(), CONTENT_TYPES.into()),
+            ("_rels/.rels".to_string(), RELATIONSHIPS.into()),
+            (
+                "3D/3dmodel.model".to_string(),
+                model_xml(args, model, textures, &name)?,
+            ),
+        ];
+        let file = pending.create(&threemf_path)?;
+        let mut threemf_writter = BufWriter::new(file);
+        threemf_writter.write_all(&zip_stored(&files, 1))?;
+        threemf_writter.flush()?;
+
+        Ok(vec![threemf_path])
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::gltf_import::read_gltf;
+use crate::model::{Face, Material, Piece, RawPiece, RawPrimitive};
+use crate::output::write_file;
+use crate::roundtrip::write_3do;
+use crate::FIXED_POINT_SCALE;
+use ta_3do_to_obj::palette::{colour_name, PALETTE};
+use ta_3do_to_obj::records::TA_VERSION_SIGNATURE;
+
+/// An object of the file as it's read, before it's put in the tree.
+#[derive(Default)]
+pub struct Object {
+    /// the whole name the file gives it
+    pub name: String,
+    /// named by the file, which wins over a dotted name
+    pub parent: Option<String>,
+    /// where it pivots in place, when the file says
+    pub pivot: Option<[f64; 3]>,
+    /// whether a dotted name puts it under another when it has no parent
+    pub dotted: bool,
+    /// material, `None` for the default colour, and the corners as indexes
+    /// into every vertex of the file
+    pub faces: Vec<(Option<Material>, Vec<usize>)>,
+}
+
+/// The palette index a material named like the converter names colours
+/// stands for, `RRGGBB_index` or `RRGGBB` with --rgb-material-names, after
+/// any prefix.
+pub fn colour_index(material: &str) -> Option<usize> {
+    let hex_of = |name: &str| name.get(name.len().saturating_sub(6)..).map(str::to_string);
+    if let Some((hex, index)) = material.rsplit_once('_') {
+        if let (Some(hex), Ok(index)) = (hex_of(hex), index.parse::<usize>()) {
+            if index < PALETTE.len() && colour_name(PALETTE[index]).eq_ignore_ascii_case(&hex) {
+                return Some(index);
+            }
+        }
+    }
+    if material.len() != 6 {
+        return None;
+    }
+    PALETTE
+        .iter()
+        .position(|&colour| colour_name(colour).eq_ignore_ascii_case(material))
+}
+
+/// The 3do's integers for a point of the obj, mirrored back in x.
+pub fn to_raw(point: [f64; 3]) -> [i32; 3] {
+    let scale = *FIXED_POINT_SCALE;
+    [
+        (-point[0] * scale).round() as i32,
+        (point[1] * scale).round() as i32,
+        (point[2] * scale).round() as i32,
+    ]
+}
+
+/// Reads the objects of an obj, with every vertex in place. Pieces come
+/// from `o` lines, or from `g` lines in a file without any.
+fn read_obj(obj: &str) -> Result<(Vec<Object>, Vec<[f64; 3]>), String> {
+    let object_keyword = if obj.lines().any(|line| line.starts_with("o ")) {
+        "o"
+    } else {
+        "g"
+    };
+
+    let mut objects: Vec<Object> = Vec::new();
+    let mut vertexes = Vec::new();
+    let mut pivots: HashMap<String, [f64; 3]> = HashMap::new();
+    let mut transform: Option<(Option<String>, [f64; 3])> = None;
+    let mut material = None;
+    for (number, line) in obj.lines().enumerate() {
+        let at = |problem: String| format!("line {}: {}", number + 1, problem);
+
+        // as --embed-transforms writes them
+        if let Some(fields) = line.strip_prefix("# transform:") {
+            let Some((parent, offset)) = fields.trim().split_once("offset=") else {
+                return Err(at("transform has no offset".to_string()));
+            };
+            let offset: Vec<f64> = offset
+                .split_whitespace()
+                .filter_map(|value| value.parse().ok())
+                .collect();
+            let [x, y, z] = offset[..] else {
+                return Err(at("transform offset isn't 3 numbers".to_string()));
+            };
+            let parent = parent.trim().strip_prefix("parent=").map(str::to_string);
+            let base = match &parent {
+                Some(parent) => *pivots
+                    .get(parent)
+                    .ok_or_else(|| at(format!("parent {} isn't an object before it", parent)))?,
+                None => [0.0; 3],
+            };
+            transform = Some((parent, [base[0] + x, base[1] + y, base[2] + z]));
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some(keyword) if keyword == object_keyword => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                let (parent, pivot) = transform.take().unzip();
+                if let Some(pivot) = pivot {
+                    pivots.insert(name.clone(), pivot);
+                }
+                objects.push(Object {
+                    name,
+                    parent: parent.flatten(),
+                    pivot,
+                    dotted: true,
+                    faces: Vec::new(),
+                });
+            }
+            Some("v") => {
+                let values: Vec<f64> = tokens.filter_map(|value| value.parse().ok()).collect();
+                let [x, y, z] = values[..3.min(values.len())] else {
+                    return Err(at("vertex isn't 3 numbers".to_string()));
+                };
+                // vertexes of an object with a transform are around its pivot
+                let pivot = objects.last().and_then(|o| o.pivot).unwrap_or([0.0; 3]);
+                vertexes.push([x + pivot[0], y + pivot[1], z + pivot[2]]);
+            }
+            Some("usemtl") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                material = Some(match colour_index(&name) {
+                    Some(colour) => Material::Colour(colour),
+                    None => Material::Texture(name),
+                });
+            }
+            Some("f") => {
+                let mut corners = Vec::new();
+                for corner in tokens {
+                    let index = corner.split('/').next().unwrap_or_default();
+                    let vertex = match index.parse::<i64>() {
+                        Ok(index) if index > 0 && index as usize <= vertexes.len() => {
+                            index as usize - 1
+                        }
+                        Ok(index)
+                            if index < 0 && index.unsigned_abs() as usize <= vertexes.len() =>
+                        {
+                            vertexes.len() - index.unsigned_abs() as usize
+                        }
+                        _ => return Err(at(format!("bad vertex index {}", index))),
+                    };
+                    corners.push(vertex);
+                }
+                if objects.is_empty() {
+                    // faces before any object are the usual root piece
+                    objects.push(Object {
+                        name: "base".to_string(),
+                        ..Object::default()
+                    });
+                }
+                objects
+                    .last_mut()
+                    .unwrap()
+                    .faces
+                    .push((material.clone(), corners));
+            }
+            _ => {}
+        }
+    }
+    Ok((objects, vertexes))
+}
+
+/// Builds the piece `index` and everything under it, its vertexes and
+/// origin as the 3do has them relative to `parent_raw`.
+fn build_piece(
+    objects: &[Object],
+    children: &[Vec<usize>],
+    vertexes: &[[f64; 3]],
+    pivots: &[[f64; 3]],
+    index: usize,
+    parent: Option<usize>,
+    default_colour: usize,
+) -> Result<Piece, String> {
+    let object = &objects[index];
+    let pivot = pivots[index];
+    let origin_raw = to_raw(pivot);
+    let parent_raw = parent.map_or([0; 3], |parent| to_raw(pivots[parent]));
+
+    // only the vertexes the piece's faces use, in the order they're used
+    let mut local: HashMap<usize, u16> = HashMap::new();
+    let mut piece_vertexes = Vec::new();
+    let mut faces = Vec::new();
+    let mut primitives = Vec::new();
+    for (material, corners) in &object.faces {
+        let mut vertex_indexes = Vec::new();
+        for &corner in corners {
+            let next = piece_vertexes.len();
+            let local_index = *local.entry(corner).or_insert(next as u16);
+            if local_index as usize == next {
+                if next > u16::MAX as usize {
+                    return Err(format!(
+                        "piece {} has more than {} vertexes",
+                        object.name,
+                        u16::MAX as usize + 1
+                    ));
+                }
+                piece_vertexes.push(vertexes[corner]);
+            }
+            vertex_indexes.push(local_index);
+        }
+
+        let material = material.clone().unwrap_or(Material::Colour(default_colour));
+        primitives.push(RawPrimitive {
+            color_index: match material {
+                Material::Colour(index) => index as u32,
+                Material::Texture(_) => 0,
+            },
+            is_colored: matches!(material, Material::Colour(_)) as u32,
+            texture_name: match &material {
+                Material::Texture(name) => Some(name.as_bytes().to_vec()),
+                Material::Colour(_) => None,
+            },
+            vertex_indexes: vertex_indexes.clone(),
+            always_0: 0,
+            unknown_1: 0,
+            unknown_2: 0,
+        });
+        faces.push(Face {
+            material,
+            texture: None,
+            vertex_indexes,
+            uvs: Vec::new(),
+        });
+    }
+
+    // a dotted name's last part is the piece's own name
+    let name = match &object.parent {
+        None if object.dotted => object.name.rsplit('.').next().unwrap_or_default(),
+        _ => &object.name,
+    }
+    .to_string();
+    let raw_vertexes = piece_vertexes
+        .iter()
+        .map(|&v| {
+            let raw = to_raw(v);
+            [0, 1, 2].map(|i| raw[i] - origin_raw[i])
+        })
+        .collect();
+    let children = children[index]
+        .iter()
+        .map(|&child| {
+            build_piece(
+                objects,
+                children,
+                vertexes,
+                pivots,
+                child,
+                Some(index),
+                default_colour,
+            )
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Piece {
+        raw: RawPiece {
+            version_signature: TA_VERSION_SIGNATURE,
+            name: Some(name.as_bytes().to_vec()),
+            from_parent: [0, 1, 2].map(|i| origin_raw[i] - parent_raw[i]),
+            // no primitive is the selection box
+            selection_primitive: u32::MAX,
+            always_0: 0,
+            vertexes: raw_vertexes,
+            primitives,
+        },
+        name,
+        origin: pivot,
+        vertexes: piece_vertexes,
+        faces,
+        children,
+    })
+}
+
+/// Lays objects out as the pieces of a 3do. An object's parent is the one
+/// the file names, or else the one named by everything before the last dot
+/// of its name, `base.turret` being `turret` under `base`, made empty when
+/// the file doesn't have it. An object without a pivot pivots where its
+/// parent does.
+pub fn objects_to_pieces(
+    mut objects: Vec<Object>,
+    vertexes: &[[f64; 3]],
+    default_colour: usize,
+) -> Result<Vec<Piece>, String> {
+    let mut parents: Vec<Option<usize>> = Vec::new();
+    let mut i = 0;
+    while i < objects.len() {
+        let parent_name = match &objects[i].parent {
+            Some(parent) => Some(parent.clone()),
+            None if objects[i].dotted => objects[i]
+                .name
+                .rsplit_once('.')
+                .map(|(parent, _)| parent.to_string()),
+            None => None,
+        };
+        let parent = match parent_name {
+            None => None,
+            Some(parent_name) => match objects.iter().position(|o| o.name == parent_name) {
+                Some(parent) => Some(parent),
+                None => {
+                    objects.push(Object {
+                        name: parent_name,
+                        dotted: true,
+                        ..Object::default()
+                    });
+                    Some(objects.len() - 1)
+                }
+            },
+        };
+        if parent == Some(i) {
+            return Err(format!("object {} is its own parent", objects[i].name));
+        }
+        parents.push(parent);
+        i += 1;
+    }
+
+    // pivots in place, following parents up to one that's known
+    let mut pivots = Vec::new();
+    for i in 0..objects.len() {
+        let mut ancestor = Some(i);
+        let mut steps = 0;
+        let pivot = loop {
+            match ancestor {
+                Some(a) if steps <= objects.len() => match objects[a].pivot {
+                    Some(pivot) => break pivot,
+                    None => ancestor = parents[a],
+                },
+                Some(_) => return Err(format!("object {} is its own ancestor", objects[i].name)),
+                None => break [0.0; 3],
+            }
+            steps += 1;
+        };
+        pivots.push(pivot);
+    }
+
+    let mut children = vec![Vec::new(); objects.len()];
+    let mut roots = Vec::new();
+    for (i, parent) in parents.iter().enumerate() {
+        match parent {
+            Some(parent) => children[*parent].push(i),
+            None => roots.push(i),
+        }
+    }
+    roots
+        .into_iter()
+        .map(|root| {
+            build_piece(
+                &objects,
+                &children,
+                vertexes,
+                &pivots,
+                root,
+                None,
+                default_colour,
+            )
+        })
+        .collect()
+}
+
+fn count_primitives(pieces: &[Piece]) -> (usize, usize) {
+    pieces.iter().fold((0, 0), |(count, primitives), piece| {
+        let (child_count, child_primitives) = count_primitives(&piece.children);
+        (
+            count + 1 + child_count,
+            primitives + piece.raw.primitives.len() + child_primitives,
+        )
+    })
+}
+
+/// Converts an obj, or a gltf or glb, back into a 3do at `output`, or
+/// beside it. Materials named like the converter names colours become
+/// coloured primitives, any other material is taken as a texture name, and
+/// faces without one get `default_colour`. A 3do has no uvs, the game
+/// stretching each texture over its primitive, so the file's are dropped.
+pub fn to_3do(path: &str, output: Option<&str>, default_colour: usize) -> Result<(), String> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let (objects, vertexes) = match extension.as_deref() {
+        Some("gltf" | "glb") => read_gltf(path)?,
+        _ => {
+            let obj = fs::read_to_string(path)
+                .map_err(|err| format!("unable to read {}: {}", path, err))?;
+            read_obj(&obj).map_err(|err| format!("{}: {}", path, err))?
+        }
+    };
+    let pieces = objects_to_pieces(objects, &vertexes, default_colour)
+        .map_err(|err| format!("{}: {}", path, err))?;
+    if pieces.is_empty() {
+        return Err(format!("{} has no faces to convert", path));
+    }
+
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(path)
+            .with_extension("3do")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let data = write_3do(&pieces);
+    write_file(&output, |file| file.write_all(&data))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+
+    let (count, primitives) = count_primitives(&pieces);
+    println!(
+        "Wrote {}: {} pieces, {} primitives",
+        output, count, primitives
+    );
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io::Write;
+
+use ta_3do_to_obj::{read_3do, write_3do, Piece};
+
+use crate::output::write_file;
+use crate::FIXED_POINT_SCALE;
+
+/// `value` times `scale`, failing when that no longer fits a 3do.
+fn scaled(value: i32, scale: f64) -> Result<i32, String> {
+    let scaled = (value as f64 * scale).round();
+    if scaled < i32::MIN as f64 || scaled > i32::MAX as f64 {
+        return Err(format!(
+            "scaling {} units by {} is too far out for a 3do",
+            value as f64 / *FIXED_POINT_SCALE,
+            scale
+        ));
+    }
+    Ok(scaled as i32)
+}
+
+/// Scales every vertex and offset of `pieces` and everything under them.
+fn scale_pieces(pieces: &mut [Piece], scale: f64) -> Result<(), String> {
+    for piece in pieces {
+        for coordinate in piece
+            .raw
+            .vertexes
+            .iter_mut()
+            .flatten()
+            .chain(&mut piece.raw.from_parent)
+        {
+            *coordinate = scaled(*coordinate, scale)?;
+        }
+        scale_pieces(&mut piece.children, scale)?;
+    }
+    Ok(())
+}
+
+/// Writes the 3do at `input` to `output` scaled by `scale` about its origin,
+/// vertexes and piece offsets alike so pieces stay joined where they were,
+/// then moved by `translate` game units. Moving the top pieces moves the
+/// rest with them, so only their offsets change for that.
+pub fn transform_3do(
+    input: &str,
+    output: &str,
+    scale: Option<f64>,
+    translate: Option<[f64; 3]>,
+) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| format!("unable to read {}: {}", input, err))?;
+    let mut model = read_3do(&data).map_err(|err| format!("{}: {}", input, err))?;
+
+    if let Some(scale) = scale {
+        scale_pieces(&mut model.pieces, scale).map_err(|err| format!("{}: {}", input, err))?;
+    }
+    if let Some(translate) = translate {
+        for root in &mut model.pieces {
+            for (coordinate, by) in root.raw.from_parent.iter_mut().zip(translate) {
+                *coordinate = coordinate.saturating_add((by * *FIXED_POINT_SCALE).round() as i32);
+            }
+        }
+    }
+
+    let data = write_3do(&model);
+    write_file(output, |file| file.write_all(&data))
+        .map_err(|err| format!("unable to write {}: {}", output, err))?;
+    println!("Wrote {}", output);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+
+use crate::model::{Material, Model};
+use crate::output::write_file;
+
+/// Where texture usage is gathered across conversions, in the working folder.
+const USAGE_FILE: &str = "texture_usage.json";
+const SUMMARY_FILE: &str = "texture_usage.txt";
+
+#[derive(Serialize, Deserialize)]
+struct TextureUse {
+    model: String,
+    faces: u32,
+    /// whether the texture was found in a gaf when the model was converted
+    found: bool,
+}
+
+/// Which models use each texture and on how many faces, kept up to date as
+/// models are converted with --texture-usage.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TextureUsage {
+    textures: BTreeMap<String, Vec<TextureUse>>,
+}
+
+impl TextureUsage {
+    pub fn load() -> TextureUsage {
+        fs::read(USAGE_FILE)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Replaces what was recorded for `input` with the textures its model
+    /// uses now.
+    pub fn record(&mut self, input: &str, model: &Model, found: impl Fn(&str) -> bool) {
+        for uses in self.textures.values_mut() {
+            uses.retain(|texture_use| texture_use.model != input);
+        }
+        self.textures.retain(|_, uses| !uses.is_empty());
+
+        let mut faces: BTreeMap<&str, u32> = BTreeMap::new();
+        for piece in model.all_pieces() {
+            for face in &piece.faces {
+                if let Material::Texture(name) = &face.material {
+                    *faces.entry(name).or_default() += 1;
+                }
+            }
+        }
+        for (name, faces) in faces {
+            self.textures
+                .entry(name.to_string())
+                .or_default()
+                .push(TextureUse {
+                    model: input.to_string(),
+                    faces,
+                    found: found(name),
+                });
+        }
+    }
+
+    /// A line per texture, most used first, with the models using it.
+    fn summary(&self) -> String {
+        let mut textures: Vec<(&String, &Vec<TextureUse>)> = self.textures.iter().collect();
+        let total = |uses: &Vec<TextureUse>| uses.iter().map(|u| u.faces).sum::<u32>();
+        textures.sort_by_key(|(_, uses)| std::cmp::Reverse(total(uses)));
+
+        let mut summary = String::new();
+        for (name, uses) in textures {
+            let never_found = if uses.iter().any(|u| u.found) {
+                ""
+            } else {
+                ", never found"
+            };
+            writeln!(
+                summary,
+                "{} {} faces in {} models{}",
+                name,
+                total(uses),
+                uses.len(),
+                never_found
+            )
+            .unwrap();
+            for texture_use in uses {
+                writeln!(summary, "    {} {}", texture_use.model, texture_use.faces).unwrap();
+            }
+        }
+        summary
+    }
+
+    pub fn save(&self) {
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        let summary = self.summary();
+        for (path, data) in [(USAGE_FILE, &data[..]), (SUMMARY_FILE, summary.as_bytes())] {
+            if let Err(err) = write_file(path, |file| file.write_all(data)) {
+                warning!("unable to write {}: {}", path, err);
+            }
+        }
+    }
+}
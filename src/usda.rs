@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::exporter::{name_prefix, Exporter, Scene};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model, Piece};
+use crate::output::{dot_relative_path, PendingFiles};
+use crate::textures::{convert_texture, png_bytes, ImageFormat};
+use crate::{texture_folder, Args, DedupeMode, TextureFallback};
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+/// A material as it goes in the stage's Materials scope.
+struct UsdMaterial {
+    prim: String,
+    /// full path of the prim, for binding it
+    path: String,
+    diffuse: [f32; 3],
+    emissive: bool,
+    /// texture path relative to the stage, for textured materials
+    texture: Option<String>,
+}
+
+/// Turns a name into a valid prim name, only letters, digits and
+/// underscores and not starting with a digit, unique among `taken`.
+pub fn prim_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut prim: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !prim.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        prim.insert(0, '_');
+    }
+
+    let mut unique = prim.clone();
+    let mut suffix = 1;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}_{}", prim, suffix);
+        suffix += 1;
+    }
+    unique
+}
+
+fn list<T>(values: &[T], format: impl Fn(&T) -> String) -> String {
+    let values: Vec<String> = values.iter().map(format).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn write_material(usda_writter: &mut impl Write, material: &UsdMaterial) -> io::Result<()> {
+    let path = &material.path;
+    let [r, g, b] = material.diffuse;
+
+    writeln!(usda_writter, "        def Material \"{}\"", material.prim)?;
+    writeln!(usda_writter, "        {{")?;
+    writeln!(
+        usda_writter,
+        "            token outputs:surface.connect = <{}/Surface.outputs:surface>",
+        path
+    )?;
+    writeln!(usda_writter)?;
+    writeln!(usda_writter, "            def Shader \"Surface\"")?;
+    writeln!(usda_writter, "            {{")?;
+    writeln!(
+        usda_writter,
+        "                uniform token info:id = \"UsdPreviewSurface\""
+    )?;
+    match &material.texture {
+        Some(_) => writeln!(
+            usda_writter,
+            "                color3f inputs:diffuseColor.connect = <{}/Texture.outputs:rgb>",
+            path
+        )?,
+        None => writeln!(
+            usda_writter,
+            "                color3f inputs:diffuseColor = ({}, {}, {})",
+            r, g, b
+        )?,
+    }
+    if material.emissive {
+        writeln!(
+            usda_writter,
+            "                color3f inputs:emissiveColor = ({}, {}, {})",
+            r, g, b
+        )?;
+    }
+    writeln!(usda_writter, "                float inputs:roughness = 1")?;
+    writeln!(usda_writter, "                token outputs:surface")?;
+    writeln!(usda_writter, "            }}")?;
+
+    if let Some(texture) = &material.texture {
+        writeln!(usda_writter)?;
+        writeln!(usda_writter, "            def Shader \"UvReader\"")?;
+        writeln!(usda_writter, "            {{")?;
+        writeln!(
+            usda_writter,
+            "                uniform token info:id = \"UsdPrimvarReader_float2\""
+        )?;
+        writeln!(
+            usda_writter,
+            "                string inputs:varname = \"st\""
+        )?;
+        writeln!(usda_writter, "                float2 outputs:result")?;
+        writeln!(usda_writter, "            }}")?;
+        writeln!(usda_writter)?;
+        writeln!(usda_writter, "            def Shader \"Texture\"")?;
+        writeln!(usda_writter, "            {{")?;
+        writeln!(
+            usda_writter,
+            "                uniform token info:id = \"UsdUVTexture\""
+        )?;
+        writeln!(
+            usda_writter,
+            "                asset inputs:file = @{}@",
+            texture
+        )?;
+        writeln!(
+            usda_writter,
+            "                float2 inputs:st.connect = <{}/UvReader.outputs:result>",
+            path
+        )?;
+        writeln!(
+            usda_writter,
+            "                token inputs:sourceColorSpace = \"sRGB\""
+        )?;
+        writeln!(usda_writter, "                float3 outputs:rgb")?;
+        writeln!(usda_writter, "            }}")?;
+    }
+
+    writeln!(usda_writter, "        }}")?;
+    Ok(())
+}
+
+/// What every piece of the stage is written with.
+type Stage<'a> = Scene<'a, UsdMaterial>;
+
+/// Writes `piece` as an Xform translated from its parent's origin, holding
+/// its mesh and then its children. `taken` has the names of its siblings.
+fn write_piece(
+    stage: &Stage,
+    usda_writter: &mut impl Write,
+    piece: &Piece,
+    parent_origin: [f64; 3],
+    depth: usize,
+    taken: &mut HashSet<String>,
+) -> io::Result<()> {
+    let Stage {
+        prefix, materials, ..
+    } = stage;
+    let indent = "    ".repeat(depth);
+    let name = prim_name(&format!("{}{}", prefix, piece.name), taken);
+    let [x, y, z] = piece.origin;
+
+    writeln!(usda_writter, "{}def Xform \"{}\"", indent, name)?;
+    writeln!(usda_writter, "{}{{", indent)?;
+    writeln!(
+        usda_writter,
+        "{}    double3 xformOp:translate = ({}, {}, {})",
+        indent,
+        x - parent_origin[0],
+        y - parent_origin[1],
+        z - parent_origin[2]
+    )?;
+    writeln!(
+        usda_writter,
+        "{}    uniform token[] xformOpOrder = [\"xformOp:translate\"]",
+        indent
+    )?;
+
+    if !piece.faces.is_empty() {
+        let points = list(&piece.vertexes, |v| {
+            format!("({}, {}, {})", v[0] - x, v[1] - y, v[2] - z)
+        });
+        let counts = list(&piece.faces, |face| face.vertex_indexes.len().to_string());
+        let indices: Vec<u16> = piece
+            .faces
+            .iter()
+            .flat_map(|face| face.vertex_indexes.iter().copied())
+            .collect();
+
+        writeln!(usda_writter)?;
+        writeln!(usda_writter, "{}    def Mesh \"Mesh\"", indent)?;
+        writeln!(usda_writter, "{}    {{", indent)?;
+        writeln!(
+            usda_writter,
+            "{}        point3f[] points = {}",
+            indent, points
+        )?;
+        writeln!(
+            usda_writter,
+            "{}        int[] faceVertexCounts = {}",
+            indent, counts
+        )?;
+        writeln!(
+            usda_writter,
+            "{}        int[] faceVertexIndices = {}",
+            indent,
+            list(&indices, |i| i.to_string())
+        )?;
+
+        let uvs: Vec<[f64; 2]> = piece
+            .faces
+            .iter()
+            .flat_map(|face| stage.uvs(face))
+            .collect();
+        if !uvs.is_empty() {
+            writeln!(
+                usda_writter,
+                "{}        texCoord2f[] primvars:st = {} (",
+                indent,
+                list(&uvs, |uv| format!("({}, {})", uv[0], uv[1]))
+            )?;
+            writeln!(
+                usda_writter,
+                "{}            interpolation = \"faceVarying\"",
+                indent
+            )?;
+            writeln!(usda_writter, "{}        )", indent)?;
+        }
+        writeln!(
+            usda_writter,
+            "{}        uniform token subdivisionScheme = \"none\"",
+            indent
+        )?;
+
+        if !materials.is_empty() {
+            writeln!(
+                usda_writter,
+                "{}        uniform token subsetFamily:materialBind:familyType = \"nonOverlapping\"",
+                indent
+            )?;
+
+            // one subset per material, in the order the faces first use them
+            let mut subsets: Vec<(&UsdMaterial, Vec<usize>)> = Vec::new();
+            for (i, face) in piece.faces.iter().enumerate() {
+                let material = &materials[&stage.material_name(face)];
+                match subsets.iter_mut().find(|(m, _)| m.prim == material.prim) {
+                    Some((_, faces)) => faces.push(i),
+                    None => subsets.push((material, vec![i])),
+                }
+            }
+
+            for (material, faces) in subsets {
+                writeln!(usda_writter)?;
+                writeln!(
+                    usda_writter,
+                    "{}        def GeomSubset \"{}\" (",
+                    indent, material.prim
+                )?;
+                writeln!(
+                    usda_writter,
+                    "{}            prepend apiSchemas = [\"MaterialBindingAPI\"]",
+                    indent
+                )?;
+                writeln!(usda_writter, "{}        )", indent)?;
+                writeln!(usda_writter, "{}        {{", indent)?;
+                writeln!(
+                    usda_writter,
+                    "{}            uniform token elementType = \"face\"",
+                    indent
+                )?;
+                writeln!(
+                    usda_writter,
+                    "{}            uniform token familyName = \"materialBind\"",
+                    indent
+                )?;
+                writeln!(
+                    usda_writter,
+                    "{}            int[] indices = {}",
+                    indent,
+                    list(&faces, |i| i.to_string())
+                )?;
+                writeln!(
+                    usda_writter,
+                    "{}            rel material:binding = <{}>",
+                    indent, material.path
+                )?;
+                writeln!(usda_writter, "{}        }}", indent)?;
+            }
+        }
+        writeln!(usda_writter, "{}    }}", indent)?;
+    }
+
+    let mut child_taken = HashSet::from(["Mesh".to_string()]);
+    for child in &piece.children {
+        writeln!(usda_writter)?;
+        write_piece(
+            stage,
+            usda_writter,
+            child,
+            piece.origin,
+            depth + 1,
+            &mut child_taken,
+        )?;
+    }
+
+    writeln!(usda_writter, "{}}}", indent)?;
+    Ok(())
+}
+
+/// Texture files for a usdz to carry, by their path in the package.
+pub type Embedded = Vec<(String, Vec<u8>)>;
+
+/// Where a textured material's png is from the files named `output_stem`,
+/// converting the extracted bmp beside the stage, or with `embedded` reading
+/// it into the package.
+pub fn texture_file(
+    args: &Args,
+    texture: &str,
+    extracted: Option<&ExtractedTexture>,
+    file_prefix: &str,
+    output_stem: &str,
+    outputs: &mut Vec<String>,
+    embedded: Option<&mut Embedded>,
+) -> Option<String> {
+    let Some(embedded) = embedded else {
+        let file = match extracted {
+            Some(t) => {
+                let bmp = t.path();
+                match convert_texture(Path::new(&bmp), ImageFormat::Bmp, ImageFormat::Png) {
+                    Ok(png) => {
+                        let png = png.to_string_lossy().to_string();
+                        if !outputs.contains(&png) {
+                            outputs.push(png.clone());
+                        }
+                        png
+                    }
+                    Err(err) => {
+                        warning!("unable to convert {} to png, {}", bmp, err);
+                        bmp
+                    }
+                }
+            }
+            None => format!("{}{}{}.png", texture_folder(args), file_prefix, texture),
+        };
+        return Some(dot_relative_path(&file, output_stem));
+    };
+
+    let Some(extracted) = extracted else {
+        warning!(
+            "texture {} wasn't extracted, so it isn't in the usdz",
+            texture
+        );
+        return None;
+    };
+    let file = format!(
+        "textures/{}.png",
+        Path::new(&extracted.file)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+    );
+    if embedded.iter().any(|(path, _)| *path == file) {
+        return Some(file);
+    }
+    let bmp = extracted.path();
+    match png_bytes(Path::new(&bmp)) {
+        Ok(png) => {
+            embedded.push((file.clone(), png));
+            Some(file)
+        }
+        Err(err) => {
+            warning!("unable to put {} in the usdz, {}", bmp, err);
+            None
+        }
+    }
+}
+
+/// Writes the stage for `model` to `usda_writter`, adding the pngs it
+/// converts to `outputs`, or with `embedded` reading them into it instead.
+pub fn write_stage(
+    args: &Args,
+    model: &Model,
+    textures: &HashMap<String, ExtractedTexture>,
+    output_stem: &str,
+    usda_writter: &mut impl Write,
+    outputs: &mut Vec<String>,
+    mut embedded: Option<&mut Embedded>,
+) -> io::Result<()> {
+    let prefix = name_prefix(args, output_stem);
+    let file_prefix = if args.prefix_textures { &prefix } else { "" };
+
+    let mut root_taken = HashSet::new();
+    let file_name = Path::new(output_stem)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let root = prim_name(&file_name, &mut root_taken);
+
+    let mut materials = HashMap::new();
+    let mut material_prims = Vec::new();
+    let mut taken = HashSet::new();
+    if !args.no_mtl {
+        let palette = Palette::new(args.fullbright_range.clone());
+        for &colour_index in &model.used_colours {
+            let colour = palette.get(colour_index);
+            let name = Material::Colour(colour_index).name(args);
+            if materials.contains_key(&name) {
+                continue;
+            }
+            let prim = prim_name(&format!("{}{}", prefix, name), &mut taken);
+            material_prims.push(name.clone());
+            materials.insert(
+                name,
+                UsdMaterial {
+                    path: format!("/{}/Materials/{}", root, prim),
+                    prim,
+                    // UsdPreviewSurface colours are linear
+                    diffuse: colour_factors(colour, true),
+                    emissive: !args.no_emissive && palette.is_fullbright(colour_index),
+                    texture: None,
+                },
+            );
+        }
+
+        let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+        for texture in &model.used_textures {
+            let extracted_texture = textures.get(texture);
+            if merge_duplicates && extracted_texture.is_some_and(|t| t.duplicate_of.is_some()) {
+                continue;
+            }
+
+            let prim = prim_name(&format!("{}{}", prefix, texture), &mut taken);
+            material_prims.push(texture.clone());
+            materials.insert(
+                texture.clone(),
+                UsdMaterial {
+                    path: format!("/{}/Materials/{}", root, prim),
+                    prim,
+                    diffuse: [0.5, 0.5, 0.5],
+                    emissive: false,
+                    texture: if args.no_textures && args.texture_fallback == TextureFallback::Kd {
+                        None
+                    } else {
+                        texture_file(
+                            args,
+                            texture,
+                            extracted_texture,
+                            file_prefix,
+                            output_stem,
+                            outputs,
+                            embedded.as_deref_mut(),
+                        )
+                    },
+                },
+            );
+        }
+    }
+
+    writeln!(usda_writter, "#usda 1.0")?;
+    writeln!(usda_writter, "(")?;
+    writeln!(usda_writter, "    defaultPrim = \"{}\"", root)?;
+    writeln!(usda_writter, "    upAxis = \"Y\"")?;
+    writeln!(usda_writter, ")")?;
+    writeln!(usda_writter)?;
+    writeln!(usda_writter, "def Xform \"{}\"", root)?;
+    writeln!(usda_writter, "{{")?;
+
+    if !materials.is_empty() {
+        writeln!(usda_writter, "    def Scope \"Materials\"")?;
+        writeln!(usda_writter, "    {{")?;
+        for (i, name) in material_prims.iter().enumerate() {
+            if i > 0 {
+                writeln!(usda_writter)?;
+            }
+            write_material(usda_writter, &materials[name])?;
+        }
+        writeln!(usda_writter, "    }}")?;
+    }
+
+    let stage = Stage {
+        args,
+        prefix,
+        materials,
+        textures,
+    };
+    let mut piece_taken = HashSet::from(["Materials".to_string()]);
+    for piece in &model.pieces {
+        writeln!(usda_writter)?;
+        write_piece(&stage, usda_writter, piece, [0.0; 3], 1, &mut piece_taken)?;
+    }
+
+    writeln!(usda_writter, "}}")?;
+    Ok(())
+}
+
+/// Writes an ascii usd stage with an Xform per piece and UsdPreviewSurface
+/// materials bound to face subsets. Textures are referenced as pngs, made
+/// from the extracted bmps.
+pub struct UsdaExporter;
+
+impl Exporter for UsdaExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let usda_path = output_stem.to_owned() + ".usda";
+        let mut outputs = vec![usda_path.clone()];
+
+        let file = pending.create(&usda_path)?;
+        let mut usda_writter = BufWriter::new(file);
+        write_stage(
+            args,
+            model,
+            textures,
+            output_stem,
+            &mut usda_writter,
+            &mut outputs,
+            None,
+        )?;
+        usda_writter.flush()?;
+
+        Ok(outputs)
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::exporter::Exporter;
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::Model;
+use crate::output::PendingFiles;
+use crate::usda::{write_stage, Embedded};
+use crate::zip::zip_stored;
+use crate::Args;
+
+/// Usdz readers map files straight out of the package, so each one's data
+/// starts on a multiple of this.
+const USDZ_ALIGNMENT: usize = 64;
+
+/// Writes the usda stage packed into a usdz with its textures as pngs, for
+/// AR Quick Look and Omniverse.
+pub struct UsdzExporter;
+
+impl Exporter for UsdzExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let usdz_path = output_stem.to_owned() + ".usdz";
+        let stage_name = Path::new(output_stem)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+            + ".usda";
+
+        let mut stage = Vec::new();
+        let mut embedded = Embedded::new();
+        write_stage(
+            args,
+            model,
+            textures,
+            output_stem,
+            &mut stage,
+            &mut Vec::new(),
+            Some(&mut embedded),
+        )?;
+        // the stage has to be the first file in the package
+        embedded.insert(0, (stage_name, stage));
+
+        let file = pending.create(&usdz_path)?;
+        let mut usdz_writter = BufWriter::new(file);
+        usdz_writter.write_all(&zip_stored(&embedded, USDZ_ALIGNMENT))?;
+        usdz_writter.flush()?;
+
+        Ok(vec![usdz_path])
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+use std::fs;
+
+use ta_3do_to_obj::records::TA_VERSION_SIGNATURE;
+use ta_3do_to_obj::{read_3do, Piece};
+
+/// The counts over which a file fails, the same limits a conversion has.
+pub struct Limits {
+    pub vertexes: u32,
+    pub primitives: u32,
+    pub depth: u32,
+    pub objects: u32,
+    pub face_indexes: u32,
+}
+
+/// What's been seen of the tree so far, across every piece.
+#[derive(Default)]
+struct Walk {
+    names: HashSet<String>,
+    count: usize,
+    /// levels of pieces down to the deepest one, 1 for a root on its own
+    depth: u32,
+}
+
+/// Adds what's wrong with `pieces` and everything under them to `problems`.
+/// `depth` is 1 for the root and its siblings.
+fn check_pieces(
+    pieces: &[Piece],
+    depth: u32,
+    limits: &Limits,
+    walk: &mut Walk,
+    problems: &mut Vec<String>,
+) {
+    for piece in pieces {
+        walk.count += 1;
+        walk.depth = walk.depth.max(depth);
+        let raw = &piece.raw;
+        let Some(name) = &raw.name else {
+            problems.push("a piece's name runs past the end of the file".to_string());
+            continue;
+        };
+        let name = String::from_utf8_lossy(name).into_owned();
+        if name.is_empty() {
+            problems.push("a piece has no name".to_string());
+        } else if !walk.names.insert(name.to_ascii_lowercase()) {
+            problems.push(format!(
+                "more than one piece is named {}, scripts can only find one",
+                name
+            ));
+        }
+        if raw.version_signature != TA_VERSION_SIGNATURE {
+            problems.push(format!(
+                "{} has version signature {:#010x} instead of {:#010x}",
+                name, raw.version_signature, TA_VERSION_SIGNATURE
+            ));
+        }
+        if raw.always_0 != 0 {
+            problems.push(format!(
+                "{} has always_0 = {:#x}, the file may be corrupt",
+                name, raw.always_0
+            ));
+        }
+        if raw.vertexes.len() > limits.vertexes as usize {
+            problems.push(format!(
+                "{} has {} vertexes, more than {}",
+                name,
+                raw.vertexes.len(),
+                limits.vertexes
+            ));
+        }
+        if raw.primitives.len() > limits.primitives as usize {
+            problems.push(format!(
+                "{} has {} primitives, more than {}",
+                name,
+                raw.primitives.len(),
+                limits.primitives
+            ));
+        }
+        if raw.selection_primitive != u32::MAX
+            && raw.selection_primitive as usize >= raw.primitives.len()
+        {
+            problems.push(format!(
+                "{} is selected by primitive {}, but has {}",
+                name,
+                raw.selection_primitive,
+                raw.primitives.len()
+            ));
+        }
+
+        let mut used = vec![false; raw.vertexes.len()];
+        for (i, primitive) in raw.primitives.iter().enumerate() {
+            if primitive.vertex_indexes.len() > limits.face_indexes as usize {
+                problems.push(format!(
+                    "primitive {} of {} has {} corners, more than {}",
+                    i,
+                    name,
+                    primitive.vertex_indexes.len(),
+                    limits.face_indexes
+                ));
+            }
+            for &index in &primitive.vertex_indexes {
+                if let Some(used) = used.get_mut(index as usize) {
+                    *used = true;
+                }
+            }
+            if let Some(&index) = primitive
+                .vertex_indexes
+                .iter()
+                .find(|&&index| index as usize >= raw.vertexes.len())
+            {
+                problems.push(format!(
+                    "primitive {} of {} uses vertex {}, but the piece has {}",
+                    i,
+                    name,
+                    index,
+                    raw.vertexes.len()
+                ));
+            }
+            match &primitive.texture_name {
+                Some(texture) if texture.is_empty() => problems.push(format!(
+                    "primitive {} of {} names an empty texture",
+                    i, name
+                )),
+                Some(_) if primitive.is_colored != 0 => problems.push(format!(
+                    "primitive {} of {} has both a colour and a texture, --material-priority picks one",
+                    i, name
+                )),
+                _ => {}
+            }
+            for (field, value) in [
+                ("always_0", primitive.always_0),
+                ("unknown_1", primitive.unknown_1),
+                ("unknown_2", primitive.unknown_2),
+            ] {
+                if value != 0 {
+                    problems.push(format!(
+                        "primitive {} of {} has {} = {:#x} instead of 0",
+                        i, name, field, value
+                    ));
+                }
+            }
+        }
+        // the vertexes of a piece without primitives, like a flare, are
+        // there for scripts to find
+        let unused = used.iter().filter(|&&used| !used).count();
+        if !raw.primitives.is_empty() && unused > 0 {
+            problems.push(format!(
+                "{} has {} vertexes no primitive uses",
+                name, unused
+            ));
+        }
+        check_pieces(&piece.children, depth + 1, limits, walk, problems);
+    }
+}
+
+/// What's wrong with the 3do at `path`, nothing for a file the game and
+/// a conversion with `limits` would both take as it is.
+fn problems(path: &str, limits: &Limits) -> Vec<String> {
+    let model = match fs::read(path)
+        .map_err(|err| format!("unable to read it: {}", err))
+        .and_then(|data| read_3do(&data))
+    {
+        Ok(model) => model,
+        Err(err) => return vec![err],
+    };
+    let mut walk = Walk::default();
+    let mut problems = Vec::new();
+    check_pieces(&model.pieces, 1, limits, &mut walk, &mut problems);
+    if walk.depth > limits.depth {
+        problems.push(format!(
+            "{} levels of pieces, more than {}",
+            walk.depth, limits.depth
+        ));
+    }
+    if walk.count > limits.objects as usize {
+        problems.push(format!(
+            "{} pieces, more than {}",
+            walk.count, limits.objects
+        ));
+    }
+    problems
+}
+
+/// Checks each of `files` and prints a pass or fail line for it, with what's
+/// wrong under a failing one. Returns whether every file passed.
+pub fn validate_3do(files: &[String], limits: &Limits) -> bool {
+    let mut valid = true;
+    for file in files {
+        let problems = problems(file, limits);
+        if problems.is_empty() {
+            println!("ok    {}", file);
+            continue;
+        }
+        valid = false;
+        println!("FAIL  {}", file);
+        for problem in problems {
+            println!("      {}", problem);
+        }
+    }
+    valid
+}
@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Checks that a face corner like `3/7` or `-1/-1/-1` points at elements that
+/// exist, given how many of each the file has.
+fn check_corner(corner: &str, counts: [usize; 3], line: &str, problems: &mut Vec<String>) {
+    let kinds = ["vertex", "uv", "normal"];
+    for (i, index) in corner.split('/').enumerate().take(3) {
+        if index.is_empty() {
+            continue;
+        }
+        let valid = match index.parse::<i64>() {
+            Ok(index) if index > 0 => index as usize <= counts[i],
+            Ok(index) if index < 0 => index.unsigned_abs() as usize <= counts[i],
+            _ => false,
+        };
+        if !valid {
+            problems.push(format!(
+                "{}: {} index {} is out of range, there are {}",
+                line, kinds[i], index, counts[i]
+            ));
+        }
+    }
+}
+
+/// Re-reads a written obj and its mtl and returns a description of every
+/// problem found, each starting with the file and line it's on.
+pub fn verify_output(obj_path: &str, check_textures: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    let obj = match fs::read_to_string(obj_path) {
+        Ok(obj) => obj,
+        Err(err) => return vec![format!("{}: unable to read, {}", obj_path, err)],
+    };
+    let obj_folder = Path::new(obj_path).parent().unwrap_or(Path::new(""));
+
+    let mut counts = [0; 3];
+    let mut faces = Vec::new();
+    let mut used_materials = Vec::new();
+    let mut mtllibs = Vec::new();
+    for (number, line) in obj.lines().enumerate() {
+        let location = format!("{}:{}", obj_path, number + 1);
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => counts[0] += 1,
+            Some("vt") => counts[1] += 1,
+            Some("vn") => counts[2] += 1,
+            Some("f") => {
+                let corners: Vec<String> = tokens.map(|t| t.to_string()).collect();
+                if corners.len() < 3 {
+                    problems.push(format!(
+                        "{}: face has only {} corners",
+                        location,
+                        corners.len()
+                    ));
+                }
+                faces.push((location, corners));
+            }
+            Some("usemtl") => used_materials.push((location, tokens.collect::<Vec<_>>().join(" "))),
+            Some("mtllib") => mtllibs.push(tokens.collect::<Vec<_>>().join(" ")),
+            _ => {}
+        }
+    }
+
+    for (location, corners) in &faces {
+        for corner in corners {
+            check_corner(corner, counts, location, &mut problems);
+        }
+    }
+
+    // without a library usemtl lines only group faces
+    if mtllibs.is_empty() {
+        return problems;
+    }
+
+    let mut materials = HashSet::new();
+    for mtllib in &mtllibs {
+        let mtl_path = obj_folder.join(mtllib);
+        let mtl_name = mtl_path.to_string_lossy();
+        let mtl = match fs::read_to_string(&mtl_path) {
+            Ok(mtl) => mtl,
+            Err(err) => {
+                problems.push(format!("{}: unable to read, {}", mtl_name, err));
+                continue;
+            }
+        };
+        let mtl_folder = mtl_path.parent().unwrap_or(Path::new(""));
+
+        for (number, line) in mtl.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("newmtl") => {
+                    materials.insert(tokens.collect::<Vec<_>>().join(" "));
+                }
+                Some("map_Kd") if check_textures => {
+                    let texture = tokens.last().unwrap_or_default();
+                    if !mtl_folder.join(texture).exists() {
+                        problems.push(format!(
+                            "{}:{}: texture {} doesn't exist",
+                            mtl_name,
+                            number + 1,
+                            texture
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (location, material) in used_materials {
+        if !materials.contains(&material) {
+            problems.push(format!(
+                "{}: material {} isn't in the mtl",
+                location, material
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Rebuilds where every vertex of an obj written with --embed-transforms
+/// ends up from its `# transform:` comments, and returns each way that
+/// differs from `pieces`, the name and vertexes of each piece as the
+/// model has them in place.
+pub fn check_transforms(obj_path: &str, pieces: &[(String, &[[f64; 3]])]) -> Vec<String> {
+    let obj = match fs::read_to_string(obj_path) {
+        Ok(obj) => obj,
+        Err(err) => return vec![format!("{}: unable to read, {}", obj_path, err)],
+    };
+
+    let mut problems = Vec::new();
+    let mut pivots: HashMap<String, [f64; 3]> = HashMap::new();
+    let mut transform: Option<(String, [f64; 3])> = None;
+    // name, pivot and vertexes in place of each object with a transform
+    let mut objects: Vec<(String, [f64; 3], Vec<[f64; 3]>)> = Vec::new();
+    let mut in_piece = false;
+    for (number, line) in obj.lines().enumerate() {
+        let location = format!("{}:{}", obj_path, number + 1);
+        if let Some(fields) = line.strip_prefix("# transform:") {
+            let (parent, offset) = match fields.trim().split_once("offset=") {
+                Some((parent, offset)) => (parent.trim().strip_prefix("parent="), offset),
+                None => {
+                    problems.push(format!("{}: transform has no offset", location));
+                    continue;
+                }
+            };
+            let offset: Vec<f64> = offset
+                .split_whitespace()
+                .filter_map(|value| value.parse().ok())
+                .collect();
+            let [x, y, z] = offset[..] else {
+                problems.push(format!("{}: transform offset isn't 3 numbers", location));
+                continue;
+            };
+            let base = match parent {
+                None => [0.0; 3],
+                Some(parent) => match pivots.get(parent) {
+                    Some(pivot) => *pivot,
+                    None => {
+                        problems.push(format!(
+                            "{}: parent {} isn't an object before it",
+                            location, parent
+                        ));
+                        [0.0; 3]
+                    }
+                },
+            };
+            transform = Some((location, [base[0] + x, base[1] + y, base[2] + z]));
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("o") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                in_piece = transform.is_some();
+                if let Some((_, pivot)) = transform.take() {
+                    pivots.insert(name.clone(), pivot);
+                    objects.push((name, pivot, Vec::new()));
+                }
+            }
+            Some("v") if in_piece => {
+                let values: Vec<f64> = tokens.filter_map(|value| value.parse().ok()).collect();
+                if let (Some((_, pivot, vertexes)), &[x, y, z]) = (objects.last_mut(), &values[..])
+                {
+                    vertexes.push([pivot[0] + x, pivot[1] + y, pivot[2] + z]);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((location, _)) = transform {
+        problems.push(format!(
+            "{}: transform isn't followed by an object",
+            location
+        ));
+    }
+
+    if objects.len() != pieces.len() {
+        problems.push(format!(
+            "{}: has {} objects with a transform but the model has {} pieces",
+            obj_path,
+            objects.len(),
+            pieces.len()
+        ));
+    }
+    for ((name, _, vertexes), (piece_name, piece_vertexes)) in objects.iter().zip(pieces) {
+        if name != piece_name {
+            problems.push(format!(
+                "{}: object {} is where piece {} should be",
+                obj_path, name, piece_name
+            ));
+            continue;
+        }
+        let moved = vertexes.len() != piece_vertexes.len()
+            || vertexes
+                .iter()
+                .zip(piece_vertexes.iter())
+                .any(|(a, b)| (0..3).any(|i| (a[i] - b[i]).abs() > 1e-9 * (1.0 + b[i].abs())));
+        if moved {
+            problems.push(format!(
+                "{}: {} doesn't end up where the piece is once its transforms are applied",
+                obj_path, name
+            ));
+        }
+    }
+    problems
+}
@@ -0,0 +1,189 @@
+use std::borrow::Cow;
+
+use crate::palette::{Palette, Rgb, PALETTE};
+use crate::records::{
+    add_offset, check_count, check_tree, read_cstring, read_object, read_primitive,
+    read_vertex_indexes, read_vertexes, ObjectRecord, DEFAULT_MAX_DEPTH, DEFAULT_MAX_FACE_INDEXES,
+    DEFAULT_MAX_OBJECTS, DEFAULT_MAX_PRIMITIVES, DEFAULT_MAX_VERTEXES, OBJECT_SIZE, PRIMITIVE_SIZE,
+    TA_VERSION_SIGNATURE,
+};
+
+/// What a primitive is drawn with, picked the way a conversion with the
+/// default flags picks it: the texture when it has one, otherwise the colour.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Surface<'a> {
+    /// A palette colour. `rgb` is `None` for an index past the end of the
+    /// palette.
+    Colour { index: usize, rgb: Option<Rgb> },
+    /// name of a gaf entry
+    Texture(Cow<'a, str>),
+}
+
+impl Surface<'_> {
+    /// The name a conversion gives the material, the texture's name or the
+    /// colour's hex value and index.
+    pub fn material_name(&self) -> Cow<'_, str> {
+        match self {
+            Surface::Colour {
+                index,
+                rgb: Some(_),
+            } => Palette::default().name(*index).into(),
+            Surface::Colour { index, rgb: None } => format!("colour_{}", index).into(),
+            Surface::Texture(name) => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// A primitive as it's visited, borrowing from the file.
+pub struct Primitive<'a> {
+    /// `None` for primitives the game doesn't draw, which conversions skip
+    pub surface: Option<Surface<'a>>,
+    /// indexes into the vertexes of the piece, as visited
+    pub vertex_indexes: &'a [u16],
+}
+
+/// Callbacks for `visit_3do`, each doing nothing unless it's implemented.
+/// Pieces are visited parents first, each one's vertexes and primitives
+/// between its `piece_start` and `piece_end`, and its children after its
+/// `piece_end`.
+pub trait Visitor {
+    /// `origin` is where the piece pivots, in file units, and `depth` is 0
+    /// for the root and its siblings.
+    fn piece_start(&mut self, _name: &str, _origin: [i32; 3], _depth: u32) {}
+
+    /// A vertex of the current piece, moved into place, in file units.
+    fn vertex(&mut self, _position: [i32; 3]) {}
+
+    fn primitive(&mut self, _primitive: &Primitive) {}
+
+    fn piece_end(&mut self, _name: &str) {}
+}
+
+/// Visits every piece of a 3do in one pass over `data`, without building a
+/// `Model`. Nothing is kept between pieces, so the only memory it takes
+/// beyond the file is one piece's vertexes or one primitive's indexes at a
+/// time. Fails on a file that isn't a 3do or is over the default limits.
+pub fn visit_3do(data: &[u8], visitor: &mut impl Visitor) -> Result<(), String> {
+    if data.len() < OBJECT_SIZE {
+        return Err("too short to be a 3do file".to_string());
+    }
+    let version_signature = read_object(data, 0)?.version_signature;
+    if version_signature != TA_VERSION_SIGNATURE {
+        return Err(format!(
+            "version signature {:#010x}, it may not be a 3do file",
+            version_signature
+        ));
+    }
+
+    let mut objects = 0;
+    visit_siblings(data, 0, [0; 3], 0, &mut objects, visitor)
+}
+
+fn visit_siblings(
+    data: &[u8],
+    mut object_offset: u32,
+    parent: [i32; 3],
+    depth: u32,
+    objects: &mut u32,
+    visitor: &mut impl Visitor,
+) -> Result<(), String> {
+    loop {
+        let object = read_object(data, object_offset)?;
+        let origin = [
+            add_offset(parent[0], object.x_from_parent, "piece x offset")?,
+            add_offset(parent[1], object.y_from_parent, "piece y offset")?,
+            add_offset(parent[2], object.z_from_parent, "piece z offset")?,
+        ];
+
+        let name = match read_cstring(data, object.offset_to_object_name, 256) {
+            Ok(name) => String::from_utf8_lossy(name).into_owned(),
+            Err(_) => format!("piece_{:x}", object.offset_to_object_name),
+        };
+        *objects += 1;
+        check_tree(
+            &name,
+            object_offset,
+            depth + 1,
+            *objects,
+            DEFAULT_MAX_DEPTH,
+            DEFAULT_MAX_OBJECTS,
+        )?;
+
+        visitor.piece_start(&name, origin, depth);
+        visit_piece(data, &object, origin, visitor)
+            .map_err(|err| format!("piece {} has {}", name, err))?;
+        visitor.piece_end(&name);
+
+        if object.offset_to_child_object != 0 {
+            visit_siblings(
+                data,
+                object.offset_to_child_object,
+                origin,
+                depth + 1,
+                objects,
+                visitor,
+            )?;
+        }
+        if object.offset_to_sibling_object == 0 {
+            return Ok(());
+        }
+        object_offset = object.offset_to_sibling_object;
+    }
+}
+
+fn visit_piece(
+    data: &[u8],
+    object: &ObjectRecord,
+    origin: [i32; 3],
+    visitor: &mut impl Visitor,
+) -> Result<(), String> {
+    check_count(object.number_of_vertexes, DEFAULT_MAX_VERTEXES, "vertexes")?;
+    for v in read_vertexes(data, object)? {
+        visitor.vertex([origin[0] + v[0], origin[1] + v[1], origin[2] + v[2]]);
+    }
+
+    check_count(
+        object.number_of_primitives,
+        DEFAULT_MAX_PRIMITIVES,
+        "primitives",
+    )?;
+    for index in 0..object.number_of_primitives {
+        // one at a time, to keep to one primitive's worth of memory
+        let p = read_primitive(
+            data,
+            object.offset_to_primitive_array + index * PRIMITIVE_SIZE as u32,
+        )?;
+
+        check_count(
+            p.number_of_vertex_indexes,
+            DEFAULT_MAX_FACE_INDEXES,
+            "corners",
+        )
+        .map_err(|err| format!("primitive {} with {}", index, err))?;
+        let indexes = read_vertex_indexes(data, &p)
+            .map_err(|err| format!("primitive {} with {}", index, err))?;
+
+        // an empty name is no texture, as in read_model, and so is one that
+        // can't be read
+        let texture = match p.offset_to_texture_name {
+            0 => None,
+            offset => read_cstring(data, offset, 256)
+                .ok()
+                .filter(|texture| !texture.is_empty())
+                .map(String::from_utf8_lossy),
+        };
+        let surface = match texture {
+            Some(texture) => Some(Surface::Texture(texture)),
+            None if p.is_colored != 0 => Some(Surface::Colour {
+                index: p.color_index as usize,
+                rgb: PALETTE.get(p.color_index as usize).copied(),
+            }),
+            None => None,
+        };
+        visitor.primitive(&Primitive {
+            surface,
+            vertex_indexes: &indexes,
+        });
+    }
+    Ok(())
+}
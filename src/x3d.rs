@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufWriter, Write};
+
+use crate::exporter::{escape, name_prefix, Exporter, Scene};
+use crate::gaf_extractor::ExtractedTexture;
+use crate::model::{Material, Model, Piece};
+use crate::output::PendingFiles;
+use crate::usda::{prim_name, texture_file};
+use crate::{Args, ColorSpace, DedupeMode, TextureFallback};
+use ta_3do_to_obj::palette::{colour_factors, Palette};
+
+/// An appearance, written in full by the first shape using it and reused by
+/// name after that.
+struct Appearance {
+    def: String,
+    diffuse: [f32; 3],
+    emissive: bool,
+    /// png path relative to the scene, for textured materials
+    texture: Option<String>,
+}
+
+/// The faces of a piece that share an appearance, as a shape's indexed face
+/// set.
+struct Shape<'a> {
+    appearance: Option<&'a Appearance>,
+    /// corner indexes into the piece's points, each face ended by -1
+    coord_index: Vec<i64>,
+    /// uvs of the corners in order, when every face has them
+    uvs: Option<Vec<[f64; 2]>>,
+}
+
+impl Shape<'_> {
+    /// The uv of each corner is its own, numbered in order with the same -1
+    /// after each face.
+    fn tex_coord_index(&self) -> Vec<i64> {
+        let mut next = 0;
+        self.coord_index
+            .iter()
+            .map(|&i| {
+                if i < 0 {
+                    return -1;
+                }
+                next += 1;
+                next - 1
+            })
+            .collect()
+    }
+}
+
+fn join<T>(values: impl IntoIterator<Item = T>, format: impl Fn(T) -> String) -> String {
+    values.into_iter().map(format).collect::<Vec<_>>().join(" ")
+}
+
+/// Groups a piece's faces into a shape per appearance, in the order the
+/// faces first use them, with the points around the piece's origin.
+fn shapes<'a>(scene: &'a Scene<Appearance>, piece: &Piece) -> Vec<Shape<'a>> {
+    let mut shapes: Vec<Shape> = Vec::new();
+    for face in &piece.faces {
+        let appearance = scene.material(face);
+        let i = match shapes
+            .iter()
+            .position(|shape| shape.appearance.map(|a| &a.def) == appearance.map(|a| &a.def))
+        {
+            Some(i) => i,
+            None => {
+                shapes.push(Shape {
+                    appearance,
+                    coord_index: Vec::new(),
+                    uvs: Some(Vec::new()),
+                });
+                shapes.len() - 1
+            }
+        };
+        let shape = &mut shapes[i];
+        shape
+            .coord_index
+            .extend(face.vertex_indexes.iter().map(|&v| v as i64));
+        shape.coord_index.push(-1);
+
+        if face.uvs.len() != face.vertex_indexes.len() {
+            shape.uvs = None;
+        }
+        if let Some(uvs) = &mut shape.uvs {
+            uvs.extend(scene.uvs(face));
+        }
+    }
+    shapes
+}
+
+fn write_vrml_appearance(
+    wrl_writter: &mut impl Write,
+    indent: &str,
+    appearance: Option<&Appearance>,
+    written: &mut HashSet<String>,
+) -> io::Result<()> {
+    let Some(appearance) = appearance else {
+        writeln!(
+            wrl_writter,
+            "{}appearance Appearance {{ material Material {{ }} }}",
+            indent
+        )?;
+        return Ok(());
+    };
+    if !written.insert(appearance.def.clone()) {
+        writeln!(wrl_writter, "{}appearance USE {}", indent, appearance.def)?;
+        return Ok(());
+    }
+
+    let [r, g, b] = appearance.diffuse;
+    writeln!(
+        wrl_writter,
+        "{}appearance DEF {} Appearance {{",
+        indent, appearance.def
+    )?;
+    write!(
+        wrl_writter,
+        "{}  material Material {{ diffuseColor {} {} {}",
+        indent, r, g, b
+    )?;
+    if appearance.emissive {
+        write!(wrl_writter, " emissiveColor {} {} {}", r, g, b)?;
+    }
+    writeln!(wrl_writter, " }}")?;
+    if let Some(texture) = &appearance.texture {
+        writeln!(
+            wrl_writter,
+            "{}  texture ImageTexture {{ url \"{}\" }}",
+            indent, texture
+        )?;
+    }
+    writeln!(wrl_writter, "{}}}", indent)?;
+    Ok(())
+}
+
+fn write_x3d_appearance(
+    x3d_writter: &mut impl Write,
+    indent: &str,
+    appearance: Option<&Appearance>,
+    written: &mut HashSet<String>,
+) -> io::Result<()> {
+    let Some(appearance) = appearance else {
+        writeln!(
+            x3d_writter,
+            "{}<Appearance><Material/></Appearance>",
+            indent
+        )?;
+        return Ok(());
+    };
+    if !written.insert(appearance.def.clone()) {
+        writeln!(
+            x3d_writter,
+            "{}<Appearance USE=\"{}\"/>",
+            indent, appearance.def
+        )?;
+        return Ok(());
+    }
+
+    let [r, g, b] = appearance.diffuse;
+    writeln!(
+        x3d_writter,
+        "{}<Appearance DEF=\"{}\">",
+        indent, appearance.def
+    )?;
+    write!(
+        x3d_writter,
+        "{}  <Material diffuseColor=\"{} {} {}\"",
+        indent, r, g, b
+    )?;
+    if appearance.emissive {
+        write!(x3d_writter, " emissiveColor=\"{} {} {}\"", r, g, b)?;
+    }
+    writeln!(x3d_writter, "/>")?;
+    if let Some(texture) = &appearance.texture {
+        writeln!(
+            x3d_writter,
+            "{}  <ImageTexture url='\"{}\"'/>",
+            indent,
+            escape(texture)
+        )?;
+    }
+    writeln!(x3d_writter, "{}</Appearance>", indent)?;
+    Ok(())
+}
+
+/// Writes `piece` as a Transform translated from its parent's origin,
+/// holding a shape per appearance and then its children, as vrml97 with
+/// `vrml`. The piece's points are written with the first shape and reused by
+/// the others.
+fn write_piece(
+    scene: &Scene<Appearance>,
+    vrml: bool,
+    writter: &mut impl Write,
+    piece: &Piece,
+    parent: ([f64; 3], usize),
+    taken: &mut HashSet<String>,
+    written: &mut HashSet<String>,
+) -> io::Result<()> {
+    let (parent_origin, depth) = parent;
+    let indent = "  ".repeat(depth);
+    let def = prim_name(&format!("{}{}", scene.prefix, piece.name), taken);
+    let points_def = prim_name(&format!("{}_points", def), taken);
+    let [x, y, z] = piece.origin;
+    let translation = format!(
+        "{} {} {}",
+        x - parent_origin[0],
+        y - parent_origin[1],
+        z - parent_origin[2]
+    );
+    let points = join(&piece.vertexes, |v| {
+        format!("{} {} {}", v[0] - x, v[1] - y, v[2] - z)
+    });
+    let shapes = shapes(scene, piece);
+
+    if vrml {
+        writeln!(writter, "{}DEF {} Transform {{", indent, def)?;
+        writeln!(writter, "{}  translation {}", indent, translation)?;
+        writeln!(writter, "{}  children [", indent)?;
+    } else {
+        writeln!(
+            writter,
+            "{}<Transform DEF=\"{}\" translation=\"{}\">",
+            indent, def, translation
+        )?;
+    }
+
+    // vrml's shapes and children sit a level in, in the children list
+    let levels = if vrml { 2 } else { 1 };
+    let shape_indent = "  ".repeat(depth + levels);
+    let inner = format!("{}  ", shape_indent);
+    for (i, shape) in shapes.iter().enumerate() {
+        let coord_index = join(&shape.coord_index, |i| i.to_string());
+        let tex_coord_index = join(shape.tex_coord_index(), |i| i.to_string());
+        if vrml {
+            writeln!(writter, "{}Shape {{", shape_indent)?;
+            write_vrml_appearance(writter, &inner, shape.appearance, written)?;
+            writeln!(writter, "{}geometry IndexedFaceSet {{", inner)?;
+            writeln!(writter, "{}  solid FALSE", inner)?;
+            if i == 0 {
+                writeln!(
+                    writter,
+                    "{}  coord DEF {} Coordinate {{ point [ {} ] }}",
+                    inner, points_def, points
+                )?;
+            } else {
+                writeln!(writter, "{}  coord USE {}", inner, points_def)?;
+            }
+            writeln!(writter, "{}  coordIndex [ {} ]", inner, coord_index)?;
+            if let Some(uvs) = &shape.uvs {
+                writeln!(
+                    writter,
+                    "{}  texCoord TextureCoordinate {{ point [ {} ] }}",
+                    inner,
+                    join(uvs, |uv| format!("{} {}", uv[0], uv[1]))
+                )?;
+                writeln!(writter, "{}  texCoordIndex [ {} ]", inner, tex_coord_index)?;
+            }
+            writeln!(writter, "{}}}", inner)?;
+            writeln!(writter, "{}}}", shape_indent)?;
+        } else {
+            writeln!(writter, "{}<Shape>", shape_indent)?;
+            write_x3d_appearance(writter, &inner, shape.appearance, written)?;
+            write!(
+                writter,
+                "{}<IndexedFaceSet solid=\"false\" coordIndex=\"{}\"",
+                inner, coord_index
+            )?;
+            if shape.uvs.is_some() {
+                write!(writter, " texCoordIndex=\"{}\"", tex_coord_index)?;
+            }
+            writeln!(writter, ">")?;
+            if i == 0 {
+                writeln!(
+                    writter,
+                    "{}  <Coordinate DEF=\"{}\" point=\"{}\"/>",
+                    inner, points_def, points
+                )?;
+            } else {
+                writeln!(writter, "{}  <Coordinate USE=\"{}\"/>", inner, points_def)?;
+            }
+            if let Some(uvs) = &shape.uvs {
+                writeln!(
+                    writter,
+                    "{}  <TextureCoordinate point=\"{}\"/>",
+                    inner,
+                    join(uvs, |uv| format!("{} {}", uv[0], uv[1]))
+                )?;
+            }
+            writeln!(writter, "{}</IndexedFaceSet>", inner)?;
+            writeln!(writter, "{}</Shape>", shape_indent)?;
+        }
+    }
+
+    for child in &piece.children {
+        write_piece(
+            scene,
+            vrml,
+            writter,
+            child,
+            (piece.origin, depth + levels),
+            taken,
+            written,
+        )?;
+    }
+
+    if vrml {
+        writeln!(writter, "{}  ]", indent)?;
+        writeln!(writter, "{}}}", indent)?;
+    } else {
+        writeln!(writter, "{}</Transform>", indent)?;
+    }
+    Ok(())
+}
+
+/// Writes an x3d scene, or with `vrml` a vrml97 world, with a Transform per
+/// piece nested under its parent's, and an appearance per colour and
+/// texture. Textures are referenced as pngs, made from the extracted bmps.
+pub struct X3dExporter {
+    pub vrml: bool,
+}
+
+impl Exporter for X3dExporter {
+    fn export(
+        &self,
+        args: &Args,
+        model: &Model,
+        textures: &HashMap<String, ExtractedTexture>,
+        output_stem: &str,
+        pending: &mut PendingFiles,
+    ) -> io::Result<Vec<String>> {
+        let path = output_stem.to_owned() + if self.vrml { ".wrl" } else { ".x3d" };
+        let mut outputs = vec![path.clone()];
+        let prefix = name_prefix(args, output_stem);
+        let file_prefix = if args.prefix_textures { &prefix } else { "" };
+
+        // DEF names are shared by every node of the scene
+        let mut taken = HashSet::new();
+        let mut appearances = HashMap::new();
+        if !args.no_mtl {
+            let palette = Palette::new(args.fullbright_range.clone());
+            for &colour_index in &model.used_colours {
+                let name = Material::Colour(colour_index).name(args);
+                if appearances.contains_key(&name) {
+                    continue;
+                }
+                appearances.insert(
+                    name.clone(),
+                    Appearance {
+                        def: prim_name(&format!("{}{}", prefix, name), &mut taken),
+                        diffuse: colour_factors(
+                            palette.get(colour_index),
+                            args.color_space == ColorSpace::Linear,
+                        ),
+                        emissive: !args.no_emissive && palette.is_fullbright(colour_index),
+                        texture: None,
+                    },
+                );
+            }
+
+            let merge_duplicates = args.dedupe_textures == Some(DedupeMode::Merge);
+            let untextured = args.no_textures && args.texture_fallback == TextureFallback::Kd;
+            for texture in &model.used_textures {
+                let extracted = textures.get(texture);
+                if merge_duplicates && extracted.is_some_and(|t| t.duplicate_of.is_some()) {
+                    continue;
+                }
+                appearances.insert(
+                    texture.clone(),
+                    Appearance {
+                        def: prim_name(&format!("{}{}", prefix, texture), &mut taken),
+                        diffuse: [0.5, 0.5, 0.5],
+                        emissive: false,
+                        texture: if untextured {
+                            None
+                        } else {
+                            texture_file(
+                                args,
+                                texture,
+                                extracted,
+                                file_prefix,
+                                output_stem,
+                                &mut outputs,
+                                None,
+                            )
+                        },
+                    },
+                );
+            }
+        }
+
+        let scene = Scene {
+            args,
+            prefix,
+            materials: appearances,
+            textures,
+        };
+        let file = pending.create(&path)?;
+        let mut writter = BufWriter::new(file);
+        let generator = format!("ta-3do-to-obj {}", env!("CARGO_PKG_VERSION"));
+        let depth = if self.vrml {
+            writeln!(writter, "#VRML V2.0 utf8")?;
+            writeln!(writter, "# written by {}", generator)?;
+            writeln!(writter)?;
+            0
+        } else {
+            writeln!(writter, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+            writeln!(
+                writter,
+                "<!DOCTYPE X3D PUBLIC \"ISO//Web3D//DTD X3D 3.3//EN\" \"http://www.web3d.org/specifications/x3d-3.3.dtd\">"
+            )?;
+            writeln!(writter, "<X3D profile=\"Interchange\" version=\"3.3\">")?;
+            writeln!(writter, "  <head>")?;
+            writeln!(
+                writter,
+                "    <meta name=\"generator\" content=\"{}\"/>",
+                generator
+            )?;
+            writeln!(writter, "  </head>")?;
+            writeln!(writter, "  <Scene>")?;
+            2
+        };
+
+        let mut written = HashSet::new();
+        for piece in &model.pieces {
+            write_piece(
+                &scene,
+                self.vrml,
+                &mut writter,
+                piece,
+                ([0.0; 3], depth),
+                &mut taken,
+                &mut written,
+            )?;
+        }
+
+        if !self.vrml {
+            writeln!(writter, "  </Scene>")?;
+            writeln!(writter, "</X3D>")?;
+        }
+        writter.flush()?;
+
+        Ok(outputs)
+    }
+}
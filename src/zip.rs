@@ -0,0 +1,80 @@
+use flate2::Crc;
+use ta_3do_to_obj::push_u32;
+
+/// Id of the extra field that pads a local header out to the alignment.
+const PADDING_FIELD: u16 = 0x1986;
+
+/// 1980-01-01, the earliest date a zip holds, so packages of the same
+/// model are the same bytes.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Stores `files` uncompressed in a zip, in order, with each one's data
+/// starting on a multiple of `alignment` for readers that map it.
+pub fn zip_stored(files: &[(String, Vec<u8>)], alignment: usize) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut central = Vec::new();
+    for (name, data) in files {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let offset = zip.len();
+
+        // an extra field takes at least its own 4 byte header
+        let mut padding = (alignment - (offset + 30 + name.len()) % alignment) % alignment;
+        if padding > 0 && padding < 4 {
+            padding += alignment;
+        }
+
+        // version needed, flags, stored, time and date, then the sizes
+        let mut fields = Vec::new();
+        push_u16(&mut fields, 20);
+        push_u16(&mut fields, 0);
+        push_u16(&mut fields, 0);
+        push_u16(&mut fields, 0);
+        push_u16(&mut fields, DOS_DATE);
+        push_u32(&mut fields, crc.sum());
+        push_u32(&mut fields, data.len() as u32);
+        push_u32(&mut fields, data.len() as u32);
+        push_u16(&mut fields, name.len() as u16);
+
+        push_u32(&mut zip, 0x04034b50);
+        zip.extend_from_slice(&fields);
+        push_u16(&mut zip, padding as u16);
+        zip.extend_from_slice(name.as_bytes());
+        if padding > 0 {
+            push_u16(&mut zip, PADDING_FIELD);
+            push_u16(&mut zip, (padding - 4) as u16);
+            zip.resize(zip.len() + padding - 4, 0);
+        }
+        zip.extend_from_slice(data);
+
+        push_u32(&mut central, 0x02014b50);
+        // version made by
+        push_u16(&mut central, 20);
+        central.extend_from_slice(&fields);
+        // no extra field or comment, disk 0, no attributes
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u32(&mut central, 0);
+        push_u32(&mut central, offset as u32);
+        central.extend_from_slice(name.as_bytes());
+    }
+
+    let central_offset = zip.len();
+    zip.extend_from_slice(&central);
+    push_u32(&mut zip, 0x06054b50);
+    push_u16(&mut zip, 0);
+    push_u16(&mut zip, 0);
+    push_u16(&mut zip, files.len() as u16);
+    push_u16(&mut zip, files.len() as u16);
+    push_u32(&mut zip, central.len() as u32);
+    push_u32(&mut zip, central_offset as u32);
+    // no comment
+    push_u16(&mut zip, 0);
+    zip
+}
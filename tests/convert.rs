@@ -0,0 +1,801 @@
+//! End to end conversions of 3do and gaf files built by `support`, checking
+//! the obj, mtl and textures that come out.
+
+mod support;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Output;
+
+use support::{
+    bmp_pixels, build_3do, build_gaf, build_hpi, parse_mtl, parse_obj, Frame, Obj, Piece,
+    Workspace, TRANSPARENCY_INDEX, UNIT,
+};
+
+/// The first colours of the game palette, by index.
+const PALETTE: [[u8; 3]; 10] = [
+    [0x00, 0x00, 0x00],
+    [0x80, 0x00, 0x00],
+    [0x00, 0x80, 0x00],
+    [0x80, 0x80, 0x00],
+    [0x00, 0x00, 0x80],
+    [0x80, 0x00, 0x80],
+    [0x00, 0x80, 0x80],
+    [0x80, 0x80, 0x80],
+    [0xC0, 0xDC, 0xC0],
+    [0x54, 0x54, 0xFC],
+];
+
+/// A unit square on the ground, the way round the game draws its top.
+fn square(name: &str, offset: [i32; 3]) -> Piece {
+    Piece::new(name, offset).vertexes(&[[0, 0, 0], [UNIT, 0, 0], [UNIT, 0, UNIT], [0, 0, UNIT]])
+}
+
+/// A base with a turret on it, the turret with a barrel next to it.
+fn unit() -> Vec<u8> {
+    let barrel = Piece::new("barrel", [UNIT / 2, 0, 2 * UNIT])
+        .vertexes(&[[0, 0, 0], [0, UNIT, 0], [0, 0, UNIT]])
+        .colour(4, &[0, 1, 2]);
+    let turret = square("turret", [0, UNIT, 0]).texture("armtex1", &[0, 1, 2, 3]);
+    let base = square("base", [0, 0, 0])
+        .colour(3, &[0, 1, 2, 3])
+        .child(turret)
+        .child(barrel);
+    build_3do(&[base])
+}
+
+#[test]
+fn pieces_are_placed_by_their_parents() {
+    let workspace = Workspace::new("placed");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&["unit.3do", "--no-textures"]);
+
+    let obj = parse_obj(&workspace.read("unit.obj"));
+    assert_eq!(obj.objects, ["base", "turret", "barrel"]);
+    // x is mirrored, to turn the game's left handed coordinates around
+    let expected = [
+        [0.0, 0.0, 0.0],
+        [-1.0, 0.0, 0.0],
+        [-1.0, 0.0, 1.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 1.0, 0.0],
+        [-1.0, 1.0, 0.0],
+        [-1.0, 1.0, 1.0],
+        [0.0, 1.0, 1.0],
+        [-0.5, 0.0, 2.0],
+        [-0.5, 1.0, 2.0],
+        [-0.5, 0.0, 3.0],
+    ];
+    assert_eq!(obj.vertexes, expected);
+}
+
+/// A triangle with its first corner on the piece's pivot.
+fn marker(name: &str, offset: [i32; 3]) -> Piece {
+    Piece::new(name, offset)
+        .vertexes(&[[0, 0, 0], [UNIT, 0, 0], [0, 0, UNIT]])
+        .colour(1, &[0, 1, 2])
+}
+
+#[test]
+fn sibling_chains_under_a_child_share_its_offset() {
+    let arm = marker("arm", [UNIT, 0, 0])
+        .child(marker("s1", [0, UNIT, 0]).child(marker("tip", [0, 0, UNIT])))
+        .child(marker("s2", [0, 2 * UNIT, 0]))
+        .child(marker("s3", [0, 3 * UNIT, 0]).child(marker("tip2", [UNIT, 0, 0])));
+    let base = marker("base", [0, 0, 0])
+        .child(arm)
+        .child(marker("leg", [0, 0, -UNIT]));
+    let workspace = Workspace::new("sibling_chains");
+    workspace.write("unit.3do", &build_3do(&[base]));
+    workspace.run(&["unit.3do", "--no-textures"]);
+
+    let obj = parse_obj(&workspace.read("unit.obj"));
+    assert_eq!(
+        obj.objects,
+        ["base", "arm", "s1", "tip", "s2", "s3", "tip2", "leg"]
+    );
+    let pivots: Vec<[f64; 3]> = obj.vertexes.iter().step_by(3).copied().collect();
+    assert_eq!(
+        pivots,
+        [
+            [0.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [-1.0, 1.0, 0.0],
+            [-1.0, 1.0, 1.0],
+            [-1.0, 2.0, 0.0],
+            [-1.0, 3.0, 0.0],
+            [-2.0, 3.0, 0.0],
+            [0.0, 0.0, -1.0],
+        ]
+    );
+
+    let output = workspace.run(&["unit.3do", "--no-textures", "--rebuild", "--debug-offsets"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("      tip2: from parent [65536, 0, 0], placed at [131072, 196608, 0]"),
+        "{}",
+        stdout
+    );
+}
+
+/// The vertexes of an obj written with --embed-transforms moved back into
+/// place by the `# transform:` comment before each object.
+fn placed_by_transforms(text: &str) -> Vec<[f64; 3]> {
+    let mut pivots: HashMap<String, [f64; 3]> = HashMap::new();
+    let mut pivot = [0.0; 3];
+    let mut vertexes = Vec::new();
+    for line in text.lines() {
+        if let Some(fields) = line.strip_prefix("# transform:") {
+            let mut parent = [0.0; 3];
+            for field in fields.split_whitespace() {
+                if let Some(name) = field.strip_prefix("parent=") {
+                    parent = pivots[name];
+                }
+            }
+            let offset: Vec<f64> = fields
+                .split_once("offset=")
+                .unwrap()
+                .1
+                .split_whitespace()
+                .map(|c| c.parse().unwrap())
+                .collect();
+            pivot = [0, 1, 2].map(|i| parent[i] + offset[i]);
+        } else if let Some(name) = line.strip_prefix("o ") {
+            pivots.insert(name.to_string(), pivot);
+        } else if let Some(position) = line.strip_prefix("v ") {
+            let c: Vec<f64> = position
+                .split_whitespace()
+                .map(|c| c.parse().unwrap())
+                .collect();
+            vertexes.push([0, 1, 2].map(|i| c[i] + pivot[i]));
+        }
+    }
+    vertexes
+}
+
+#[test]
+fn transform_comments_put_pieces_back_in_place() {
+    let arm = marker("arm", [UNIT, 0, UNIT / 2])
+        .child(marker("s1", [0, UNIT, 0]).child(marker("tip", [0, 0, UNIT])))
+        .child(marker("s2", [-UNIT, 2 * UNIT, 0]));
+    let base = square("base", [0, 0, 0])
+        .colour(3, &[0, 1, 2, 3])
+        .child(arm)
+        .child(marker("leg", [0, 0, -UNIT]));
+    let workspace = Workspace::new("transforms");
+    workspace.write("unit.3do", &build_3do(&[base]));
+    workspace.run(&["unit.3do", "--no-textures", "-o", "world.obj"]);
+    workspace.run(&[
+        "unit.3do",
+        "--no-textures",
+        "--embed-transforms",
+        "-o",
+        "local.obj",
+    ]);
+
+    let world = parse_obj(&workspace.read("world.obj")).vertexes;
+    let local = workspace.read("local.obj");
+    assert_ne!(parse_obj(&local).vertexes, world);
+    assert_eq!(placed_by_transforms(&local), world);
+}
+
+#[test]
+fn faces_use_their_own_pieces_vertexes() {
+    let workspace = Workspace::new("faces");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&["unit.3do", "--no-textures"]);
+
+    let obj = parse_obj(&workspace.read("unit.obj"));
+    let faces: Vec<(&str, &[usize])> = obj
+        .faces
+        .iter()
+        .map(|face| (face.object.as_str(), face.corners.as_slice()))
+        .collect();
+    assert_eq!(
+        faces,
+        [
+            ("base", &[1, 2, 3, 4][..]),
+            ("turret", &[5, 6, 7, 8][..]),
+            ("barrel", &[9, 10, 11][..]),
+        ]
+    );
+}
+
+#[test]
+fn primitives_get_their_colour_or_texture() {
+    let workspace = Workspace::new("materials");
+    workspace.write("unit.3do", &unit());
+    workspace.write(
+        "gaf_textures/tex.gaf",
+        &build_gaf(&[("armtex1", vec![Frame::new(2, 1, &[1, 2])])]),
+    );
+    workspace.run(&["unit.3do"]);
+
+    let obj = parse_obj(&workspace.read("unit.obj"));
+    let materials: Vec<&str> = obj
+        .faces
+        .iter()
+        .map(|face| face.material.as_str())
+        .collect();
+    assert_eq!(materials, ["808000_3", "armtex1", "000080_4"]);
+
+    let mtl = parse_mtl(&workspace.read("unit.mtl"));
+    assert_eq!(mtl["808000_3"]["Kd"], "0.5019608 0.5019608 0");
+    assert_eq!(mtl["000080_4"]["Kd"], "0 0 0.5019608");
+    assert_eq!(mtl["armtex1"]["map_Kd"], "./textures/armtex1.bmp");
+}
+
+/// The texture coordinates of a textured square and a coloured triangle
+/// converted with `--uv-mode mode`, and the ones each face uses.
+fn uvs(mode: &str) -> (Vec<[f64; 2]>, Vec<Vec<usize>>) {
+    let workspace = Workspace::new(&format!("uvs_{}", mode));
+    let base = square("base", [0, 0, 0])
+        .texture("armtex1", &[0, 1, 2, 3])
+        .colour(3, &[0, 1, 3]);
+    workspace.write("unit.3do", &build_3do(&[base]));
+    workspace.run(&["unit.3do", "--no-textures", "--uv-mode", mode]);
+
+    let obj = parse_obj(&workspace.read("unit.obj"));
+    let faces = obj.faces.into_iter().map(|face| face.uv_corners).collect();
+    (obj.uvs, faces)
+}
+
+#[test]
+fn quad_uvs_are_shared_by_every_face() {
+    let (uvs, faces) = uvs("quad");
+    assert_eq!(uvs, [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]);
+    assert_eq!(faces, [vec![1, 2, 3, 4], vec![1, 2, 3]]);
+}
+
+#[test]
+fn per_face_uvs_follow_each_faces_shape() {
+    let (uvs, faces) = uvs("per-face");
+    assert_eq!(
+        uvs,
+        [
+            [0.0, 1.0],
+            [1.0, 1.0],
+            [1.0, 0.0],
+            [0.0, 0.0],
+            [0.0, 1.0],
+            [1.0, 1.0],
+            [0.0, 0.0],
+        ]
+    );
+    assert_eq!(faces, [vec![1, 2, 3, 4], vec![5, 6, 7]]);
+}
+
+#[test]
+fn no_uvs_leaves_them_out() {
+    let (uvs, faces) = uvs("none");
+    assert!(uvs.is_empty());
+    assert_eq!(faces, [Vec::<usize>::new(), Vec::new()]);
+}
+
+#[test]
+fn primitives_without_a_surface_are_left_out() {
+    let workspace = Workspace::new("surfaceless");
+    let piece = square("base", [0, 0, 0])
+        .colour(1, &[0, 1, 2])
+        .texture("", &[0, 2, 3]);
+    workspace.write("flat.3do", &build_3do(&[piece]));
+    workspace.run(&["flat.3do", "--no-textures"]);
+
+    let obj = parse_obj(&workspace.read("flat.obj"));
+    assert_eq!(obj.faces.len(), 1);
+    assert_eq!(obj.faces[0].material, "800000_1");
+    assert_eq!(obj.faces[0].corners, [1, 2, 3]);
+}
+
+/// A flat `size` by `size` grid of unit squares, each its own primitive
+/// drawn with `surface`.
+fn grid(size: u16, surface: impl Fn(Piece, &[u16]) -> Piece) -> Vec<u8> {
+    let row = size + 1;
+    let vertexes: Vec<[i32; 3]> = (0..row * row)
+        .map(|i| [(i % row) as i32 * UNIT, 0, (i / row) as i32 * UNIT])
+        .collect();
+    let mut piece = Piece::new("base", [0, 0, 0]).vertexes(&vertexes);
+    for z in 0..size {
+        for x in 0..size {
+            let corner = z * row + x;
+            piece = surface(piece, &[corner, corner + 1, corner + row + 1, corner + row]);
+        }
+    }
+    build_3do(&[piece])
+}
+
+#[test]
+fn coplanar_faces_merge_only_when_coloured() {
+    let workspace = Workspace::new("merge");
+    workspace.write(
+        "colour.3do",
+        &grid(3, |piece, corners| piece.colour(3, corners)),
+    );
+    workspace.write(
+        "texture.3do",
+        &grid(3, |piece, corners| piece.texture("armtex1", corners)),
+    );
+    workspace.run(&[
+        "colour.3do",
+        "texture.3do",
+        "--no-textures",
+        "--merge-coplanar",
+    ]);
+
+    let colour = parse_obj(&workspace.read("colour.obj"));
+    // each row becomes a strip, and strips share more than one edge
+    assert_eq!(colour.faces.len(), 3);
+    assert!(colour.faces.iter().all(|face| face.corners.len() == 8));
+    let texture = parse_obj(&workspace.read("texture.obj"));
+    assert_eq!(texture.faces.len(), 9);
+    assert!(texture
+        .faces
+        .iter()
+        .all(|face| face.uv_corners == [1, 2, 3, 4]));
+}
+
+/// Converts `data` expecting it to fail, returning what it printed.
+fn failure(test: &str, data: &[u8]) -> String {
+    let workspace = Workspace::new(test);
+    workspace.write("unit.3do", data);
+    let output = workspace.output(&["unit.3do", "--no-textures"]);
+    // 101 would be a panic
+    assert_eq!(output.status.code(), Some(1));
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Sets the u32 at `offset` of `data`.
+fn patch(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn offsets_past_the_end_are_errors() {
+    // the root's child, vertexes and primitives, and the turret's first
+    // primitive's corners, each pointing past the end in turn
+    let sites = [
+        (48, "an object at 0xfffff0 running past the end of the file"),
+        (36, "piece base has vertexes at 0xfffff0"),
+        (40, "piece base has primitives at 0xfffff0"),
+    ];
+    for (field, expected) in sites {
+        let mut data = unit();
+        patch(&mut data, field, 0xfffff0);
+        let stderr = failure("past_the_end", &data);
+        assert!(stderr.contains(expected), "{}", stderr);
+    }
+
+    let mut data = unit();
+    let primitives = u32::from_le_bytes(data[40..44].try_into().unwrap()) as usize;
+    patch(&mut data, primitives + 12, 0xfffff0);
+    let stderr = failure("past_the_end", &data);
+    assert!(
+        stderr.contains("piece base has primitive 0 with corners at 0xfffff0"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn validate_reports_what_the_game_would_trip_over() {
+    let turret = square("turret", [0, UNIT, 0]).texture("armtex1", &[0, 1, 2, 3]);
+    let base = square("base", [0, 0, 0])
+        .colour(3, &[0, 1, 2])
+        .child(turret);
+    let mut data = build_3do(&[base]);
+    patch(&mut data, 32, 7);
+    // the turret's header is second, and its primitive gets a colour too
+    let primitive = u32::from_le_bytes(data[52 + 40..52 + 44].try_into().unwrap()) as usize;
+    patch(&mut data, primitive + 20, 5);
+    patch(&mut data, primitive + 28, 1);
+
+    let workspace = Workspace::new("validate");
+    workspace.write("unit.3do", &data);
+    let output = workspace.output(&["validate", "unit.3do", "--max-depth", "1"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let problems: Vec<&str> = stdout.lines().skip(1).map(str::trim).collect();
+    assert_eq!(
+        problems,
+        [
+            "base has always_0 = 0x7, the file may be corrupt",
+            "base has 1 vertexes no primitive uses",
+            "primitive 0 of turret has both a colour and a texture, --material-priority picks one",
+            "primitive 0 of turret has unknown_1 = 0x5 instead of 0",
+            "2 levels of pieces, more than 1",
+        ]
+    );
+}
+
+#[test]
+fn unchanged_models_are_counted_as_skipped() {
+    let workspace = Workspace::new("skipped");
+    workspace.write("a.3do", &unit());
+    workspace.write("b.3do", &unit());
+    let args = ["a.3do", "b.3do", "--no-textures"];
+    let first = workspace.run(&args);
+    assert!(String::from_utf8_lossy(&first.stderr).contains("Converted 2 of 2 models."));
+
+    let second = workspace.run(&args);
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(
+        stderr
+            .contains("Converted 0 of 2 models, skipped 2 unchanged since their last conversion."),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn changed_gafs_and_option_files_are_converted_again() {
+    let workspace = Workspace::new("stale");
+    let piece = square("base", [0, 0, 0]).texture("armtex1", &[0, 1, 2, 3]);
+    workspace.write("unit.3do", &build_3do(&[piece]));
+    let gaf = |index| build_gaf(&[("armtex1", vec![Frame::new(1, 1, &[index])])]);
+    workspace.write("gaf_textures/tex.gaf", &gaf(1));
+    workspace.write("names.txt", b"armtex1 metal\n");
+    let args = ["unit.3do", "--out-dir", "out", "--texture-map", "names.txt"];
+    let skipped = |output: Output| {
+        String::from_utf8_lossy(&output.stdout)
+            .contains("hasn't changed since it was last converted")
+    };
+
+    assert!(!skipped(workspace.run(&args)));
+    assert!(workspace.path("out/.ta-3do-to-obj-cache.json").exists());
+    assert!(skipped(workspace.run(&args)));
+
+    workspace.write("gaf_textures/tex.gaf", &gaf(2));
+    assert!(!skipped(workspace.run(&args)));
+    assert_eq!(
+        bmp_pixels(&workspace.path("out/textures/metal.bmp")),
+        [PALETTE[2]]
+    );
+
+    workspace.write("names.txt", b"armtex1 steel\n");
+    assert!(!skipped(workspace.run(&args)));
+    assert!(workspace.path("out/textures/steel.bmp").exists());
+}
+
+/// Converts a square textured with `frame`, returning the pixels of the
+/// texture it extracts.
+fn extract(test: &str, frame: Frame) -> Vec<[u8; 3]> {
+    let workspace = Workspace::new(test);
+    let piece = square("base", [0, 0, 0]).texture("armtex1", &[0, 1, 2, 3]);
+    workspace.write("unit.3do", &build_3do(&[piece]));
+    workspace.write(
+        "gaf_textures/tex.gaf",
+        &build_gaf(&[
+            ("other", vec![Frame::new(1, 1, &[8])]),
+            ("armtex1", vec![frame]),
+        ]),
+    );
+    workspace.run(&["unit.3do"]);
+    bmp_pixels(&workspace.path("textures/armtex1.bmp"))
+}
+
+#[test]
+fn uncompressed_frames_become_textures() {
+    let pixels = extract("uncompressed", Frame::new(3, 2, &[1, 2, 3, 4, 5, 6]));
+    let expected: Vec<[u8; 3]> = [1, 2, 3, 4, 5, 6].map(|i| PALETTE[i]).to_vec();
+    assert_eq!(pixels, expected);
+}
+
+#[test]
+fn compressed_frames_become_textures() {
+    // a row of literals, one of repeats between skips, and one that ends
+    // in skipped pixels, which come back as the transparency index
+    const T: usize = TRANSPARENCY_INDEX as usize;
+    #[rustfmt::skip]
+    let indexes: [usize; 18] = [
+        1, 2, 3, 4, 5, 6,
+        T, T, 7, 7, 7, T,
+        8, 8, 1, T, T, T,
+    ];
+    let pixels = extract(
+        "compressed",
+        Frame::new(6, 3, &indexes.map(|i| i as u8)).compressed(),
+    );
+    let expected: Vec<[u8; 3]> = indexes.iter().map(|&i| PALETTE[i]).collect();
+    assert_eq!(pixels, expected);
+}
+
+/// Converts `unit()` with `--format format`, returning the file it writes
+/// as `output`.
+fn export(format: &str, output: &str) -> Vec<u8> {
+    let workspace = Workspace::new(&format!("export_{}", format));
+    workspace.write("unit.3do", &unit());
+    workspace.run(&["unit.3do", "--no-textures", "--format", format]);
+    fs::read(workspace.path(output)).unwrap()
+}
+
+/// How many times `text` is in `data`.
+fn count(data: &[u8], text: &str) -> usize {
+    data.windows(text.len())
+        .filter(|window| *window == text.as_bytes())
+        .count()
+}
+
+#[test]
+fn dae_has_a_node_per_piece() {
+    let dae = export("dae", "unit.dae");
+    for piece in ["base", "turret", "barrel"] {
+        let node = format!("<node id=\"{}\" name=\"{}\" type=\"NODE\">", piece, piece);
+        assert_eq!(count(&dae, &node), 1, "{}", node);
+    }
+}
+
+#[test]
+fn fbx_has_a_model_per_piece() {
+    let fbx = export("fbx", "unit.fbx");
+    for piece in ["base", "turret", "barrel"] {
+        assert_eq!(count(&fbx, &format!("\"Model::{}\", \"Mesh\"", piece)), 1);
+    }
+}
+
+#[test]
+fn usda_has_an_xform_per_piece() {
+    let usda = export("usda", "unit.usda");
+    for piece in ["base", "turret", "barrel"] {
+        assert_eq!(count(&usda, &format!("def Xform \"{}\"", piece)), 1);
+    }
+}
+
+#[test]
+fn usdz_packages_the_usda() {
+    let usdz = export("usdz", "unit.usdz");
+    assert!(usdz.starts_with(b"PK\x03\x04"));
+    assert_eq!(count(&usdz, "unit.usda"), 2);
+    assert_eq!(count(&usdz, "def Xform \"turret\""), 1);
+}
+
+#[test]
+fn x3d_and_vrml_have_a_transform_per_piece() {
+    let x3d = export("x3d", "unit.x3d");
+    let vrml = export("vrml", "unit.wrl");
+    for piece in ["base", "turret", "barrel"] {
+        assert_eq!(count(&x3d, &format!("<Transform DEF=\"{}\"", piece)), 1);
+        assert_eq!(count(&vrml, &format!("DEF {} Transform", piece)), 1);
+    }
+}
+
+/// The names of the nodes of a gltf, in order.
+fn node_names(gltf: &[u8]) -> Vec<String> {
+    let gltf: serde_json::Value = serde_json::from_slice(gltf).unwrap();
+    gltf["nodes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|node| node["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn gltf_has_a_node_per_piece() {
+    let gltf = export("gltf", "unit.gltf");
+    assert_eq!(node_names(&gltf), ["base", "turret", "barrel"]);
+}
+
+#[test]
+fn glb_holds_the_gltf_json() {
+    let glb = export("glb", "unit.glb");
+    let u32_at = |offset: usize| u32::from_le_bytes(glb[offset..offset + 4].try_into().unwrap());
+    assert!(glb.starts_with(b"glTF"));
+    assert_eq!(u32_at(8) as usize, glb.len());
+    assert_eq!(&glb[16..20], b"JSON");
+    let json = &glb[20..20 + u32_at(12) as usize];
+    assert_eq!(node_names(json), ["base", "turret", "barrel"]);
+}
+
+#[test]
+fn stl_and_3mf_have_every_triangle() {
+    // two for each square and one for the barrel
+    let stl = export("stl", "unit.stl");
+    assert_eq!(u32::from_le_bytes(stl[80..84].try_into().unwrap()), 5);
+    assert_eq!(stl.len(), 84 + 5 * 50);
+
+    let threemf = export("3mf", "unit.3mf");
+    assert!(threemf.starts_with(b"PK\x03\x04"));
+    assert_eq!(count(&threemf, "<triangle "), 5);
+}
+
+#[test]
+fn ply_has_a_face_per_primitive() {
+    let ply = String::from_utf8(export("ply", "unit.ply")).unwrap();
+    assert!(ply.starts_with("ply\n"), "{}", ply);
+    assert!(ply.contains("\nelement face 3\n"), "{}", ply);
+}
+
+#[test]
+fn s3o_converts_back_to_the_same_pieces() {
+    let workspace = Workspace::new("s3o");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&["unit.3do", "--no-textures", "--format", "s3o"]);
+    workspace.run(&["unit.s3o", "--no-textures", "-o", "back.obj"]);
+
+    let obj = parse_obj(&workspace.read("back.obj"));
+    assert_eq!(obj.objects, ["base", "turret", "barrel"]);
+}
+
+/// The pieces of the 3do at `path` in the workspace, by converting it.
+fn objects(workspace: &Workspace, path: &str) -> Obj {
+    workspace.run(&[path, "--no-textures", "-o", "check.obj"]);
+    parse_obj(&workspace.read("check.obj"))
+}
+
+#[test]
+fn to3do_nests_objects_by_their_names() {
+    let workspace = Workspace::new("to3do");
+    workspace.write(
+        "model.obj",
+        b"o base\nv 0 0 0\nv 1 0 0\nv 1 0 1\nf 1 2 3\n\
+          o base.turret\nv 0 1 0\nv 1 1 0\nv 1 1 1\nf 4 5 6\n",
+    );
+    workspace.run(&["to3do", "model.obj", "-o", "model.3do"]);
+    assert_eq!(objects(&workspace, "model.3do").objects, ["base", "turret"]);
+}
+
+#[test]
+fn merge_attaches_pieces_under_the_named_one() {
+    let workspace = Workspace::new("merge");
+    workspace.write("hull.3do", &unit());
+    workspace.write("gun.3do", &build_3do(&[marker("gun", [0, 0, 0])]));
+    workspace.run(&["merge", "hull.3do", "turret", "gun.3do", "-o", "tank.3do"]);
+    assert_eq!(
+        objects(&workspace, "tank.3do").objects,
+        ["base", "turret", "gun", "barrel"]
+    );
+}
+
+#[test]
+fn transform_scales_every_vertex() {
+    let workspace = Workspace::new("transform");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&["transform", "unit.3do", "--scale", "2", "-o", "big.3do"]);
+
+    let small = objects(&workspace, "unit.3do").vertexes;
+    let big = objects(&workspace, "big.3do").vertexes;
+    let doubled: Vec<[f64; 3]> = small.iter().map(|v| v.map(|c| c * 2.0)).collect();
+    assert_eq!(big, doubled);
+}
+
+#[test]
+fn optimize_merges_vertexes_and_drops_degenerate_primitives() {
+    let workspace = Workspace::new("optimize");
+    let base = Piece::new("base", [0, 0, 0])
+        .vertexes(&[
+            [0, 0, 0],
+            [UNIT, 0, 0],
+            [UNIT, 0, UNIT],
+            [0, 0, UNIT],
+            [0, 0, 0],
+        ])
+        .colour(3, &[0, 1, 2, 3])
+        .colour(4, &[0, 4, 1]);
+    workspace.write("unit.3do", &build_3do(&[base]));
+    workspace.run(&["optimize", "unit.3do", "-o", "small.3do"]);
+
+    let obj = objects(&workspace, "small.3do");
+    assert_eq!(obj.vertexes.len(), 4);
+    assert_eq!(obj.faces.len(), 1);
+}
+
+#[test]
+fn retexture_renames_textures() {
+    let workspace = Workspace::new("retexture");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&[
+        "retexture",
+        "unit.3do",
+        "--map",
+        "armtex1=coretex1",
+        "-o",
+        "core.3do",
+    ]);
+
+    let obj = objects(&workspace, "core.3do");
+    let materials: Vec<&str> = obj
+        .faces
+        .iter()
+        .map(|face| face.material.as_str())
+        .collect();
+    assert_eq!(materials, ["808000_3", "coretex1", "000080_4"]);
+}
+
+#[test]
+fn rename_piece_renames_pieces() {
+    let workspace = Workspace::new("rename_piece");
+    workspace.write("unit.3do", &unit());
+    workspace.run(&[
+        "rename-piece",
+        "unit.3do",
+        "--map",
+        "turret=head",
+        "-o",
+        "renamed.3do",
+    ]);
+    assert_eq!(
+        objects(&workspace, "renamed.3do").objects,
+        ["base", "head", "barrel"]
+    );
+}
+
+#[test]
+fn hpi_list_prints_every_file() {
+    let workspace = Workspace::new("hpi_list");
+    let model = unit();
+    workspace.write(
+        "units.hpi",
+        &build_hpi(&[("objects3d/unit.3do", &model), ("readme.txt", b"hi")]),
+    );
+    let output = workspace.run(&["hpi", "list", "units.hpi"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "objects3d/".to_string(),
+            format!("objects3d/unit.3do\t{}\tnone", model.len()),
+            "readme.txt\t2\tnone".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn diff_exits_with_1_only_when_models_differ() {
+    let workspace = Workspace::new("diff");
+    workspace.write("a.3do", &unit());
+    workspace.write("b.3do", &unit());
+    workspace.run(&["diff", "a.3do", "b.3do"]);
+
+    workspace.run(&["transform", "a.3do", "--scale", "2", "-o", "b.3do"]);
+    let output = workspace.output(&["diff", "a.3do", "b.3do"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!output.stdout.is_empty());
+}
+
+/// Sends `request` to the server on `port` and reads its one line reply.
+fn ask(port: u16, request: &str) -> serde_json::Value {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    writeln!(stream, "{}", request).unwrap();
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).unwrap();
+    serde_json::from_str(&reply).unwrap()
+}
+
+#[test]
+fn serve_converts_each_request_until_shut_down() {
+    let workspace = Workspace::new("serve");
+    workspace.write("unit.3do", &unit());
+    workspace.write(
+        "gaf_textures/tex.gaf",
+        &build_gaf(&[("armtex1", vec![Frame::new(2, 1, &[1, 2])])]),
+    );
+    // a port nothing else is listening on
+    let port = TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut server = workspace.spawn(&["--serve", &port.to_string()]);
+    // it logs to stderr, as it would with --json
+    let mut stderr = BufReader::new(server.stderr.take().unwrap());
+    let mut line = String::new();
+    while !line.starts_with("Listening") {
+        line.clear();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0, "the server quit");
+    }
+
+    let reply = ask(port, r#"{"input": "unit.3do", "inline": true}"#);
+    assert_eq!(reply["success"], true, "{}", reply);
+    let obj = parse_obj(reply["files"]["unit.obj"].as_str().unwrap());
+    assert_eq!(obj.objects, ["base", "turret", "barrel"]);
+
+    let reply = ask(port, r#"{"input": "missing.3do"}"#);
+    assert_eq!(reply["success"], false, "{}", reply);
+    assert!(reply["error"].is_string(), "{}", reply);
+
+    assert_eq!(ask(port, r#"{"shutdown": true}"#)["shutdown"], true);
+    assert!(server.wait().unwrap().success());
+}
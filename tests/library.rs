@@ -0,0 +1,183 @@
+//! Reading and writing 3do files through the library, without converting
+//! them.
+
+use ta_3do_to_obj::{
+    read_3do, visit_3do, write_3do, Model, Piece, Primitive, RawPiece, RawPrimitive, Surface,
+    Visitor,
+};
+
+/// One unit in the 3do's fixed-point coordinates.
+const UNIT: i32 = 65536;
+
+fn piece(name: &str, from_parent: [i32; 3], vertexes: &[[i32; 3]]) -> Piece {
+    Piece {
+        raw: RawPiece {
+            version_signature: 1,
+            name: Some(name.as_bytes().to_vec()),
+            from_parent,
+            selection_primitive: u32::MAX,
+            always_0: 0,
+            vertexes: vertexes.to_vec(),
+            primitives: Vec::new(),
+        },
+        children: Vec::new(),
+    }
+}
+
+fn primitive(colour: Option<u32>, texture: Option<&str>, corners: &[u16]) -> RawPrimitive {
+    RawPrimitive {
+        color_index: colour.unwrap_or(0),
+        is_colored: colour.is_some() as u32,
+        texture_name: texture.map(|texture| texture.as_bytes().to_vec()),
+        vertex_indexes: corners.to_vec(),
+        always_0: 0,
+        unknown_1: 0,
+        unknown_2: 0,
+    }
+}
+
+/// A base with a turret on it and an empty flare next to that.
+fn unit() -> Model {
+    let mut turret = piece(
+        "turret",
+        [0, UNIT, 0],
+        &[[0, 0, 0], [UNIT, 0, 0], [0, 0, UNIT]],
+    );
+    turret
+        .raw
+        .primitives
+        .push(primitive(None, Some("armtex1"), &[0, 1, 2]));
+    let mut base = piece(
+        "base",
+        [0, 0, 0],
+        &[[0, 0, 0], [UNIT, 0, 0], [UNIT, 0, UNIT], [0, 0, UNIT]],
+    );
+    base.raw
+        .primitives
+        .push(primitive(Some(3), None, &[0, 1, 2, 3]));
+    base.children = vec![turret, piece("flare", [UNIT, 0, 0], &[])];
+    Model { pieces: vec![base] }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn writes_the_object_records_first() {
+    let data = write_3do(&unit());
+
+    // base, turret and flare, with the turret as the base's child and the
+    // flare as the turret's sibling
+    assert_eq!(u32_at(&data, 48), 52);
+    assert_eq!(u32_at(&data, 52 + 44), 104);
+    assert_eq!(u32_at(&data, 104 + 44), 0);
+    let name = u32_at(&data, 28) as usize;
+    assert_eq!(&data[name..name + 5], b"base\0");
+    assert_eq!(u32_at(&data, 52 + 20) as i32, UNIT);
+}
+
+#[test]
+fn reads_back_what_it_wrote() {
+    let model = unit();
+    let data = write_3do(&model);
+    let copy = read_3do(&data).unwrap();
+    assert_eq!(copy, model);
+    assert_eq!(write_3do(&copy), data);
+}
+
+#[test]
+fn changes_survive_a_write() {
+    let mut model = read_3do(&write_3do(&unit())).unwrap();
+    model.pieces[0].children[1].raw.from_parent = [0, 0, 2 * UNIT];
+    model.pieces[0].children.remove(0);
+
+    let copy = read_3do(&write_3do(&model)).unwrap();
+    assert_eq!(copy, model);
+}
+
+#[test]
+fn refuses_offsets_past_the_end() {
+    let mut data = write_3do(&unit());
+    // the base's vertex array
+    data[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(read_3do(&data).is_err());
+}
+
+/// Everything `visit_3do` hands over, as one line per callback.
+#[derive(Default)]
+struct Log(Vec<String>);
+
+impl Visitor for Log {
+    fn piece_start(&mut self, name: &str, origin: [i32; 3], depth: u32) {
+        self.0
+            .push(format!("start {} {:?} {}", name, origin, depth));
+    }
+
+    fn vertex(&mut self, position: [i32; 3]) {
+        self.0.push(format!("vertex {:?}", position));
+    }
+
+    fn primitive(&mut self, primitive: &Primitive) {
+        let surface = match &primitive.surface {
+            Some(Surface::Colour { rgb, .. }) => format!("{:?}", rgb),
+            _ => String::new(),
+        };
+        self.0.push(format!(
+            "primitive {} {:?}{}",
+            primitive
+                .surface
+                .as_ref()
+                .map_or("none".into(), |surface| surface.material_name()),
+            primitive.vertex_indexes,
+            surface
+        ));
+    }
+
+    fn piece_end(&mut self, name: &str) {
+        self.0.push(format!("end {}", name));
+    }
+}
+
+#[test]
+fn visits_pieces_parents_first() {
+    let mut model = unit();
+    model.pieces[0].children[1]
+        .raw
+        .primitives
+        .push(primitive(Some(300), None, &[]));
+    let mut log = Log::default();
+    visit_3do(&write_3do(&model), &mut log).unwrap();
+
+    let expected = [
+        "start base [0, 0, 0] 0",
+        "vertex [0, 0, 0]",
+        "vertex [65536, 0, 0]",
+        "vertex [65536, 0, 65536]",
+        "vertex [0, 0, 65536]",
+        "primitive 808000_3 [0, 1, 2, 3]Some([128, 128, 0])",
+        "end base",
+        "start turret [0, 65536, 0] 1",
+        "vertex [0, 65536, 0]",
+        "vertex [65536, 65536, 0]",
+        "vertex [0, 65536, 65536]",
+        "primitive armtex1 [0, 1, 2]",
+        "end turret",
+        "start flare [65536, 0, 0] 1",
+        "primitive colour_300 []None",
+        "end flare",
+    ];
+    assert_eq!(log.0, expected);
+}
+
+#[test]
+fn refuses_trees_too_deep_to_read() {
+    let mut model = unit();
+    for _ in 0..ta_3do_to_obj::records::DEFAULT_MAX_DEPTH {
+        let mut parent = piece("link", [0, 0, 0], &[]);
+        parent.children = model.pieces;
+        model.pieces = vec![parent];
+    }
+    let err = read_3do(&write_3do(&model)).unwrap_err();
+    assert!(err.contains("levels deep"), "{}", err);
+}
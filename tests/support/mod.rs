@@ -0,0 +1,566 @@
+//! Builds tiny 3do, gaf and hpi files for the tests, runs the converter on
+//! them and reads back the obj and mtl it writes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+
+/// One unit in the 3do's fixed-point coordinates.
+pub const UNIT: i32 = 65536;
+
+/// Palette index the gaf frames are built with as their transparent colour.
+/// Compressed frames write it as skipped pixels.
+pub const TRANSPARENCY_INDEX: u8 = 9;
+
+/// Bytes in a 3do piece's header.
+const PIECE_HEADER_SIZE: usize = 52;
+
+struct Primitive {
+    colour: Option<u32>,
+    texture: Option<String>,
+    corners: Vec<u16>,
+}
+
+/// A piece of a 3do, with its children.
+pub struct Piece {
+    name: String,
+    offset: [i32; 3],
+    vertexes: Vec<[i32; 3]>,
+    primitives: Vec<Primitive>,
+    children: Vec<Piece>,
+}
+
+impl Piece {
+    pub fn new(name: &str, offset: [i32; 3]) -> Piece {
+        Piece {
+            name: name.to_string(),
+            offset,
+            vertexes: Vec::new(),
+            primitives: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn vertexes(mut self, vertexes: &[[i32; 3]]) -> Piece {
+        self.vertexes.extend_from_slice(vertexes);
+        self
+    }
+
+    /// A primitive drawn in the palette colour `index`.
+    pub fn colour(mut self, index: u32, corners: &[u16]) -> Piece {
+        self.primitives.push(Primitive {
+            colour: Some(index),
+            texture: None,
+            corners: corners.to_vec(),
+        });
+        self
+    }
+
+    /// A primitive naming the texture `name`.
+    pub fn texture(mut self, name: &str, corners: &[u16]) -> Piece {
+        self.primitives.push(Primitive {
+            colour: None,
+            texture: Some(name.to_string()),
+            corners: corners.to_vec(),
+        });
+        self
+    }
+
+    pub fn child(mut self, child: Piece) -> Piece {
+        self.children.push(child);
+        self
+    }
+}
+
+/// A piece with the header index of its next sibling and first child.
+struct Linked<'a> {
+    piece: &'a Piece,
+    sibling: Option<usize>,
+    child: Option<usize>,
+}
+
+/// Puts `pieces` and everything under them in the order their headers are
+/// written, returning the index of the first.
+fn flatten<'a>(pieces: &'a [Piece], out: &mut Vec<Linked<'a>>) -> Option<usize> {
+    let mut first = None;
+    let mut previous: Option<usize> = None;
+    for piece in pieces {
+        let index = out.len();
+        out.push(Linked {
+            piece,
+            sibling: None,
+            child: None,
+        });
+        out[index].child = flatten(&piece.children, out);
+        match previous {
+            Some(previous) => out[previous].sibling = Some(index),
+            None => first = Some(index),
+        }
+        previous = Some(index);
+    }
+    first
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// A 3do with `pieces` as the root piece and its siblings. Every header
+/// comes first, depth first, followed by the names, vertexes and primitives.
+pub fn build_3do(pieces: &[Piece]) -> Vec<u8> {
+    let mut linked = Vec::new();
+    flatten(pieces, &mut linked);
+    let header_offset = |index: usize| (index * PIECE_HEADER_SIZE) as u32;
+    let data_start = linked.len() * PIECE_HEADER_SIZE;
+
+    let mut headers = Vec::new();
+    let mut data = Vec::new();
+    for link in &linked {
+        let piece = link.piece;
+        let offset = |data: &Vec<u8>| (data_start + data.len()) as u32;
+
+        let name_offset = offset(&data);
+        data.extend_from_slice(piece.name.as_bytes());
+        data.push(0);
+
+        let vertex_offset = offset(&data);
+        for vertex in &piece.vertexes {
+            for &value in vertex {
+                push_i32(&mut data, value);
+            }
+        }
+
+        let mut placed = Vec::new();
+        for primitive in &piece.primitives {
+            let corner_offset = offset(&data);
+            for &corner in &primitive.corners {
+                push_u16(&mut data, corner);
+            }
+            let texture_offset = match &primitive.texture {
+                Some(texture) => {
+                    let texture_offset = offset(&data);
+                    data.extend_from_slice(texture.as_bytes());
+                    data.push(0);
+                    texture_offset
+                }
+                None => 0,
+            };
+            placed.push((corner_offset, texture_offset));
+        }
+
+        let primitive_offset = offset(&data);
+        for (primitive, (corner_offset, texture_offset)) in piece.primitives.iter().zip(placed) {
+            push_u32(&mut data, primitive.colour.unwrap_or(0));
+            push_u32(&mut data, primitive.corners.len() as u32);
+            push_u32(&mut data, 0);
+            push_u32(&mut data, corner_offset);
+            push_u32(&mut data, texture_offset);
+            push_u32(&mut data, 0);
+            push_u32(&mut data, 0);
+            push_u32(&mut data, primitive.colour.is_some() as u32);
+        }
+
+        push_u32(&mut headers, 1);
+        push_u32(&mut headers, piece.vertexes.len() as u32);
+        push_u32(&mut headers, piece.primitives.len() as u32);
+        push_u32(&mut headers, 0);
+        for value in piece.offset {
+            push_i32(&mut headers, value);
+        }
+        push_u32(&mut headers, name_offset);
+        push_u32(&mut headers, 0);
+        push_u32(&mut headers, vertex_offset);
+        push_u32(&mut headers, primitive_offset);
+        push_u32(&mut headers, link.sibling.map_or(0, header_offset));
+        push_u32(&mut headers, link.child.map_or(0, header_offset));
+    }
+
+    headers.extend(data);
+    headers
+}
+
+/// A gaf frame, as palette indices a row at a time.
+pub struct Frame {
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+    compressed: bool,
+}
+
+impl Frame {
+    pub fn new(width: u16, height: u16, pixels: &[u8]) -> Frame {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        Frame {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+            compressed: false,
+        }
+    }
+
+    /// The frame run length encoded the way the game's gafs are. The
+    /// transparency index is written as skipped pixels, which is what
+    /// they're read back as.
+    pub fn compressed(mut self) -> Frame {
+        self.compressed = true;
+        self
+    }
+
+    fn encoded(&self) -> Vec<u8> {
+        if !self.compressed {
+            return self.pixels.clone();
+        }
+        let mut out = Vec::new();
+        for row in self.pixels.chunks(self.width as usize) {
+            let line = compress_line(row);
+            push_u16(&mut out, line.len() as u16);
+            out.extend(line);
+        }
+        out
+    }
+}
+
+/// One row as skips of the transparency index, repeats and literal runs,
+/// leaving out the skip at the end of the row.
+fn compress_line(row: &[u8]) -> Vec<u8> {
+    let end = row
+        .iter()
+        .rposition(|&index| index != TRANSPARENCY_INDEX)
+        .map_or(0, |i| i + 1);
+    let run = |from: usize, limit: usize| {
+        row[from..end]
+            .iter()
+            .take(limit)
+            .take_while(|&&index| index == row[from])
+            .count()
+    };
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < end {
+        if row[i] == TRANSPARENCY_INDEX {
+            let skipped = run(i, 127);
+            out.push(((skipped as u8) << 1) | 0x01);
+            i += skipped;
+        } else if run(i, 64) > 1 {
+            let repeated = run(i, 64);
+            out.push((((repeated - 1) as u8) << 2) | 0x02);
+            out.push(row[i]);
+            i += repeated;
+        } else {
+            let mut literal = 1;
+            while i + literal < end
+                && literal < 64
+                && row[i + literal] != TRANSPARENCY_INDEX
+                && run(i + literal, 2) < 2
+            {
+                literal += 1;
+            }
+            out.push(((literal - 1) as u8) << 2);
+            out.extend_from_slice(&row[i..i + literal]);
+            i += literal;
+        }
+    }
+    out
+}
+
+/// A Total Annihilation gaf holding `entries`, each a name and its frames.
+pub fn build_gaf(entries: &[(&str, Vec<Frame>)]) -> Vec<u8> {
+    let entries_start = 12 + 4 * entries.len();
+    let mut pointers = Vec::new();
+    let mut body = Vec::new();
+    for (name, frames) in entries {
+        let entry_offset = entries_start + body.len();
+        push_u32(&mut pointers, entry_offset as u32);
+
+        push_u16(&mut body, frames.len() as u16);
+        push_u16(&mut body, 1);
+        push_u32(&mut body, 0);
+        let mut padded_name = name.as_bytes().to_vec();
+        padded_name.resize(32, 0);
+        body.extend(padded_name);
+
+        let mut frame_offset = entry_offset + 40 + 8 * frames.len();
+        let mut frame_data = Vec::new();
+        for frame in frames {
+            push_u32(&mut body, frame_offset as u32);
+            push_u32(&mut body, 0);
+
+            let pixels = frame.encoded();
+            push_u16(&mut frame_data, frame.width);
+            push_u16(&mut frame_data, frame.height);
+            push_u16(&mut frame_data, 0);
+            push_u16(&mut frame_data, 0);
+            frame_data.push(TRANSPARENCY_INDEX);
+            frame_data.push(frame.compressed as u8);
+            push_u16(&mut frame_data, 0);
+            push_u32(&mut frame_data, 0);
+            push_u32(&mut frame_data, (frame_offset + 24) as u32);
+            push_u32(&mut frame_data, 0);
+            frame_offset += 24 + pixels.len();
+            frame_data.extend(pixels);
+        }
+        body.extend(frame_data);
+    }
+
+    let mut out = Vec::new();
+    push_u32(&mut out, 0x00010100);
+    push_u32(&mut out, entries.len() as u32);
+    push_u32(&mut out, 0);
+    out.extend(pointers);
+    out.extend(body);
+    out
+}
+
+/// Lays out the directory of the files at `paths`, each split into the
+/// folders it's under and its name, returning the directory's offset. Where
+/// each file's data offset goes is added to `contents`, to be filled in once
+/// the files follow the directory.
+fn hpi_directory<'a>(
+    out: &mut Vec<u8>,
+    paths: &[(Vec<&'a str>, &'a [u8])],
+    contents: &mut Vec<(usize, &'a [u8])>,
+) -> u32 {
+    let mut names: Vec<&str> = Vec::new();
+    for (path, _) in paths {
+        if !names.contains(&path[0]) {
+            names.push(path[0]);
+        }
+    }
+    let directory_offset = out.len() as u32;
+    push_u32(out, names.len() as u32);
+    push_u32(out, directory_offset + 8);
+    let entries_start = out.len();
+    out.resize(entries_start + 9 * names.len(), 0);
+
+    for (i, name) in names.iter().enumerate() {
+        let name_offset = out.len() as u32;
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+
+        let under: Vec<(Vec<&str>, &[u8])> = paths
+            .iter()
+            .filter(|(path, _)| path[0] == *name && path.len() > 1)
+            .map(|(path, data)| (path[1..].to_vec(), *data))
+            .collect();
+        let is_directory = !under.is_empty();
+        let data_offset = if is_directory {
+            hpi_directory(out, &under, contents)
+        } else {
+            let (_, data) = paths.iter().find(|(path, _)| path[0] == *name).unwrap();
+            let data_offset = out.len() as u32;
+            contents.push((out.len(), data));
+            push_u32(out, 0);
+            push_u32(out, data.len() as u32);
+            out.push(0);
+            data_offset
+        };
+
+        let entry = entries_start + 9 * i;
+        out[entry..entry + 4].copy_from_slice(&name_offset.to_le_bytes());
+        out[entry + 4..entry + 8].copy_from_slice(&data_offset.to_le_bytes());
+        out[entry + 8] = is_directory as u8;
+    }
+    directory_offset
+}
+
+/// A Total Annihilation HPI archive of `files`, each a path inside it and
+/// the file's contents, stored without compression or encryption.
+pub fn build_hpi(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let paths: Vec<(Vec<&str>, &[u8])> = files
+        .iter()
+        .map(|(path, data)| (path.split('/').collect(), *data))
+        .collect();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"HAPI");
+    push_u32(&mut out, 0x00010000);
+    // directory size, key and start, once the directory is laid out
+    out.resize(20, 0);
+    let mut contents = Vec::new();
+    let start = hpi_directory(&mut out, &paths, &mut contents);
+    let directory_size = out.len() as u32;
+    out[8..12].copy_from_slice(&directory_size.to_le_bytes());
+    out[16..20].copy_from_slice(&start.to_le_bytes());
+
+    for (data_offset, data) in contents {
+        let offset = out.len() as u32;
+        out[data_offset..data_offset + 4].copy_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// A face of an obj, by the `o` and `usemtl` it comes under.
+pub struct ObjFace {
+    pub object: String,
+    pub material: String,
+    /// vertex numbers, from 1 as the obj has them
+    pub corners: Vec<usize>,
+    /// texture coordinate numbers of the corners, empty if it has none
+    pub uv_corners: Vec<usize>,
+}
+
+pub struct Obj {
+    pub objects: Vec<String>,
+    pub vertexes: Vec<[f64; 3]>,
+    pub uvs: Vec<[f64; 2]>,
+    pub faces: Vec<ObjFace>,
+}
+
+/// The objects, vertexes, texture coordinates and faces of an obj, ignoring
+/// everything else.
+pub fn parse_obj(text: &str) -> Obj {
+    let mut obj = Obj {
+        objects: Vec::new(),
+        vertexes: Vec::new(),
+        uvs: Vec::new(),
+        faces: Vec::new(),
+    };
+    let mut material = String::new();
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("o") => obj.objects.push(words.collect::<Vec<_>>().join(" ")),
+            Some("usemtl") => material = words.collect::<Vec<_>>().join(" "),
+            Some("v") => {
+                let values: Vec<f64> = words.map(|word| word.parse().unwrap()).collect();
+                obj.vertexes.push([values[0], values[1], values[2]]);
+            }
+            Some("vt") => {
+                let values: Vec<f64> = words.map(|word| word.parse().unwrap()).collect();
+                obj.uvs.push([values[0], values[1]]);
+            }
+            Some("f") => {
+                let corners: Vec<Vec<usize>> = words
+                    .map(|word| word.split('/').map(|n| n.parse().unwrap()).collect())
+                    .collect();
+                obj.faces.push(ObjFace {
+                    object: obj.objects.last().cloned().unwrap_or_default(),
+                    material: material.clone(),
+                    corners: corners.iter().map(|corner| corner[0]).collect(),
+                    uv_corners: corners
+                        .iter()
+                        .filter_map(|corner| corner.get(1))
+                        .copied()
+                        .collect(),
+                })
+            }
+            _ => {}
+        }
+    }
+    obj
+}
+
+/// Each material of an mtl, with its statements after `newmtl` by keyword.
+pub fn parse_mtl(text: &str) -> BTreeMap<String, BTreeMap<String, String>> {
+    let mut materials: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut current = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if keyword == "newmtl" {
+            current = Some(rest.to_string());
+            materials.entry(rest.to_string()).or_default();
+        } else if let Some(name) = &current {
+            let statements = materials.get_mut(name).unwrap();
+            statements.insert(keyword.to_string(), rest.to_string());
+        }
+    }
+    materials
+}
+
+/// A folder of its own for one test, removed again when it's dropped.
+pub struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    pub fn new(test: &str) -> Workspace {
+        let dir = std::env::temp_dir()
+            .join("ta-3do-to-obj-tests")
+            .join(format!("{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Workspace { dir }
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn write(&self, path: &str, data: &[u8]) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path))
+            .unwrap_or_else(|err| panic!("unable to read {}: {}", path, err))
+    }
+
+    /// Runs the converter in the workspace, whatever comes of it.
+    pub fn output(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_ta-3do-to-obj"))
+            .args(args)
+            .current_dir(&self.dir)
+            .output()
+            .unwrap()
+    }
+
+    /// Starts the converter in the workspace without waiting for it, with
+    /// its stderr piped back.
+    pub fn spawn(&self, args: &[&str]) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_ta-3do-to-obj"))
+            .args(args)
+            .current_dir(&self.dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    /// Runs the converter in the workspace, failing the test if it fails.
+    pub fn run(&self, args: &[&str]) -> Output {
+        let output = self.output(args);
+        assert!(
+            output.status.success(),
+            "ta-3do-to-obj {} failed:\n{}{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The colour of each pixel of a bmp the converter wrote, a row at a time.
+pub fn bmp_pixels(path: &Path) -> Vec<[u8; 3]> {
+    let image = bmp::open(path).unwrap();
+    let mut pixels = Vec::new();
+    for y in 0..image.get_height() {
+        for x in 0..image.get_width() {
+            let pixel = image.get_pixel(x, y);
+            pixels.push([pixel.r, pixel.g, pixel.b]);
+        }
+    }
+    pixels
+}